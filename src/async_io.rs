@@ -0,0 +1,377 @@
+//
+// async_io.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+//! Serialization to and deserialization from asynchronous I/O objects.
+
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use bytes::BytesMut;
+use serde::{de, ser};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::Decoder;
+use tokio_util::io::poll_read_buf;
+use super::codec::HipackCodec;
+use super::de::from_slice;
+use super::error::{Result, Error};
+use super::framing::MessageWriter;
+use super::ser::to_vec;
+
+
+// Writes the contents of the buffer starting at the given position, which
+// is advanced as data is written.
+fn poll_write_from<W>(writer: &mut W, cx: &mut Context, buf: &[u8], pos: &mut usize, limit: usize)
+    -> Poll<Result<()>>
+    where W: AsyncWrite + Unpin + ?Sized
+{
+    while buf.len() - *pos > limit {
+        match Pin::new(&mut *writer).poll_write(cx, &buf[*pos..]) {
+            Poll::Ready(Ok(0)) => {
+                let error = io::Error::new(io::ErrorKind::WriteZero, "failed to write whole buffer");
+                return Poll::Ready(Err(Error::IoError(error)));
+            },
+            Poll::Ready(Ok(written)) => *pos += written,
+            Poll::Ready(Err(error)) => return Poll::Ready(Err(Error::IoError(error))),
+            Poll::Pending => return Poll::Pending,
+        }
+    }
+    Poll::Ready(Ok(()))
+}
+
+
+/// Future returned by `to_writer_async()`.
+pub struct WriteAsync<'a, W: 'a + ?Sized> {
+    writer: &'a mut W,
+    buf: Vec<u8>,
+    pos: usize,
+    // Error from serializing the value, reported when first polled.
+    error: Option<Error>,
+}
+
+
+impl<'a, W> Future for WriteAsync<'a, W> where W: AsyncWrite + Unpin + ?Sized {
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        if let Some(error) = this.error.take() {
+            return Poll::Ready(Err(error));
+        }
+        match poll_write_from(this.writer, cx, &this.buf, &mut this.pos, 0) {
+            Poll::Ready(Ok(())) => Pin::new(&mut *this.writer).poll_flush(cx).map_err(From::from),
+            poll => poll,
+        }
+    }
+}
+
+
+/// Serializes a value to an asynchronous writer.
+///
+/// The value is serialized into memory right away, and the returned
+/// future writes it out without blocking, then flushes the writer.
+#[inline]
+pub fn to_writer_async<'a, W, T>(writer: &'a mut W, value: &T) -> WriteAsync<'a, W>
+    where W: AsyncWrite + Unpin + ?Sized, T: ser::Serialize
+{
+    let (buf, error) = match to_vec(value) {
+        Ok(buf) => (buf, None),
+        Err(error) => (Vec::new(), Some(error)),
+    };
+    WriteAsync { writer: writer, buf: buf, pos: 0, error: error }
+}
+
+
+/// Writes messages in the framed wire format, see the `framing` module,
+/// to an asynchronous writer.
+///
+/// Messages are serialized into an internal buffer, which is written out
+/// as the writer accepts more data. Once more than a high watermark of
+/// data is buffered, writing a message waits until the writer has taken
+/// enough of it, so bursts of messages cannot use unbounded memory when
+/// the other end does not keep up.
+pub struct AsyncMessageWriter<W> {
+    writer: W,
+    buf: Vec<u8>,
+    pos: usize,
+    high_watermark: usize,
+}
+
+
+impl<W: AsyncWrite + Unpin> AsyncMessageWriter<W> {
+    #[inline]
+    pub fn new(writer: W) -> Self {
+        AsyncMessageWriter { writer: writer, buf: Vec::new(), pos: 0, high_watermark: 64 * 1024 }
+    }
+
+    /// Sets the amount of bytes which may be buffered before writing a
+    /// message waits for the writer. The default is 64 KiB, and zero
+    /// makes each message be written out and flushed before continuing.
+    #[inline]
+    pub fn high_watermark(mut self, bytes: usize) -> Self {
+        self.high_watermark = bytes;
+        self
+    }
+
+    /// Returns the amount of bytes which have not been written out yet.
+    #[inline]
+    pub fn buffered(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    /// Serializes a message into the buffer. The returned future waits
+    /// until the buffered data is below the high watermark.
+    pub fn write_message<T>(&mut self, value: &T) -> Drain<'_, W>
+        where T: ser::Serialize
+    {
+        let len = self.buf.len();
+        let error = MessageWriter::new(&mut self.buf).write_message(value).err();
+        if error.is_some() {
+            self.buf.truncate(len);
+        }
+        let limit = self.high_watermark;
+        Drain { writer: self, limit: limit, error: error }
+    }
+
+    /// Returns a future which writes out all the buffered data and
+    /// flushes the writer.
+    #[inline]
+    pub fn flush(&mut self) -> Drain<'_, W> {
+        Drain { writer: self, limit: 0, error: None }
+    }
+
+    /// Returns the writer, dropping any data which has not been written
+    /// out yet.
+    #[inline]
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context, limit: usize) -> Poll<Result<()>> {
+        if self.buffered() <= limit {
+            return Poll::Ready(Ok(()));
+        }
+        let result = poll_write_from(&mut self.writer, cx, &self.buf, &mut self.pos, limit);
+        if self.pos == self.buf.len() {
+            self.buf.clear();
+            self.pos = 0;
+        } else if self.pos > self.high_watermark {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+        }
+        match result {
+            Poll::Ready(Ok(())) if limit == 0 => {
+                Pin::new(&mut self.writer).poll_flush(cx).map_err(From::from)
+            },
+            result => result,
+        }
+    }
+}
+
+
+/// Future returned by `AsyncMessageWriter::write_message()` and
+/// `AsyncMessageWriter::flush()`.
+pub struct Drain<'a, W: 'a> {
+    writer: &'a mut AsyncMessageWriter<W>,
+    limit: usize,
+    // Error from serializing the message, reported when first polled.
+    error: Option<Error>,
+}
+
+
+impl<'a, W: AsyncWrite + Unpin> Future for Drain<'a, W> {
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        if let Some(error) = this.error.take() {
+            return Poll::Ready(Err(error));
+        }
+        this.writer.poll_drain(cx, this.limit)
+    }
+}
+
+
+/// Future returned by `from_reader_async()`.
+pub struct ReadAsync<R, T> {
+    reader: R,
+    buf: Vec<u8>,
+    output: PhantomData<fn() -> T>,
+}
+
+
+impl<R, T> Future for ReadAsync<R, T> where R: AsyncRead + Unpin, T: de::DeserializeOwned {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T>> {
+        let this = self.get_mut();
+        loop {
+            match poll_read_buf(Pin::new(&mut this.reader), cx, &mut this.buf) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(from_slice(&this.buf)),
+                Poll::Ready(Ok(_)) => (),
+                Poll::Ready(Err(error)) => return Poll::Ready(Err(Error::IoError(error))),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+
+/// Deserializes a value from an asynchronous reader.
+///
+/// The returned future reads the whole input into memory without
+/// blocking, then deserializes it.
+#[inline]
+pub fn from_reader_async<R, T>(reader: R) -> ReadAsync<R, T>
+    where R: AsyncRead + Unpin, T: de::DeserializeOwned
+{
+    ReadAsync { reader: reader, buf: Vec::new(), output: PhantomData }
+}
+
+
+/// Reads messages in the framed wire format, see the `framing` module,
+/// from an asynchronous reader.
+///
+/// Input is buffered internally, and each message is deserialized once
+/// it has been received completely.
+pub struct AsyncMessageReader<R> {
+    reader: R,
+    buf: BytesMut,
+    eof: bool,
+}
+
+
+impl<R: AsyncRead + Unpin> AsyncMessageReader<R> {
+    #[inline]
+    pub fn new(reader: R) -> Self {
+        AsyncMessageReader { reader: reader, buf: BytesMut::new(), eof: false }
+    }
+
+    /// Returns a future which reads the payload of the next message,
+    /// skipping its headers, or `None` once the end of the input is
+    /// reached in between messages.
+    #[inline]
+    pub fn read_message<T>(&mut self) -> ReadMessage<'_, R, T>
+        where T: de::DeserializeOwned
+    {
+        ReadMessage { reader: self, codec: HipackCodec::new() }
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+
+/// Future returned by `AsyncMessageReader::read_message()`.
+pub struct ReadMessage<'a, R: 'a, T> {
+    reader: &'a mut AsyncMessageReader<R>,
+    codec: HipackCodec<T>,
+}
+
+
+impl<'a, R, T> Future for ReadMessage<'a, R, T>
+    where R: AsyncRead + Unpin, T: de::DeserializeOwned
+{
+    type Output = Result<Option<T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Option<T>>> {
+        let this = self.get_mut();
+        let reader = &mut *this.reader;
+        loop {
+            if reader.eof {
+                return Poll::Ready(this.codec.decode_eof(&mut reader.buf));
+            }
+            match this.codec.decode(&mut reader.buf) {
+                Ok(None) => (),
+                result => return Poll::Ready(result),
+            }
+            match poll_read_buf(Pin::new(&mut reader.reader), cx, &mut reader.buf) {
+                Poll::Ready(Ok(0)) => reader.eof = true,
+                Poll::Ready(Ok(_)) => (),
+                Poll::Ready(Err(error)) => return Poll::Ready(Err(Error::IoError(error))),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use tokio::io::AsyncReadExt;
+    use tokio::runtime::Builder;
+
+    #[test]
+    fn test_to_writer_async() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let mut value = BTreeMap::new();
+        value.insert("a", vec![1, 2]);
+
+        let mut writer = Vec::new();
+        runtime.block_on(to_writer_async(&mut writer, &value)).unwrap();
+        assert_eq!("{a:[1,2]}", String::from_utf8(writer).unwrap());
+
+        let mut writer = Vec::new();
+        assert!(runtime.block_on(to_writer_async(&mut writer, &vec![()])).is_err());
+        assert!(writer.is_empty());
+    }
+
+    #[test]
+    fn test_async_message_writer() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let mut value = BTreeMap::new();
+        value.insert("a", 1);
+
+        let (client, mut server) = tokio::io::duplex(10);
+        let mut writer = AsyncMessageWriter::new(client).high_watermark(16);
+        runtime.block_on(writer.write_message(&value)).unwrap();
+        runtime.block_on(writer.write_message(&value)).unwrap();
+        assert_eq!(12, writer.buffered());
+        // Over the watermark, with the other end only taking 10 bytes.
+        runtime.block_on(writer.write_message(&value)).unwrap();
+        assert_eq!(8, writer.buffered());
+        assert!(runtime.block_on(writer.write_message(&vec![()])).is_err());
+        assert_eq!(8, writer.buffered());
+
+        let mut buf = [0; 18];
+        runtime.block_on(server.read_exact(&mut buf[..10])).unwrap();
+        runtime.block_on(writer.flush()).unwrap();
+        assert_eq!(0, writer.buffered());
+        runtime.block_on(server.read_exact(&mut buf[10..])).unwrap();
+        assert_eq!(b"{a:1}\n{a:1}\n{a:1}\n", &buf);
+    }
+
+    #[test]
+    fn test_from_reader_async() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let value: BTreeMap<String, Vec<u32>> =
+            runtime.block_on(from_reader_async(&b"a: [1 2]"[..])).unwrap();
+        assert_eq!(vec![1, 2], value["a"]);
+        assert!(runtime.block_on(from_reader_async::<_, Vec<u32>>(&b"a: ["[..])).is_err());
+    }
+
+    #[test]
+    fn test_async_message_reader() {
+        type Message = BTreeMap<String, u32>;
+
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let input = (&b"{a: 1} :hea"[..]).chain(&b"ders {id: 2} {b"[..]).chain(&b": 2}\nc: 3"[..]);
+        let mut reader = AsyncMessageReader::new(input);
+        let first: Option<Message> = runtime.block_on(reader.read_message()).unwrap();
+        assert_eq!(1, first.unwrap()["a"]);
+        let second: Option<Message> = runtime.block_on(reader.read_message()).unwrap();
+        assert_eq!(2, second.unwrap()["b"]);
+        let third: Option<Message> = runtime.block_on(reader.read_message()).unwrap();
+        assert_eq!(3, third.unwrap()["c"]);
+        let end: Option<Message> = runtime.block_on(reader.read_message()).unwrap();
+        assert!(end.is_none());
+    }
+}
@@ -0,0 +1,84 @@
+//
+// base64.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+// Standard Base64 (RFC 4648) with padding, for byte strings annotated
+// as `:base64`.
+
+const ALPHABET: &'static [u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+
+pub fn encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+            group | (byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+
+#[inline]
+fn decode_char(ch: u8) -> Option<u32> {
+    ALPHABET.iter().position(|&c| c == ch).map(|value| value as u32)
+}
+
+
+/// Returns `None` if the input is not valid Base64. Padding is optional,
+/// but when present it must make the length a multiple of four.
+pub fn decode(encoded: &str) -> Option<Vec<u8>> {
+    let padded = encoded.as_bytes();
+    let padding = padded.iter().rev().take_while(|&&ch| ch == b'=').count();
+    let encoded = &padded[..padded.len() - padding];
+    if encoded.len() % 4 == 1 || (padding != 0 && (padding > 2 || padded.len() % 4 != 0)) {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(encoded.len() * 3 / 4);
+    for chunk in encoded.chunks(4) {
+        let mut group = 0;
+        for (i, &ch) in chunk.iter().enumerate() {
+            match decode_char(ch) {
+                Some(value) => group |= value << (18 - 6 * i),
+                None => return None,
+            }
+        }
+        for i in 0..chunk.len() - 1 {
+            bytes.push((group >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64() {
+        for &(bytes, encoded) in &[(&b""[..], ""), (b"f", "Zg=="), (b"fo", "Zm8="),
+                                   (b"foo", "Zm9v"), (b"foob", "Zm9vYg=="),
+                                   (b"\xFF\x00\xFE", "/wD+")] {
+            assert_eq!(encoded, encode(bytes));
+            assert_eq!(Some(bytes.to_vec()), decode(encoded));
+        }
+        assert_eq!(Some(b"fo".to_vec()), decode("Zm8"));
+        assert_eq!(None, decode("Zm9vY"));
+        assert_eq!(None, decode("Zm9v!"));
+        assert_eq!(None, decode("Zm8=="));
+        assert_eq!(None, decode("Zg="));
+        assert_eq!(None, decode("Zm9v===="));
+        assert_eq!(None, decode("="));
+    }
+}
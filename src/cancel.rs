@@ -0,0 +1,41 @@
+//
+// cancel.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+//! Cancellation of serialization and deserialization, e.g. to stop
+//! reading a large document once a request times out. Tokens are given
+//! to `Serializer::cancellation()` or `Deserializer::cancellation()`,
+//! which check them as they go through dictionaries and lists.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+
+/// Signals that an ongoing serialization or deserialization has to be
+/// aborted. The token is checked before each item of a dictionary or list
+/// is processed, and the operation fails with `Error::Cancelled` once it
+/// reports being cancelled.
+///
+/// Implemented for closures returning `bool`, and for `Arc<AtomicBool>`
+/// which can be set from another thread.
+pub trait CancellationToken {
+    fn is_cancelled(&self) -> bool;
+}
+
+
+impl<F> CancellationToken for F where F: Fn() -> bool {
+    #[inline]
+    fn is_cancelled(&self) -> bool {
+        self()
+    }
+}
+
+
+impl CancellationToken for Arc<AtomicBool> {
+    #[inline]
+    fn is_cancelled(&self) -> bool {
+        self.load(Ordering::Relaxed)
+    }
+}
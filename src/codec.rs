@@ -0,0 +1,146 @@
+//
+// codec.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+//! Codec for framed HiPack messages, to be used with `tokio_util::codec`.
+
+use std::marker::PhantomData;
+use bytes::{Buf, BufMut, BytesMut};
+use serde::{de, ser};
+use tokio_util::codec::{Decoder, Encoder};
+use super::error::{Result, Error};
+use super::framing::{MessageReader, MessageWriter};
+use super::parser::is_incomplete;
+
+
+/// Encodes and decodes messages in the framed wire format described in
+/// the `framing` module, e.g. for `Framed<TcpStream, HipackCodec<T>>`.
+///
+/// Decoded messages are of type `T`, and their headers are skipped.
+/// Messages must be enclosed in braces to be decoded before the end of
+/// the input. Buffered data is parsed again each time more of it arrives
+/// until a message is complete, so very large messages are better read
+/// with a `MessageReader`.
+pub struct HipackCodec<T> {
+    output: PhantomData<fn() -> T>,
+}
+
+
+impl<T> HipackCodec<T> {
+    #[inline]
+    pub fn new() -> Self {
+        HipackCodec { output: PhantomData }
+    }
+}
+
+
+impl<T> Default for HipackCodec<T> {
+    #[inline]
+    fn default() -> Self {
+        HipackCodec::new()
+    }
+}
+
+
+impl<T: de::DeserializeOwned> HipackCodec<T> {
+    fn decode_message(&mut self, src: &mut BytesMut, eof: bool) -> Result<Option<T>> {
+        let (result, offset) = {
+            let mut reader = MessageReader::new(&src[..]);
+            let result = reader.read_message();
+            (result, reader.byte_offset())
+        };
+        match result {
+            // Without braces, the message only ends with the input.
+            Ok(Some(_)) if !eof && offset == src.len() && !src.ends_with(b"}") => Ok(None),
+            Ok(Some(value)) => {
+                src.advance(offset);
+                Ok(Some(value))
+            },
+            Ok(None) => {
+                if eof {
+                    src.clear();
+                }
+                Ok(None)
+            },
+            Err(ref err) if !eof && is_incomplete(err.inner(), src.len()) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+
+impl<T: de::DeserializeOwned> Decoder for HipackCodec<T> {
+    type Item = T;
+    type Error = Error;
+
+    #[inline]
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<T>> {
+        self.decode_message(src, false)
+    }
+
+    #[inline]
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<T>> {
+        self.decode_message(src, true)
+    }
+}
+
+
+impl<T, U: ser::Serialize> Encoder<U> for HipackCodec<T> {
+    type Error = Error;
+
+    #[inline]
+    fn encode(&mut self, item: U, dst: &mut BytesMut) -> Result<()> {
+        MessageWriter::new(dst.writer()).write_message(&item)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+    use std::collections::BTreeMap;
+
+    type Message = BTreeMap<String, Vec<u32>>;
+
+    #[test]
+    fn test_encode() {
+        let mut message = Message::new();
+        message.insert("a".to_string(), vec![1, 2]);
+        let mut buf = BytesMut::new();
+        let mut codec = HipackCodec::<Message>::new();
+        codec.encode(&message, &mut buf).unwrap();
+        codec.encode(Message::new(), &mut buf).unwrap();
+        assert_eq!(&b"{a:[1,2]}\n{}\n"[..], &buf[..]);
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut codec = HipackCodec::<Message>::new();
+        let mut buf = BytesMut::from(&b"{a: [1"[..]);
+        assert_eq!(None, codec.decode(&mut buf).unwrap());
+        buf.extend_from_slice(b" 2]} :headers {id: 1} {b");
+        assert_eq!(vec![1, 2], codec.decode(&mut buf).unwrap().unwrap()["a"]);
+        assert_eq!(None, codec.decode(&mut buf).unwrap());
+        buf.extend_from_slice(b": []}\n");
+        assert!(codec.decode(&mut buf).unwrap().unwrap()["b"].is_empty());
+        assert_eq!(None, codec.decode(&mut buf).unwrap());
+        buf.extend_from_slice(b"c: [3]");
+        assert_eq!(None, codec.decode(&mut buf).unwrap());
+        assert_eq!(vec![3], codec.decode_eof(&mut buf).unwrap().unwrap()["c"]);
+        assert_eq!(None, codec.decode_eof(&mut buf).unwrap());
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"{a: [\"unterminated"[..]);
+        assert_eq!(None, codec.decode(&mut buf).unwrap());
+        assert!(codec.decode_eof(&mut buf).is_err());
+
+        let mut buf = BytesMut::from(&b"{a: x}"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+        let mut buf = BytesMut::from(&b"{a: [1"[..]);
+        assert!(codec.decode_eof(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,277 @@
+//
+// codegen.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use std::collections::{BTreeMap, VecDeque};
+use super::schema::{Field, Schema};
+use super::value::Value;
+
+
+const KEYWORDS: &'static [&'static str] = &[
+    "as", "async", "await", "box", "break", "const", "continue", "crate", "dyn",
+    "else", "enum", "extern", "false", "fn", "for", "if", "impl", "in", "let",
+    "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "self",
+    "static", "struct", "super", "trait", "true", "try", "type", "unsafe", "use",
+    "where", "while", "yield",
+];
+
+// Keywords which cannot be raw identifiers.
+const RESERVED_IDENTS: &'static [&'static str] = &["crate", "self", "super"];
+
+// Types used in the output, which generated structs must not shadow.
+const RESERVED_TYPES: &'static [&'static str] = &["Option", "String", "Vec"];
+
+
+/// Generates Rust definitions for the types of a document with the given
+/// schema, with `name` as the name of the top-level struct. Dictionaries
+/// become structs with `Serialize` and `Deserialize` derives, which need
+/// `serde_derive` in scope, and keys missing from some samples become
+/// `Option` fields. Integers get the first of `i64`, `u64`, `i128` and
+/// `u128` which holds the values seen, and values of unknown or varying
+/// type become `Value`s. Keys which are keywords become raw identifiers.
+///
+/// The output is meant as a starting point, to be reviewed and adapted.
+pub fn generate_types(schema: &Schema, name: &str) -> String {
+    let mut generator = Generator::default();
+    generator.names.extend(RESERVED_TYPES.iter().map(|name| name.to_string()));
+    let root = generator.type_name(schema, name);
+    match *schema {
+        Schema::Dict(_) => (),
+        _ => generator.output.push_str(&format!("pub type {} = {};\n", type_ident(name), root)),
+    }
+    generator.write_pending();
+    generator.output
+}
+
+
+/// Generates Rust definitions for the types of a sample document, see
+/// `generate_types()`.
+pub fn generate_types_from_value(value: &Value, name: &str) -> String {
+    generate_types(&Schema::infer(value), name)
+}
+
+
+#[derive(Default)]
+struct Generator<'a> {
+    output: String,
+    names: Vec<String>,
+    // Structs referenced by the ones already written.
+    pending: VecDeque<(String, &'a BTreeMap<String, Field>)>,
+}
+
+
+impl<'a> Generator<'a> {
+    // Rust type of values with the given schema, where `hint` is used to
+    // name the struct of dictionaries.
+    fn type_name(&mut self, schema: &'a Schema, hint: &str) -> String {
+        match *schema {
+            Schema::Any | Schema::Union(_) => "serde_hipack::Value".to_string(),
+            Schema::Bool => "bool".to_string(),
+            Schema::Integer { min, max } => integer_type(min, max).to_string(),
+            Schema::Float => "f64".to_string(),
+            Schema::String => "String".to_string(),
+            Schema::List(ref schema) => format!("Vec<{}>", self.type_name(schema, &singular(hint))),
+            Schema::Dict(ref fields) => {
+                let name = unique(type_ident(hint), &self.names);
+                self.names.push(name.clone());
+                self.pending.push_back((name.clone(), fields));
+                name
+            },
+        }
+    }
+
+    fn write_pending(&mut self) {
+        while let Some((name, fields)) = self.pending.pop_front() {
+            if !self.output.is_empty() {
+                self.output.push('\n');
+            }
+            self.output.push_str("#[derive(Debug, Serialize, Deserialize)]\n");
+            self.output.push_str(&format!("pub struct {} {{\n", name));
+            let mut idents = Vec::new();
+            for (key, field) in fields {
+                let ident = unique(field_ident(key), &idents);
+                let mut ty = self.type_name(&field.schema, key);
+                if field.optional {
+                    ty = format!("Option<{}>", ty);
+                    self.output.push_str("    #[serde(default)]\n");
+                }
+                if ident != *key {
+                    self.output.push_str(&format!("    #[serde(rename = {:?})]\n", key));
+                }
+                self.output.push_str(&format!("    pub {}: {},\n", ident, ty));
+                idents.push(ident);
+            }
+            self.output.push_str("}\n");
+        }
+    }
+}
+
+
+// Smallest of the usual integer types which holds the given range, or
+// `Value` if none does.
+fn integer_type(min: i128, max: u128) -> &'static str {
+    if min >= i64::min_value() as i128 && max <= i64::max_value() as u128 {
+        "i64"
+    } else if min == 0 && max <= u64::max_value() as u128 {
+        "u64"
+    } else if max <= i128::max_value() as u128 {
+        "i128"
+    } else if min == 0 {
+        "u128"
+    } else {
+        "serde_hipack::Value"
+    }
+}
+
+
+fn unique(name: String, used: &[String]) -> String {
+    let mut candidate = name.clone();
+    let mut suffix = 2;
+    while used.contains(&candidate) {
+        candidate = format!("{}{}", name, suffix);
+        suffix += 1;
+    }
+    candidate
+}
+
+
+// Name for the items of a list, e.g. "server" for "servers".
+fn singular(name: &str) -> String {
+    if name.len() > 1 && name.ends_with('s') && !name.ends_with("ss") {
+        name[..name.len() - 1].to_string()
+    } else {
+        format!("{}_item", name)
+    }
+}
+
+
+// Converts a key into a snake_case identifier.
+fn field_ident(key: &str) -> String {
+    let mut ident = String::new();
+    let mut lower = false;
+    for ch in key.chars() {
+        if ch.is_ascii_alphanumeric() {
+            if ch.is_ascii_uppercase() && lower {
+                ident.push('_');
+            }
+            lower = ch.is_ascii_lowercase() || ch.is_ascii_digit();
+            ident.push(ch.to_ascii_lowercase());
+        } else {
+            if !ident.ends_with('_') {
+                ident.push('_');
+            }
+            lower = false;
+        }
+    }
+    let ident = ident.trim_matches('_').to_string();
+    if ident.is_empty() {
+        "field".to_string()
+    } else if ident.starts_with(|ch: char| ch.is_ascii_digit()) || RESERVED_IDENTS.contains(&&ident[..]) {
+        format!("_{}", ident)
+    } else if KEYWORDS.contains(&&ident[..]) {
+        format!("r#{}", ident)
+    } else {
+        ident
+    }
+}
+
+
+// Converts a key into a CamelCase type name.
+fn type_ident(key: &str) -> String {
+    let mut ident = String::new();
+    let mut upper = true;
+    for ch in key.chars() {
+        if ch.is_ascii_alphanumeric() {
+            if upper {
+                ident.push(ch.to_ascii_uppercase());
+            } else {
+                ident.push(ch);
+            }
+            upper = false;
+        } else {
+            upper = true;
+        }
+    }
+    if ident.is_empty() || ident.starts_with(|ch: char| ch.is_ascii_digit()) {
+        ident.insert(0, 'T');
+    }
+    ident
+}
+
+
+#[cfg(all(test, feature = "de"))]
+mod tests {
+    use super::*;
+    use de::from_str;
+
+    #[test]
+    fn test_generate() {
+        let values: Vec<Value> = vec![
+            from_str("name: \"web\" servers: [{host: \"a\" port: 80}] tls {enabled: True}").unwrap(),
+            from_str("name: \"db\" servers: [{host: \"b\" max-conns: 10}] type: 1.5 x: []").unwrap(),
+        ];
+        let expected = "\
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Config {
+    pub name: String,
+    pub servers: Vec<Server>,
+    #[serde(default)]
+    pub tls: Option<Tls>,
+    #[serde(default)]
+    #[serde(rename = \"type\")]
+    pub r#type: Option<f64>,
+    #[serde(default)]
+    pub x: Option<Vec<serde_hipack::Value>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Server {
+    pub host: String,
+    #[serde(default)]
+    #[serde(rename = \"max-conns\")]
+    pub max_conns: Option<i64>,
+    #[serde(default)]
+    pub port: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Tls {
+    pub enabled: bool,
+}
+";
+        assert_eq!(expected, generate_types(&Schema::infer_all(&values), "Config"));
+    }
+
+    #[test]
+    fn test_generate_names() {
+        assert_eq!("http_port", field_ident("httpPort"));
+        assert_eq!("_2nd", field_ident("2nd"));
+        assert_eq!("HttpServer", type_ident("http-server"));
+        let value: Value = from_str("a: {b: {c: 1}} b: 2").unwrap();
+        let output = generate_types_from_value(&value, "a");
+        assert!(output.contains("pub struct A {\n    pub a: A2,\n    pub b: i64,\n}"));
+        assert!(output.contains("pub struct B {\n    pub c: i64,\n}"));
+        assert_eq!("pub type T = Vec<i64>;\n",
+                   generate_types(&Schema::List(Box::new(Schema::Integer { min: -1, max: 1 })), "t"));
+        assert_eq!("r#match", field_ident("match"));
+        assert_eq!("_self", field_ident("self"));
+        let value: Value = from_str("string {vec: []} option: 1").unwrap();
+        let output = generate_types_from_value(&value, "option");
+        assert!(output.contains("pub struct Option2 {\n    pub option: i64,\n    pub string: String2,\n}"));
+        assert!(output.contains("pub struct String2 {\n    pub vec: Vec<serde_hipack::Value>,\n}"));
+    }
+
+    #[test]
+    fn test_generate_integers() {
+        let types: Vec<&str> = [(0, 1), (i64::min_value() as i128, 0), (0, u64::max_value() as u128),
+                                (-1, u64::max_value() as u128), (0, u128::max_value()),
+                                (-1, u128::max_value())]
+            .iter().map(|&(min, max)| integer_type(min, max)).collect();
+        assert_eq!(vec!["i64", "i64", "u64", "i128", "u128", "serde_hipack::Value"], types);
+        let value: Value = from_str("a: 0xFFFFFFFFFFFFFFFF b: -0x8000000000000001").unwrap();
+        let output = generate_types_from_value(&value, "T");
+        assert!(output.contains("    pub a: u64,\n    pub b: i128,\n"));
+    }
+}
@@ -0,0 +1,83 @@
+//
+// compare.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+//! Structural comparison of HiPack documents, e.g. to check whether a
+//! configuration file changed in more than its formatting. Documents are
+//! compared as they are parsed, without reading them into values.
+
+use std::io::Read;
+use super::error::Result;
+use super::parser::{Parser, Event};
+
+
+fn same_event(a: &Event, b: &Event) -> bool {
+    match (a, b) {
+        (&Event::F64(a), &Event::F64(b)) => a == b || (a.is_nan() && b.is_nan()),
+        (a, b) => a == b,
+    }
+}
+
+
+/// Checks whether two HiPack documents are structurally equal.
+///
+/// Both inputs are parsed in lockstep, without building any intermediate
+/// values, so this is suitable for comparing large documents. Whitespace,
+/// comments, optional separators, and the way numbers are spelled (e.g.
+/// `0x10` and `16`) do not matter, but dictionary keys must appear in the
+/// same order in both documents. Comparison stops at the first difference,
+/// so syntax errors after it are not reported.
+///
+/// Inputs are read one byte at a time: wrap them in a `BufReader` when
+/// they are not already buffered.
+pub fn compare_streams<A, B>(a: A, b: B) -> Result<bool>
+    where A: Read, B: Read
+{
+    let mut a = Parser::new(a.bytes());
+    let mut b = Parser::new(b.bytes());
+    // The parser keeps track of nesting with an explicit stack, and nothing
+    // is built recursively from the events, so nesting needs no limit.
+    a.set_recursion_limit(usize::max_value());
+    b.set_recursion_limit(usize::max_value());
+    loop {
+        match (try!(a.next_event()), try!(b.next_event())) {
+            (None, None) => return Ok(true),
+            (Some(ref ea), Some(ref eb)) if same_event(ea, eb) => (),
+            _ => return Ok(false),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compare(a: &str, b: &str) -> bool {
+        compare_streams(a.as_bytes(), b.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_equal() {
+        assert!(compare("", "{}"));
+        assert!(compare("a: 1, b: [True \"x\"]", "{\n  a: 0x1\n  b [True, \"x\"] # Comment\n}"));
+        assert!(compare("f: NaN", "f:NaN"));
+        let deep = format!("a: {}{}", "[".repeat(100000), "]".repeat(100000));
+        assert!(compare(&deep, &deep));
+    }
+
+    #[test]
+    fn test_different() {
+        assert!(!compare("a: 1", "a: 1.0"));
+        assert!(!compare("a: 1", "a: 1 b: 2"));
+        assert!(!compare("a: [1]", "a: [1 2]"));
+        assert!(!compare("a: 1 b: 2", "b: 2 a: 1"));
+    }
+
+    #[test]
+    fn test_syntax_error() {
+        assert!(compare_streams("a: 1".as_bytes(), "a 1".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,2638 @@
+//
+// de.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Read};
+use std::marker::PhantomData;
+use std::{fmt, mem, str, vec};
+use std::ops::Range;
+use std::time::Instant;
+use serde::de::{self, IntoDeserializer};
+use super::base64;
+use super::cancel::CancellationToken;
+use super::error::{Result, Error, ErrorCode, Warning, WarningCode};
+use super::parser::{Parser, Event};
+pub use super::parser::ParserOptions;
+use super::ser::{Annotated, Commented, NullStrategy, BASE64_ANNOTATION, RAW_VALUE_NAME, to_string};
+use super::value::{self, Value, ANNOTATED_NAME, ANNOTATIONS_KEY, NUMBER_KEY, SPANNED_NAME,
+                   VALUE_KEY, VALUE_NAME};
+
+mod spanned;
+pub use self::spanned::{Position, Spanned};
+
+
+/// Number of items read between checks of the deadline, see
+/// `Deserializer::deadline()`.
+pub const DEADLINE_CHECK_INTERVAL: usize = 64;
+
+
+/// Converts the value of an annotated item before it is deserialized.
+pub type AnnotationHandler = Box<Fn(Value) -> Result<Value>>;
+
+
+pub struct Deserializer<Iter: Iterator<Item=io::Result<u8>>, In = CopiedInput> {
+    parser: Parser<Iter>,
+    input: In,
+    peeked: Option<Event>,
+    null: NullStrategy,
+    overflow: OverflowPolicy,
+    duplicates: Option<DuplicateKeyPolicy>,
+    integral_floats: bool,
+    lenient_bools: bool,
+    handlers: HashMap<String, AnnotationHandler>,
+    cancel: Option<Box<CancellationToken>>,
+    memory: usize,
+    memory_limit: Option<usize>,
+    memory_callback: Option<Box<FnMut(usize)>>,
+    deadline: Option<Instant>,
+    events: usize,
+    fold_keys: bool,
+    aliases: HashMap<String, String>,
+    deprecated: HashMap<String, String>,
+    defaults: BTreeMap<String, Value>,
+    warnings: Option<Box<FnMut(Warning)>>,
+    report: Option<FieldReport>,
+    // Keys of the dictionaries being read, tracked to report deprecated
+    // keys and field presence.
+    path: Vec<String>,
+    // Last key read, as written in the input, for the path of errors.
+    key: String,
+}
+
+
+/// Where the bytes read by a `Deserializer` come from, which tells
+/// whether strings can be borrowed from the input instead of copied.
+pub trait Input<'de> {
+    /// Returns the bytes of the input in `range`, if they outlive `'de`.
+    fn borrow(&self, range: Range<usize>) -> Option<&'de [u8]>;
+}
+
+
+/// Input read from an iterator or a reader, which strings are copied from.
+pub struct CopiedInput;
+
+impl<'de> Input<'de> for CopiedInput {
+    #[inline]
+    fn borrow(&self, _range: Range<usize>) -> Option<&'de [u8]> {
+        None
+    }
+}
+
+
+/// Input read from a slice, which strings can be borrowed from.
+pub struct SliceInput<'a>(&'a [u8]);
+
+impl<'a> Input<'a> for SliceInput<'a> {
+    #[inline]
+    fn borrow(&self, range: Range<usize>) -> Option<&'a [u8]> {
+        self.0.get(range)
+    }
+}
+
+
+/// Paths of the fields found in the input, and of the ones which were
+/// missing and got a default value (e.g. `None` for optional fields),
+/// in the order they are read. Paths are written like the ones given
+/// to `Deserializer::deprecated_key()`, using the names of keys after
+/// applying aliases.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FieldReport {
+    pub present: Vec<String>,
+    pub defaulted: Vec<String>,
+}
+
+
+/// What to do with integers which do not fit in the type they are
+/// deserialized into, e.g. `300` for an `u8` field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    /// Fail with an error which tells the range of the type.
+    Error,
+    /// Use the closest value the type can represent, e.g. `255` for an
+    /// `u8` field, reporting `WarningCode::IntegerClamped`.
+    Saturate,
+}
+
+
+/// What to do with keys which appear more than once in a dictionary.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DuplicateKeyPolicy {
+    /// Fail with `ErrorCode::DuplicateKey`.
+    Error,
+    /// Keep the first value, skipping the entries which repeat the key.
+    FirstWins,
+    /// Keep the last value. Dictionaries are read in full into `Value`s
+    /// before they are deserialized, so errors in their entries are
+    /// reported at the end of the dictionary, without their path.
+    LastWins,
+}
+
+
+impl<Iter> Deserializer<Iter> where Iter: Iterator<Item=io::Result<u8>> {
+    #[inline]
+    pub fn new(rdr: Iter) -> Self {
+        Deserializer::with_input(rdr, CopiedInput)
+    }
+
+    /// Turns the deserializer into an iterator over the messages of an
+    /// input containing several of them back to back.
+    #[inline]
+    pub fn into_iter<T>(self) -> StreamDeserializer<Iter, T>
+        where T: de::DeserializeOwned
+    {
+        StreamDeserializer { de: self, offset: 0, failed: false, output: PhantomData }
+    }
+}
+
+
+impl<'a> Deserializer<io::Bytes<&'a [u8]>, SliceInput<'a>> {
+    /// Creates a deserializer which reads from a slice, and borrows from
+    /// it the strings written without escape sequences, so they can be
+    /// deserialized into `&str` or `Cow<str>` without copying them.
+    #[inline]
+    pub fn from_slice(v: &'a [u8]) -> Self {
+        Deserializer::with_input(v.bytes(), SliceInput(v))
+    }
+}
+
+
+impl<Iter, In> Deserializer<Iter, In> where Iter: Iterator<Item=io::Result<u8>> {
+    #[inline]
+    fn with_input(rdr: Iter, input: In) -> Self {
+        Deserializer {
+            parser: Parser::new(rdr),
+            input: input,
+            peeked: None,
+            null: NullStrategy::Error,
+            overflow: OverflowPolicy::Error,
+            duplicates: None,
+            integral_floats: false,
+            lenient_bools: false,
+            handlers: HashMap::new(),
+            cancel: None,
+            memory: 0,
+            memory_limit: None,
+            memory_callback: None,
+            deadline: None,
+            events: 0,
+            fold_keys: false,
+            aliases: HashMap::new(),
+            deprecated: HashMap::new(),
+            defaults: BTreeMap::new(),
+            warnings: None,
+            report: None,
+            path: Vec::new(),
+            key: String::new(),
+        }
+    }
+
+    /// Registers a handler for values with a given annotation, e.g. to
+    /// decode a string annotated as `:base64` into a list of bytes. The
+    /// handler receives the value as parsed, and returns the value which
+    /// is deserialized instead. When a value has several annotations with
+    /// handlers, they are applied in the order the annotations are written.
+    pub fn annotation_handler<F>(mut self, annotation: &str, handler: F) -> Self
+        where F: Fn(Value) -> Result<Value> + 'static
+    {
+        self.handlers.insert(annotation.to_string(), Box::new(handler));
+        self
+    }
+
+    /// Registers a parser for scalars of a custom type written with a given
+    /// annotation, e.g. `:rational 1/3` or `:complex 1+2i`. The value is
+    /// read as a raw token up to the next whitespace, comma, or delimiter
+    /// (or as a quoted string), and passed to the parser, which returns
+    /// the value deserialized instead, like annotation handlers do.
+    pub fn scalar_parser<F>(mut self, annotation: &str, parser: F) -> Self
+        where F: Fn(&str) -> Result<Value> + 'static
+    {
+        self.parser.add_scalar_annotation(annotation);
+        self.annotation_handler(annotation, move |value| match value {
+            Value::String(ref token) => parser(token),
+            _ => Err(de::Error::custom("expected scalar")),
+        })
+    }
+
+    /// Sets a token which is checked before reading each item of a
+    /// dictionary or list, stopping deserialization with
+    /// `Error::Cancelled` once it is cancelled.
+    pub fn cancellation<C>(mut self, token: C) -> Self
+        where C: CancellationToken + 'static
+    {
+        self.cancel = Some(Box::new(token));
+        self
+    }
+
+    #[inline]
+    fn check_cancelled(&self) -> Result<()> {
+        match self.cancel {
+            Some(ref token) if token.is_cancelled() => Err(Error::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    /// Limits the amount of memory, in bytes, used by the values read from
+    /// a message. Deserialization fails with `ErrorCode::MemoryLimitExceeded`
+    /// once the limit is exceeded.
+    ///
+    /// Memory usage is estimated while parsing: each string, key, and
+    /// annotation accounts for its length, and each value or dictionary
+    /// entry for the size of a `Value`. The actual usage depends on the
+    /// types being deserialized into, so the limit is approximate.
+    #[inline]
+    pub fn memory_limit(mut self, limit: usize) -> Self {
+        self.memory_limit = Some(limit);
+        self
+    }
+
+    /// Sets caps on the size of the input, for reading untrusted data, see
+    /// `ParserOptions`.
+    #[inline]
+    pub fn parser_options(mut self, options: ParserOptions) -> Self {
+        self.parser.set_options(options);
+        self
+    }
+
+    /// Limits how deep lists and dictionaries may be nested, 128 levels by
+    /// default. Deeper values fail with `ErrorCode::RecursionLimitExceeded`
+    /// instead of overflowing the stack while they are deserialized.
+    ///
+    /// Deserialization is recursive, so the limit must stay finite: each
+    /// level takes up to a few kilobytes of stack in debug builds, and the
+    /// default fits in the 2 MiB stack of spawned threads. Raise it only
+    /// along with the stack size, see `std::thread::Builder::stack_size()`.
+    /// Readers which build no values, like `pull::PullParser`, keep track
+    /// of nesting with an explicit stack instead, and have no limit.
+    #[inline]
+    pub fn recursion_limit(mut self, limit: usize) -> Self {
+        self.parser.set_recursion_limit(limit);
+        self
+    }
+
+    /// Sets a callback which receives the estimated amount of memory used
+    /// so far, in bytes, each time it grows. See `memory_limit()`.
+    pub fn memory_callback<C>(mut self, callback: C) -> Self
+        where C: FnMut(usize) + 'static
+    {
+        self.memory_callback = Some(Box::new(callback));
+        self
+    }
+
+    /// Sets a point in time after which parsing stops with
+    /// `Error::DeadlineExceeded`, to bound the time spent on untrusted
+    /// input. The clock is checked every `DEADLINE_CHECK_INTERVAL` items
+    /// read, so the deadline may be slightly overrun.
+    #[inline]
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Sets how `None` and unit values are recognized, which should match
+    /// the strategy used to serialize the input. Missing dictionary keys
+    /// are always deserialized as `None`.
+    #[inline]
+    pub fn null_strategy(mut self, strategy: NullStrategy) -> Self {
+        self.null = strategy;
+        self
+    }
+
+    /// Sets what to do with integers out of the range of the type they are
+    /// deserialized into. The default is `OverflowPolicy::Error`.
+    #[inline]
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.overflow = policy;
+        self
+    }
+
+    /// Enables deserializing floats which have no fractional part, like
+    /// `42.0`, into integer types, as some producers write all numbers as
+    /// floats. Other floats are still rejected, as they are by default.
+    #[inline]
+    pub fn integral_floats(mut self, enabled: bool) -> Self {
+        self.integral_floats = enabled;
+        self
+    }
+
+    /// Enables reading the strings `"yes"`, `"no"`, `"1"`, and `"0"` as
+    /// booleans, reporting `WarningCode::BoolCoerced`, for documents
+    /// converted from older configuration formats. Case is ignored.
+    #[inline]
+    pub fn lenient_bools(mut self, enabled: bool) -> Self {
+        self.lenient_bools = enabled;
+        self
+    }
+
+    /// Sets what to do with keys which appear more than once in a
+    /// dictionary, after applying case folding and aliases. By default
+    /// entries are passed as they are to the type being deserialized, so
+    /// maps keep the last value and structs fail.
+    #[inline]
+    pub fn duplicate_keys(mut self, policy: DuplicateKeyPolicy) -> Self {
+        self.duplicates = Some(policy);
+        self
+    }
+
+    /// Enables converting dictionary keys to lowercase before they are
+    /// deserialized, so fields of structs (whose names should then be
+    /// lowercase) are matched regardless of case. Useful for documents
+    /// produced by systems which are sloppy with the case of keys.
+    #[inline]
+    pub fn fold_key_case(mut self, enabled: bool) -> Self {
+        self.fold_keys = enabled;
+        self
+    }
+
+    /// Registers an alternative name for a dictionary key, e.g. to keep
+    /// accepting the old name of a renamed configuration option: keys
+    /// named `alias` are deserialized as if they were named `key`. When
+    /// key case folding is enabled, the alias should be lowercase.
+    pub fn key_alias(mut self, alias: &str, key: &str) -> Self {
+        self.aliases.insert(alias.to_string(), key.to_string());
+        self
+    }
+
+    /// Sets a callback which receives warnings about problems found in
+    /// the input which do not stop deserialization, e.g. deprecated keys.
+    pub fn warning_handler<C>(mut self, callback: C) -> Self
+        where C: FnMut(Warning) + 'static
+    {
+        self.warnings = Some(Box::new(callback));
+        self
+    }
+
+    /// Marks a key as deprecated, so using it is reported as a warning
+    /// which includes the suggested replacement, e.g. while migrating a
+    /// configuration schema. The key is given as a path of dictionary keys
+    /// starting from the top level, like `/server/hostname`, and items of
+    /// lists do not add segments to the path, so `/servers/hostname`
+    /// refers to the key in every dictionary of the `servers` list. Paths
+    /// use the names of keys after applying aliases, except for the last
+    /// segment, which is matched as written (after case folding).
+    pub fn deprecated_key(mut self, path: &str, replacement: &str) -> Self {
+        self.deprecated.insert(path.to_string(), replacement.to_string());
+        self
+    }
+
+    /// Registers a value which is used when a key is missing, e.g. for
+    /// types from other crates which cannot be given `#[serde(default)]`.
+    /// The key is given as a path like for `deprecated_key()`, e.g.
+    /// `/server/port`, and the value is only added to dictionaries which
+    /// are present in the input: to supply a missing dictionary, register
+    /// a default for it as a whole.
+    pub fn default_value<V: Into<Value>>(mut self, path: &str, value: V) -> Self {
+        self.defaults.insert(path.to_string(), value.into());
+        self
+    }
+
+    /// Enables collecting a `FieldReport`, which tells apart fields set
+    /// in the input from missing ones, e.g. for configuration interfaces
+    /// which need to distinguish "unset" from "set to the default value".
+    /// The report is retrieved with `take_field_report()`.
+    #[inline]
+    pub fn field_report(mut self, enabled: bool) -> Self {
+        self.report = if enabled { Some(FieldReport::default()) } else { None };
+        self
+    }
+
+    /// Returns the fields reported since the last call, or an empty report
+    /// when `field_report()` is not enabled.
+    pub fn take_field_report(&mut self) -> FieldReport {
+        match self.report {
+            Some(ref mut report) => mem::replace(report, FieldReport::default()),
+            None => FieldReport::default(),
+        }
+    }
+
+    /// Enables reading a pragmatic subset of JSON, to ease migrating
+    /// existing documents: keys may be quoted, booleans may be written
+    /// in lowercase, strings may use JSON escape sequences, and `null`
+    /// values inside dictionaries are dropped along with their keys, so
+    /// optional fields end up being `None`. Other uses of `null` are
+    /// reported as errors.
+    #[inline]
+    pub fn json_compat(mut self, enabled: bool) -> Self {
+        self.parser.set_json_compat(enabled);
+        self
+    }
+
+    // Prepares for reading another message from the same input, used
+    // by the framing module and StreamDeserializer. Returns false at the end of the input.
+    #[doc(hidden)]
+    pub fn next_message(&mut self) -> Result<bool> {
+        self.peeked = None;
+        self.memory = 0;
+        self.path.clear();
+        self.parser.next_message()
+    }
+
+    // Number of bytes consumed from the input.
+    #[doc(hidden)]
+    #[inline]
+    pub fn byte_offset(&self) -> usize {
+        self.parser.offset()
+    }
+
+    // Builds an error at the current position of the input.
+    #[doc(hidden)]
+    #[inline]
+    pub fn error(&self, code: ErrorCode) -> Error {
+        self.parser.error(code)
+    }
+
+    // Reads the annotation preceding a message, used by the framing module.
+    #[doc(hidden)]
+    pub fn message_annotation(&mut self) -> Result<Option<String>> {
+        self.parser.parse_message_annotation()
+    }
+
+    // Reads the raw bytes of an attachment frame, used by the framing module.
+    #[doc(hidden)]
+    pub fn read_blob(&mut self) -> Result<Vec<u8>> {
+        let available = match self.memory_limit {
+            Some(limit) => limit.saturating_sub(self.memory),
+            None => usize::MAX,
+        };
+        let blob = try!(self.parser.parse_blob(available));
+        try!(self.use_memory(blob.len()));
+        Ok(blob)
+    }
+
+    // Consumes the rest of the current message without deserializing it.
+    #[doc(hidden)]
+    pub fn skip_message(&mut self) -> Result<()> {
+        self.peeked = None;
+        while let Some(_) = try!(self.parse_event()) {}
+        Ok(())
+    }
+
+    /// Checks that the whole input has been consumed.
+    pub fn end(&mut self) -> Result<()> {
+        match try!(self.peek_event()) {
+            None => Ok(()),
+            Some(_) => Err(self.parser.error(ErrorCode::TrailingCharacters)),
+        }
+    }
+
+    fn peek_event(&mut self) -> Result<Option<&Event>> {
+        if self.peeked.is_none() {
+            self.peeked = try!(self.parse_event());
+        }
+        Ok(self.peeked.as_ref())
+    }
+
+    // Consumes the next value if it is the representation of a null.
+    fn parse_null(&mut self) -> Result<bool> {
+        match self.null {
+            NullStrategy::EmptyString => {
+                if let Some(&Event::String(ref v)) = try!(self.peek_event()) {
+                    if !v.is_empty() {
+                        return Ok(false);
+                    }
+                } else {
+                    return Ok(false);
+                }
+            },
+            NullStrategy::AnnotatedDict => {
+                if let Some(&Event::Annotation(ref v)) = try!(self.peek_event()) {
+                    if v != value::NONE_ANNOTATION {
+                        return Ok(false);
+                    }
+                } else {
+                    return Ok(false);
+                }
+                try!(self.next_event());
+                if try!(self.next_event()) != Event::DictStart ||
+                   try!(self.next_event()) != Event::DictEnd {
+                    return Err(self.parser.error(ErrorCode::InvalidNull));
+                }
+                return Ok(true);
+            },
+            NullStrategy::Error | NullStrategy::Skip => return Ok(false),
+        }
+        try!(self.next_event());
+        Ok(true)
+    }
+
+    fn parse_annotations(&mut self) -> Result<Vec<String>> {
+        let mut annotations = Vec::new();
+        while let Some(&Event::Annotation(_)) = try!(self.peek_event()) {
+            if let Event::Annotation(annotation) = try!(self.next_event()) {
+                annotations.push(annotation);
+            }
+        }
+        Ok(annotations)
+    }
+
+    // Reads the events of the next value, recycling the buffers of keys.
+    fn skip_value(&mut self) -> Result<()> {
+        let mut depth = 0;
+        loop {
+            match try!(self.next_event()) {
+                Event::Annotation(_) => continue,
+                Event::Key(key) => {
+                    self.parser.recycle_key(key);
+                    continue;
+                },
+                Event::DictStart | Event::ListStart => depth += 1,
+                Event::DictEnd | Event::ListEnd if depth == 0 => {
+                    return Err(self.parser.error(ErrorCode::ExpectedValue));
+                },
+                Event::DictEnd | Event::ListEnd => depth -= 1,
+                _ => (),
+            }
+            if depth == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    // Reads the next value and passes it through the handlers registered
+    // for its annotations. Returns None if there are no such handlers.
+    fn handle_annotations<'de>(&mut self, annotations: &[String]) -> Result<Option<Value>>
+        where In: Input<'de>
+    {
+        if !annotations.iter().any(|annotation| self.handlers.contains_key(annotation)) {
+            return Ok(None);
+        }
+        let mut value: Value = try!(de::Deserialize::deserialize(&mut *self));
+        for annotation in annotations {
+            if let Some(handler) = self.handlers.get(annotation) {
+                value = try!(handler(value));
+            }
+        }
+        Ok(Some(value))
+    }
+
+    fn parse_event(&mut self) -> Result<Option<Event>> {
+        if let Some(deadline) = self.deadline {
+            if self.events % DEADLINE_CHECK_INTERVAL == 0 && Instant::now() >= deadline {
+                return Err(Error::DeadlineExceeded);
+            }
+            self.events += 1;
+        }
+        let event = try!(self.parser.next_event());
+        let size = match event {
+            None | Some(Event::ListEnd) | Some(Event::DictEnd) => return Ok(event),
+            Some(Event::String(ref v)) | Some(Event::Key(ref v)) |
+            Some(Event::Annotation(ref v)) => v.len() + mem::size_of::<Value>(),
+            Some(Event::Bytes(ref v)) => v.len() + mem::size_of::<Value>(),
+            Some(_) => mem::size_of::<Value>(),
+        };
+        try!(self.use_memory(size));
+        Ok(event)
+    }
+
+    // Adds to the estimated amount of memory used by the current message.
+    pub(crate) fn use_memory(&mut self, size: usize) -> Result<()> {
+        self.memory += size;
+        if let Some(ref mut callback) = self.memory_callback {
+            callback(self.memory);
+        }
+        match self.memory_limit {
+            Some(limit) if self.memory > limit => {
+                Err(self.parser.error(ErrorCode::MemoryLimitExceeded))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    // Adds the current position to errors reported by visitors, which do
+    // not know about it.
+    fn locate(&self, err: Error) -> Error {
+        match err {
+            Error::SyntaxError(code, 0, 0, 0) => self.parser.error(code),
+            Error::AtPath(path, err) => Error::AtPath(path, Box::new(self.locate(*err))),
+            err => err,
+        }
+    }
+
+    // Reports a warning at the current position, also used by the framing module.
+    pub(crate) fn warn(&mut self, code: WarningCode) {
+        let (line, column) = self.parser.position();
+        if let Some(ref mut callback) = self.warnings {
+            callback(Warning { code: code, line: line, column: column });
+        }
+    }
+
+    #[inline]
+    fn tracks_path(&self) -> bool {
+        !self.deprecated.is_empty() || !self.defaults.is_empty() || self.report.is_some()
+    }
+
+    // Builds the path of a key from the first `depth` segments of the
+    // current path.
+    fn key_path(&self, depth: usize, key: &str) -> String {
+        let mut path = String::new();
+        for segment in &self.path[..depth] {
+            path.push('/');
+            path.push_str(segment);
+        }
+        path.push('/');
+        path.push_str(key);
+        path
+    }
+
+    // Default values for the keys of the dictionary being read for which
+    // `present` returns false.
+    fn missing_defaults<F>(&self, present: F) -> Vec<(String, Value)>
+        where F: Fn(&str) -> bool
+    {
+        if self.defaults.is_empty() {
+            return Vec::new();
+        }
+        let prefix = self.key_path(self.path.len() - 1, "");
+        self.defaults.range(prefix.clone()..)
+            .take_while(|&(path, _)| path.starts_with(&prefix))
+            .filter_map(|(path, value)| {
+                let key = &path[prefix.len()..];
+                if key.contains('/') || present(key) {
+                    None
+                } else {
+                    Some((key.to_string(), value.clone()))
+                }
+            })
+            .collect()
+    }
+
+    // Records a key read from the input as the last segment of the
+    // current path, warning when the key is deprecated.
+    fn visit_path_key(&mut self, key: &str) {
+        if !self.tracks_path() {
+            return;
+        }
+        let depth = self.path.len().saturating_sub(1);
+        let path = self.key_path(depth, key);
+        if let Some(replacement) = self.deprecated.get(&path).cloned() {
+            self.warn(WarningCode::DeprecatedKey(path, replacement));
+        }
+        let key = self.aliases.get(key).map_or(key, |key| &key[..]).to_string();
+        if self.report.is_some() {
+            let path = self.key_path(depth, &key);
+            if let Some(ref mut report) = self.report {
+                report.present.push(path);
+            }
+        }
+        if let Some(segment) = self.path.last_mut() {
+            *segment = key;
+        }
+    }
+
+    fn next_event(&mut self) -> Result<Event> {
+        match self.peeked.take() {
+            Some(event) => Ok(event),
+            None => match try!(self.parse_event()) {
+                Some(event) => Ok(event),
+                None => Err(self.parser.error(ErrorCode::UnexpectedEof)),
+            },
+        }
+    }
+}
+
+
+impl<Iter, In> Deserializer<Iter, In> where Iter: Iterator<Item=io::Result<u8>> {
+    // Reads any value, where `fields` are the names of the fields of the
+    // struct being deserialized, if any, used to build the FieldReport.
+    fn deserialize_value<'de, V>(&mut self,
+                                 visitor: V,
+                                 fields: Option<&'static [&'static str]>) -> Result<V::Value>
+        where V: de::Visitor<'de>, In: Input<'de>
+    {
+        // Annotations are ignored unless explicitly requested.
+        let annotations = try!(self.parse_annotations());
+        if let Some(value) = try!(self.handle_annotations(&annotations)) {
+            return visit_handled(value, visitor);
+        }
+        let result = match try!(self.next_event()) {
+            Event::Bool(v) => visitor.visit_bool(v),
+            Event::I64(v) => visitor.visit_i64(v),
+            Event::U64(v) => visitor.visit_u64(v),
+            Event::I128(v) => visitor.visit_i128(v),
+            Event::U128(v) => visitor.visit_u128(v),
+            Event::F64(v) => visitor.visit_f64(v),
+            Event::Key(v) => self.visit_key(v, visitor),
+            Event::String(v) => self.visit_string(v, visitor),
+            Event::Bytes(v) => visitor.visit_byte_buf(v),
+            Event::ListStart => {
+                let mut seq = SeqAccess::new(self);
+                visitor.visit_seq(&mut seq).and_then(|value| seq.end().map(|()| value))
+            },
+            Event::DictStart if self.duplicates == Some(DuplicateKeyPolicy::LastWins) => {
+                self.visit_last_wins(visitor)
+            },
+            Event::DictStart => {
+                let mut map = MapAccess::new(self, fields);
+                visitor.visit_map(&mut map).and_then(|value| map.end().map(|()| value))
+            },
+            Event::ListEnd | Event::DictEnd | Event::Annotation(_) => {
+                Err(self.parser.error(ErrorCode::ExpectedValue))
+            },
+        };
+        result.map_err(|err| self.locate(err))
+    }
+
+    // Values are read recursively, so the arms of deserialize_value() which
+    // are not needed to read nested values are kept out of it, in order
+    // to make the stack used for each nesting level smaller.
+
+    #[inline(never)]
+    fn visit_key<'de, V>(&mut self, v: String, visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de>, In: Input<'de>
+    {
+        self.key.clear();
+        self.key.push_str(&v);
+        let v = if self.fold_keys { v.to_lowercase() } else { v };
+        self.visit_path_key(&v);
+        // Visitors only borrow keys, so matching them against the
+        // names of struct fields allocates nothing, and the buffer
+        // is reused for the next key.
+        let result = match self.aliases.get(&v) {
+            Some(key) => visitor.visit_str(key),
+            None => visitor.visit_str(&v),
+        };
+        self.parser.recycle_key(v);
+        result
+    }
+
+    // Strings written without escape sequences are passed borrowed from
+    // the input when it allows it, see `SliceInput`.
+    #[inline(never)]
+    fn visit_string<'de, V>(&mut self, v: String, visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de>, In: Input<'de>
+    {
+        let (start, end) = self.parser.token_span();
+        let text = self.input.borrow(start.offset + 1..end.offset.saturating_sub(1));
+        match text.and_then(|text| str::from_utf8(text).ok()) {
+            Some(text) if text == v => visitor.visit_borrowed_str(text),
+            _ => visitor.visit_string(v),
+        }
+    }
+
+    #[inline(never)]
+    fn visit_last_wins<'de, V>(&mut self, visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de>, In: Input<'de>
+    {
+        let entries = try!(self.read_last_wins());
+        let mut map = de::value::MapDeserializer::new(entries.into_iter());
+        visitor.visit_map(&mut map).and_then(|value| map.end().map(|()| value))
+    }
+
+    // Whether the next value is a number, which VALUE_NAME passes as text.
+    fn at_number(&mut self) -> Result<bool> {
+        Ok(match try!(self.peek_event()) {
+            Some(&Event::I64(_)) | Some(&Event::U64(_)) | Some(&Event::I128(_)) |
+            Some(&Event::U128(_)) | Some(&Event::F64(_)) => true,
+            _ => false,
+        })
+    }
+
+    #[inline(never)]
+    fn deserialize_annotated<'de, V>(&mut self,
+                                     name: &'static str,
+                                     annotations: Vec<String>,
+                                     visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de>, In: Input<'de>
+    {
+        let handled = try!(self.handle_annotations(&annotations));
+        visitor.visit_map(AnnotatedAccess {
+            de: self,
+            annotations: Some(annotations),
+            handled: handled,
+            value: false,
+            numbers: name == VALUE_NAME,
+            number: false,
+        })
+    }
+
+    // Reads the entries of a dictionary, whose start was already read,
+    // overwriting the values of repeated keys, which keep the position
+    // where they were first found.
+    fn read_last_wins<'de>(&mut self) -> Result<Vec<(String, Value)>> where In: Input<'de> {
+        if self.tracks_path() {
+            self.path.push(String::new());
+        }
+        let mut entries = Vec::new();
+        let mut positions = HashMap::new();
+        loop {
+            try!(self.check_cancelled());
+            let raw = match try!(self.next_event()) {
+                Event::DictEnd => break,
+                Event::Key(key) => key,
+                _ => return Err(self.parser.error(ErrorCode::ExpectedKey)),
+            };
+            let key = if self.fold_keys { raw.to_lowercase() } else { raw.clone() };
+            self.visit_path_key(&key);
+            let key = self.aliases.get(&key).cloned().unwrap_or(key);
+            let value = try!(de::Deserialize::deserialize(&mut *self).map_err(|err| at_path(err, &raw)));
+            if let Some(&index) = positions.get(&key) {
+                entries[index] = (key, value);
+                continue;
+            }
+            positions.insert(key.clone(), entries.len());
+            entries.push((key, value));
+        }
+        entries.extend(self.missing_defaults(|key| positions.contains_key(key)));
+        if self.tracks_path() {
+            self.path.pop();
+        }
+        Ok(entries)
+    }
+
+    // Name of a key after applying case folding and aliases.
+    fn effective_key(&self, key: &str) -> String {
+        let key = if self.fold_keys { key.to_lowercase() } else { key.to_string() };
+        match self.aliases.get(&key) {
+            Some(alias) => alias.clone(),
+            None => key,
+        }
+    }
+
+    // Reads an integer into a type whose range goes from `min` to `max`.
+    fn deserialize_integer<'de, V>(&mut self, visitor: V, min: i128, max: u128)
+        -> Result<V::Value>
+        where V: de::Visitor<'de>, In: Input<'de>
+    {
+        let clamped = Cell::new(None);
+        let result = self.deserialize_value(Narrowing {
+            visitor: visitor,
+            min: min,
+            max: max,
+            policy: self.overflow,
+            floats: self.integral_floats,
+            clamped: &clamped,
+        }, None);
+        if let Some((value, used)) = clamped.take() {
+            self.warn(WarningCode::IntegerClamped(value, used));
+        }
+        result
+    }
+}
+
+
+macro_rules! deserialize_integers {
+    ($($method:ident => $ty:ident),*) => {
+        $(
+            #[inline]
+            fn $method<V>(self, visitor: V) -> Result<V::Value>
+                where V: de::Visitor<'de>
+            {
+                self.deserialize_integer(visitor, $ty::min_value() as i128, $ty::max_value() as u128)
+            }
+        )*
+    }
+}
+
+
+impl<'de, 'a, Iter, In> de::Deserializer<'de> for &'a mut Deserializer<Iter, In>
+    where Iter: Iterator<Item=io::Result<u8>>, In: Input<'de>
+{
+    type Error = Error;
+
+    #[inline]
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de>
+    {
+        self.deserialize_value(visitor, None)
+    }
+
+    fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de>
+    {
+        if !self.lenient_bools {
+            return self.deserialize_value(visitor, None);
+        }
+        let coerced = Cell::new(None);
+        let result = self.deserialize_value(LenientBool { visitor: visitor, coerced: &coerced }, None);
+        if let Some(value) = coerced.take() {
+            self.warn(WarningCode::BoolCoerced(value));
+        }
+        result
+    }
+
+    #[inline]
+    fn deserialize_struct<V>(self,
+                             _name: &'static str,
+                             fields: &'static [&'static str],
+                             visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de>
+    {
+        self.deserialize_value(visitor, Some(fields))
+    }
+
+    fn deserialize_newtype_struct<V>(self, name: &'static str, visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de>
+    {
+        if name == RAW_VALUE_NAME {
+            return visit_raw(self, visitor);
+        }
+        if name == SPANNED_NAME {
+            return spanned::deserialize_spanned(self, visitor);
+        }
+        // Other newtype structs are written as lists with one item.
+        if name != ANNOTATED_NAME && name != VALUE_NAME {
+            return self.deserialize_value(visitor, None);
+        }
+        let annotations = try!(self.parse_annotations());
+        // Values without annotations are given directly, as deserializers
+        // for other formats do, which saves stack for each nested value.
+        if annotations.is_empty() && !(name == VALUE_NAME && try!(self.at_number())) {
+            return visitor.visit_newtype_struct(self);
+        }
+        self.deserialize_annotated(name, annotations, visitor)
+    }
+
+    // HiPack has no null value, so options are present unless they use
+    // the representation given by the NullStrategy: missing dictionary
+    // keys are handled by the visitors of structs.
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de>
+    {
+        if try!(self.parse_null()) {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de>
+    {
+        if try!(self.parse_null()) {
+            visitor.visit_unit()
+        } else {
+            self.deserialize_value(visitor, None)
+        }
+    }
+
+    #[inline]
+    fn deserialize_unit_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de>
+    {
+        self.deserialize_unit(visitor)
+    }
+
+    #[inline]
+    fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de>
+    {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    // Byte strings may be lists of integers, strings (using escapes for
+    // bytes which are not valid UTF-8), or Base64 strings annotated as
+    // `:base64`. See ser::BytesEncoding.
+    fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de>
+    {
+        let annotations = try!(self.parse_annotations());
+        let base64 = annotations.iter().any(|annotation| annotation == BASE64_ANNOTATION);
+        if !base64 {
+            if let Some(value) = try!(self.handle_annotations(&annotations)) {
+                return de::Deserializer::deserialize_any(value::Deserializer::new(value), visitor);
+            }
+        }
+        let bytes = match try!(self.next_event()) {
+            Event::String(v) => {
+                if !base64 {
+                    v.into_bytes()
+                } else {
+                    let bytes = match base64::decode(&v) {
+                        Some(bytes) => bytes,
+                        None => return Err(self.parser.error(ErrorCode::InvalidBase64)),
+                    };
+                    try!(self.use_memory(bytes.len()));
+                    bytes
+                }
+            },
+            Event::Bytes(_) if base64 => return Err(self.parser.error(ErrorCode::InvalidBase64)),
+            Event::Bytes(v) => v,
+            event => {
+                self.peeked = Some(event);
+                return self.deserialize_value(visitor, None);
+            },
+        };
+        visitor.visit_byte_buf(bytes).map_err(|err| self.locate(err))
+    }
+
+    // Unit variants are strings, and other variants are either annotated
+    // with their name or a dictionary with their name as the only key.
+    // See ser::EnumStyle.
+    fn deserialize_enum<V>(self,
+                           _enum: &'static str,
+                           _variants: &'static [&'static str],
+                           visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de>
+    {
+        let annotations = try!(self.parse_annotations());
+        if let Some(variant) = annotations.into_iter().next() {
+            return visitor.visit_enum(VariantAccess { de: self, variant: variant, data: true });
+        }
+        match try!(self.next_event()) {
+            Event::String(variant) => {
+                visitor.visit_enum(VariantAccess { de: self, variant: variant, data: false })
+            },
+            Event::DictStart => {
+                let variant = match try!(self.next_event()) {
+                    Event::Key(variant) => variant,
+                    _ => return Err(self.parser.error(ErrorCode::ExpectedVariant)),
+                };
+                let value = try!(visitor.visit_enum(VariantAccess {
+                    de: &mut *self,
+                    variant: variant,
+                    data: true,
+                }));
+                match try!(self.next_event()) {
+                    Event::DictEnd => Ok(value),
+                    _ => Err(self.parser.error(ErrorCode::TrailingCharacters)),
+                }
+            },
+            _ => Err(self.parser.error(ErrorCode::ExpectedVariant)),
+        }
+    }
+
+    deserialize_integers! {
+        deserialize_i8 => i8, deserialize_i16 => i16, deserialize_i32 => i32,
+        deserialize_i64 => i64, deserialize_i128 => i128,
+        deserialize_u8 => u8, deserialize_u16 => u16, deserialize_u32 => u32,
+        deserialize_u64 => u64, deserialize_u128 => u128
+    }
+
+    forward_to_deserialize_any! {
+        f32 f64 char str string seq tuple tuple_struct map identifier
+    }
+
+    // Ignored values are skipped without handing their contents to the
+    // visitor, or running the handlers of their annotations.
+    fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de>
+    {
+        self.parser.set_skipping(true);
+        let result = self.skip_value();
+        self.parser.set_skipping(false);
+        try!(result.map_err(|err| self.locate(err)));
+        visitor.visit_unit()
+    }
+}
+
+
+// Reads strings which spell booleans as booleans, keeping the string in
+// `coerced` to warn about it.
+struct LenientBool<'a, V> {
+    visitor: V,
+    coerced: &'a Cell<Option<String>>,
+}
+
+
+impl<'de, 'a, V> de::Visitor<'de> for LenientBool<'a, V> where V: de::Visitor<'de> {
+    type Value = V::Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.visitor.expecting(f)
+    }
+
+    fn visit_bool<E>(self, v: bool) -> ::std::result::Result<V::Value, E>
+        where E: de::Error
+    {
+        self.visitor.visit_bool(v)
+    }
+
+    fn visit_str<E>(self, v: &str) -> ::std::result::Result<V::Value, E>
+        where E: de::Error
+    {
+        let value = if v.eq_ignore_ascii_case("yes") || v == "1" {
+            true
+        } else if v.eq_ignore_ascii_case("no") || v == "0" {
+            false
+        } else {
+            return self.visitor.visit_str(v);
+        };
+        self.coerced.set(Some(v.to_string()));
+        self.visitor.visit_bool(value)
+    }
+}
+
+
+// Checks that integers are in the range of the type being deserialized,
+// applying the OverflowPolicy to those which are not. The value and the
+// one used instead are kept in `clamped` to warn about them. Other values
+// are passed on as they are, except for floats with no fractional part
+// when `floats` is set.
+struct Narrowing<'a, V> {
+    visitor: V,
+    min: i128,
+    max: u128,
+    policy: OverflowPolicy,
+    floats: bool,
+    clamped: &'a Cell<Option<(String, String)>>,
+}
+
+
+impl<'a, V> Narrowing<'a, V> {
+    fn out_of_range<E, T>(&self, v: T) -> E where E: de::Error, T: fmt::Display {
+        E::custom(format!("Integer {} out of range {}..={}", v, self.min, self.max))
+    }
+}
+
+
+impl<'de, 'a, V> de::Visitor<'de> for Narrowing<'a, V> where V: de::Visitor<'de> {
+    type Value = V::Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.visitor.expecting(f)
+    }
+
+    fn visit_u128<E>(self, v: u128) -> ::std::result::Result<V::Value, E>
+        where E: de::Error
+    {
+        let v = match self.policy {
+            _ if v <= self.max => v,
+            OverflowPolicy::Error => return Err(self.out_of_range(v)),
+            OverflowPolicy::Saturate => {
+                self.clamped.set(Some((v.to_string(), self.max.to_string())));
+                self.max
+            },
+        };
+        if v <= u64::max_value() as u128 {
+            self.visitor.visit_u64(v as u64)
+        } else {
+            self.visitor.visit_u128(v)
+        }
+    }
+
+    fn visit_i128<E>(self, v: i128) -> ::std::result::Result<V::Value, E>
+        where E: de::Error
+    {
+        let v = match self.policy {
+            _ if v >= self.min => v,
+            OverflowPolicy::Error => return Err(self.out_of_range(v)),
+            OverflowPolicy::Saturate => {
+                self.clamped.set(Some((v.to_string(), self.min.to_string())));
+                self.min
+            },
+        };
+        if v >= 0 {
+            self.visit_u128(v as u128)
+        } else if v >= i64::min_value() as i128 {
+            self.visitor.visit_i64(v as i64)
+        } else {
+            self.visitor.visit_i128(v)
+        }
+    }
+
+    #[inline]
+    fn visit_u64<E>(self, v: u64) -> ::std::result::Result<V::Value, E>
+        where E: de::Error
+    {
+        self.visit_u128(v as u128)
+    }
+
+    #[inline]
+    fn visit_i64<E>(self, v: i64) -> ::std::result::Result<V::Value, E>
+        where E: de::Error
+    {
+        self.visit_i128(v as i128)
+    }
+
+    fn visit_bool<E>(self, v: bool) -> ::std::result::Result<V::Value, E>
+        where E: de::Error
+    {
+        self.visitor.visit_bool(v)
+    }
+
+    fn visit_f64<E>(self, v: f64) -> ::std::result::Result<V::Value, E>
+        where E: de::Error
+    {
+        if !self.floats || !v.is_finite() || v.fract() != 0.0 {
+            self.visitor.visit_f64(v)
+        } else if v < 0.0 {
+            // Casts saturate, so values beyond 128 bits are out of range.
+            self.visit_i128(v as i128)
+        } else {
+            self.visit_u128(v as u128)
+        }
+    }
+
+    fn visit_str<E>(self, v: &str) -> ::std::result::Result<V::Value, E>
+        where E: de::Error
+    {
+        self.visitor.visit_str(v)
+    }
+
+    fn visit_string<E>(self, v: String) -> ::std::result::Result<V::Value, E>
+        where E: de::Error
+    {
+        self.visitor.visit_string(v)
+    }
+
+    fn visit_borrowed_str<E>(self, v: &'de str) -> ::std::result::Result<V::Value, E>
+        where E: de::Error
+    {
+        self.visitor.visit_borrowed_str(v)
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> ::std::result::Result<V::Value, E>
+        where E: de::Error
+    {
+        self.visitor.visit_bytes(v)
+    }
+
+    fn visit_borrowed_bytes<E>(self, v: &'de [u8]) -> ::std::result::Result<V::Value, E>
+        where E: de::Error
+    {
+        self.visitor.visit_borrowed_bytes(v)
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> ::std::result::Result<V::Value, E>
+        where E: de::Error
+    {
+        self.visitor.visit_byte_buf(v)
+    }
+
+    fn visit_none<E>(self) -> ::std::result::Result<V::Value, E>
+        where E: de::Error
+    {
+        self.visitor.visit_none()
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> ::std::result::Result<V::Value, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        self.visitor.visit_some(deserializer)
+    }
+
+    fn visit_unit<E>(self) -> ::std::result::Result<V::Value, E>
+        where E: de::Error
+    {
+        self.visitor.visit_unit()
+    }
+
+    fn visit_newtype_struct<D>(self, deserializer: D) -> ::std::result::Result<V::Value, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        self.visitor.visit_newtype_struct(deserializer)
+    }
+
+    fn visit_seq<A>(self, seq: A) -> ::std::result::Result<V::Value, A::Error>
+        where A: de::SeqAccess<'de>
+    {
+        self.visitor.visit_seq(seq)
+    }
+
+    fn visit_map<A>(self, map: A) -> ::std::result::Result<V::Value, A::Error>
+        where A: de::MapAccess<'de>
+    {
+        self.visitor.visit_map(map)
+    }
+
+    fn visit_enum<A>(self, data: A) -> ::std::result::Result<V::Value, A::Error>
+        where A: de::EnumAccess<'de>
+    {
+        self.visitor.visit_enum(data)
+    }
+}
+
+
+// Prefixes the path of an error with the dictionary key or list index
+// of the value it happened in.
+#[cold]
+fn at_index(err: Error, index: usize) -> Error {
+    at_path(err, &format!("[{}]", index))
+}
+
+
+#[inline(never)]
+fn visit_handled<'de, V>(value: Value, visitor: V) -> Result<V::Value>
+    where V: de::Visitor<'de>
+{
+    de::Deserializer::deserialize_any(value::Deserializer::new(value), visitor)
+}
+
+
+#[inline(never)]
+fn visit_raw<'de, Iter, In, V>(de: &mut Deserializer<Iter, In>, visitor: V) -> Result<V::Value>
+    where Iter: Iterator<Item=io::Result<u8>>, In: Input<'de>, V: de::Visitor<'de>
+{
+    if de.peeked.is_none() {
+        // Only inputs which can be borrowed from give empty ranges.
+        let offset = de.parser.offset();
+        let borrowed = de.input.borrow(offset..offset).is_some();
+        if let Some((range, bytes)) = try!(de.parser.skip_raw_value(!borrowed)) {
+            return match de.input.borrow(range) {
+                Some(text) => match str::from_utf8(text) {
+                    Ok(text) => visitor.visit_borrowed_str(text),
+                    Err(_) => Err(From::from(String::from_utf8(text.to_vec()).unwrap_err())),
+                },
+                None => visitor.visit_string(try!(String::from_utf8(bytes))),
+            };
+        }
+    }
+    // The value was already read, or is not the value of an entry or an
+    // item, e.g. the whole message, so it is written back in compact form.
+    let value: Value = try!(de::Deserialize::deserialize(&mut *de));
+    visitor.visit_string(try!(to_string(&value)))
+}
+
+
+fn at_path(err: Error, segment: &str) -> Error {
+    match err {
+        Error::AtPath(path, err) => {
+            let separator = if path.starts_with('[') { "" } else { "." };
+            Error::AtPath(format!("{}{}{}", segment, separator, path), err)
+        },
+        // Not caused by any value in particular.
+        err @ Error::IoError(_) |
+        err @ Error::Cancelled |
+        err @ Error::DeadlineExceeded |
+        err @ Error::SyntaxError(ErrorCode::MemoryLimitExceeded, ..) => err,
+        err => Error::AtPath(segment.to_string(), Box::new(err)),
+    }
+}
+
+
+struct SeqAccess<'a, Iter: 'a + Iterator<Item=io::Result<u8>>, In: 'a> {
+    de: &'a mut Deserializer<Iter, In>,
+    done: bool,
+    index: usize,
+}
+
+
+impl<'a, Iter, In> SeqAccess<'a, Iter, In> where Iter: Iterator<Item=io::Result<u8>> {
+    fn new(de: &'a mut Deserializer<Iter, In>) -> Self {
+        SeqAccess { de: de, done: false, index: 0 }
+    }
+
+    // Checks that the visitor read all the items.
+    fn end(&mut self) -> Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        match try!(self.de.next_event()) {
+            Event::ListEnd => {
+                self.done = true;
+                Ok(())
+            },
+            _ => Err(self.de.parser.error(ErrorCode::TrailingCharacters)),
+        }
+    }
+}
+
+
+impl<'de, 'a, Iter, In> de::SeqAccess<'de> for SeqAccess<'a, Iter, In>
+    where Iter: Iterator<Item=io::Result<u8>>, In: Input<'de>
+{
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+        where T: de::DeserializeSeed<'de>
+    {
+        if self.done {
+            return Ok(None);
+        }
+        try!(self.de.check_cancelled());
+        // The end of the list is looked for without reading the next item,
+        // which would prevent reading it as a RawValue.
+        let end = match self.de.peeked {
+            Some(ref event) => *event == Event::ListEnd,
+            None => try!(self.de.parser.at_list_end()),
+        };
+        if end {
+            try!(self.de.next_event());
+            self.done = true;
+            return Ok(None);
+        }
+        match seed.deserialize(&mut *self.de) {
+            Ok(value) => {
+                self.index += 1;
+                Ok(Some(value))
+            },
+            Err(err) => Err(at_index(err, self.index)),
+        }
+    }
+}
+
+
+struct MapAccess<'a, Iter: 'a + Iterator<Item=io::Result<u8>>, In: 'a> {
+    de: &'a mut Deserializer<Iter, In>,
+    done: bool,
+    // Fields of the struct being deserialized, and the keys read so far,
+    // to report the missing fields once the dictionary ends.
+    fields: Option<&'static [&'static str]>,
+    keys: Vec<String>,
+    // Key of the entry being read, for the path of errors.
+    key: String,
+    // Keys read so far, when there is a DuplicateKeyPolicy.
+    seen: HashSet<String>,
+    // Default values of missing keys, given once the end of the
+    // dictionary has been read, and the one of the last key given.
+    defaults: Option<vec::IntoIter<(String, Value)>>,
+    default: Option<Value>,
+}
+
+
+impl<'a, Iter, In> MapAccess<'a, Iter, In> where Iter: Iterator<Item=io::Result<u8>> {
+    fn new(de: &'a mut Deserializer<Iter, In>, fields: Option<&'static [&'static str]>) -> Self {
+        if de.tracks_path() {
+            de.path.push(String::new());
+        }
+        let fields = if de.report.is_some() { fields } else { None };
+        MapAccess {
+            de: de,
+            done: false,
+            fields: fields,
+            keys: Vec::new(),
+            key: String::new(),
+            seen: HashSet::new(),
+            defaults: None,
+            default: None,
+        }
+    }
+
+    fn finish(&mut self) {
+        self.done = true;
+        if let Some(fields) = self.fields {
+            // The segment of this dictionary is the last one.
+            let depth = self.de.path.len() - 1;
+            for field in fields {
+                if !self.keys.iter().any(|key| key == field) {
+                    let path = self.de.key_path(depth, field);
+                    if let Some(ref mut report) = self.de.report {
+                        report.defaulted.push(path);
+                    }
+                }
+            }
+        }
+        if self.de.tracks_path() {
+            self.de.path.pop();
+        }
+    }
+
+    // Checks that the visitor read all the entries.
+    fn end(&mut self) -> Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        if self.defaults.is_some() {
+            self.finish();
+            return Ok(());
+        }
+        match try!(self.de.next_event()) {
+            Event::DictEnd => {
+                self.finish();
+                Ok(())
+            },
+            _ => Err(self.de.parser.error(ErrorCode::TrailingCharacters)),
+        }
+    }
+}
+
+
+impl<'a, Iter, In> MapAccess<'a, Iter, In> where Iter: Iterator<Item=io::Result<u8>> {
+    // Gives the key of the next default value, after the end of the
+    // dictionary has been read.
+    fn next_default<'de, K>(&mut self, seed: K) -> Result<Option<K::Value>>
+        where K: de::DeserializeSeed<'de>
+    {
+        let (key, value) = match self.defaults.as_mut().and_then(|defaults| defaults.next()) {
+            Some(entry) => entry,
+            None => {
+                self.finish();
+                return Ok(None);
+            },
+        };
+        self.default = Some(value);
+        self.key.clear();
+        self.key.push_str(&key);
+        if let Some(segment) = self.de.path.last_mut() {
+            segment.clone_from(&key);
+        }
+        let de: de::value::StringDeserializer<Error> = key.into_deserializer();
+        seed.deserialize(de).map(Some)
+    }
+}
+
+
+impl<'de, 'a, Iter, In> de::MapAccess<'de> for MapAccess<'a, Iter, In>
+    where Iter: Iterator<Item=io::Result<u8>>, In: Input<'de>
+{
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+        where K: de::DeserializeSeed<'de>
+    {
+        if self.done {
+            return Ok(None);
+        }
+        if self.defaults.is_some() {
+            return self.next_default(seed);
+        }
+        try!(self.de.check_cancelled());
+        let checked = self.de.duplicates.is_some();
+        loop {
+            let key = match try!(self.de.peek_event()) {
+                Some(&Event::DictEnd) => {
+                    try!(self.de.next_event());
+                    let keys = &self.keys;
+                    let defaults = self.de.missing_defaults(|key| keys.iter().any(|k| k == key));
+                    self.defaults = Some(defaults.into_iter());
+                    return self.next_default(seed);
+                },
+                Some(&Event::Key(ref key)) if checked => key.clone(),
+                _ => break,
+            };
+            if self.seen.insert(self.de.effective_key(&key)) {
+                break;
+            }
+            if self.de.duplicates == Some(DuplicateKeyPolicy::Error) {
+                return Err(self.de.error(ErrorCode::DuplicateKey(key)));
+            }
+            try!(self.de.next_event());
+            try!(<de::IgnoredAny as de::Deserialize>::deserialize(&mut *self.de).map_err(|err| at_path(err, &key)));
+            try!(self.de.check_cancelled());
+        }
+        self.de.key.clear();
+        let key = match seed.deserialize(&mut *self.de) {
+            Ok(key) => key,
+            Err(err) if self.de.key.is_empty() => return Err(err),
+            Err(err) => return Err(at_path(err, &self.de.key)),
+        };
+        self.key.clear();
+        self.key.push_str(&self.de.key);
+        if self.fields.is_some() || !self.de.defaults.is_empty() {
+            if let Some(key) = self.de.path.last() {
+                self.keys.push(key.clone());
+            }
+        }
+        Ok(Some(key))
+    }
+
+    #[inline]
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+        where V: de::DeserializeSeed<'de>
+    {
+        match self.default.take() {
+            Some(value) => seed.deserialize(value::Deserializer::new(value)),
+            None => seed.deserialize(&mut *self.de),
+        }.map_err(|err| at_path(err, &self.key))
+    }
+}
+
+
+struct VariantAccess<'a, Iter: 'a + Iterator<Item=io::Result<u8>>, In: 'a> {
+    de: &'a mut Deserializer<Iter, In>,
+    variant: String,
+    // Whether the variant is followed by its data.
+    data: bool,
+}
+
+
+impl<'de, 'a, Iter, In> de::EnumAccess<'de> for VariantAccess<'a, Iter, In>
+    where Iter: Iterator<Item=io::Result<u8>>, In: Input<'de>
+{
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V>(mut self, seed: V) -> Result<(V::Value, Self)>
+        where V: de::DeserializeSeed<'de>
+    {
+        let variant = mem::replace(&mut self.variant, String::new());
+        let de: de::value::StringDeserializer<Error> = variant.into_deserializer();
+        let value = try!(seed.deserialize(de));
+        Ok((value, self))
+    }
+}
+
+
+impl<'de, 'a, Iter, In> de::VariantAccess<'de> for VariantAccess<'a, Iter, In>
+    where Iter: Iterator<Item=io::Result<u8>>, In: Input<'de>
+{
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        if self.data {
+            return Err(de::Error::invalid_type(de::Unexpected::NewtypeVariant, &"unit variant"));
+        }
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
+        where T: de::DeserializeSeed<'de>
+    {
+        if !self.data {
+            return Err(de::Error::invalid_type(de::Unexpected::UnitVariant, &"newtype variant"));
+        }
+        seed.deserialize(self.de)
+    }
+
+    fn tuple_variant<V>(self, _len: usize, visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de>
+    {
+        if !self.data {
+            return Err(de::Error::invalid_type(de::Unexpected::UnitVariant, &"tuple variant"));
+        }
+        self.de.deserialize_value(visitor, None)
+    }
+
+    fn struct_variant<V>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de>
+    {
+        if !self.data {
+            return Err(de::Error::invalid_type(de::Unexpected::UnitVariant, &"struct variant"));
+        }
+        self.de.deserialize_value(visitor, Some(fields))
+    }
+}
+
+
+struct AnnotatedAccess<'a, Iter: 'a + Iterator<Item=io::Result<u8>>, In: 'a> {
+    de: &'a mut Deserializer<Iter, In>,
+    annotations: Option<Vec<String>>,
+    handled: Option<Value>,
+    value: bool,
+    // Whether numbers are passed with NUMBER_KEY, see VALUE_NAME, and
+    // whether the value is one.
+    numbers: bool,
+    number: bool,
+}
+
+
+impl<'de, 'a, Iter, In> de::MapAccess<'de> for AnnotatedAccess<'a, Iter, In>
+    where Iter: Iterator<Item=io::Result<u8>>, In: Input<'de>
+{
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+        where K: de::DeserializeSeed<'de>
+    {
+        let key = if self.annotations.is_some() {
+            ANNOTATIONS_KEY
+        } else if !self.value {
+            self.number = self.numbers && self.handled.is_none() && try!(self.de.at_number());
+            if self.number { NUMBER_KEY } else { VALUE_KEY }
+        } else {
+            return Ok(None);
+        };
+        let de: de::value::StrDeserializer<Error> = key.into_deserializer();
+        seed.deserialize(de).map(Some)
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+        where V: de::DeserializeSeed<'de>
+    {
+        match self.annotations.take() {
+            Some(annotations) => seed.deserialize(annotations.into_deserializer()),
+            None => {
+                self.value = true;
+                if self.number {
+                    // Nothing else has been read since the number was.
+                    try!(self.de.next_event());
+                    let text = self.de.parser.number_text().to_string();
+                    let de: de::value::StringDeserializer<Error> = text.into_deserializer();
+                    return seed.deserialize(de);
+                }
+                match self.handled.take() {
+                    Some(value) => seed.deserialize(value::Deserializer::new(value)),
+                    None => seed.deserialize(&mut *self.de),
+                }
+            },
+        }
+    }
+}
+
+
+impl<'de, T: de::Deserialize<'de>> de::Deserialize<'de> for Annotated<T> {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> ::std::result::Result<Self, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        deserializer.deserialize_newtype_struct(ANNOTATED_NAME, AnnotatedValueVisitor(PhantomData))
+    }
+}
+
+
+struct AnnotatedValueVisitor<T>(PhantomData<T>);
+
+
+impl<'de, T: de::Deserialize<'de>> de::Visitor<'de> for AnnotatedValueVisitor<T> {
+    type Value = Annotated<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an annotated value")
+    }
+
+    // Used by the HiPack Deserializer, see ANNOTATED_NAME.
+    fn visit_map<V>(self, mut visitor: V) -> ::std::result::Result<Annotated<T>, V::Error>
+        where V: de::MapAccess<'de>
+    {
+        let annotations = match try!(visitor.next_key::<String>()) {
+            Some(ref key) if key == ANNOTATIONS_KEY => try!(visitor.next_value()),
+            _ => return Err(de::Error::custom("expected annotations")),
+        };
+        let value = match try!(visitor.next_key::<String>()) {
+            Some(ref key) if key == VALUE_KEY => try!(visitor.next_value()),
+            _ => return Err(de::Error::custom("expected annotated value")),
+        };
+        Ok(Annotated { annotations: annotations, value: value })
+    }
+
+    // Used by deserializers for other formats, which have no annotations.
+    fn visit_newtype_struct<D>(self, deserializer: D) -> ::std::result::Result<Annotated<T>, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        de::Deserialize::deserialize(deserializer).map(Annotated::new)
+    }
+}
+
+
+// Comments are not kept when reading, so only the value is read.
+impl<'de, T: de::Deserialize<'de>> de::Deserialize<'de> for Commented<T> {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> ::std::result::Result<Self, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        de::Deserialize::deserialize(deserializer).map(|value| Commented::new(String::new(), value))
+    }
+}
+
+
+/// Iterator which deserializes successive messages from an input. Each
+/// message must be a dictionary enclosed in braces, except for the last
+/// one. Iteration stops after the first error.
+pub struct StreamDeserializer<Iter, T>
+    where Iter: Iterator<Item=io::Result<u8>>, T: de::DeserializeOwned
+{
+    de: Deserializer<Iter>,
+    offset: usize,
+    failed: bool,
+    output: PhantomData<T>,
+}
+
+
+impl<Iter, T> StreamDeserializer<Iter, T>
+    where Iter: Iterator<Item=io::Result<u8>>, T: de::DeserializeOwned
+{
+    #[inline]
+    pub fn new(rdr: Iter) -> Self {
+        Deserializer::new(rdr).into_iter()
+    }
+
+    /// Returns the offset in the input right after the last message which
+    /// was successfully deserialized, or zero if there is none.
+    #[inline]
+    pub fn byte_offset(&self) -> usize {
+        self.offset
+    }
+
+    fn next_value(&mut self) -> Result<Option<T>> {
+        if !try!(self.de.next_message()) {
+            return Ok(None);
+        }
+        let value = try!(de::Deserialize::deserialize(&mut self.de));
+        try!(self.de.end());
+        self.offset = self.de.parser.offset();
+        Ok(Some(value))
+    }
+}
+
+
+impl<Iter, T> Iterator for StreamDeserializer<Iter, T>
+    where Iter: Iterator<Item=io::Result<u8>>, T: de::DeserializeOwned
+{
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Result<T>> {
+        if self.failed {
+            return None;
+        }
+        match self.next_value() {
+            Ok(value) => value.map(Ok),
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            },
+        }
+    }
+}
+
+
+#[inline]
+pub fn from_iter<I, T>(iter: I) -> Result<T>
+    where I: Iterator<Item=io::Result<u8>>, T: de::DeserializeOwned
+{
+    let mut de = Deserializer::new(iter);
+    let value = try!(de::Deserialize::deserialize(&mut de));
+    try!(de.end());
+    Ok(value)
+}
+
+#[inline]
+pub fn from_reader<R, T>(rdr: R) -> Result<T>
+    where R: Read, T: de::DeserializeOwned
+{
+    from_iter(rdr.bytes())
+}
+
+/// Deserializes a value from a slice. Strings written without escape
+/// sequences are borrowed from the input, so they can be read into `&str`
+/// fields, and into `Cow<str>` fields marked with `#[serde(borrow)]`
+/// without copying them. Strings with escapes are always copied.
+#[inline]
+pub fn from_slice<'a, T>(v: &'a [u8]) -> Result<T>
+    where T: de::Deserialize<'a>
+{
+    let mut de = Deserializer::from_slice(v);
+    let value = try!(de::Deserialize::deserialize(&mut de));
+    try!(de.end());
+    Ok(value)
+}
+
+/// Deserializes a value from a string, see `from_slice()`.
+#[inline]
+pub fn from_str<'a, T>(s: &'a str) -> Result<T>
+    where T: de::Deserialize<'a>
+{
+    from_slice(s.as_bytes())
+}
+
+/// Deserializes a value, returning along with it a report of which of
+/// its fields were present in the input and which got a default value.
+pub fn from_str_with_report<T>(s: &str) -> Result<(T, FieldReport)>
+    where T: de::DeserializeOwned
+{
+    let mut de = Deserializer::new(s.bytes().map(Ok)).field_report(true);
+    let value = try!(de::Deserialize::deserialize(&mut de));
+    try!(de.end());
+    Ok((value, de.take_field_report()))
+}
+
+/// Deserializes many independent documents concurrently, using the global
+/// Rayon thread pool. Results are returned in the same order as the input.
+#[cfg(feature = "rayon")]
+pub fn from_slices_parallel<T>(slices: &[&[u8]]) -> Vec<Result<T>>
+    where T: de::DeserializeOwned + Send
+{
+    use rayon::prelude::*;
+    slices.par_iter().map(|v| from_slice(v)).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ser::{Annotated, NullStrategy};
+    use value::Value;
+    use error::{Limit, Result};
+    use serde::de;
+    use serde::de::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_dict() {
+        let value: BTreeMap<String, Vec<u32>> = from_str("a: [1 2] b: []").unwrap();
+        assert_eq!(vec![1, 2], value["a"]);
+        assert!(value["b"].is_empty());
+
+        let value: BTreeMap<String, BTreeMap<String, String>> =
+            from_str("{outer {inner: \"x\"}}").unwrap();
+        assert_eq!("x", value["outer"]["inner"]);
+    }
+
+    #[test]
+    fn test_scalars() {
+        let value: BTreeMap<String, f64> = from_str("pi: 2.5, e: 0.75").unwrap();
+        assert_eq!(2.5, value["pi"]);
+        assert_eq!(0.75, value["e"]);
+        let value: BTreeMap<String, Option<bool>> = from_str("t: True").unwrap();
+        assert_eq!(Some(true), value["t"]);
+        let value: BTreeMap<String, i8> = from_str("n: -0x10").unwrap();
+        assert_eq!(-16, value["n"]);
+    }
+
+    #[test]
+    fn test_annotations() {
+        let value: BTreeMap<String, u32> = from_str("a: :x 1").unwrap();
+        assert_eq!(1, value["a"]);
+
+        let value: BTreeMap<String, Annotated<Vec<u32>>> = from_str("a: :x:y [:z 1] b: []").unwrap();
+        assert_eq!(Annotated::new(vec![1]).annotate("x").annotate("y"), value["a"]);
+        assert_eq!(Annotated::new(vec![]), value["b"]);
+    }
+
+    #[test]
+    fn test_annotation_handler() {
+        fn hex(value: Value) -> Result<Value> {
+            match value {
+                Value::String(ref s) if s.len() % 2 == 0 => {
+                    let mut bytes = Vec::new();
+                    for i in 0..s.len() / 2 {
+                        match u8::from_str_radix(&s[i * 2..i * 2 + 2], 16) {
+                            Ok(byte) => bytes.push(Value::U64(byte as u64)),
+                            Err(_) => return Err(de::Error::custom("invalid hex")),
+                        }
+                    }
+                    Ok(Value::List(bytes))
+                },
+                _ => Err(de::Error::custom("expected hex string")),
+            }
+        }
+
+        let input = "a: :hex \"cafe\" b: [:other :hex \"00\"]";
+        let mut de = Deserializer::new(input.bytes().map(Ok)).annotation_handler("hex", hex);
+        let value = BTreeMap::<String, Value>::deserialize(&mut de).unwrap();
+        let a = Value::List(vec![Value::U64(0xCA), Value::U64(0xFE)]);
+        let b = Value::List(vec![Value::U64(0)]);
+        assert_eq!(Value::Annotated(vec!["hex".to_string()], Box::new(a)), value["a"]);
+        assert_eq!(Value::List(vec![Value::Annotated(vec!["other".to_string(), "hex".to_string()],
+                                                     Box::new(b))]),
+                   value["b"]);
+
+        let mut de = Deserializer::new("a: :hex \"cafe\" b: :hex \"00\"".bytes().map(Ok))
+            .annotation_handler("hex", hex);
+        let value = BTreeMap::<String, Annotated<Vec<u8>>>::deserialize(&mut de).unwrap();
+        assert_eq!(Annotated::new(vec![0xCA, 0xFE]).annotate("hex"), value["a"]);
+        assert_eq!(vec![0], value["b"].value);
+
+        let mut de = Deserializer::new("a: :hex \"xyz\"".bytes().map(Ok))
+            .annotation_handler("hex", hex);
+        assert!(BTreeMap::<String, Vec<u8>>::deserialize(&mut de).is_err());
+    }
+
+    #[test]
+    fn test_scalar_parser() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Rational(i64, i64);
+
+        #[derive(Debug, Deserialize)]
+        struct Numbers {
+            a: Rational,
+            b: Vec<Rational>,
+            c: u32,
+        }
+
+        fn rational(token: &str) -> Result<Value> {
+            let mut parts = token.splitn(2, '/').map(|part| part.parse::<i64>());
+            match (parts.next(), parts.next()) {
+                (Some(Ok(n)), Some(Ok(d))) if d != 0 => {
+                    Ok(Value::List(vec![Value::I64(n), Value::I64(d)]))
+                },
+                _ => Err(de::Error::custom("invalid rational")),
+            }
+        }
+
+        let input = "a: :rational 1/3 b: [:rational -2/5, :rational \"7/1\"] c: 1";
+        let mut de = Deserializer::new(input.bytes().map(Ok)).scalar_parser("rational", rational);
+        let value = Numbers::deserialize(&mut de).unwrap();
+        assert_eq!(Rational(1, 3), value.a);
+        assert_eq!(vec![Rational(-2, 5), Rational(7, 1)], value.b);
+        assert_eq!(1, value.c);
+
+        let mut de = Deserializer::new("a: :rational 1/0".bytes().map(Ok))
+            .scalar_parser("rational", rational);
+        assert!(BTreeMap::<String, Rational>::deserialize(&mut de).is_err());
+        assert!(from_str::<BTreeMap<String, Value>>("a: :rational 1/3").is_err());
+    }
+
+    #[test]
+    fn test_null_strategy() {
+        let input = "a: [1 \"\" 2] b: \"\"";
+        let mut de = Deserializer::new(input.bytes().map(Ok))
+            .null_strategy(NullStrategy::EmptyString);
+        let value = BTreeMap::<String, Vec<Option<u8>>>::deserialize(&mut de);
+        assert!(value.is_err());
+
+        let mut de = Deserializer::new(input.bytes().map(Ok))
+            .null_strategy(NullStrategy::EmptyString);
+        let value = BTreeMap::<String, Option<Vec<Option<u8>>>>::deserialize(&mut de).unwrap();
+        assert_eq!(Some(vec![Some(1), None, Some(2)]), value["a"]);
+        assert_eq!(None, value["b"]);
+
+        let input = "a: [1 :none {} 2] b: :none {}";
+        let mut de = Deserializer::new(input.bytes().map(Ok))
+            .null_strategy(NullStrategy::AnnotatedDict);
+        let value = BTreeMap::<String, Option<Vec<Option<u8>>>>::deserialize(&mut de).unwrap();
+        assert_eq!(Some(vec![Some(1), None, Some(2)]), value["a"]);
+        assert_eq!(None, value["b"]);
+
+        let mut de = Deserializer::new("a: :none {b: 1}".bytes().map(Ok))
+            .null_strategy(NullStrategy::AnnotatedDict);
+        assert!(BTreeMap::<String, Option<u8>>::deserialize(&mut de).is_err());
+    }
+
+    #[test]
+    fn test_cancellation() {
+        use std::cell::Cell;
+        use error::Error;
+
+        let items = Cell::new(0);
+        let mut de = Deserializer::new("a: [1 2 3] b: [4]".bytes().map(Ok)).cancellation(|| false);
+        assert_eq!(vec![4], BTreeMap::<String, Vec<u8>>::deserialize(&mut de).unwrap()["b"]);
+
+        let mut de = Deserializer::new("a: [1 2 3] b: [4]".bytes().map(Ok)).cancellation(move || {
+            items.set(items.get() + 1);
+            items.get() > 3
+        });
+        match BTreeMap::<String, Vec<u8>>::deserialize(&mut de) {
+            Err(Error::Cancelled) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_deadline() {
+        use std::time::{Duration, Instant};
+        use error::Error;
+
+        let input = "a: [1 2 3] b: [4]";
+        let deadline = Instant::now() + Duration::from_secs(3600);
+        let mut de = Deserializer::new(input.bytes().map(Ok)).deadline(deadline);
+        assert!(BTreeMap::<String, Vec<u8>>::deserialize(&mut de).is_ok());
+
+        let mut de = Deserializer::new(input.bytes().map(Ok)).deadline(Instant::now());
+        match BTreeMap::<String, Vec<u8>>::deserialize(&mut de) {
+            Err(Error::DeadlineExceeded) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        use std::mem;
+        use std::rc::Rc;
+        use std::cell::Cell;
+        use error::{Error, ErrorCode};
+
+        let size = mem::size_of::<Value>();
+        let used = Rc::new(Cell::new(0));
+        let callback_used = used.clone();
+        let mut de = Deserializer::new("a: [\"xyz\"]".bytes().map(Ok))
+            .memory_callback(move |memory| callback_used.set(memory));
+        BTreeMap::<String, Vec<String>>::deserialize(&mut de).unwrap();
+        assert_eq!(4 * size + 4, used.get());
+
+        let mut de = Deserializer::new("a: [\"xyz\"]".bytes().map(Ok)).memory_limit(4 * size + 4);
+        assert!(BTreeMap::<String, Vec<String>>::deserialize(&mut de).is_ok());
+
+        let mut de = Deserializer::new("a: [\"xyz\"]".bytes().map(Ok)).memory_limit(4 * size + 3);
+        match BTreeMap::<String, Vec<String>>::deserialize(&mut de) {
+            Err(Error::SyntaxError(ErrorCode::MemoryLimitExceeded, _, _, _)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_stream_deserializer() {
+        let input = "{a: 1} {a: 2}\n{}";
+        let mut stream = StreamDeserializer::<_, BTreeMap<String, u32>>::new(input.bytes().map(Ok));
+        assert_eq!(1, stream.next().unwrap().unwrap()["a"]);
+        assert_eq!(6, stream.byte_offset());
+        assert_eq!(2, stream.next().unwrap().unwrap()["a"]);
+        assert_eq!(13, stream.byte_offset());
+        assert!(stream.next().unwrap().unwrap().is_empty());
+        assert_eq!(input.len(), stream.byte_offset());
+        assert!(stream.next().is_none());
+
+        let mut stream = Deserializer::new("{a: 1} {a: x} {a: 3}".bytes().map(Ok))
+            .into_iter::<BTreeMap<String, u32>>();
+        assert!(stream.next().unwrap().is_ok());
+        assert!(stream.next().unwrap().is_err());
+        assert!(stream.next().is_none());
+        assert_eq!(6, stream.byte_offset());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_from_slices_parallel() {
+        let slices: Vec<&[u8]> = vec![b"a: 1", b"a: x", b"a: 3"];
+        let results = from_slices_parallel::<BTreeMap<String, u32>>(&slices);
+        assert_eq!(3, results.len());
+        assert_eq!(1, results[0].as_ref().unwrap()["a"]);
+        assert!(results[1].is_err());
+        assert_eq!(3, results[2].as_ref().unwrap()["a"]);
+    }
+
+    #[test]
+    fn test_fold_key_case() {
+        let input = "Name: \"x\" NESTED: {InnerKey: 1}";
+        let mut de = Deserializer::new(input.bytes().map(Ok));
+        let value = BTreeMap::<String, Value>::deserialize(&mut de).unwrap();
+        assert!(value.contains_key("Name"));
+
+        let mut de = Deserializer::new(input.bytes().map(Ok)).fold_key_case(true);
+        let value = BTreeMap::<String, Value>::deserialize(&mut de).unwrap();
+        assert_eq!(Value::String("x".to_string()), value["name"]);
+        let mut inner = value::Map::new();
+        inner.insert("innerkey".to_string(), Value::U64(1));
+        assert_eq!(Value::Dict(inner), value["nested"]);
+    }
+
+    #[test]
+    fn test_key_alias() {
+        let input = "hostname: \"a\" Port: 1";
+        let mut de = Deserializer::new(input.bytes().map(Ok))
+            .key_alias("hostname", "host")
+            .key_alias("port", "port_number")
+            .fold_key_case(true);
+        let value = BTreeMap::<String, Value>::deserialize(&mut de).unwrap();
+        assert_eq!(Value::String("a".to_string()), value["host"]);
+        assert_eq!(Value::U64(1), value["port_number"]);
+        assert!(!value.contains_key("hostname"));
+    }
+
+    #[test]
+    fn test_deprecated_key() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+        use error::{Warning, WarningCode};
+
+        let warnings = Rc::new(RefCell::new(Vec::new()));
+        let callback_warnings = warnings.clone();
+        let input = "hostname: \"a\"\nservers: [{port: 1} {port: 2 hostname: \"b\"}]";
+        let mut de = Deserializer::new(input.bytes().map(Ok))
+            .deprecated_key("/servers/port", "/servers/port_number")
+            .deprecated_key("/hostname", "/host")
+            .warning_handler(move |warning| callback_warnings.borrow_mut().push(warning));
+        BTreeMap::<String, Value>::deserialize(&mut de).unwrap();
+        let warnings = warnings.borrow();
+        assert_eq!(3, warnings.len());
+        assert_eq!(Warning {
+            code: WarningCode::DeprecatedKey("/hostname".to_string(), "/host".to_string()),
+            line: 1,
+            column: 11,
+        }, warnings[0]);
+        assert_eq!(2, warnings[1].line);
+        assert!(warnings[1..].iter().all(|warning| {
+            warning.code == WarningCode::DeprecatedKey("/servers/port".to_string(),
+                                                       "/servers/port_number".to_string())
+        }));
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Endpoint {
+        host: String,
+        port: u16,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Cluster {
+        name: String,
+        servers: Vec<Endpoint>,
+    }
+
+    #[test]
+    fn test_default_value() {
+        let input = "servers: [{host: \"a\"} {host: \"b\" port: 81}]";
+        let de = || {
+            Deserializer::new(input.bytes().map(Ok))
+                .default_value("/servers/port", 80)
+                .default_value("/name", "x")
+                .default_value("/servers/host/port", 1)
+        };
+        let cluster = Cluster::deserialize(&mut de()).unwrap();
+        assert_eq!("x", cluster.name);
+        assert_eq!(Endpoint { host: "a".to_string(), port: 80 }, cluster.servers[0]);
+        assert_eq!(Endpoint { host: "b".to_string(), port: 81 }, cluster.servers[1]);
+
+        let mut de = de().duplicate_keys(DuplicateKeyPolicy::LastWins);
+        let value = Value::deserialize(&mut de).unwrap();
+        assert_eq!(Value::from("x"), value["name"]);
+        assert_eq!(Value::U64(80), value["servers"][0]["port"]);
+        assert_eq!(Value::U64(81), value["servers"][1]["port"]);
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Options {
+        name: Option<String>,
+        port: Option<u64>,
+    }
+
+    #[test]
+    fn test_field_report() {
+        let input = "server: {port: 80} client: {name: \"x\" port: 1}";
+        let (value, report) = from_str_with_report::<BTreeMap<String, Options>>(input).unwrap();
+        assert_eq!(Options { name: None, port: Some(80) }, value["server"]);
+        assert_eq!(vec!["/server", "/server/port", "/client", "/client/name", "/client/port"],
+                   report.present);
+        assert_eq!(vec!["/server/name"], report.defaulted);
+
+        let mut de = Deserializer::new("port: 1".bytes().map(Ok));
+        Options::deserialize(&mut de).unwrap();
+        assert_eq!(FieldReport::default(), de.take_field_report());
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    enum Shape {
+        Point,
+        Circle(f64),
+        Rect(u32, u32),
+        Named { id: u32 },
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(untagged)]
+    enum Setting {
+        Flag(bool),
+        Port(u16),
+        Name(String),
+        Ports(Vec<u16>),
+        Server { host: String, port: u16 },
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Common {
+        name: String,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Service {
+        #[serde(flatten)]
+        common: Common,
+        #[serde(flatten)]
+        settings: BTreeMap<String, Setting>,
+    }
+
+    #[test]
+    fn test_deserialize_any() {
+        let input = "name: \"web\" debug: True port: 0x50 alias: \"www\" extra: [80, 443] \
+                     upstream: :ignored {host: \"b\" port: 81}";
+        let service: Service = from_str(input).unwrap();
+        assert_eq!("web", service.common.name);
+        assert_eq!(Setting::Flag(true), service.settings["debug"]);
+        assert_eq!(Setting::Port(80), service.settings["port"]);
+        assert_eq!(Setting::Name("www".to_string()), service.settings["alias"]);
+        assert_eq!(Setting::Ports(vec![80, 443]), service.settings["extra"]);
+        assert_eq!(Setting::Server { host: "b".to_string(), port: 81 }, service.settings["upstream"]);
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Partial {
+            name: String,
+        }
+        let input = "skipped: {a: [1, \"x\", {b: 0x10 c: [[]] d: :x 1.5}]} name: \"web\"";
+        assert_eq!(Partial { name: "web".to_string() }, from_str(input).unwrap());
+        assert!(from_str::<Partial>("skipped: {a: [1 name: \"web\"").is_err());
+        assert!(from_str::<Partial>("skipped: \"\\zz\" name: \"web\"").is_err());
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Wrappers<'a> {
+        #[serde(borrow)]
+        name: ::std::borrow::Cow<'a, str>,
+        path: ::std::borrow::Cow<'a, str>,
+        limit: Box<u32>,
+        tags: ::std::rc::Rc<[String]>,
+        hosts: ::std::sync::Arc<Vec<::std::rc::Rc<str>>>,
+    }
+
+    #[test]
+    fn test_wrappers() {
+        let input = "name: \"web\" path: \"/srv\" limit: 10 tags [\"a\"] hosts [\"x\" \"y\"]";
+        let value: Wrappers = from_str(input).unwrap();
+        match value.name {
+            ::std::borrow::Cow::Borrowed(name) => assert_eq!("web", name),
+            ::std::borrow::Cow::Owned(_) => panic!("string was copied"),
+        }
+        // Fields without #[serde(borrow)] always own their strings.
+        assert!(match value.path { ::std::borrow::Cow::Owned(_) => true, _ => false });
+        assert_eq!(10, *value.limit);
+        assert_eq!(vec!["a".to_string()], &value.tags[..]);
+        assert_eq!("y", &*value.hosts[1]);
+        let output = ::ser::to_string(&value).unwrap();
+        assert_eq!("{name:\"web\",path:\"/srv\",limit:10,tags:[\"a\"],hosts:[\"x\",\"y\"]}", output);
+        assert_eq!(value, from_str(&output).unwrap());
+        let escaped: Wrappers = from_str("name: \"w\\\"b\" path: \"\" limit: 1 tags [] hosts []").unwrap();
+        assert!(match escaped.name { ::std::borrow::Cow::Owned(ref name) => name == "w\"b", _ => false });
+        let items: BTreeMap<String, Vec<&str>> = from_str("items: [\"x\" \"\"]").unwrap();
+        assert_eq!(vec!["x", ""], items["items"]);
+    }
+
+    #[test]
+    fn test_enum() {
+        let expected = vec![Shape::Point, Shape::Circle(1.5), Shape::Rect(2, 3),
+                            Shape::Named { id: 4 }];
+        let input = "shapes: [\"Point\" {Circle: 1.5} {Rect: [2 3]} {Named: {id: 4}}]";
+        assert_eq!(expected, from_str::<BTreeMap<String, Vec<Shape>>>(input).unwrap()["shapes"]);
+        let input = "shapes: [\"Point\" :Circle 1.5 :Rect [2 3] :Named {id: 4}]";
+        assert_eq!(expected, from_str::<BTreeMap<String, Vec<Shape>>>(input).unwrap()["shapes"]);
+
+        assert!(from_str::<BTreeMap<String, Shape>>("a: \"Square\"").is_err());
+        assert!(from_str::<BTreeMap<String, Shape>>("a: {Point: 1}").is_err());
+        assert!(from_str::<BTreeMap<String, Shape>>("a: {Circle: 1.5 Rect: [1 2]}").is_err());
+        assert!(from_str::<BTreeMap<String, Shape>>("a: \"Circle\"").is_err());
+        assert!(from_str::<BTreeMap<String, Shape>>("a: 1").is_err());
+    }
+
+    #[test]
+    fn test_bytes() {
+        use std::fmt;
+        use std::rc::Rc;
+        use std::cell::Cell;
+
+        // Deserialized as a byte string, like serde_bytes::ByteBuf.
+        #[derive(Debug)]
+        struct ByteBuf(Vec<u8>);
+
+        impl<'de> Deserialize<'de> for ByteBuf {
+            fn deserialize<D>(deserializer: D) -> ::std::result::Result<Self, D::Error>
+                where D: de::Deserializer<'de>
+            {
+                struct ByteBufVisitor;
+                impl<'de> de::Visitor<'de> for ByteBufVisitor {
+                    type Value = ByteBuf;
+                    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                        f.write_str("a byte string")
+                    }
+                    fn visit_byte_buf<E>(self, v: Vec<u8>) -> ::std::result::Result<ByteBuf, E> {
+                        Ok(ByteBuf(v))
+                    }
+                    fn visit_bytes<E>(self, v: &[u8]) -> ::std::result::Result<ByteBuf, E> {
+                        Ok(ByteBuf(v.to_vec()))
+                    }
+                    fn visit_seq<V>(self, mut visitor: V) -> ::std::result::Result<ByteBuf, V::Error>
+                        where V: de::SeqAccess<'de>
+                    {
+                        let mut bytes = Vec::new();
+                        while let Some(byte) = try!(visitor.next_element()) {
+                            bytes.push(byte);
+                        }
+                        Ok(ByteBuf(bytes))
+                    }
+                }
+                deserializer.deserialize_byte_buf(ByteBufVisitor)
+            }
+        }
+
+        for input in &["a: [104 105 255]", "a: \"hi\\FF\"", "a: :base64 \"aGn/\""] {
+            let value: BTreeMap<String, ByteBuf> = from_str(input).unwrap();
+            assert_eq!(b"hi\xFF", &value["a"].0[..]);
+        }
+        let value: BTreeMap<String, ByteBuf> = from_str("a: \"hi\"").unwrap();
+        assert_eq!(b"hi", &value["a"].0[..]);
+        let error = from_str::<BTreeMap<String, ByteBuf>>("a: :base64 \"a!\"").unwrap_err();
+        assert_eq!("a: Invalid Base64 string at line 1 column 16", error.to_string());
+        assert!(from_str::<BTreeMap<String, String>>("a: \"hi\\FF\"").is_err());
+
+        // Decoded bytes count towards the memory used, besides the string.
+        let memory = |input: &str| {
+            let used = Rc::new(Cell::new(0));
+            let callback_used = used.clone();
+            let mut de = Deserializer::new(input.bytes().map(Ok))
+                .memory_callback(move |memory| callback_used.set(memory));
+            BTreeMap::<String, ByteBuf>::deserialize(&mut de).unwrap();
+            used.get()
+        };
+        assert_eq!(4 + 3, memory("a: :base64 \"aGn/\"") - memory("a: :base64 \"\""));
+    }
+
+    #[test]
+    fn test_type_mismatch() {
+        assert!(from_str::<BTreeMap<String, u32>>("a: \"x\"").is_err());
+        assert!(from_str::<BTreeMap<String, bool>>("a: [True]").is_err());
+    }
+
+    #[test]
+    fn test_parser_options() {
+        fn limit_error(input: &str, options: ParserOptions) -> Option<Limit> {
+            let mut de = Deserializer::new(input.bytes().map(Ok)).parser_options(options);
+            match Value::deserialize(&mut de) {
+                Ok(_) => None,
+                Err(error) => match *error.inner() {
+                    Error::SyntaxError(ErrorCode::LimitExceeded(limit), _, _, _) => Some(limit),
+                    ref error => panic!("unexpected error {:?}", error),
+                },
+            }
+        }
+
+        let input = "a: [1 2 3] b: \"four\" c: {d: 1 e: 2}";
+        let options = ParserOptions {
+            max_input_size: Some(input.len()),
+            max_string_length: Some(4),
+            max_list_length: Some(3),
+            max_dict_size: Some(3),
+        };
+        assert_eq!(None, limit_error(input, options.clone()));
+        let opts = ParserOptions { max_input_size: Some(10), ..options.clone() };
+        assert_eq!(Some(Limit::InputSize), limit_error(input, opts));
+        let opts = ParserOptions { max_string_length: Some(3), ..options.clone() };
+        assert_eq!(Some(Limit::StringLength), limit_error(input, opts));
+        let opts = ParserOptions { max_string_length: Some(1), ..options.clone() };
+        assert_eq!(Some(Limit::StringLength), limit_error("ab: 1", opts));
+        let opts = ParserOptions { max_list_length: Some(2), ..options.clone() };
+        assert_eq!(Some(Limit::ListLength), limit_error(input, opts));
+        let opts = ParserOptions { max_dict_size: Some(2), ..options.clone() };
+        assert_eq!(Some(Limit::DictSize), limit_error(input, opts));
+        let opts = ParserOptions { max_dict_size: Some(2), ..ParserOptions::default() };
+        assert_eq!(Some(Limit::DictSize), limit_error("x {a: 1 b: 2 c: 3}", opts));
+    }
+
+    #[test]
+    fn test_key_order() {
+        use indexmap::IndexMap;
+
+        let input = "{z:{y:1,b:2},a:{c:3},m:{}}";
+        let value: IndexMap<String, IndexMap<String, u8>> = from_str(input).unwrap();
+        assert_eq!(vec!["z", "a", "m"], value.keys().collect::<Vec<_>>());
+        assert_eq!(vec!["y", "b"], value["z"].keys().collect::<Vec<_>>());
+        assert_eq!(input, ::ser::to_string(&value).unwrap());
+
+        let input = "z: 1 a: 2 z: 3 m: 4";
+        for &(policy, z) in &[(DuplicateKeyPolicy::FirstWins, 1), (DuplicateKeyPolicy::LastWins, 3)] {
+            let mut de = Deserializer::new(input.bytes().map(Ok)).duplicate_keys(policy);
+            let value = IndexMap::<String, u8>::deserialize(&mut de).unwrap();
+            assert_eq!(vec![("z", z), ("a", 2), ("m", 4)],
+                       value.iter().map(|(key, &value)| (&key[..], value)).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_duplicate_keys() {
+        fn parse<T>(input: &str, policy: DuplicateKeyPolicy) -> Result<T>
+            where T: de::DeserializeOwned
+        {
+            let mut de = Deserializer::new(input.bytes().map(Ok)).duplicate_keys(policy);
+            T::deserialize(&mut de)
+        }
+
+        let input = "name: \"a\" port: 1 name: \"b\"";
+        let first = Options { name: Some("a".to_string()), port: Some(1) };
+        let last = Options { name: Some("b".to_string()), port: Some(1) };
+        assert_eq!(first, parse(input, DuplicateKeyPolicy::FirstWins).unwrap());
+        assert_eq!(last, parse(input, DuplicateKeyPolicy::LastWins).unwrap());
+        assert!(from_str::<Options>(input).is_err());
+        match parse::<Options>(input, DuplicateKeyPolicy::Error).unwrap_err() {
+            Error::SyntaxError(ErrorCode::DuplicateKey(ref key), _, 1, 25) => assert_eq!("name", key),
+            error => panic!("unexpected error {:?}", error),
+        }
+
+        let input = "x {a: [1 {b: 2}] A: 3} x {a: 4}";
+        let value: Value = parse(input, DuplicateKeyPolicy::FirstWins).unwrap();
+        assert_eq!(value, from_str("x {a: [1 {b: 2}] A: 3}").unwrap());
+        let value: Value = parse(input, DuplicateKeyPolicy::LastWins).unwrap();
+        assert_eq!(value, from_str("x {a: 4}").unwrap());
+        let error = parse::<Value>("x {a: 1 a: 2}", DuplicateKeyPolicy::Error).unwrap_err();
+        assert_eq!(Some("x"), error.path());
+        let mut de = Deserializer::new(input.bytes().map(Ok))
+            .fold_key_case(true)
+            .duplicate_keys(DuplicateKeyPolicy::Error);
+        assert!(Value::deserialize(&mut de).is_err());
+    }
+
+    #[test]
+    fn test_recursion_limit() {
+        let input = format!("a: {}{}", "[".repeat(200), "]".repeat(200));
+        let mut de = Deserializer::new(input.bytes().map(Ok));
+        let error = Value::deserialize(&mut de).unwrap_err();
+        assert_eq!((Some(131), Some(1), Some(132)), (error.offset(), error.line(), error.column()));
+        let mut de = Deserializer::new(input.bytes().map(Ok)).recursion_limit(200);
+        assert!(Value::deserialize(&mut de).is_ok());
+        assert_eq!(None, Error::Cancelled.line());
+        assert_eq!(None, from_str::<Options>("port: 1 x").unwrap_err().path());
+    }
+
+    #[test]
+    fn test_error_path() {
+        let input = "servers: [{port: 80} {port: 81} {name: \"x\" port: \"http\"}]";
+        let error = from_str::<BTreeMap<String, Vec<Options>>>(input).unwrap_err();
+        assert_eq!(Some("servers[2].port"), error.path());
+        match *error.inner() {
+            Error::SyntaxError(ErrorCode::Custom(_), _, 1, _) => (),
+            ref error => panic!("unexpected error {:?}", error),
+        }
+        let error = from_str::<BTreeMap<String, Vec<Vec<u8>>>>("a: [[1] [2 300]]").unwrap_err();
+        assert_eq!(Some("a[1][1]"), error.path());
+        assert!(error.to_string().starts_with("a[1][1]: "));
+        assert_eq!(Some("port"), from_str::<Options>("port: True").unwrap_err().path());
+        assert_eq!(None, from_str::<Vec<u8>>("").unwrap_err().path());
+    }
+
+    #[test]
+    fn test_integers() {
+        let input = "a: 340282366920938463463374607431768211455 b: -0x80000000000000000000000000000000";
+        let value: BTreeMap<String, i128> = from_str("a: 1 b: -0x80000000000000000000000000000000")
+            .unwrap();
+        assert_eq!(1, value["a"]);
+        assert_eq!(i128::min_value(), value["b"]);
+        let value: BTreeMap<String, u128> =
+            from_str("a: 340282366920938463463374607431768211455").unwrap();
+        assert_eq!(u128::max_value(), value["a"]);
+        let value: BTreeMap<String, Value> = from_str(input).unwrap();
+        assert_eq!(Value::U128(u128::max_value()), value["a"]);
+        assert_eq!(Value::I128(i128::min_value()), value["b"]);
+
+        let error = from_str::<BTreeMap<String, Vec<u8>>>("a: [1 300]").unwrap_err();
+        assert_eq!(Some("a[1]"), error.path());
+        assert!(error.to_string().starts_with("a[1]: Integer 300 out of range 0..=255 at line 1"));
+        assert!(from_str::<BTreeMap<String, u64>>("a: 0x10000000000000000").is_err());
+        assert!(from_str::<BTreeMap<String, u32>>("a: -1").is_err());
+
+        let mut de = Deserializer::new("a: [300 -1 7]".bytes().map(Ok))
+            .overflow_policy(OverflowPolicy::Saturate);
+        let value = BTreeMap::<String, Vec<u8>>::deserialize(&mut de).unwrap();
+        assert_eq!(vec![255, 0, 7], value["a"]);
+        use std::rc::Rc;
+        use std::cell::RefCell;
+        let warnings = Rc::new(RefCell::new(Vec::new()));
+        let callback_warnings = warnings.clone();
+        let mut de = Deserializer::new("a: -200".bytes().map(Ok))
+            .overflow_policy(OverflowPolicy::Saturate)
+            .warning_handler(move |warning| callback_warnings.borrow_mut().push(warning));
+        assert_eq!(-128, BTreeMap::<String, i8>::deserialize(&mut de).unwrap()["a"]);
+        assert_eq!(vec![WarningCode::IntegerClamped("-200".to_string(), "-128".to_string())],
+                   warnings.borrow().iter().map(|warning| warning.code.clone()).collect::<Vec<_>>());
+        let mut de = Deserializer::new("b: [0x10000000000000000 -0x10000000000000000]"
+                                           .bytes().map(Ok))
+            .overflow_policy(OverflowPolicy::Saturate);
+        let value = BTreeMap::<String, Vec<i64>>::deserialize(&mut de).unwrap();
+        assert_eq!(vec![i64::max_value(), i64::min_value()], value["b"]);
+
+        // Values other than integers reach the visitor as they are.
+        #[derive(Debug, PartialEq)]
+        enum Port<'a> { Number(u16), Service(&'a str) }
+        impl<'de: 'a, 'a> Deserialize<'de> for Port<'a> {
+            fn deserialize<D>(deserializer: D) -> ::std::result::Result<Port<'a>, D::Error>
+                where D: de::Deserializer<'de>
+            {
+                struct PortVisitor;
+                impl<'de> de::Visitor<'de> for PortVisitor {
+                    type Value = Port<'de>;
+                    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                        f.write_str("a port number or service name")
+                    }
+                    fn visit_u64<E>(self, v: u64) -> ::std::result::Result<Port<'de>, E> {
+                        Ok(Port::Number(v as u16))
+                    }
+                    fn visit_borrowed_str<E>(self, v: &'de str) -> ::std::result::Result<Port<'de>, E> {
+                        Ok(Port::Service(v))
+                    }
+                }
+                deserializer.deserialize_u16(PortVisitor)
+            }
+        }
+        let value: BTreeMap<String, Vec<Port>> = from_slice(b"a: [80 \"http\"]").unwrap();
+        assert_eq!(vec![Port::Number(80), Port::Service("http")], value["a"]);
+    }
+
+    #[test]
+    fn test_lenient_bools() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Flags {
+            a: bool,
+            b: bool,
+            c: bool,
+        }
+
+        let input = "a: \"Yes\" b: \"0\" c: True";
+        assert!(from_str::<Flags>(input).is_err());
+        use std::rc::Rc;
+        use std::cell::RefCell;
+        let warnings = Rc::new(RefCell::new(Vec::new()));
+        let callback_warnings = warnings.clone();
+        let mut de = Deserializer::new(input.bytes().map(Ok))
+            .lenient_bools(true)
+            .warning_handler(move |warning| callback_warnings.borrow_mut().push(warning));
+        assert_eq!(Flags { a: true, b: false, c: true }, Flags::deserialize(&mut de).unwrap());
+        assert_eq!(vec![WarningCode::BoolCoerced("Yes".to_string()),
+                        WarningCode::BoolCoerced("0".to_string())],
+                   warnings.borrow().iter().map(|warning| warning.code.clone()).collect::<Vec<_>>());
+        let mut de = Deserializer::new("a: \"maybe\" b: True c: True".bytes().map(Ok))
+            .lenient_bools(true);
+        assert!(Flags::deserialize(&mut de).is_err());
+    }
+
+    #[test]
+    fn test_integral_floats() {
+        let input = "a: [42.0 -1e3 0.0]";
+        assert!(from_str::<BTreeMap<String, Vec<i32>>>(input).is_err());
+        let mut de = Deserializer::new(input.bytes().map(Ok)).integral_floats(true);
+        let value = BTreeMap::<String, Vec<i32>>::deserialize(&mut de).unwrap();
+        assert_eq!(vec![42, -1000, 0], value["a"]);
+        for input in &["a: 1.5", "a: 300.0", "a: NaN"] {
+            let mut de = Deserializer::new(input.bytes().map(Ok)).integral_floats(true);
+            assert!(BTreeMap::<String, u8>::deserialize(&mut de).is_err(), "{}", input);
+        }
+        let mut de = Deserializer::new("a: 1e40".bytes().map(Ok))
+            .integral_floats(true)
+            .overflow_policy(OverflowPolicy::Saturate);
+        assert_eq!(u64::max_value(), BTreeMap::<String, u64>::deserialize(&mut de).unwrap()["a"]);
+    }
+
+    #[test]
+    fn test_json_compat() {
+        let input = "{\"a\": \"x\", \"b\": null, \"c\": \"\\u00e9\\ud83d\\ude00\"}";
+        assert!(from_str::<BTreeMap<String, String>>(input).is_err());
+
+        let mut de = Deserializer::new(input.bytes().map(Ok)).json_compat(true);
+        let value: BTreeMap<String, Option<String>> = Deserialize::deserialize(&mut de).unwrap();
+        de.end().unwrap();
+        assert_eq!(2, value.len());
+        assert_eq!(Some("é😀".to_string()), value["c"]);
+        assert!(!value.contains_key("b"));
+
+        let mut de = Deserializer::new("{\"a\": [true]}".bytes().map(Ok)).json_compat(true);
+        assert_eq!(vec![true], BTreeMap::<String, Vec<bool>>::deserialize(&mut de).unwrap()["a"]);
+
+        let mut de = Deserializer::new("{\"a\": [null]}".bytes().map(Ok)).json_compat(true);
+        assert!(BTreeMap::<String, Vec<bool>>::deserialize(&mut de).is_err());
+    }
+}
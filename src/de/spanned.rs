@@ -0,0 +1,277 @@
+//
+// spanned.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use std::{fmt, io};
+use std::marker::PhantomData;
+use std::ops::Range;
+use serde::{de, ser};
+use error::{Error, Result};
+use value::{SPANNED_NAME, SPAN_END_KEY, SPAN_START_KEY, VALUE_KEY};
+use super::{Deserializer, Input};
+
+
+/// Position in the input. Lines and columns start at 1, as in errors.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+
+/// A value along with the positions in the input where it starts and
+/// ends, e.g. to report problems in the value found after deserializing
+/// it:
+///
+/// ```
+/// # #[macro_use]
+/// # extern crate serde_derive;
+/// # extern crate serde_hipack;
+/// # use serde_hipack::de::{from_str, Spanned};
+/// #[derive(Deserialize)]
+/// struct Config {
+///     port: Spanned<u16>,
+/// }
+///
+/// # fn main() {
+/// let config: Config = from_str("name: \"web\"\nport: 80").unwrap();
+/// if *config.port.get_ref() < 1024 {
+///     let start = config.port.start();
+///     println!("Privileged port at line {} column {}", start.line, start.column);
+/// #   assert_eq!((2, 7), (start.line, start.column));
+/// }
+/// # }
+/// ```
+///
+/// The span includes the annotations of the value, and it ends after its
+/// last character. Values which are not read straight from HiPack text
+/// (e.g. from a `Value`, or when reading dictionaries as
+/// `DuplicateKeyPolicy::LastWins`) have empty spans at the start of the
+/// input. The Serializer writes the value alone.
+#[derive(Clone, Debug)]
+pub struct Spanned<T> {
+    value: T,
+    start: Position,
+    end: Position,
+}
+
+
+impl<T> Spanned<T> {
+    /// Wraps a value, with an empty span.
+    #[inline]
+    pub fn new(value: T) -> Self {
+        Spanned { value: value, start: Position::default(), end: Position::default() }
+    }
+
+    #[inline]
+    pub fn start(&self) -> Position {
+        self.start
+    }
+
+    #[inline]
+    pub fn end(&self) -> Position {
+        self.end
+    }
+
+    /// Byte range of the value in the input.
+    #[inline]
+    pub fn span(&self) -> Range<usize> {
+        self.start.offset..self.end.offset
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &T {
+        &self.value
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+
+/// Spans are not compared, only values.
+impl<T: PartialEq> PartialEq for Spanned<T> {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+
+impl<T: ser::Serialize> ser::Serialize for Spanned<T> {
+    #[inline]
+    fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+        where S: ser::Serializer
+    {
+        self.value.serialize(serializer)
+    }
+}
+
+
+impl<'de, T: de::Deserialize<'de>> de::Deserialize<'de> for Spanned<T> {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> ::std::result::Result<Self, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        deserializer.deserialize_newtype_struct(SPANNED_NAME, SpannedVisitor(PhantomData))
+    }
+}
+
+
+struct SpannedVisitor<T>(PhantomData<T>);
+
+
+impl<'de, T: de::Deserialize<'de>> de::Visitor<'de> for SpannedVisitor<T> {
+    type Value = Spanned<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a value with its span")
+    }
+
+    // Used by the HiPack Deserializer, see SPANNED_NAME.
+    fn visit_map<V>(self, mut visitor: V) -> ::std::result::Result<Spanned<T>, V::Error>
+        where V: de::MapAccess<'de>
+    {
+        try!(expect_key(&mut visitor, SPAN_START_KEY));
+        let (offset, line, column) = try!(visitor.next_value());
+        let start = Position { offset: offset, line: line, column: column };
+        try!(expect_key(&mut visitor, VALUE_KEY));
+        let value = try!(visitor.next_value());
+        try!(expect_key(&mut visitor, SPAN_END_KEY));
+        let (offset, line, column) = try!(visitor.next_value());
+        let end = Position { offset: offset, line: line, column: column };
+        Ok(Spanned { value: value, start: start, end: end })
+    }
+
+    // Used by deserializers for other formats, which have no positions.
+    fn visit_newtype_struct<D>(self, deserializer: D) -> ::std::result::Result<Spanned<T>, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        de::Deserialize::deserialize(deserializer).map(Spanned::new)
+    }
+}
+
+
+fn expect_key<'de, V>(visitor: &mut V, name: &str) -> ::std::result::Result<(), V::Error>
+    where V: de::MapAccess<'de>
+{
+    match try!(visitor.next_key::<String>()) {
+        Some(ref key) if key == name => Ok(()),
+        _ => Err(de::Error::custom("expected spanned value")),
+    }
+}
+
+
+// Passes the value being read as a map, see SPANNED_NAME.
+pub fn deserialize_spanned<'de, Iter, In, V>(de: &mut Deserializer<Iter, In>, visitor: V) -> Result<V::Value>
+    where Iter: Iterator<Item=io::Result<u8>>, In: Input<'de>, V: de::Visitor<'de>
+{
+    if de.peeked.is_none() {
+        try!(de.parser.skip_to_value());
+    }
+    let start = position(de);
+    visitor.visit_map(SpannedAccess { de: de, start: Some(start), value: false, end: false })
+}
+
+
+fn position<Iter, In>(de: &Deserializer<Iter, In>) -> Position
+    where Iter: Iterator<Item=io::Result<u8>>
+{
+    let (line, column) = de.parser.position();
+    Position { offset: de.parser.offset(), line: line, column: column }
+}
+
+
+struct SpannedAccess<'a, Iter: 'a + Iterator<Item=io::Result<u8>>, In: 'a> {
+    de: &'a mut Deserializer<Iter, In>,
+    start: Option<Position>,
+    value: bool,
+    end: bool,
+}
+
+
+impl<'de, 'a, Iter, In> de::MapAccess<'de> for SpannedAccess<'a, Iter, In>
+    where Iter: Iterator<Item=io::Result<u8>>, In: Input<'de>
+{
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+        where K: de::DeserializeSeed<'de>
+    {
+        let key = if self.start.is_some() {
+            SPAN_START_KEY
+        } else if !self.value {
+            VALUE_KEY
+        } else if !self.end {
+            SPAN_END_KEY
+        } else {
+            return Ok(None);
+        };
+        let de: de::value::StrDeserializer<Error> = de::IntoDeserializer::into_deserializer(key);
+        seed.deserialize(de).map(Some)
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+        where V: de::DeserializeSeed<'de>
+    {
+        let position = match self.start.take() {
+            Some(start) => start,
+            None if !self.value => {
+                self.value = true;
+                return seed.deserialize(&mut *self.de);
+            },
+            None => {
+                self.end = true;
+                position(self.de)
+            },
+        };
+        let items = vec![position.offset, position.line, position.column];
+        seed.deserialize(de::value::SeqDeserializer::<_, Error>::new(items.into_iter()))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use super::Spanned;
+    use de::from_str;
+    use value::{Deserializer, Value};
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    struct Server {
+        host: Spanned<String>,
+        ports: Vec<Spanned<u16>>,
+    }
+
+    #[test]
+    fn test_spanned() {
+        let text = "host: \"example.org\"\nports [80, 443]";
+        let server: Server = from_str(text).unwrap();
+        assert_eq!("example.org", server.host.get_ref());
+        assert_eq!("\"example.org\"", &text[server.host.span()]);
+        assert_eq!((1, 7), (server.host.start().line, server.host.start().column));
+        assert_eq!(vec![80, 443], server.ports.iter().map(|p| *p.get_ref()).collect::<Vec<_>>());
+        assert_eq!("443", &text[server.ports[1].span()]);
+        assert_eq!(2, server.ports[1].start().line);
+
+        // Annotations are part of the span.
+        let text = "item: :a 12 ";
+        let mut items: BTreeMap<String, Spanned<Value>> = from_str(text).unwrap();
+        assert_eq!(":a 12", &text[items.remove("item").unwrap().span()]);
+
+        let value: Spanned<u16> = Spanned::deserialize(Deserializer::new(Value::U64(8))).unwrap();
+        assert_eq!(8, value.into_inner());
+    }
+}
@@ -0,0 +1,176 @@
+//
+// diff.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+//! Structural differences between two values.
+//!
+//! `diff()` compares two values, e.g. revisions of a generated
+//! configuration, and lists what changed at each path. Paths use the
+//! same syntax as `Value::pointer()`, so each change can be looked up in
+//! the values being compared:
+//!
+//! ```text
+//! for change in diff(&old, &new) {
+//!     println!("{:?} {}", change.kind(), change.path());
+//! }
+//! ```
+
+use super::value::Value;
+
+
+/// A difference between two values, at a path like `/servers/0/port`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    /// A dictionary entry or a list item which is only in the new value.
+    Added { path: String, value: Value },
+    /// A dictionary entry or a list item which is only in the old value.
+    Removed { path: String, value: Value },
+    /// A value which differs in type, annotations, or (for values which
+    /// are not dictionaries or lists) contents.
+    Modified { path: String, old: Value, new: Value },
+}
+
+
+/// Kind of a `Change`, without its data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+
+impl Change {
+    /// Path of the value which changed, empty for the values themselves.
+    pub fn path(&self) -> &str {
+        match *self {
+            Change::Added { ref path, .. } |
+            Change::Removed { ref path, .. } |
+            Change::Modified { ref path, .. } => path,
+        }
+    }
+
+    pub fn kind(&self) -> ChangeKind {
+        match *self {
+            Change::Added { .. } => ChangeKind::Added,
+            Change::Removed { .. } => ChangeKind::Removed,
+            Change::Modified { .. } => ChangeKind::Modified,
+        }
+    }
+}
+
+
+/// Lists the changes which turn `old` into `new`, in the order of the
+/// entries of dictionaries and the items of lists. Dictionaries and lists
+/// are compared entry by entry and item by item, so inserting an item in
+/// the middle of a list shows up as modifying the items after it.
+pub fn diff(old: &Value, new: &Value) -> Vec<Change> {
+    let mut changes = Vec::new();
+    diff_at(&mut String::new(), old, new, &mut changes);
+    changes
+}
+
+
+fn diff_at(path: &mut String, old: &Value, new: &Value, changes: &mut Vec<Change>) {
+    match (old, new) {
+        (&Value::Dict(ref old_entries), &Value::Dict(ref new_entries)) => {
+            for (key, old_value) in old_entries {
+                let len = push_segment(path, key);
+                match new_entries.get(key) {
+                    Some(new_value) => diff_at(path, old_value, new_value, changes),
+                    None => {
+                        changes.push(Change::Removed { path: path.clone(), value: old_value.clone() });
+                    },
+                }
+                path.truncate(len);
+            }
+            for (key, new_value) in new_entries {
+                if !old_entries.contains_key(key) {
+                    let len = push_segment(path, key);
+                    changes.push(Change::Added { path: path.clone(), value: new_value.clone() });
+                    path.truncate(len);
+                }
+            }
+        },
+        (&Value::List(ref old_items), &Value::List(ref new_items)) => {
+            for index in 0..old_items.len().max(new_items.len()) {
+                let len = push_segment(path, &index.to_string());
+                match (old_items.get(index), new_items.get(index)) {
+                    (Some(old_item), Some(new_item)) => diff_at(path, old_item, new_item, changes),
+                    (Some(old_item), None) => {
+                        changes.push(Change::Removed { path: path.clone(), value: old_item.clone() });
+                    },
+                    (None, Some(new_item)) => {
+                        changes.push(Change::Added { path: path.clone(), value: new_item.clone() });
+                    },
+                    (None, None) => (),
+                }
+                path.truncate(len);
+            }
+        },
+        (&Value::Annotated(ref old_annotations, ref old_value),
+         &Value::Annotated(ref new_annotations, ref new_value))
+            if old_annotations == new_annotations => diff_at(path, old_value, new_value, changes),
+        (old, new) if old != new => {
+            changes.push(Change::Modified { path: path.clone(), old: old.clone(), new: new.clone() });
+        },
+        _ => (),
+    }
+}
+
+
+// Appends a segment to a path, escaped as in JSON Pointer, returning the
+// length of the path before it.
+fn push_segment(path: &mut String, segment: &str) -> usize {
+    let len = path.len();
+    path.push('/');
+    path.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+    len
+}
+
+
+#[cfg(all(test, feature = "de"))]
+mod tests {
+    use super::*;
+    use de::from_str;
+
+    fn value(input: &str) -> Value {
+        from_str(input).unwrap()
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = value("a/b~ {x: 1} name: \"web\" servers [{host: \"a\" port: 80} {host: \"b\"}] \
+                         t: :x 1");
+        let new = value("a/b~ {x: 1.5} name: \"web\" servers [{host: \"a\" port: 8080 tls: True}] \
+                         t: :y 1");
+        assert_eq!(vec![
+            Change::Modified { path: "/a~1b~0/x".to_string(), old: Value::U64(1), new: Value::F64(1.5) },
+            Change::Modified { path: "/servers/0/port".to_string(),
+                               old: Value::U64(80),
+                               new: Value::U64(8080) },
+            Change::Added { path: "/servers/0/tls".to_string(), value: Value::Bool(true) },
+            Change::Removed { path: "/servers/1".to_string(), value: value("host: \"b\"") },
+            Change::Modified { path: "/t".to_string(), old: old["t"].clone(), new: new["t"].clone() },
+        ], diff(&old, &new));
+        for change in diff(&old, &new) {
+            if change.kind() != ChangeKind::Added {
+                assert!(old.pointer(change.path()).is_some(), "{}", change.path());
+            }
+        }
+    }
+
+    #[test]
+    fn test_diff_same() {
+        let old = value("a: [1 {b: :x \"s\"}]");
+        assert_eq!(Vec::<Change>::new(), diff(&old, &old.clone()));
+        let changes = diff(&old, &Value::Bool(true));
+        assert_eq!(1, changes.len());
+        assert_eq!("", changes[0].path());
+        assert_eq!(ChangeKind::Modified, changes[0].kind());
+        // How numbers are written is not a change.
+        assert!(diff(&value("a: 0xFF b: [1e3]"), &value("a: 255 b: [1000.0]")).is_empty());
+    }
+}
@@ -0,0 +1,799 @@
+//
+// document.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use std::{fmt, mem, ops};
+use std::io::Read;
+use std::str::FromStr;
+use super::de::from_str;
+use super::error::{Error, ErrorCode, Result};
+use super::parser::{Parser, is_key_char, is_number_char, is_whitespace};
+use super::ser::{check_key, to_string};
+use super::value::Value;
+
+
+/// A HiPack document which keeps everything as written in the input:
+/// whitespace, comments, separators, the order of keys, and the spelling
+/// of values (e.g. `0x10` instead of `16`). Formatting it gives back the
+/// exact input.
+///
+/// Documents can be edited, e.g. to update configuration files which are
+/// maintained by hand, and formatting them changes only the edited parts:
+///
+/// ```
+/// # use serde_hipack::document::{Document, Item};
+/// # use serde_hipack::error::Result;
+/// # fn run() -> Result<()> {
+/// let text = "server {\n  host: \"a\"  # Fixed.\n  port: 80\n}\n";
+/// let mut doc: Document = try!(text.parse());
+/// doc["server"]["port"] = try!(Item::new(8080));
+/// try!(doc.insert("name", try!(Item::new("web"))));
+/// assert_eq!(doc.to_string(),
+///            "server {\n  host: \"a\"  # Fixed.\n  port: 8080\n}\nname: \"web\"\n");
+/// # Ok(())
+/// # }
+/// # fn main() { run().unwrap() }
+/// ```
+///
+/// New entries and items follow the layout of the ones next to them, and
+/// new values are written in compact form. Indexing panics when there is
+/// no entry for the key, like for `Value`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Document {
+    prefix: String,
+    braced: bool,
+    root: Dict,
+    suffix: String,
+}
+
+
+/// Entries of a dictionary, and whatever follows the last one.
+#[derive(Clone, Debug, PartialEq)]
+struct Dict {
+    entries: Vec<Entry>,
+    trailing: String,
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    // Whitespace, comments, and the comma in between the previous entry
+    // (or the opening brace) and the key.
+    prefix: String,
+    key: String,
+    // Whitespace and the colon in between the key and the value.
+    separator: String,
+    value: Item,
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+struct List {
+    // Items with the text preceding them, as in Entry::prefix.
+    items: Vec<(String, Item)>,
+    trailing: String,
+}
+
+
+/// Value in a `Document`, along with its annotations, as written.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Item {
+    // Annotation names, with the whitespace following each of them.
+    annotations: Vec<(String, String)>,
+    kind: ItemKind,
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+enum ItemKind {
+    // Raw text of strings (with their quotes), numbers, and booleans.
+    Scalar(String),
+    List(List),
+    Dict(Dict),
+}
+
+
+impl Document {
+    /// Parses a document, which must be valid HiPack.
+    pub fn parse(input: &str) -> Result<Document> {
+        let mut parser = Parser::new(input.as_bytes().bytes());
+        while let Some(_) = try!(parser.next_event()) {}
+        Ok(Scanner { input: input, pos: 0 }.document())
+    }
+
+    /// Value of an entry of the top-level dictionary.
+    #[inline]
+    pub fn get(&self, key: &str) -> Option<&Item> {
+        self.root.get(key)
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Item> {
+        self.root.get_mut(key)
+    }
+
+    /// Replaces the value of an entry of the top-level dictionary, which
+    /// keeps its place and the comments around it, or adds an entry at the
+    /// end. Returns the previous value.
+    #[inline]
+    pub fn insert(&mut self, key: &str, item: Item) -> Result<Option<Item>> {
+        self.root.insert(key, item)
+    }
+
+    /// Removes an entry of the top-level dictionary, along with the
+    /// comments in front of it.
+    #[inline]
+    pub fn remove(&mut self, key: &str) -> Option<Item> {
+        self.root.remove(key)
+    }
+}
+
+
+impl Item {
+    /// Makes an item holding a value, written in compact form.
+    pub fn new<V: Into<Value>>(value: V) -> Result<Item> {
+        let text = try!(to_string(&value.into()));
+        Ok(Scanner { input: &text, pos: 0 }.item())
+    }
+
+    #[inline]
+    pub fn is_dict(&self) -> bool {
+        match self.kind { ItemKind::Dict(_) => true, _ => false }
+    }
+
+    #[inline]
+    pub fn is_list(&self) -> bool {
+        match self.kind { ItemKind::List(_) => true, _ => false }
+    }
+
+    pub fn annotations(&self) -> Vec<&str> {
+        self.annotations.iter().map(|&(ref name, _)| &name[..]).collect()
+    }
+
+    /// Parses the item into a `Value`. Numbers keep their spelling, see
+    /// `value::Number`.
+    pub fn to_value(&self) -> Result<Value> {
+        match try!(from_str(&format!("v: {}", self))) {
+            Value::Dict(entries) => Ok(entries.into_iter().next().unwrap().1),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Number of entries of a dictionary, or items of a list.
+    pub fn len(&self) -> usize {
+        match self.kind {
+            ItemKind::Dict(ref dict) => dict.entries.len(),
+            ItemKind::List(ref list) => list.items.len(),
+            ItemKind::Scalar(_) => 0,
+        }
+    }
+
+    /// Whether a dictionary or a list has no entries or items, which is
+    /// always the case for other values, like for `len()`.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Value of an entry, if the item is a dictionary.
+    pub fn get(&self, key: &str) -> Option<&Item> {
+        match self.kind {
+            ItemKind::Dict(ref dict) => dict.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Item> {
+        match self.kind {
+            ItemKind::Dict(ref mut dict) => dict.get_mut(key),
+            _ => None,
+        }
+    }
+
+    /// Replaces the value of an entry, or adds one, like
+    /// `Document::insert()`. Fails if the item is not a dictionary.
+    pub fn insert(&mut self, key: &str, item: Item) -> Result<Option<Item>> {
+        match self.kind {
+            ItemKind::Dict(ref mut dict) => dict.insert(key, item),
+            _ => Err(Error::SyntaxError(ErrorCode::InvalidValue("Not a dictionary".to_string()),
+                                        0, 0, 0)),
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Item> {
+        match self.kind {
+            ItemKind::Dict(ref mut dict) => dict.remove(key),
+            _ => None,
+        }
+    }
+
+    /// Item of a list at the given position.
+    pub fn get_index(&self, index: usize) -> Option<&Item> {
+        match self.kind {
+            ItemKind::List(ref list) => list.items.get(index).map(|&(_, ref item)| item),
+            _ => None,
+        }
+    }
+
+    pub fn get_index_mut(&mut self, index: usize) -> Option<&mut Item> {
+        match self.kind {
+            ItemKind::List(ref mut list) => list.items.get_mut(index).map(|&mut (_, ref mut item)| item),
+            _ => None,
+        }
+    }
+
+    /// Adds an item at the end of a list. Fails if the item is not a list.
+    pub fn push(&mut self, item: Item) -> Result<()> {
+        match self.kind {
+            ItemKind::List(ref mut list) => {
+                let prefix = {
+                    let prefixes: Vec<&str> = list.items.iter().map(|&(ref p, _)| &p[..]).collect();
+                    next_prefix(&prefixes, &list.trailing, "")
+                };
+                let prefix = after_tail(&mut list.trailing, prefix);
+                list.items.push((prefix, item));
+                Ok(())
+            },
+            _ => Err(Error::SyntaxError(ErrorCode::InvalidValue("Not a list".to_string()), 0, 0, 0)),
+        }
+    }
+
+    // Whether the item is a dictionary or a list without annotations, which
+    // may follow keys without a colon.
+    fn is_bare_compound(&self) -> bool {
+        self.annotations.is_empty() && (self.is_dict() || self.is_list())
+    }
+}
+
+
+impl Dict {
+    // Duplicate keys are allowed, and the last one wins, as when reading.
+    fn position(&self, key: &str) -> Option<usize> {
+        self.entries.iter().rposition(|entry| entry.key == key)
+    }
+
+    fn get(&self, key: &str) -> Option<&Item> {
+        self.position(key).map(|index| &self.entries[index].value)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut Item> {
+        match self.position(key) {
+            Some(index) => Some(&mut self.entries[index].value),
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, key: &str, item: Item) -> Result<Option<Item>> {
+        if let Some(index) = self.position(key) {
+            return Ok(Some(mem::replace(&mut self.entries[index].value, item)));
+        }
+        try!(check_key(key));
+        let separator = match self.entries.last() {
+            Some(entry) if has_colon(&entry.separator) && !entry.separator.contains('#') => {
+                entry.separator.clone()
+            },
+            _ => ": ".to_string(),
+        };
+        let prefix = {
+            let prefixes: Vec<&str> = self.entries.iter().map(|entry| &entry.prefix[..]).collect();
+            next_prefix(&prefixes, &self.trailing, spacing(&separator))
+        };
+        let prefix = after_tail(&mut self.trailing, prefix);
+        self.entries.push(Entry { prefix: prefix, key: key.to_string(), separator: separator, value: item });
+        Ok(None)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Item> {
+        let index = match self.position(key) {
+            Some(index) => index,
+            None => return None,
+        };
+        let entry = self.entries.remove(index);
+        // What follows the entry on its line goes away with it, and what
+        // follows the previous entry stays in place.
+        let kept = line_tail(&entry.prefix);
+        let next = match self.entries.get_mut(index) {
+            Some(next) => &mut next.prefix,
+            None => &mut self.trailing,
+        };
+        let joined = join_tail(kept, &next[line_tail(next).len()..]);
+        if index == 0 {
+            // The first entry cannot be preceded by a comma.
+            *next = without_comma(&joined);
+        } else if !joined.is_empty() || next.is_empty() {
+            *next = joined;
+        }
+        // Otherwise the next entry keeps the comma which separates it from
+        // the previous one on the same line.
+        Some(entry.value)
+    }
+}
+
+
+// Length of the whitespace and comments at the start of some text.
+fn trivia_len(text: &str) -> usize {
+    let mut scanner = Scanner { input: text, pos: 0 };
+    scanner.trivia();
+    scanner.pos
+}
+
+
+fn has_colon(separator: &str) -> bool {
+    separator[trivia_len(separator)..].starts_with(':')
+}
+
+
+// Whitespace after the colon of an entry, which the document also uses
+// after the commas in between entries on the same line.
+fn spacing(separator: &str) -> &str {
+    let after = &separator[trivia_len(separator)..];
+    let after = if after.starts_with(':') { &after[1..] } else { after };
+    if after.chars().all(|ch| ch == ' ' || ch == '\t') { after } else { "" }
+}
+
+
+fn without_comma(prefix: &str) -> String {
+    let len = trivia_len(prefix);
+    if prefix[len..].starts_with(',') {
+        format!("{}{}", &prefix[..len], &prefix[len + 1..])
+    } else {
+        prefix.to_string()
+    }
+}
+
+
+// Text which follows an item on the same line, when it has a comma or a
+// comment, up to the end of the line and without the newline. The rest
+// of the text in between items belongs to the next one.
+fn line_tail(text: &str) -> &str {
+    let line = match text.find('\n') {
+        Some(end) => &text[..end],
+        None => text,
+    };
+    if line.contains(',') || line.contains('#') { line } else { "" }
+}
+
+
+// Puts the text which follows an item on its line in front of the text
+// preceding the next item, keeping a single comma, and the newline which
+// ends a comment, which is enough to separate the items.
+fn join_tail(tail: &str, rest: &str) -> String {
+    let rest = if tail.trim_start().starts_with(',') { without_comma(rest) } else { rest.to_string() };
+    if tail.contains('#') && !rest.starts_with('\n') {
+        format!("{}\n{}", tail, without_comma(&rest))
+    } else {
+        format!("{}{}", tail, rest)
+    }
+}
+
+
+// Takes the text which follows the last item on its line out of the text
+// trailing the items, into the prefix of a new item added after it.
+fn after_tail(trailing: &mut String, prefix: String) -> String {
+    let len = line_tail(trailing).len();
+    let tail: String = trailing.drain(..len).collect();
+    join_tail(&tail, &prefix)
+}
+
+
+// Text to put in front of a new item, following the layout of the ones
+// before it: on its own line with the same indentation, or after a comma
+// or whitespace. A comma after a single item is followed by the given
+// spacing, unless the item is followed by a comment. Comments are not
+// copied.
+fn next_prefix(prefixes: &[&str], trailing: &str, spacing: &str) -> String {
+    let last = match prefixes.last() {
+        Some(last) => *last,
+        None => return String::new(),
+    };
+    let comma = prefixes.len() > 1 && without_comma(last) != last;
+    if let Some(newline) = last.rfind('\n') {
+        format!("{}\n{}", if comma { "," } else { "" }, &last[newline + 1..])
+    } else if prefixes.len() > 1 {
+        last.to_string()
+    } else if trailing.contains('\n') {
+        format!("\n{}", last)
+    } else if last.is_empty() && line_tail(trailing).is_empty() {
+        format!(",{}", spacing)
+    } else if last.is_empty() {
+        ",".to_string()
+    } else {
+        last.to_string()
+    }
+}
+
+
+impl<'a> ops::Index<&'a str> for Document {
+    type Output = Item;
+
+    fn index(&self, key: &str) -> &Item {
+        self.get(key).expect("no entry found for key")
+    }
+}
+
+
+impl<'a> ops::IndexMut<&'a str> for Document {
+    fn index_mut(&mut self, key: &str) -> &mut Item {
+        self.get_mut(key).expect("no entry found for key")
+    }
+}
+
+
+impl<'a> ops::Index<&'a str> for Item {
+    type Output = Item;
+
+    fn index(&self, key: &str) -> &Item {
+        self.get(key).expect("no entry found for key")
+    }
+}
+
+
+impl<'a> ops::IndexMut<&'a str> for Item {
+    fn index_mut(&mut self, key: &str) -> &mut Item {
+        self.get_mut(key).expect("no entry found for key")
+    }
+}
+
+
+impl ops::Index<usize> for Item {
+    type Output = Item;
+
+    fn index(&self, index: usize) -> &Item {
+        self.get_index(index).expect("no item found at position")
+    }
+}
+
+
+impl ops::IndexMut<usize> for Item {
+    fn index_mut(&mut self, index: usize) -> &mut Item {
+        self.get_index_mut(index).expect("no item found at position")
+    }
+}
+
+
+impl FromStr for Document {
+    type Err = Error;
+
+    #[inline]
+    fn from_str(s: &str) -> Result<Document> {
+        Document::parse(s)
+    }
+}
+
+
+impl fmt::Display for Document {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(f.write_str(&self.prefix));
+        if self.braced {
+            try!(f.write_str("{"));
+        }
+        try!(self.root.fmt(f));
+        if self.braced {
+            try!(f.write_str("}"));
+        }
+        f.write_str(&self.suffix)
+    }
+}
+
+
+impl fmt::Display for Dict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in &self.entries {
+            try!(write!(f, "{}{}", entry.prefix, entry.key));
+            // Same for keys of dictionaries and lists, which need no colon.
+            if !entry.value.is_bare_compound() && !has_colon(&entry.separator) {
+                try!(f.write_str(":"));
+            }
+            try!(write!(f, "{}{}", entry.separator, entry.value));
+        }
+        f.write_str(&self.trailing)
+    }
+}
+
+
+impl fmt::Display for List {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(ref prefix, ref item) in &self.items {
+            try!(write!(f, "{}{}", prefix, item));
+        }
+        f.write_str(&self.trailing)
+    }
+}
+
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(ref name, ref whitespace) in &self.annotations {
+            try!(write!(f, ":{}{}", name, whitespace));
+        }
+        match self.kind {
+            ItemKind::Scalar(ref text) => f.write_str(text),
+            ItemKind::List(ref list) => write!(f, "[{}]", list),
+            ItemKind::Dict(ref dict) => write!(f, "{{{}}}", dict),
+        }
+    }
+}
+
+
+// Splits input which is already known to be valid into a Document.
+struct Scanner<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+
+impl<'a> Scanner<'a> {
+    #[inline]
+    fn peek(&self) -> Option<u8> {
+        self.input.as_bytes().get(self.pos).cloned()
+    }
+
+    fn take_while<P>(&mut self, predicate: P) -> String where P: Fn(u8) -> bool {
+        let start = self.pos;
+        while self.peek().map_or(false, &predicate) {
+            self.pos += 1;
+        }
+        self.input[start..self.pos].to_string()
+    }
+
+    // Whitespace and comments.
+    fn trivia(&mut self) -> String {
+        let start = self.pos;
+        loop {
+            match self.peek() {
+                Some(ch) if is_whitespace(ch) => self.pos += 1,
+                Some(b'#') => {
+                    while self.peek().map_or(false, |ch| ch != b'\n') {
+                        self.pos += 1;
+                    }
+                },
+                _ => return self.input[start..self.pos].to_string(),
+            }
+        }
+    }
+
+    // Text in between items, which may contain a comma.
+    fn item_prefix(&mut self, first: bool) -> String {
+        let mut prefix = self.trivia();
+        if !first && self.peek() == Some(b',') {
+            self.pos += 1;
+            prefix.push(',');
+            prefix.push_str(&self.trivia());
+        }
+        prefix
+    }
+
+    fn document(mut self) -> Document {
+        let prefix = self.trivia();
+        let braced = self.peek() == Some(b'{');
+        if braced {
+            self.pos += 1;
+        }
+        let root = self.dict();
+        if braced {
+            self.pos += 1;
+        }
+        Document { prefix: prefix, braced: braced, root: root, suffix: self.input[self.pos..].to_string() }
+    }
+
+    // Reads entries up to the closing brace, which is left unconsumed.
+    fn dict(&mut self) -> Dict {
+        let mut entries = Vec::new();
+        loop {
+            let prefix = self.item_prefix(entries.is_empty());
+            match self.peek() {
+                None | Some(b'}') => return Dict { entries: entries, trailing: prefix },
+                Some(_) => (),
+            }
+            let key = self.take_while(is_key_char);
+            let mut separator = self.trivia();
+            if self.peek() == Some(b':') {
+                self.pos += 1;
+                separator.push(':');
+                separator.push_str(&self.trivia());
+            }
+            let value = self.item();
+            entries.push(Entry { prefix: prefix, key: key, separator: separator, value: value });
+        }
+    }
+
+    fn list(&mut self) -> List {
+        let mut items = Vec::new();
+        loop {
+            let prefix = self.item_prefix(items.is_empty());
+            if self.peek() == Some(b']') {
+                return List { items: items, trailing: prefix };
+            }
+            let item = self.item();
+            items.push((prefix, item));
+        }
+    }
+
+    fn item(&mut self) -> Item {
+        let mut annotations = Vec::new();
+        while self.peek() == Some(b':') {
+            self.pos += 1;
+            let name = self.take_while(is_key_char);
+            annotations.push((name, self.trivia()));
+        }
+        let kind = match self.peek() {
+            Some(b'[') => {
+                self.pos += 1;
+                let list = self.list();
+                self.pos += 1;
+                ItemKind::List(list)
+            },
+            Some(b'{') => {
+                self.pos += 1;
+                let dict = self.dict();
+                self.pos += 1;
+                ItemKind::Dict(dict)
+            },
+            Some(b'"') => {
+                let start = self.pos;
+                self.pos += 1;
+                loop {
+                    match self.peek() {
+                        Some(b'\\') => self.pos += 2,
+                        Some(b'"') => break,
+                        _ => self.pos += 1,
+                    }
+                }
+                self.pos += 1;
+                ItemKind::Scalar(self.input[start..self.pos].to_string())
+            },
+            _ => ItemKind::Scalar(self.take_while(is_number_char)),
+        };
+        Item { annotations: annotations, kind: kind }
+    }
+}
+
+
+/// First line where formatting a document differs from its input, as
+/// reported by `verify_roundtrip()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoundtripDiff {
+    pub line: usize,
+    /// The line as written in the input.
+    pub expected: String,
+    /// The line as written back.
+    pub actual: String,
+}
+
+
+impl fmt::Display for RoundtripDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}:\n- {}\n+ {}", self.line, self.expected, self.actual)
+    }
+}
+
+
+/// Parses `input` into a `Document` and formats it back, returning where
+/// the output differs, if it is not byte-identical to the input.
+pub fn verify_roundtrip(input: &str) -> Result<Option<RoundtripDiff>> {
+    let output = try!(Document::parse(input)).to_string();
+    Ok(first_difference(input, &output))
+}
+
+
+fn first_difference(input: &str, output: &str) -> Option<RoundtripDiff> {
+    if input == output {
+        return None;
+    }
+    let mut expected = input.split('\n');
+    let mut actual = output.split('\n');
+    let mut line = 1;
+    loop {
+        match (expected.next(), actual.next()) {
+            (Some(a), Some(b)) if a == b => line += 1,
+            (a, b) => return Some(RoundtripDiff {
+                line: line,
+                expected: a.unwrap_or("").to_string(),
+                actual: b.unwrap_or("").to_string(),
+            }),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use value::Value;
+
+    #[test]
+    fn test_roundtrip() {
+        let inputs = [
+            "",
+            "  # Only a comment\n",
+            "a: 1, b: 0x1F c:-07 d [True, \"x\\\"y\"] # Trailing\n",
+            "{\n  # Server\n  server {\n    port: 0755\n    hosts [ \"a\" ,\"b\", ]\n  }\n}\n\n",
+            "a: :x:y  :z [:t 1 2.5e3 {}]\nb: :w {c:{d:\"\\FF\"}} e: NaN",
+        ];
+        for input in &inputs {
+            assert_eq!(None, verify_roundtrip(input).unwrap(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_edit() {
+        let input = "# Config\nserver {\n  host: \"a\"  # Main\n  port: 80\n}\nports [80, 443]\n";
+        let mut doc: Document = input.parse().unwrap();
+        assert_eq!(Value::U64(80), doc["server"]["port"].to_value().unwrap());
+        assert_eq!(Some(443), doc["ports"][1].to_value().unwrap().as_u64());
+        assert_eq!(2, doc["ports"].len());
+        assert!(!doc["server"].is_empty() && doc["server"]["port"].is_empty());
+
+        doc["server"]["port"] = Item::new(8080).unwrap();
+        doc["server"].insert("tls", Item::new(true).unwrap()).unwrap();
+        doc["ports"].push(Item::new(8443).unwrap()).unwrap();
+        assert!(doc.insert("name", Item::new("web").unwrap()).unwrap().is_none());
+        assert!(doc.insert("bad key", Item::new(1).unwrap()).is_err());
+        assert!(doc["ports"].insert("a", Item::new(1).unwrap()).is_err());
+        assert_eq!("# Config\nserver {\n  host: \"a\"  # Main\n  port: 8080\n  tls: True\n}\n\
+                    ports [80, 443, 8443]\nname: \"web\"\n",
+                   doc.to_string());
+
+        // Keys of compound values may have no colon.
+        doc["ports"] = Item::new(1).unwrap();
+        assert!(doc.remove("server").unwrap().is_dict());
+        assert_eq!("# Config\n\nports: 1\nname: \"web\"\n", doc.to_string());
+
+        let mut doc: Document = "{a{b:1} c:2,d:[[1] [2]]}".parse().unwrap();
+        doc["a"] = Item::new(5).unwrap();
+        doc["d"][0] = Item::new("x").unwrap();
+        assert!(doc.remove("c").is_some());
+        doc.insert("e", Item::new(vec![1, 2]).unwrap()).unwrap();
+        assert_eq!("{a:5,d:[\"x\" [2]],e:[1,2]}", doc.to_string());
+        assert!(doc.remove("a").is_some());
+        assert_eq!("{d:[\"x\" [2]],e:[1,2]}", doc.to_string());
+        assert!(doc.to_string().parse::<Document>().is_ok());
+
+        let edit = |input: &str, remove: &str, insert: &str| {
+            let mut doc: Document = input.parse().unwrap();
+            if !remove.is_empty() {
+                assert!(doc.remove(remove).is_some());
+            }
+            if !insert.is_empty() {
+                doc.insert(insert, Item::new(2).unwrap()).unwrap();
+            }
+            let output = doc.to_string();
+            assert!(output.parse::<Document>().is_ok(), "{:?}", output);
+            output
+        };
+        // Comments and commas stay with the entry they follow.
+        assert_eq!("a: 1 # c\nb: 2\n", edit("a: 1 # c\n", "", "b"));
+        assert_eq!("a: 1,\nb: 2\n", edit("a: 1,\n", "", "b"));
+        assert_eq!("a: 1, # c\nb: 2\n", edit("a: 1, # c\n", "", "b"));
+        assert_eq!("a: 1\nx: 0 # x\nb: 2\n", edit("a: 1\nx: 0 # x\n", "", "b"));
+        assert_eq!("a: 1 # c\nb: 2", edit("a: 1 # c", "", "b"));
+        assert_eq!("{a: 1 # c\n}", edit("{a: 1 # c\n}", "", ""));
+        assert_eq!("{a:1,b:2}", edit("{a:1,}", "", "b"));
+        assert_eq!("a: 1, b: 2", edit("a: 1", "", "b"));
+        assert_eq!("{a:1,b:2}", edit("{a:1}", "", "b"));
+        assert_eq!("{a:1, x:0 # x\n b:2\n}", edit("{a:1, x:0 # x\n}", "", "b"));
+
+        assert_eq!("\nb: 2\n", edit("a: 1 # one\nb: 2\n", "a", ""));
+        assert_eq!("a: 1 # one\n", edit("a: 1 # one\nb: 2 # two\n", "b", ""));
+        assert_eq!("a: 1,\nc: 3\n", edit("a: 1,\nb: 2, # two\nc: 3\n", "b", ""));
+        assert_eq!("{a: 1 # one\n}", edit("{a: 1 # one\nb: 2}", "b", ""));
+        assert_eq!("a: 1 # one\n c: 3", edit("a: 1 # one\nb: 2 c: 3", "b", ""));
+        assert_eq!("{a:1,c:3}", edit("{a:1,b:2,c:3}", "b", ""));
+        assert_eq!("{b:2,c:3}", edit("{a:1,b:2,c:3}", "a", ""));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(verify_roundtrip("a: [1").is_err());
+        assert!("a 1".parse::<Document>().is_err());
+    }
+
+    #[test]
+    fn test_difference() {
+        assert_eq!(Some(RoundtripDiff { line: 2, expected: "b: 2".to_string(), actual: "b: 3".to_string() }),
+                   first_difference("a: 1\nb: 2\n", "a: 1\nb: 3\n"));
+        assert_eq!("line 1:\n- a\n+ ", first_difference("a", "").unwrap().to_string());
+    }
+}
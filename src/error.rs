@@ -9,22 +9,168 @@ use std::error;
 use std::fmt;
 use std::io;
 use std::string::FromUtf8Error;
+use serde::{de, ser};
+
+
+/// Limit set with `ParserOptions` which was exceeded by the input.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    InputSize,
+    StringLength,
+    ListLength,
+    DictSize,
+}
 
 
 #[derive(Clone, PartialEq)]
 pub enum ErrorCode {
     InvalidKey,
+    InvalidKeyChar(char),
     UnrepresentableValue,
+    UnexpectedEof,
+    UnterminatedString,
+    ExpectedKey,
+    ExpectedColon,
+    ExpectedValue,
+    ExpectedSeparator,
+    InvalidEscape,
+    InvalidNumber,
+    TrailingCharacters,
+    MismatchedDelimiter,
+    RecursionLimitExceeded,
+    LimitExceeded(Limit),
+    QuotedKey,
+    InvalidBool,
+    NullValue,
+    InvalidAnnotation,
+    InvalidNull,
+    AnnotationTypeMismatch,
+    MemoryLimitExceeded,
+    UnmatchedResponse,
+    ExpectedVariant,
+    InvalidValue(String),
+    InvalidBase64,
+    UnknownField(String),
+    DuplicateKey(String),
+    MissingField(&'static str),
+    Custom(String),
 }
 
 
 impl fmt::Debug for ErrorCode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use std::fmt::Debug;
         match *self {
             ErrorCode::InvalidKey => "Invalid key".fmt(f),
+            ErrorCode::InvalidKeyChar(ch) => write!(f, "Invalid character {:?} in key", ch),
             ErrorCode::UnrepresentableValue => "Value cannot be represented".fmt(f),
+            ErrorCode::UnexpectedEof => "Unexpected end of input".fmt(f),
+            ErrorCode::UnterminatedString => "Unterminated string".fmt(f),
+            ErrorCode::ExpectedKey => "Expected key".fmt(f),
+            ErrorCode::ExpectedColon => "Expected colon".fmt(f),
+            ErrorCode::ExpectedValue => "Expected value".fmt(f),
+            ErrorCode::ExpectedSeparator => "Expected comma or whitespace".fmt(f),
+            ErrorCode::InvalidEscape => "Invalid escape sequence".fmt(f),
+            ErrorCode::InvalidNumber => "Invalid number".fmt(f),
+            ErrorCode::TrailingCharacters => "Trailing characters".fmt(f),
+            ErrorCode::MismatchedDelimiter => "Mismatched closing delimiter".fmt(f),
+            ErrorCode::RecursionLimitExceeded => "Recursion limit exceeded".fmt(f),
+            ErrorCode::LimitExceeded(limit) => f.write_str(match limit {
+                Limit::InputSize => "Input size limit exceeded",
+                Limit::StringLength => "String length limit exceeded",
+                Limit::ListLength => "List length limit exceeded",
+                Limit::DictSize => "Dictionary size limit exceeded",
+            }),
+            ErrorCode::QuotedKey => "Quoted key".fmt(f),
+            ErrorCode::InvalidBool => "Invalid boolean".fmt(f),
+            ErrorCode::NullValue => "Null value".fmt(f),
+            ErrorCode::InvalidAnnotation => "Invalid annotation".fmt(f),
+            ErrorCode::InvalidNull => "Invalid null representation".fmt(f),
+            ErrorCode::AnnotationTypeMismatch => "Value does not match intrinsic annotation".fmt(f),
+            ErrorCode::MemoryLimitExceeded => "Memory limit exceeded".fmt(f),
+            ErrorCode::UnmatchedResponse => "Response does not match any request".fmt(f),
+            ErrorCode::ExpectedVariant => "Expected enum variant".fmt(f),
+            ErrorCode::InvalidValue(ref msg) => f.write_str(msg),
+            ErrorCode::InvalidBase64 => f.write_str("Invalid Base64 string"),
+            ErrorCode::UnknownField(ref field) => write!(f, "Unknown field \"{}\"", field),
+            ErrorCode::DuplicateKey(ref key) => write!(f, "Duplicate key \"{}\"", key),
+            ErrorCode::MissingField(ref field) => write!(f, "Missing field \"{}\"", field),
+            ErrorCode::Custom(ref msg) => f.write_str(msg),
+        }
+    }
+}
+
+
+impl ErrorCode {
+    /// Returns a hint on how to fix the error, for mistakes which are
+    /// commonly made when coming from other formats, notably JSON.
+    pub fn suggestion(&self) -> Option<&'static str> {
+        match *self {
+            ErrorCode::QuotedKey => Some("HiPack keys are written without quotes, e.g. key: value"),
+            ErrorCode::InvalidBool => Some("HiPack booleans are True/False"),
+            ErrorCode::NullValue => Some("HiPack has no null value, omit the key instead"),
+            _ => None,
+        }
+    }
+}
+
+
+/// Problems found in the input which do not stop deserialization.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WarningCode {
+    /// A deprecated key was used: path of the key, and the suggested
+    /// replacement.
+    DeprecatedKey(String, String),
+    /// An integer did not fit in the type it was deserialized into, with
+    /// `OverflowPolicy::Saturate`: the value, and the one used instead.
+    IntegerClamped(String, String),
+    /// A string was read as a boolean, with `Deserializer::lenient_bools()`.
+    BoolCoerced(String),
+    /// Sequence numbers of framed messages were skipped, e.g. because
+    /// messages were lost: the number expected, and the one received.
+    SequenceGap(u64, u64),
+    /// A framed message had a sequence number which was already seen,
+    /// e.g. because it was sent again.
+    SequenceDuplicate(u64),
+    /// A framed message had a sequence number which was skipped before,
+    /// e.g. because messages were reordered. Readers remember which of the
+    /// last 64 numbers were received, and report older ones as late.
+    SequenceLate(u64),
+}
+
+
+/// Warning reported through `Deserializer::warning_handler()`, at the
+/// given position of the input.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+    pub code: WarningCode,
+    pub line: usize,
+    pub column: usize,
+}
+
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.code {
+            WarningCode::DeprecatedKey(ref path, ref replacement) => {
+                try!(write!(f, "Deprecated key \"{}\", use \"{}\" instead", path, replacement));
+            },
+            WarningCode::IntegerClamped(ref value, ref used) => {
+                try!(write!(f, "Integer {} out of range, using {}", value, used));
+            },
+            WarningCode::BoolCoerced(ref value) => {
+                try!(write!(f, "String \"{}\" used as a boolean", value));
+            },
+            WarningCode::SequenceGap(expected, received) => {
+                try!(write!(f, "Expected message {}, received {}", expected, received));
+            },
+            WarningCode::SequenceDuplicate(received) => {
+                try!(write!(f, "Message {} received again", received));
+            },
+            WarningCode::SequenceLate(received) => {
+                try!(write!(f, "Message {} received late", received));
+            },
         }
+        write!(f, " at line {} column {}", self.line, self.column)
     }
 }
 
@@ -34,6 +180,82 @@ pub enum Error {
     SyntaxError(ErrorCode, usize, usize, usize), // Error, offset, line, column
     FromUtf8Error(FromUtf8Error),
     IoError(io::Error),
+    Cancelled,
+    DeadlineExceeded,
+    /// Error in a nested value, with the path to it, e.g. `servers[2].port`.
+    AtPath(String, Box<Error>),
+}
+
+
+impl Error {
+    /// Path to the value which caused the error, if it was nested.
+    pub fn path(&self) -> Option<&str> {
+        match *self {
+            Error::AtPath(ref path, _) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Byte offset in the input where the error was found, if known.
+    pub fn offset(&self) -> Option<usize> {
+        self.position().map(|(offset, _, _)| offset)
+    }
+
+    /// Line of the input where the error was found, if known.
+    pub fn line(&self) -> Option<usize> {
+        self.position().map(|(_, line, _)| line)
+    }
+
+    /// Column of the input where the error was found, if known.
+    pub fn column(&self) -> Option<usize> {
+        self.position().map(|(_, _, column)| column)
+    }
+
+    /// Formats the error for humans, showing the line of the `source` input
+    /// where it was found with a caret under the error column:
+    ///
+    /// ```text
+    /// error: "Invalid number" at line 2 column 4
+    ///   |
+    /// 2 | b: 1x2
+    ///   |    ^
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        let mut output = format!("error: {}\n", self);
+        let (line, column) = match (self.line(), self.column()) {
+            (Some(line), Some(column)) => (line, column),
+            _ => return output,
+        };
+        let text = match source.lines().nth(line - 1) {
+            Some(text) => text,
+            None => return output,
+        };
+        let number = line.to_string();
+        let gutter = " ".repeat(number.len());
+        // Tabs are kept so the caret lines up however they are displayed.
+        let indent: String = text.chars().take(column - 1).map(|ch| {
+            if ch == '\t' { '\t' } else { ' ' }
+        }).collect();
+        output.push_str(&format!("{} |\n{} | {}\n{} | {}^\n", gutter, number, text, gutter, indent));
+        output
+    }
+
+    fn position(&self) -> Option<(usize, usize, usize)> {
+        match *self.inner() {
+            // Lines start at 1, so a zero means that the position is unknown.
+            Error::SyntaxError(_, _, 0, _) => None,
+            Error::SyntaxError(_, offset, line, column) => Some((offset, line, column)),
+            _ => None,
+        }
+    }
+
+    /// The error itself, without the path to the value which caused it.
+    pub fn inner(&self) -> &Error {
+        match *self {
+            Error::AtPath(_, ref error) => error,
+            ref error => error,
+        }
+    }
 }
 
 
@@ -43,6 +265,9 @@ impl error::Error for Error {
             Error::SyntaxError(..) => "syntax error",
             Error::FromUtf8Error(ref error) => error.description(),
             Error::IoError(ref error) => error::Error::description(error),
+            Error::Cancelled => "cancelled",
+            Error::DeadlineExceeded => "deadline exceeded",
+            Error::AtPath(_, ref error) => error.description(),
         }
     }
 
@@ -51,6 +276,9 @@ impl error::Error for Error {
             Error::SyntaxError(..) => None,
             Error::FromUtf8Error(ref error) => Some(error),
             Error::IoError(ref error) => Some(error),
+            Error::Cancelled => None,
+            Error::DeadlineExceeded => None,
+            Error::AtPath(_, ref error) => error.cause(),
         }
     }
 }
@@ -60,15 +288,54 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::SyntaxError(ref code, _, line, column) => {
-                write!(f, "{:?} at line {} column {}", code, line, column)
+                try!(write!(f, "{:?}", code));
+                // Errors found outside of the input, e.g. when writing
+                // files, have no position.
+                if line != 0 {
+                    try!(write!(f, " at line {} column {}", line, column));
+                }
+                match code.suggestion() {
+                    Some(suggestion) => write!(f, " ({})", suggestion),
+                    None => Ok(()),
+                }
             },
             Error::FromUtf8Error(ref error) => fmt::Display::fmt(error, f),
             Error::IoError(ref error) => fmt::Display::fmt(error, f),
+            Error::Cancelled => "Operation cancelled".fmt(f),
+            Error::DeadlineExceeded => "Deadline exceeded".fmt(f),
+            Error::AtPath(ref path, ref error) => write!(f, "{}: {}", path, error),
         }
     }
 }
 
 
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::SyntaxError(ErrorCode::Custom(msg.to_string()), 0, 0, 0)
+    }
+}
+
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::SyntaxError(ErrorCode::Custom(msg.to_string()), 0, 0, 0)
+    }
+
+    fn invalid_value(unexp: de::Unexpected, exp: &de::Expected) -> Error {
+        let msg = format!("Invalid value: {}, expected {}", unexp, exp);
+        Error::SyntaxError(ErrorCode::InvalidValue(msg), 0, 0, 0)
+    }
+
+    fn unknown_field(field: &str, _expected: &'static [&'static str]) -> Error {
+        Error::SyntaxError(ErrorCode::UnknownField(field.to_string()), 0, 0, 0)
+    }
+
+    fn missing_field(field: &'static str) -> Error {
+        Error::SyntaxError(ErrorCode::MissingField(field), 0, 0, 0)
+    }
+}
+
+
 impl From<io::Error> for Error {
     fn from(error: io::Error) -> Error {
         Error::IoError(error)
@@ -85,3 +352,26 @@ impl From<FromUtf8Error> for Error {
 
 pub type Result<T> = result::Result<T, Error>;
 
+
+#[cfg(all(test, feature = "de"))]
+mod tests {
+    use super::*;
+    use de::from_str;
+    use value::Value;
+
+    #[test]
+    fn test_render() {
+        let source = "a: 1\n\tb: 1x2\n";
+        let error = from_str::<Value>(source).unwrap_err();
+        assert_eq!("error: b: \"Invalid number\" at line 2 column 5\n  |\n2 | \tb: 1x2\n  | \t   ^\n",
+                   error.render(source));
+        assert_eq!("error: Operation cancelled\n", Error::Cancelled.render(source));
+    }
+
+    #[test]
+    fn test_display_without_position() {
+        let error = Error::SyntaxError(ErrorCode::UnmatchedResponse, 0, 0, 0);
+        assert_eq!(None, error.line());
+        assert_eq!(format!("{:?}", ErrorCode::UnmatchedResponse), error.to_string());
+    }
+}
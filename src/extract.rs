@@ -0,0 +1,126 @@
+//
+// extract.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+//! Extraction of single fields from streams of framed messages, e.g. to
+//! sum up a metric over logs. Each message is parsed as it is read,
+//! without building values for it.
+
+use std::io::Read;
+use super::error::Result;
+use super::parser::{Parser, Event};
+
+
+// Position inside a dictionary (last key read) or a list (next index).
+enum Level {
+    Dict(Option<String>),
+    List(usize),
+}
+
+
+fn matches(levels: &[Level], path: &[String]) -> bool {
+    levels.len() == path.len() && levels.iter().zip(path).all(|(level, segment)| {
+        match *level {
+            Level::Dict(Some(ref key)) => key == segment,
+            Level::Dict(None) => false,
+            Level::List(index) => segment.parse() == Ok(index),
+        }
+    })
+}
+
+
+/// Extracts a numeric field from each message of a stream in the framed
+/// wire format (see the `framing` module), e.g. for quick analytics over
+/// logs.
+///
+/// The field is given as a path like `/metrics/latency_ms`, where each
+/// segment is a dictionary key or a list index, and `~1` and `~0` may be
+/// used in keys to write `/` and `~`, respectively. Messages are parsed
+/// without building any intermediate values, and the ones where the field
+/// is missing or not a number are skipped.
+///
+/// Input is read one byte at a time: wrap it in a `BufReader` when it is
+/// not already buffered.
+pub fn extract_column<R>(rdr: R, path: &str) -> Result<Vec<f64>>
+    where R: Read
+{
+    let path: Vec<String> = path.split('/').skip(1)
+        .map(|segment| segment.replace("~1", "/").replace("~0", "~"))
+        .collect();
+    let mut parser = Parser::new(rdr.bytes());
+    // The parser keeps track of nesting with an explicit stack, and the
+    // events are handled in a loop, so nesting needs no limit.
+    parser.set_recursion_limit(usize::max_value());
+    let mut column = Vec::new();
+    while try!(parser.next_message()) {
+        let mut levels = Vec::new();
+        while let Some(event) = try!(parser.next_event()) {
+            let value = match event {
+                Event::Key(key) => {
+                    if let Some(&mut Level::Dict(ref mut current)) = levels.last_mut() {
+                        *current = Some(key);
+                    }
+                    continue;
+                },
+                Event::DictStart => {
+                    levels.push(Level::Dict(None));
+                    continue;
+                },
+                Event::ListStart => {
+                    levels.push(Level::List(0));
+                    continue;
+                },
+                Event::Annotation(_) => continue,
+                Event::DictEnd | Event::ListEnd => {
+                    levels.pop();
+                    None
+                },
+                Event::I64(v) => Some(v as f64),
+                Event::U64(v) => Some(v as f64),
+                Event::I128(v) => Some(v as f64),
+                Event::U128(v) => Some(v as f64),
+                Event::F64(v) => Some(v),
+                Event::Bool(_) | Event::String(_) | Event::Bytes(_) => None,
+            };
+            if let Some(value) = value {
+                if matches(&levels, &path) {
+                    column.push(value);
+                }
+            }
+            // A complete item has been read.
+            if let Some(&mut Level::List(ref mut index)) = levels.last_mut() {
+                *index += 1;
+            }
+        }
+    }
+    Ok(column)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_column() {
+        let input = "{metrics: {latency_ms: 12, count: 3}}\n\
+                     {metrics: {latency_ms: 0.5}}\n\
+                     {other: {latency_ms: 7}}\n\
+                     {metrics: {latency_ms: \"slow\"}}\n\
+                     {metrics: {latency_ms: :ms 0x10}}";
+        assert_eq!(vec![12.0, 0.5, 16.0],
+                   extract_column(input.as_bytes(), "/metrics/latency_ms").unwrap());
+        assert!(extract_column(input.as_bytes(), "/metrics/none").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_extract_column_path() {
+        let input = "{a: [1 [2 3] {b: 4}], a/b: 5}";
+        assert_eq!(vec![3.0], extract_column(input.as_bytes(), "/a/1/1").unwrap());
+        assert_eq!(vec![4.0], extract_column(input.as_bytes(), "/a/2/b").unwrap());
+        assert_eq!(vec![5.0], extract_column(input.as_bytes(), "/a~1b").unwrap());
+        assert!(extract_column("{a: 1".as_bytes(), "/a").is_err());
+    }
+}
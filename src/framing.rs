@@ -0,0 +1,497 @@
+//
+// framing.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+//! Streams of HiPack messages, e.g. for IPC.
+//!
+//! In the framed wire format each message is a top-level dictionary
+//! enclosed in braces, and messages are concatenated one after another,
+//! optionally separated by whitespace. The closing brace delimits each
+//! message, so it can be handled without waiting for more input.
+//!
+//! Messages may be preceded by a small dictionary of header fields
+//! annotated as `:headers`, which works as an envelope: headers can be
+//! read without parsing the payload which follows them, e.g. to route
+//! messages by their content type.
+//!
+//! ```text
+//! :headers {content-type: "text/plain" message-id: 42} {text: "Hello"}
+//! ```
+//!
+//! Binary data can be sent without escaping it into strings as attachment
+//! frames, which precede the message (and its headers) and contain raw
+//! bytes after their length and a newline. The payload refers to the
+//! attachments of the message by their index, annotated as `:attachment`:
+//!
+//! ```text
+//! :attachment 4
+//! ....:attachment 2
+//! ..{icon: :attachment 0 thumbnail: :attachment 1}
+//! ```
+//!
+//! Over transports which may lose, reorder or repeat messages, writers can
+//! number them with `MessageWriter::sequence()`, which adds an increasing
+//! number to the headers of each message. Readers report numbers which skip
+//! some messages, arrive after higher ones, or were already received,
+//! through their `warning_handler()`, and keep reading.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use serde::{de, ser};
+use super::error::{Result, ErrorCode, Warning, WarningCode};
+use super::de::Deserializer;
+use super::ser::{Annotated, Serializer};
+use super::value::Value;
+
+
+/// Header fields of a message.
+pub type Headers = BTreeMap<String, Value>;
+
+/// Header field for the media type of the payload.
+pub const CONTENT_TYPE: &'static str = "content-type";
+/// Header field for an identifier of the message.
+pub const MESSAGE_ID: &'static str = "message-id";
+/// Header field for the time at which the message was sent.
+pub const TIMESTAMP: &'static str = "timestamp";
+/// Header field for the identifier of the message being replied to.
+pub const CORRELATION_ID: &'static str = "correlation-id";
+/// Header field for the sequence number of the message, see
+/// `MessageWriter::sequence()`.
+pub const SEQUENCE: &'static str = "sequence";
+
+/// Annotation of attachment frames, and of the references to them.
+pub const ATTACHMENT: &'static str = "attachment";
+
+const HEADERS_ANNOTATION: &'static str = "headers";
+
+
+/// Writes messages in the framed wire format.
+pub struct MessageWriter<W: Write> {
+    writer: W,
+    // Sequence number of the next message, when numbering them.
+    sequence: Option<u64>,
+}
+
+
+impl<W: Write> MessageWriter<W> {
+    #[inline]
+    pub fn new(writer: W) -> Self {
+        MessageWriter { writer: writer, sequence: None }
+    }
+
+    /// Numbers the messages written from now on, starting with `first`
+    /// and increasing by one for each message, in the `SEQUENCE` header.
+    #[inline]
+    pub fn sequence(mut self, first: u64) -> Self {
+        self.sequence = Some(first);
+        self
+    }
+
+    /// Writes a message and flushes the writer. The value must serialize
+    /// as a dictionary (e.g. a struct or a map).
+    pub fn write_message<T>(&mut self, value: &T) -> Result<()>
+        where T: ser::Serialize
+    {
+        self.write_message_with_headers(&Headers::new(), value)
+    }
+
+    /// Writes a message preceded by header fields, which are left out
+    /// when there are none, and flushes the writer.
+    pub fn write_message_with_headers<T>(&mut self, headers: &Headers, value: &T) -> Result<()>
+        where T: ser::Serialize
+    {
+        self.write_message_with_attachments(headers, value, &[])
+    }
+
+    /// Writes a message preceded by attachment frames with raw binary data
+    /// and header fields, and flushes the writer. The payload refers to
+    /// each attachment by its index in `attachments`, see `attachment()`.
+    pub fn write_message_with_attachments<T>(&mut self,
+                                             headers: &Headers,
+                                             value: &T,
+                                             attachments: &[&[u8]]) -> Result<()>
+        where T: ser::Serialize
+    {
+        for data in attachments {
+            try!(write!(self.writer, ":{} {}\n", ATTACHMENT, data.len()));
+            try!(self.writer.write_all(data));
+        }
+        let headers = match self.sequence {
+            Some(sequence) => {
+                let mut headers = headers.clone();
+                headers.insert(SEQUENCE.to_string(), Value::U64(sequence));
+                self.sequence = Some(sequence.wrapping_add(1));
+                Cow::Owned(headers)
+            },
+            None => Cow::Borrowed(headers),
+        };
+        if !headers.is_empty() {
+            try!(write!(self.writer, ":{} ", HEADERS_ANNOTATION));
+            try!(ser::Serialize::serialize(&*headers, &mut Serializer::new(&mut self.writer)));
+            try!(self.writer.write_all(b" "));
+        }
+        try!(value.serialize(&mut Serializer::new(&mut self.writer)));
+        try!(self.writer.write_all(b"\n"));
+        self.writer.flush().map_err(From::from)
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+
+/// Returns a reference to the attachment with the given index, to be
+/// included in the payload of a message.
+#[inline]
+pub fn attachment(index: usize) -> Annotated<u64> {
+    Annotated::new(index as u64).annotate(ATTACHMENT)
+}
+
+
+/// Reads messages in the framed wire format.
+///
+/// Input is read one byte at a time: wrap it in a `BufReader` when it is
+/// not already buffered.
+pub struct MessageReader<R: Read> {
+    de: Deserializer<io::Bytes<R>>,
+    // Set after the headers of a message have been read.
+    payload: bool,
+    attachments: Vec<Vec<u8>>,
+    // Sequence number expected for the next numbered message, and which
+    // of the numbers before it were received, as a bit for each of them
+    // going backwards, so the lowest bit is for the one just before.
+    sequence: Option<u64>,
+    received: u64,
+}
+
+
+impl<R: Read> MessageReader<R> {
+    #[inline]
+    pub fn new(reader: R) -> Self {
+        MessageReader {
+            de: Deserializer::new(reader.bytes()),
+            payload: false,
+            attachments: Vec::new(),
+            sequence: None,
+            received: 0,
+        }
+    }
+
+    /// Sets a callback which receives warnings about problems found in
+    /// the input, including gaps, late arrivals and duplicates in the
+    /// sequence numbers of messages.
+    #[inline]
+    pub fn warning_handler<C>(mut self, callback: C) -> Self
+        where C: FnMut(Warning) + 'static
+    {
+        self.de = self.de.warning_handler(callback);
+        self
+    }
+
+    /// Limits the amount of memory used by each message, counting its
+    /// headers, its payload and its attachments. See
+    /// `Deserializer::memory_limit()`.
+    #[inline]
+    pub fn memory_limit(mut self, limit: usize) -> Self {
+        self.de = self.de.memory_limit(limit);
+        self
+    }
+
+    /// Reads the header fields of the next message, which are empty if it
+    /// has none, or returns `None` once the end of the input is reached in
+    /// between messages. The payload must then be read with
+    /// `read_message()` or skipped with `skip_message()`.
+    pub fn read_headers(&mut self) -> Result<Option<Headers>> {
+        if self.payload {
+            try!(self.skip_message());
+        }
+        self.attachments.clear();
+        // Attachments count towards the memory used by the frames which
+        // follow them, up to the payload of the message.
+        let mut attached = 0;
+        let annotation = loop {
+            if !try!(self.de.next_message()) {
+                if self.attachments.is_empty() {
+                    return Ok(None);
+                }
+                return Err(self.de.error(ErrorCode::UnexpectedEof));
+            }
+            try!(self.de.use_memory(attached));
+            match try!(self.de.message_annotation()) {
+                Some(ref annotation) if annotation == ATTACHMENT => {
+                    let data = try!(self.de.read_blob());
+                    attached += data.len();
+                    self.attachments.push(data);
+                },
+                annotation => break annotation,
+            }
+        };
+        let headers = match annotation {
+            None => Headers::new(),
+            Some(ref annotation) if annotation == HEADERS_ANNOTATION => {
+                let headers = try!(de::Deserialize::deserialize(&mut self.de));
+                try!(self.de.end());
+                if !try!(self.de.next_message()) {
+                    return Err(self.de.error(ErrorCode::UnexpectedEof));
+                }
+                try!(self.de.use_memory(attached));
+                headers
+            },
+            Some(_) => return Err(self.de.error(ErrorCode::InvalidAnnotation)),
+        };
+        if let Some(sequence) = headers.get(SEQUENCE) {
+            match sequence.as_u64() {
+                Some(sequence) => self.check_sequence(sequence),
+                None => return Err(self.de.error(ErrorCode::InvalidValue(
+                    "Sequence number must be an unsigned integer".to_string()))),
+            }
+        }
+        self.payload = true;
+        Ok(Some(headers))
+    }
+
+    // Numbers wrap around, so those up to half of the range ahead of the
+    // expected one are considered to follow it, and the rest to precede it.
+    fn check_sequence(&mut self, sequence: u64) {
+        let expected = match self.sequence {
+            Some(expected) => expected,
+            None => sequence,
+        };
+        let ahead = sequence.wrapping_sub(expected);
+        if ahead >= 1 << 63 {
+            let behind = expected.wrapping_sub(sequence) - 1;
+            // Older numbers are too far behind to tell, and count as late.
+            let bit = if behind < 64 { 1 << behind } else { 0 };
+            if self.received & bit != 0 {
+                self.de.warn(WarningCode::SequenceDuplicate(sequence));
+            } else {
+                self.de.warn(WarningCode::SequenceLate(sequence));
+                self.received |= bit;
+            }
+            // Keep expecting the number after the highest one seen.
+            return;
+        }
+        if ahead > 0 {
+            self.de.warn(WarningCode::SequenceGap(expected, sequence));
+        }
+        self.received = if ahead < 63 { self.received << (ahead + 1) | 1 } else { 1 };
+        self.sequence = Some(sequence.wrapping_add(1));
+    }
+
+    /// Reads the payload of the next message, skipping its headers unless
+    /// they were already read with `read_headers()`. Returns `None` once
+    /// the end of the input is reached in between messages.
+    pub fn read_message<T>(&mut self) -> Result<Option<T>>
+        where T: de::DeserializeOwned
+    {
+        if !self.payload && try!(self.read_headers()).is_none() {
+            return Ok(None);
+        }
+        self.payload = false;
+        let value = try!(de::Deserialize::deserialize(&mut self.de));
+        try!(self.de.end());
+        Ok(Some(value))
+    }
+
+    /// Returns the attachments of the message whose headers were read
+    /// last, indexed as referred to by its payload.
+    #[inline]
+    pub fn attachments(&self) -> &[Vec<u8>] {
+        &self.attachments
+    }
+
+    /// Takes the attachments of the message whose headers were read last,
+    /// leaving none in the reader.
+    #[inline]
+    pub fn take_attachments(&mut self) -> Vec<Vec<u8>> {
+        ::std::mem::replace(&mut self.attachments, Vec::new())
+    }
+
+    /// Returns the number of bytes consumed from the input.
+    #[inline]
+    pub fn byte_offset(&self) -> usize {
+        self.de.byte_offset()
+    }
+
+    /// Skips the payload of a message whose headers have been read,
+    /// without deserializing it.
+    pub fn skip_message(&mut self) -> Result<()> {
+        if self.payload {
+            self.payload = false;
+            try!(self.de.skip_message());
+        }
+        Ok(())
+    }
+}
+
+
+impl<R: Read> Iterator for MessageReader<R> {
+    type Item = Result<Value>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_message() {
+            Ok(Some(value)) => Some(Ok(value)),
+            Ok(None) => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use error::Error;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut first = BTreeMap::new();
+        first.insert("a".to_string(), vec![1, 2]);
+        let second: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+
+        let mut writer = MessageWriter::new(Vec::new());
+        writer.write_message(&first).unwrap();
+        writer.write_message(&second).unwrap();
+        let data = writer.into_inner();
+        assert_eq!("{a:[1,2]}\n{}\n", String::from_utf8(data.clone()).unwrap());
+
+        let mut reader = MessageReader::new(&data[..]);
+        assert_eq!(Some(first), reader.read_message().unwrap());
+        assert_eq!(Some(second), reader.read_message().unwrap());
+        assert_eq!(None, reader.read_message::<BTreeMap<String, Vec<u32>>>().unwrap());
+    }
+
+    #[test]
+    fn test_headers() {
+        let mut headers = Headers::new();
+        headers.insert(CONTENT_TYPE.to_string(), Value::String("text/plain".to_string()));
+        headers.insert(MESSAGE_ID.to_string(), Value::U64(42));
+        let mut payload = BTreeMap::new();
+        payload.insert("text".to_string(), "Hello".to_string());
+
+        let mut writer = MessageWriter::new(Vec::new());
+        writer.write_message_with_headers(&headers, &payload).unwrap();
+        writer.write_message(&payload).unwrap();
+        writer.write_message_with_headers(&headers, &payload).unwrap();
+        let data = writer.into_inner();
+        assert!(data.starts_with(b":headers {content-type:\"text/plain\",message-id:42} {text:"));
+
+        let mut reader = MessageReader::new(&data[..]);
+        assert_eq!(Some(headers.clone()), reader.read_headers().unwrap());
+        assert_eq!(Some(payload.clone()), reader.read_message().unwrap());
+        assert_eq!(Some(Headers::new()), reader.read_headers().unwrap());
+        reader.skip_message().unwrap();
+        assert_eq!(Some(payload), reader.read_message().unwrap());
+        assert_eq!(None, reader.read_headers().unwrap());
+
+        let mut reader = MessageReader::new(":headers {a: 1} {} {b: 2}".as_bytes());
+        assert_eq!(1, reader.read_headers().unwrap().unwrap().len());
+        assert!(reader.read_headers().unwrap().unwrap().is_empty());
+        assert!(MessageReader::new(":other {} {}".as_bytes()).read_headers().is_err());
+        assert!(MessageReader::new(":headers {}".as_bytes()).read_headers().is_err());
+    }
+
+    #[test]
+    fn test_attachments() {
+        let mut payload = BTreeMap::new();
+        payload.insert("icon", attachment(0));
+        payload.insert("thumbnail", attachment(1));
+        let icon = [0u8, b'\n', b'}', 255];
+
+        let mut writer = MessageWriter::new(Vec::new());
+        writer.write_message_with_attachments(&Headers::new(), &payload, &[&icon, b"}{"]).unwrap();
+        writer.write_message(&BTreeMap::<String, u32>::new()).unwrap();
+        let data = writer.into_inner();
+        assert_eq!(&b":attachment 4\n\0\n}\xFF:attachment 2\n}{\
+                     {icon::attachment 0,thumbnail::attachment 1}\n{}\n"[..], &data[..]);
+
+        let mut reader = MessageReader::new(&data[..]);
+        let value: BTreeMap<String, Value> = reader.read_message().unwrap().unwrap();
+        assert_eq!(Value::Annotated(vec![ATTACHMENT.to_string()], Box::new(Value::U64(1))),
+                   value["thumbnail"]);
+        assert_eq!(vec![icon.to_vec(), b"}{".to_vec()], reader.take_attachments());
+        assert!(reader.read_headers().unwrap().unwrap().is_empty());
+        assert!(reader.attachments().is_empty());
+
+        assert!(MessageReader::new(&b":attachment 4\nab"[..]).read_headers().is_err());
+        assert!(MessageReader::new(&b":attachment 1\na"[..]).read_headers().is_err());
+        assert!(MessageReader::new(&b":attachment x\n{}"[..]).read_headers().is_err());
+
+        // Attachments add up, and count towards the memory limit of the message.
+        let mut reader = MessageReader::new(&data[..]).memory_limit(5);
+        match reader.read_headers() {
+            Err(Error::SyntaxError(ErrorCode::MemoryLimitExceeded, _, _, _)) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        let mut reader = MessageReader::new(&b":attachment 1000000\n"[..]).memory_limit(100);
+        match reader.read_headers() {
+            Err(Error::SyntaxError(ErrorCode::MemoryLimitExceeded, _, _, _)) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_sequence() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut writer = MessageWriter::new(Vec::new()).sequence(7);
+        writer.write_message(&BTreeMap::<String, u32>::new()).unwrap();
+        let mut headers = Headers::new();
+        headers.insert(MESSAGE_ID.to_string(), Value::U64(1));
+        writer.write_message_with_headers(&headers, &BTreeMap::<String, u32>::new()).unwrap();
+        assert_eq!(":headers {sequence:7} {}\n:headers {message-id:1,sequence:8} {}\n",
+                   String::from_utf8(writer.into_inner()).unwrap());
+
+        let warnings = Rc::new(RefCell::new(Vec::new()));
+        let callback_warnings = warnings.clone();
+        let input = ":headers {sequence: 1} {} :headers {sequence: 2} {} {} \
+                     :headers {sequence: 5} {} :headers {sequence: 3} {} :headers {sequence: 3} {} \
+                     :headers {sequence: 2} {} :headers {sequence: 6} {}";
+        let reader = MessageReader::new(input.as_bytes())
+            .warning_handler(move |warning| callback_warnings.borrow_mut().push(warning.code));
+        assert_eq!(8, reader.map(Result::unwrap).count());
+        assert_eq!(vec![WarningCode::SequenceGap(3, 5), WarningCode::SequenceLate(3),
+                        WarningCode::SequenceDuplicate(3), WarningCode::SequenceDuplicate(2)],
+                   *warnings.borrow());
+
+        // Numbers wrap around after the largest one.
+        warnings.borrow_mut().clear();
+        let callback_warnings = warnings.clone();
+        let input = format!(":headers {{sequence: {}}} {{}} :headers {{sequence: {}}} {{}} \
+                             :headers {{sequence: 1}} {{}} :headers {{sequence: 0}} {{}} \
+                             :headers {{sequence: {}}} {{}}",
+                            u64::max_value() - 1, u64::max_value(), u64::max_value());
+        let reader = MessageReader::new(input.as_bytes())
+            .warning_handler(move |warning| callback_warnings.borrow_mut().push(warning.code));
+        assert_eq!(5, reader.map(Result::unwrap).count());
+        assert_eq!(vec![WarningCode::SequenceGap(0, 1), WarningCode::SequenceLate(0),
+                        WarningCode::SequenceDuplicate(u64::max_value())],
+                   *warnings.borrow());
+
+        let mut reader = MessageReader::new(":headers {sequence: \"1\"} {}".as_bytes());
+        assert!(reader.read_headers().is_err());
+    }
+
+    #[test]
+    fn test_concatenated() {
+        let mut reader = MessageReader::new("{a: 1}{a: 2} {a: 3 b: [True]}".as_bytes());
+        assert_eq!(3, reader.by_ref().map(Result::unwrap).count());
+
+        let mut reader = MessageReader::new("{a: 1} {a: ".as_bytes());
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_err());
+    }
+}
@@ -0,0 +1,213 @@
+//
+// intern.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+//! Sharing strings among many parsed documents.
+//!
+//! Services which keep thousands of similar documents in memory (e.g.
+//! configurations of many hosts) end up storing the same keys and string
+//! values over and over. An `InternPool` keeps a single copy of each
+//! string, and `SharedValue` is a variant of `Value` which refers to the
+//! strings of a pool instead of owning them.
+//!
+//! ```text
+//! let pool = InternPool::new();
+//! let a = pool.from_str("host: \"localhost\" port: 80").unwrap();
+//! let b = pool.from_str("host: \"localhost\" port: 8080").unwrap();
+//! assert_eq!(3, pool.len());  // "host", "localhost", and "port".
+//! ```
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+use serde::ser;
+use super::de::from_str;
+use super::error::Result;
+use super::ser::serialize_annotated;
+use super::value::{Number, Value};
+
+
+/// Represents any valid HiPack value, with dictionary keys and strings
+/// shared through an `InternPool`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SharedValue {
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    I128(i128),
+    U128(u128),
+    F64(f64),
+    /// A number which keeps the text it was written with.
+    Number(Number),
+    String(Arc<str>),
+    List(Vec<SharedValue>),
+    Dict(BTreeMap<Arc<str>, SharedValue>),
+    /// A value with HEP-1 annotations, which is never empty.
+    Annotated(Vec<String>, Box<SharedValue>),
+}
+
+
+impl ser::Serialize for SharedValue {
+    fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+        where S: ser::Serializer
+    {
+        match *self {
+            SharedValue::Bool(v) => serializer.serialize_bool(v),
+            SharedValue::I64(v) => serializer.serialize_i64(v),
+            SharedValue::U64(v) => serializer.serialize_u64(v),
+            SharedValue::I128(v) => serializer.serialize_i128(v),
+            SharedValue::U128(v) => serializer.serialize_u128(v),
+            SharedValue::F64(v) => serializer.serialize_f64(v),
+            SharedValue::Number(ref v) => v.serialize(serializer),
+            SharedValue::String(ref v) => serializer.serialize_str(v),
+            SharedValue::List(ref v) => v.serialize(serializer),
+            SharedValue::Dict(ref v) => {
+                serializer.collect_map(v.iter().map(|(key, value)| (&key[..], value)))
+            },
+            SharedValue::Annotated(ref annotations, ref v) => {
+                serialize_annotated(serializer, annotations, v)
+            },
+        }
+    }
+}
+
+
+impl<'a> From<&'a SharedValue> for Value {
+    fn from(value: &'a SharedValue) -> Value {
+        match *value {
+            SharedValue::Bool(v) => Value::Bool(v),
+            SharedValue::I64(v) => Value::I64(v),
+            SharedValue::U64(v) => Value::U64(v),
+            SharedValue::I128(v) => Value::I128(v),
+            SharedValue::U128(v) => Value::U128(v),
+            SharedValue::F64(v) => Value::F64(v),
+            SharedValue::Number(ref v) => Value::Number(v.clone()),
+            SharedValue::String(ref v) => Value::String(v.to_string()),
+            SharedValue::List(ref v) => Value::List(v.iter().map(From::from).collect()),
+            SharedValue::Dict(ref v) => {
+                Value::Dict(v.iter().map(|(key, value)| (key.to_string(), value.into())).collect())
+            },
+            SharedValue::Annotated(ref annotations, ref v) => {
+                Value::Annotated(annotations.clone(), Box::new((&**v).into()))
+            },
+        }
+    }
+}
+
+
+/// Keeps a single copy of each string added to it. A pool may be shared
+/// among threads, e.g. behind an `Arc`.
+#[derive(Debug, Default)]
+pub struct InternPool {
+    strings: Mutex<HashSet<Arc<str>>>,
+}
+
+
+impl InternPool {
+    #[inline]
+    pub fn new() -> Self {
+        InternPool { strings: Mutex::new(HashSet::new()) }
+    }
+
+    /// Returns the copy of a string kept in the pool, adding it first if
+    /// the pool does not have it yet.
+    pub fn intern(&self, s: &str) -> Arc<str> {
+        let mut strings = self.strings.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(string) = strings.get(s) {
+            return string.clone();
+        }
+        let string: Arc<str> = Arc::from(s);
+        strings.insert(string.clone());
+        string
+    }
+
+    /// Converts a value, replacing its keys and strings with the copies
+    /// kept in the pool.
+    pub fn intern_value(&self, value: Value) -> SharedValue {
+        match value {
+            Value::Bool(v) => SharedValue::Bool(v),
+            Value::I64(v) => SharedValue::I64(v),
+            Value::U64(v) => SharedValue::U64(v),
+            Value::I128(v) => SharedValue::I128(v),
+            Value::U128(v) => SharedValue::U128(v),
+            Value::F64(v) => SharedValue::F64(v),
+            Value::Number(v) => SharedValue::Number(v),
+            Value::String(v) => SharedValue::String(self.intern(&v)),
+            Value::List(v) => {
+                SharedValue::List(v.into_iter().map(|value| self.intern_value(value)).collect())
+            },
+            Value::Dict(v) => {
+                SharedValue::Dict(v.into_iter().map(|(key, value)| {
+                    (self.intern(&key), self.intern_value(value))
+                }).collect())
+            },
+            Value::Annotated(annotations, v) => {
+                SharedValue::Annotated(annotations, Box::new(self.intern_value(*v)))
+            },
+        }
+    }
+
+    /// Parses a document, sharing its keys and strings through the pool.
+    pub fn from_str(&self, s: &str) -> Result<SharedValue> {
+        Ok(self.intern_value(try!(from_str(s))))
+    }
+
+    /// Number of strings kept in the pool.
+    pub fn len(&self) -> usize {
+        self.strings.lock().unwrap_or_else(|err| err.into_inner()).len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes the strings which are no longer used by any value, to
+    /// reclaim memory after documents have been dropped.
+    pub fn purge(&self) {
+        let mut strings = self.strings.lock().unwrap_or_else(|err| err.into_inner());
+        strings.retain(|string| Arc::strong_count(string) > 1);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use ser::to_string;
+    use value::Value;
+
+    #[test]
+    fn test_intern_pool() {
+        let pool = InternPool::new();
+        let a = pool.from_str("host: \"localhost\" port: 80 tags: [\"x\"]").unwrap();
+        let b = pool.from_str("host: \"localhost\" port: 8080 tags: [\"x\"]").unwrap();
+        assert_eq!(5, pool.len());
+        match (&a, &b) {
+            (&SharedValue::Dict(ref a), &SharedValue::Dict(ref b)) => {
+                match (&a["host"], &b["host"]) {
+                    (&SharedValue::String(ref a), &SharedValue::String(ref b)) => {
+                        assert!(Arc::ptr_eq(a, b));
+                    },
+                    _ => panic!("expected strings"),
+                }
+            },
+            _ => panic!("expected dictionaries"),
+        }
+
+        assert_eq!("{host:\"localhost\",port:80,tags:[\"x\"]}", to_string(&a).unwrap());
+        match (&b).into() {
+            Value::Dict(value) => assert_eq!(Value::U64(8080), value["port"]),
+            _ => panic!("expected a dictionary"),
+        }
+
+        drop(a);
+        pool.purge();
+        assert_eq!(5, pool.len());
+        drop(b);
+        pool.purge();
+        assert!(pool.is_empty());
+    }
+}
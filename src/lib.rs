@@ -4,7 +4,103 @@
 // Distributed under terms of the MIT license.
 //
 
+#[cfg_attr(feature = "value", macro_use)]
 extern crate serde;
+#[cfg(all(test, feature = "ser"))]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "ser")]
+extern crate itoa;
+#[cfg(feature = "ser")]
+extern crate ryu;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(any(test, feature = "preserve_order"))]
+extern crate indexmap;
+#[cfg(feature = "tokio")]
+extern crate bytes;
+#[cfg(any(test, feature = "tokio"))]
+extern crate tokio;
+#[cfg(feature = "tokio")]
+extern crate tokio_util;
+#[cfg(feature = "axum")]
+extern crate axum;
+#[cfg(feature = "actix")]
+extern crate actix_web;
+#[cfg(feature = "tracing")]
+extern crate tracing_core;
+#[cfg(feature = "tracing")]
+extern crate tracing_subscriber;
+#[cfg(all(test, feature = "tracing"))]
+extern crate tracing;
+#[cfg(feature = "json")]
+extern crate serde_json;
+#[cfg(feature = "config")]
+extern crate config;
+#[cfg(feature = "figment")]
+extern crate figment;
+#[cfg(feature = "allocator_api")]
+extern crate allocator_api2;
 
 pub mod error;
+#[cfg(feature = "ser")]
 pub mod ser;
+#[cfg(feature = "de")]
+pub mod de;
+#[cfg(feature = "value")]
+pub mod value;
+#[cfg(feature = "framing")]
+pub mod framing;
+#[cfg(feature = "framing")]
+pub mod rpc;
+#[cfg(feature = "de")]
+pub mod intern;
+pub mod text;
+pub mod mime;
+pub mod percent;
+#[cfg(feature = "value")]
+pub mod schema;
+#[cfg(feature = "value")]
+pub mod diff;
+#[cfg(feature = "de")]
+pub mod document;
+#[cfg(feature = "de")]
+pub mod split;
+#[cfg(feature = "de")]
+pub mod pull;
+#[cfg(feature = "de")]
+pub mod testing;
+#[cfg(feature = "tokio")]
+pub mod codec;
+#[cfg(feature = "tokio")]
+pub mod async_io;
+#[cfg(feature = "codegen")]
+pub mod codegen;
+#[cfg(any(feature = "axum", feature = "actix"))]
+pub mod web;
+#[cfg(any(feature = "config", feature = "figment"))]
+pub mod sources;
+#[cfg(feature = "tracing")]
+pub mod logging;
+// Writing keys needs to know which characters are valid in them.
+#[cfg_attr(not(feature = "de"), allow(dead_code))]
+mod parser;
+#[cfg(feature = "de")]
+mod compare;
+#[cfg(feature = "de")]
+mod extract;
+mod cancel;
+#[cfg_attr(not(all(feature = "ser", feature = "de")), allow(dead_code))]
+mod base64;
+
+pub use cancel::CancellationToken;
+#[cfg(feature = "de")]
+pub use compare::compare_streams;
+#[cfg(feature = "de")]
+pub use document::{Document, verify_roundtrip};
+#[cfg(feature = "de")]
+pub use extract::extract_column;
+#[cfg(feature = "value")]
+pub use schema::Schema;
+#[cfg(feature = "value")]
+pub use value::Value;
@@ -0,0 +1,226 @@
+//
+// logging.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+//! Writing log events as HiPack, one compact dictionary per line, with
+//! `tracing-subscriber`:
+//!
+//! ```text
+//! tracing_subscriber::fmt().event_format(HipackFormat::new()).init();
+//! info!(port = 80, "listening");
+//! // {time:1700000000.25,level:"INFO",target:"app",spans:["serve"],message:"listening",port:80}
+//! ```
+//!
+//! Records of the `log` crate can be formatted too, by forwarding them
+//! to `tracing` with the `tracing-log` crate.
+
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::ser::Serialize;
+use tracing_core::{Event, Subscriber};
+use tracing_core::field::{Field, Visit};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::registry::LookupSpan;
+use super::ser::{KeyPolicy, Serializer};
+use super::value::{Map, Value};
+
+
+/// Formats events as compact HiPack dictionaries with their fields, and
+/// the time, level, target, and names of the spans they happened in.
+/// Invalid characters in the names of fields are replaced, as with
+/// `KeyPolicy::Replace`.
+#[derive(Clone, Debug)]
+pub struct HipackFormat {
+    time: bool,
+    target: bool,
+    spans: bool,
+}
+
+
+impl HipackFormat {
+    #[inline]
+    pub fn new() -> Self {
+        HipackFormat { time: true, target: true, spans: true }
+    }
+
+    /// Whether to add the time of events, in seconds since the Unix
+    /// epoch, as `time`. Enabled by default.
+    #[inline]
+    pub fn with_time(mut self, time: bool) -> Self {
+        self.time = time;
+        self
+    }
+
+    /// Whether to add the target of events, usually the module they come
+    /// from, as `target`. Enabled by default.
+    #[inline]
+    pub fn with_target(mut self, target: bool) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Whether to add the names of the spans the events happened in, from
+    /// the outermost one, as `spans`. Enabled by default.
+    #[inline]
+    pub fn with_spans(mut self, spans: bool) -> Self {
+        self.spans = spans;
+        self
+    }
+
+    // Collects the entries of the dictionary written for an event.
+    fn entries(&self, event: &Event, spans: Option<Vec<Value>>) -> Map {
+        let mut entries = Map::new();
+        if self.time {
+            if let Ok(time) = SystemTime::now().duration_since(UNIX_EPOCH) {
+                entries.insert("time".to_string(), Value::F64(time.as_secs_f64()));
+            }
+        }
+        let metadata = event.metadata();
+        entries.insert("level".to_string(), Value::from(metadata.level().as_str()));
+        if self.target {
+            entries.insert("target".to_string(), Value::from(metadata.target()));
+        }
+        if let Some(spans) = spans {
+            entries.insert("spans".to_string(), Value::List(spans));
+        }
+        event.record(&mut FieldVisitor(&mut entries));
+        entries
+    }
+}
+
+
+impl Default for HipackFormat {
+    #[inline]
+    fn default() -> Self {
+        HipackFormat::new()
+    }
+}
+
+
+impl<S, N> FormatEvent<S, N> for HipackFormat
+    where S: Subscriber + for<'a> LookupSpan<'a>,
+          N: for<'a> FormatFields<'a> + 'static
+{
+    fn format_event(&self, ctx: &FmtContext<S, N>, mut writer: Writer, event: &Event) -> fmt::Result {
+        let spans = if self.spans {
+            Some(ctx.event_scope()
+                .map(|scope| scope.from_root().map(|span| Value::from(span.name())).collect())
+                .unwrap_or_else(Vec::new))
+        } else {
+            None
+        };
+        let mut output = Vec::new();
+        {
+            let mut serializer = Serializer::new(&mut output).key_policy(KeyPolicy::Replace);
+            let entries = Value::Dict(self.entries(event, spans));
+            try!(entries.serialize(&mut serializer).map_err(|_| fmt::Error));
+        }
+        try!(writer.write_str(&String::from_utf8_lossy(&output)));
+        writer.write_char('\n')
+    }
+}
+
+
+// Adds the fields of an event to a dictionary.
+struct FieldVisitor<'a>(&'a mut Map);
+
+
+impl<'a> FieldVisitor<'a> {
+    #[inline]
+    fn insert(&mut self, field: &Field, value: Value) {
+        self.0.insert(field.name().to_string(), value);
+    }
+}
+
+
+impl<'a> Visit for FieldVisitor<'a> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, Value::F64(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_i128(&mut self, field: &Field, value: i128) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_u128(&mut self, field: &Field, value: u128) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, Value::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &fmt::Debug) {
+        self.insert(field, Value::String(format!("{:?}", value)));
+    }
+}
+
+
+#[cfg(all(test, feature = "de"))]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use tracing::{info, info_span, subscriber, warn};
+    use tracing_subscriber::fmt;
+    use de::from_str;
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn capture<F: FnOnce()>(format: HipackFormat, f: F) -> String {
+        let output = Output::default();
+        let writer = output.clone();
+        let subscriber = fmt().event_format(format).with_writer(move || writer.clone()).finish();
+        subscriber::with_default(subscriber, f);
+        let bytes = output.0.lock().unwrap().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn test_format() {
+        let output = capture(HipackFormat::new().with_time(false), || {
+            let span = info_span!("serve", id = 1);
+            let _guard = span.enter();
+            warn!(port = 80, tls = false, peer.addr = "a b", "listening on {}", "x");
+        });
+        assert!(output.ends_with("}\n") && output.lines().count() == 1, "{}", output);
+        let expected: Value = from_str("level: \"WARN\" message: \"listening on x\" peer.addr: \"a b\" \
+                                        port: 80 spans: [\"serve\"] \
+                                        target: \"serde_hipack::logging::tests\" tls: False").unwrap();
+        assert_eq!(expected, from_str::<Value>(&output).unwrap());
+
+        let output = capture(HipackFormat::new().with_target(false).with_spans(false), || info!(n = -1.5));
+        let value: Value = from_str(&output).unwrap();
+        let mut keys: Vec<&str> = value.as_dict().unwrap().keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(vec!["level", "n", "time"], keys);
+        assert_eq!(Some(-1.5), value["n"].as_f64());
+    }
+}
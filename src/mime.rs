@@ -0,0 +1,107 @@
+//
+// mime.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+//! Identifying HiPack documents, e.g. for HTTP content negotiation or to
+//! pick a handler for a file.
+
+use std::path::Path;
+#[cfg(feature = "de")]
+use std::{cmp, str};
+#[cfg(feature = "de")]
+use super::parser::{Parser, Event, is_incomplete};
+
+
+/// Media type of HiPack documents. It is not registered, hence the `x-`.
+pub const MIME_TYPE: &'static str = "application/x-hipack";
+
+/// Extensions of HiPack files, without the leading dot. The first one is
+/// the preferred one, used when writing files.
+pub const EXTENSIONS: &'static [&'static str] = &["hipack", "hpk"];
+
+/// Number of bytes looked at by `is_probably_hipack()`.
+#[cfg(feature = "de")]
+pub const SNIFF_LENGTH: usize = 4096;
+
+
+/// Checks whether a path has one of the `EXTENSIONS`, ignoring case.
+pub fn has_hipack_extension<P: AsRef<Path>>(path: P) -> bool {
+    match path.as_ref().extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => EXTENSIONS.iter().any(|e| e.eq_ignore_ascii_case(extension)),
+        None => false,
+    }
+}
+
+
+/// Checks whether the value of a `Content-Type` header is `MIME_TYPE`,
+/// ignoring case and parameters like `charset`.
+pub fn is_hipack_media_type(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("");
+    essence.trim().eq_ignore_ascii_case(MIME_TYPE)
+}
+
+
+/// Guesses whether some data is a HiPack document, from its first bytes
+/// (up to `SNIFF_LENGTH`), which must be valid HiPack up to where they
+/// end and contain at least a key. Documents which are also valid in
+/// other formats, like `a: 1` in YAML, are reported as HiPack.
+#[cfg(feature = "de")]
+pub fn is_probably_hipack(bytes: &[u8]) -> bool {
+    let mut bytes = &bytes[..cmp::min(bytes.len(), SNIFF_LENGTH)];
+    if let Err(err) = str::from_utf8(bytes) {
+        // Characters may have been cut by SNIFF_LENGTH, or by the caller.
+        if err.error_len().is_some() {
+            return false;
+        }
+        bytes = &bytes[..err.valid_up_to()];
+    }
+    let mut parser = Parser::new(bytes.iter().map(|&byte| Ok(byte)));
+    let mut keys = false;
+    loop {
+        match parser.next_event() {
+            Ok(Some(Event::Key(_))) => keys = true,
+            Ok(Some(_)) => (),
+            Ok(None) => return keys,
+            Err(ref err) => return keys && is_incomplete(err, bytes.len()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extension() {
+        assert!(has_hipack_extension("conf/app.hipack"));
+        assert!(has_hipack_extension("APP.HPK"));
+        assert!(!has_hipack_extension("app.json"));
+        assert!(!has_hipack_extension("hipack"));
+    }
+
+    #[test]
+    fn test_media_type() {
+        assert!(is_hipack_media_type(MIME_TYPE));
+        assert!(is_hipack_media_type("Application/X-HiPack; charset=utf-8"));
+        assert!(!is_hipack_media_type("application/json"));
+        assert!(!is_hipack_media_type("application/x-hipack-stream"));
+    }
+
+    #[test]
+    #[cfg(feature = "de")]
+    fn test_sniff() {
+        for input in &["a: 1", "# Config\n{name: \"x\" ports [80", "a: \"cut", "é: 1 b: \"\u{e9}"] {
+            assert!(is_probably_hipack(input.as_bytes()), "{:?}", input);
+        }
+        assert!(is_probably_hipack(&"é: 1".as_bytes()[..3]));
+        for input in &["", "{}", "{\"a\": 1}", "[1, 2]", "<html>", "a 1", "a: 1 ]"] {
+            assert!(!is_probably_hipack(input.as_bytes()), "{:?}", input);
+        }
+        assert!(!is_probably_hipack(b"a: \"\xFF\xFE\""));
+        let long = format!("a: [{}]", "1 ".repeat(SNIFF_LENGTH));
+        assert!(is_probably_hipack(long.as_bytes()));
+    }
+}
@@ -0,0 +1,1311 @@
+//
+// parser.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use std::io;
+use std::mem;
+use std::ops::Range;
+use super::error::{Result, Error, ErrorCode, Limit};
+
+
+/// Piece of the structure of a document. The top-level dictionary of a
+/// message starts with `DictStart` and ends with `DictEnd` as well, even
+/// when it is written without braces, and annotations come before the
+/// value they belong to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    DictStart,
+    DictEnd,
+    ListStart,
+    ListEnd,
+    Key(String),
+    Bool(bool),
+    /// Negative integers; positive ones are `U64`.
+    I64(i64),
+    U64(u64),
+    /// Integers outside of the range of `I64` and `U64`.
+    I128(i128),
+    U128(u128),
+    F64(f64),
+    String(String),
+    /// String whose contents are not valid UTF-8, written using escapes.
+    Bytes(Vec<u8>),
+    Annotation(String),
+}
+
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    // Top-level dictionary; "braced" tells whether it was enclosed in {}.
+    Message(bool, bool),
+    // Nested dictionary. The flag is true when a value is expected.
+    Dict(bool),
+    List,
+}
+
+
+// Position in the input, remembered to report errors where a construct
+// starts, and where the tokens of events are.
+#[derive(Clone, Copy)]
+pub struct Mark {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+
+/// Caps on the input, to fail fast on untrusted data instead of allocating
+/// without bounds. Limits exceeded are reported with
+/// `ErrorCode::LimitExceeded`. No limits are set by default.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ParserOptions {
+    /// Size of each message, in bytes.
+    pub max_input_size: Option<usize>,
+    /// Length of strings and keys, in bytes.
+    pub max_string_length: Option<usize>,
+    /// Number of items in a list.
+    pub max_list_length: Option<usize>,
+    /// Number of entries in a dictionary.
+    pub max_dict_size: Option<usize>,
+}
+
+
+pub struct Parser<Iter: Iterator<Item=io::Result<u8>>> {
+    rdr: Iter,
+    ch: Option<Option<u8>>,
+    offset: usize,
+    line: usize,
+    column: usize,
+    stack: Vec<State>,
+    // Set after a value has been read, until its separator is consumed.
+    separator: bool,
+    started: bool,
+    json_compat: bool,
+    // Whether more messages may follow a braced top-level dictionary.
+    multiple: bool,
+    // Set after an annotation, until the annotated value is read.
+    annotated: bool,
+    // Intrinsic annotations of the value being read.
+    intrinsics: Vec<String>,
+    // Buffer given back with recycle_key(), reused to read the next key.
+    key_buf: Vec<u8>,
+    recursion_limit: usize,
+    // Annotations of scalars written as raw tokens, and whether the value
+    // being read has one of them.
+    scalars: Vec<String>,
+    raw_scalar: bool,
+    options: ParserOptions,
+    // Offset where the current message starts, and number of items read
+    // so far in each of the compounds in the stack.
+    message_start: usize,
+    counts: Vec<usize>,
+    // Bytes consumed by skip_raw_value(), when they are kept.
+    capture: Option<Vec<u8>>,
+    // Text of the last number read.
+    number: String,
+    // Set while skipping a value, to discard the contents of strings.
+    skipping: bool,
+    // Where the token of the last event starts and ends. The end is only
+    // set when whitespace after the token has been consumed already.
+    token_start: Mark,
+    token_end: Option<Mark>,
+}
+
+
+// Errors at the end of the input mean that it is incomplete, and more of
+// it is needed. Unterminated strings are reported at their opening quote
+// instead.
+pub fn is_incomplete(err: &Error, len: usize) -> bool {
+    match *err {
+        Error::SyntaxError(ErrorCode::UnterminatedString, ..) => true,
+        Error::SyntaxError(_, offset, _, _) => offset == len,
+        _ => false,
+    }
+}
+
+
+#[inline]
+pub fn is_whitespace(ch: u8) -> bool {
+    ch == b' ' || ch == b'\t' || ch == b'\n' || ch == b'\r'
+}
+
+#[inline]
+pub fn is_key_char(ch: u8) -> bool {
+    !is_whitespace(ch) && ch != b'[' && ch != b']' && ch != b'{' &&
+        ch != b'}' && ch != b':' && ch != b','
+}
+
+#[inline]
+pub fn is_number_char(ch: u8) -> bool {
+    match ch {
+        b'0'..=b'9' | b'a'..=b'z' | b'A'..=b'Z' | b'.' | b'+' | b'-' => true,
+        _ => false,
+    }
+}
+
+#[inline]
+fn exceeds(value: usize, limit: Option<usize>) -> bool {
+    limit.map_or(false, |limit| value > limit)
+}
+
+// Lists and dictionaries nested deeper than this are rejected, to avoid
+// overflowing the stack when (de)serializing them recursively.
+pub const DEFAULT_RECURSION_LIMIT: usize = 128;
+
+// Intrinsic annotations (names starting with a dot are reserved for them)
+// declare the type of the annotated value.
+const INTRINSICS: &'static [&'static str] = &[
+    ".int", ".float", ".bool", ".string", ".list", ".dict",
+];
+
+fn has_intrinsic_type(event: &Event, intrinsic: &str) -> bool {
+    match (intrinsic, event) {
+        (".int", &Event::I64(_)) | (".int", &Event::U64(_)) |
+        (".int", &Event::I128(_)) | (".int", &Event::U128(_)) |
+        (".float", &Event::F64(_)) |
+        (".bool", &Event::Bool(_)) |
+        (".string", &Event::String(_)) | (".string", &Event::Bytes(_)) |
+        (".list", &Event::ListStart) |
+        (".dict", &Event::DictStart) => true,
+        _ => false,
+    }
+}
+
+// Parses the token of a number, which may be written in decimal, hex or
+// octal, or be a float, NaN or an infinity.
+pub fn parse_number_token(token: &str) -> Option<Event> {
+    match token {
+        "NaN" => return Some(Event::F64(::std::f64::NAN)),
+        "inf" | "+inf" => return Some(Event::F64(::std::f64::INFINITY)),
+        "-inf" => return Some(Event::F64(::std::f64::NEG_INFINITY)),
+        _ => (),
+    }
+
+    let (negative, digits) = match token.as_bytes().first() {
+        Some(&b'-') => (true, &token[1..]),
+        Some(&b'+') => (false, &token[1..]),
+        _ => (false, token),
+    };
+
+    let (radix, digits) = if digits.starts_with("0x") || digits.starts_with("0X") {
+        (16, &digits[2..])
+    } else if digits.len() > 1 && digits.starts_with("0") &&
+              !digits.contains(|c| c == '.' || c == 'e' || c == 'E') {
+        (8, &digits[1..])
+    } else {
+        (10, digits)
+    };
+
+    // Parsing the digits would accept another sign, e.g. in `++5` or `0x+5`.
+    if digits.starts_with(|c| c == '+' || c == '-') {
+        return None;
+    }
+
+    if radix == 10 && digits.contains(|c| c == '.' || c == 'e' || c == 'E') {
+        return token.parse::<f64>().ok().map(Event::F64);
+    }
+
+    // Negating the largest magnitudes wraps around to the minimums.
+    match u128::from_str_radix(digits, radix) {
+        Ok(value) if !negative && value <= u64::max_value() as u128 => {
+            Some(Event::U64(value as u64))
+        },
+        Ok(value) if !negative => Some(Event::U128(value)),
+        Ok(value) if value <= i64::max_value() as u128 + 1 => {
+            Some(Event::I64((value as i64).wrapping_neg()))
+        },
+        Ok(value) if value <= i128::max_value() as u128 + 1 => {
+            Some(Event::I128((value as i128).wrapping_neg()))
+        },
+        _ => None,
+    }
+}
+
+#[inline]
+pub fn hex_value(ch: u8) -> Option<u8> {
+    match ch {
+        b'0'..=b'9' => Some(ch - b'0'),
+        b'a'..=b'f' => Some(ch - b'a' + 10),
+        b'A'..=b'F' => Some(ch - b'A' + 10),
+        _ => None,
+    }
+}
+
+
+impl<Iter> Parser<Iter> where Iter: Iterator<Item=io::Result<u8>> {
+    #[inline]
+    pub fn new(rdr: Iter) -> Self {
+        Parser {
+            rdr: rdr,
+            ch: None,
+            offset: 0,
+            line: 1,
+            column: 1,
+            stack: Vec::new(),
+            separator: false,
+            started: false,
+            json_compat: false,
+            multiple: false,
+            annotated: false,
+            intrinsics: Vec::new(),
+            key_buf: Vec::new(),
+            recursion_limit: DEFAULT_RECURSION_LIMIT,
+            scalars: Vec::new(),
+            raw_scalar: false,
+            options: ParserOptions::default(),
+            message_start: 0,
+            counts: Vec::new(),
+            capture: None,
+            number: String::new(),
+            skipping: false,
+            token_start: Mark { offset: 0, line: 1, column: 1 },
+            token_end: None,
+        }
+    }
+
+    /// Gives back the string of an `Event::Key` which is not needed
+    /// anymore, so its buffer is reused to read the next key instead of
+    /// allocating a new one.
+    #[inline]
+    pub fn recycle_key(&mut self, key: String) {
+        self.key_buf = key.into_bytes();
+    }
+
+    /// Enables accepting a subset of JSON syntax: quoted keys, lowercase
+    /// booleans, JSON string escapes, and `null` values inside dictionaries,
+    /// which are dropped together with their keys.
+    #[inline]
+    pub fn set_json_compat(&mut self, enabled: bool) {
+        self.json_compat = enabled;
+    }
+
+    /// Enables discarding the contents of strings, which are still checked
+    /// but reported as empty, e.g. while skipping values which are not
+    /// needed, to avoid allocating memory for them.
+    #[inline]
+    pub fn set_skipping(&mut self, enabled: bool) {
+        self.skipping = enabled;
+    }
+
+    /// Makes values with the given annotation be read as a raw token (e.g.
+    /// `:rational 1/3`), which is reported as a string. The token extends
+    /// up to the next whitespace, comma, or delimiter.
+    pub fn add_scalar_annotation(&mut self, annotation: &str) {
+        self.scalars.push(annotation.to_string());
+    }
+
+    /// Sets how deep lists and dictionaries may be nested.
+    #[inline]
+    pub fn set_recursion_limit(&mut self, limit: usize) {
+        self.recursion_limit = limit;
+    }
+
+    #[inline]
+    pub fn set_options(&mut self, options: ParserOptions) {
+        self.options = options;
+    }
+
+    /// Prepares for reading another message from the same input, and
+    /// allows braced messages to be followed by more messages. Input is
+    /// not read past the closing brace of a message until this is called
+    /// again, so messages can be read as they arrive. Returns `false` if
+    /// there are no more messages.
+    pub fn next_message(&mut self) -> Result<bool> {
+        self.multiple = true;
+        self.started = false;
+        self.separator = false;
+        self.stack.clear();
+        self.counts.clear();
+        self.message_start = self.offset;
+        try!(self.skip_whitespace());
+        Ok(try!(self.peek()).is_some())
+    }
+
+    /// Parses an annotation preceding a whole message, which is only valid
+    /// in between calls to `next_message()` and `next_event()`.
+    pub fn parse_message_annotation(&mut self) -> Result<Option<String>> {
+        let annotation = try!(self.parse_annotation());
+        self.annotated = false;
+        self.intrinsics.clear();
+        Ok(annotation)
+    }
+
+    /// Reads a block of raw bytes preceded by its length, written in
+    /// decimal and followed by a newline, which is only valid in between
+    /// messages, after a message annotation. Blobs longer than `limit`
+    /// fail with `ErrorCode::MemoryLimitExceeded` before reading them.
+    pub fn parse_blob(&mut self, limit: usize) -> Result<Vec<u8>> {
+        let mut length: usize = 0;
+        let mut digits = 0;
+        while let Some(ch @ b'0'..=b'9') = try!(self.peek()) {
+            length = match length.checked_mul(10).and_then(|n| n.checked_add((ch - b'0') as usize)) {
+                Some(length) => length,
+                None => return Err(self.error(ErrorCode::InvalidNumber)),
+            };
+            digits += 1;
+            self.bump();
+        }
+        if digits == 0 || try!(self.next_char()) != Some(b'\n') {
+            return Err(self.error(ErrorCode::InvalidNumber));
+        }
+        if length > limit {
+            return Err(self.error(ErrorCode::MemoryLimitExceeded));
+        }
+        // The length comes from the input, so do not trust it to reserve memory.
+        let mut blob = Vec::with_capacity(length.min(64 * 1024));
+        while blob.len() < length {
+            match try!(self.next_char()) {
+                Some(ch) => blob.push(ch),
+                None => return Err(self.error(ErrorCode::UnexpectedEof)),
+            }
+        }
+        Ok(blob)
+    }
+
+    /// Consumes the next value, which is the value of a dictionary entry,
+    /// or an item of a list, without reading it into events: only strings,
+    /// comments and the nesting of delimiters are followed, so its text is
+    /// not checked any further. Returns the range of the input it spans,
+    /// and its bytes when `capture` is set, or `None` if no such value is
+    /// expected and nothing was consumed.
+    pub fn skip_raw_value(&mut self, capture: bool) -> Result<Option<(Range<usize>, Vec<u8>)>> {
+        if !try!(self.skip_to_value()) {
+            return Ok(None);
+        }
+        match self.stack.last().cloned() {
+            Some(State::Message(braced, true)) => *self.stack.last_mut().unwrap() = State::Message(braced, false),
+            Some(State::Dict(true)) => *self.stack.last_mut().unwrap() = State::Dict(false),
+            _ => try!(self.count_item(self.options.max_list_length, Limit::ListLength)),
+        }
+        let start = self.offset;
+        if capture {
+            self.capture = Some(Vec::new());
+        }
+        let result = self.skip_raw_bytes();
+        let bytes = self.capture.take().unwrap_or_default();
+        try!(result);
+        self.separator = true;
+        Ok(Some((start..self.offset, bytes)))
+    }
+
+    /// Consumes the separator and whitespace in front of the next value,
+    /// which is the value of a dictionary entry, or an item of a list, so
+    /// that the current position is where it starts. Returns `false` if no
+    /// such value is expected.
+    pub fn skip_to_value(&mut self) -> Result<bool> {
+        match self.stack.last() {
+            Some(&State::Message(_, true)) | Some(&State::Dict(true)) => Ok(true),
+            Some(&State::List) if !self.annotated => {
+                if self.separator {
+                    try!(self.parse_separator());
+                }
+                try!(self.skip_whitespace());
+                Ok(true)
+            },
+            _ => Ok(false),
+        }
+    }
+
+    /// Checks whether the list being read has no more items, consuming
+    /// only the separator and whitespace in front of the next item.
+    pub fn at_list_end(&mut self) -> Result<bool> {
+        if self.stack.last() != Some(&State::List) || self.annotated {
+            return Ok(false);
+        }
+        if self.separator {
+            try!(self.parse_separator());
+        }
+        try!(self.skip_whitespace());
+        Ok(try!(self.peek()) == Some(b']'))
+    }
+
+    /// Number of bytes consumed from the input.
+    #[inline]
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Line and column of the current position in the input.
+    #[inline]
+    pub fn position(&self) -> (usize, usize) {
+        (self.line, self.column)
+    }
+
+    /// Where the token of the last event read starts and ends, e.g. the
+    /// quotes of a string, or the brace of `DictEnd`. Events without a
+    /// token, like the `DictStart` of a message without braces, have an
+    /// empty span.
+    #[inline]
+    pub fn token_span(&self) -> (Mark, Mark) {
+        (self.token_start, self.token_end.unwrap_or(self.mark()))
+    }
+
+    pub fn error(&self, code: ErrorCode) -> Error {
+        Error::SyntaxError(code, self.offset, self.line, self.column)
+    }
+
+    #[inline]
+    fn mark(&self) -> Mark {
+        Mark { offset: self.offset, line: self.line, column: self.column }
+    }
+
+    fn error_at(&self, code: ErrorCode, mark: Mark) -> Error {
+        Error::SyntaxError(code, mark.offset, mark.line, mark.column)
+    }
+
+    // Errors in a token which extends up to the end of the input are
+    // reported at the end, as more input could still make it valid.
+    fn token_error(&mut self, code: ErrorCode, mark: Mark) -> Result<Error> {
+        if try!(self.peek()).is_none() {
+            Ok(self.error(code))
+        } else {
+            Ok(self.error_at(code, mark))
+        }
+    }
+
+    fn peek(&mut self) -> Result<Option<u8>> {
+        if self.ch.is_none() {
+            self.ch = Some(match self.rdr.next() {
+                Some(Err(err)) => return Err(From::from(err)),
+                Some(Ok(ch)) => {
+                    if let Some(max) = self.options.max_input_size {
+                        if self.offset - self.message_start >= max {
+                            return Err(self.error(ErrorCode::LimitExceeded(Limit::InputSize)));
+                        }
+                    }
+                    Some(ch)
+                },
+                None => None,
+            });
+        }
+        Ok(self.ch.unwrap())
+    }
+
+    fn bump(&mut self) {
+        if let Some(Some(ch)) = self.ch.take() {
+            if let Some(ref mut capture) = self.capture {
+                capture.push(ch);
+            }
+            self.offset += 1;
+            if ch == b'\n' {
+                self.line += 1;
+                self.column = 1;
+            } else if ch & 0xC0 != 0x80 {
+                // Columns count characters, not UTF-8 continuation bytes.
+                self.column += 1;
+            }
+        }
+    }
+
+    fn next_char(&mut self) -> Result<Option<u8>> {
+        let ch = try!(self.peek());
+        self.bump();
+        Ok(ch)
+    }
+
+    // Skips whitespace and comments, returns whether anything was skipped.
+    fn skip_whitespace(&mut self) -> Result<bool> {
+        let mut skipped = false;
+        loop {
+            match try!(self.peek()) {
+                Some(ch) if is_whitespace(ch) => self.bump(),
+                Some(b'#') => {
+                    while let Some(ch) = try!(self.next_char()) {
+                        if ch == b'\n' {
+                            break;
+                        }
+                    }
+                },
+                _ => return Ok(skipped),
+            }
+            skipped = true;
+        }
+    }
+
+    // Consumes the separator after an item of a dictionary or a list: it
+    // can be either a comma, whitespace, or the end of the enclosing item.
+    fn parse_separator(&mut self) -> Result<()> {
+        self.separator = false;
+        let skipped = try!(self.skip_whitespace());
+        match try!(self.peek()) {
+            Some(b',') => {
+                self.bump();
+                try!(self.skip_whitespace());
+                Ok(())
+            },
+            Some(b'}') | Some(b']') | None => Ok(()),
+            Some(_) if skipped => Ok(()),
+            Some(_) => Err(self.error(ErrorCode::ExpectedSeparator)),
+        }
+    }
+
+    // Consumes the annotations and the value which follow, checking only
+    // that strings are terminated and delimiters are balanced. Nesting is
+    // tracked with the closing delimiters expected, without recursion.
+    fn skip_raw_bytes(&mut self) -> Result<()> {
+        let mut closers = Vec::new();
+        loop {
+            let ch = match try!(self.peek()) {
+                Some(ch) => ch,
+                None => return Err(self.error(ErrorCode::UnexpectedEof)),
+            };
+            match ch {
+                b'"' => {
+                    try!(self.skip_raw_string());
+                },
+                b'{' | b'[' => {
+                    if self.stack.len() + closers.len() > self.recursion_limit {
+                        return Err(self.error(ErrorCode::RecursionLimitExceeded));
+                    }
+                    closers.push(if ch == b'{' { b'}' } else { b']' });
+                    self.bump();
+                    continue;
+                },
+                b'}' | b']' if closers.last() == Some(&ch) => {
+                    closers.pop();
+                    self.bump();
+                },
+                b'}' | b']' if !closers.is_empty() => {
+                    return Err(self.error(ErrorCode::MismatchedDelimiter));
+                },
+                _ if !closers.is_empty() => {
+                    if ch == b'#' {
+                        try!(self.skip_whitespace());
+                    } else {
+                        self.bump();
+                    }
+                    continue;
+                },
+                b':' => {
+                    self.bump();
+                    while let Some(ch) = try!(self.peek()) {
+                        if !is_key_char(ch) {
+                            break;
+                        }
+                        self.bump();
+                    }
+                    try!(self.skip_whitespace());
+                    continue;
+                },
+                b',' | b'}' | b']' | b'#' => return Err(self.error(ErrorCode::ExpectedValue)),
+                _ => {
+                    while let Some(ch) = try!(self.peek()) {
+                        if is_whitespace(ch) || b",[]{}#".contains(&ch) {
+                            break;
+                        }
+                        self.bump();
+                    }
+                },
+            }
+            if closers.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn skip_raw_string(&mut self) -> Result<()> {
+        let quote = self.mark();
+        self.bump();
+        loop {
+            match try!(self.next_char()) {
+                None => return Err(self.error_at(ErrorCode::UnterminatedString, quote)),
+                Some(b'"') => return Ok(()),
+                Some(b'\\') => {
+                    if try!(self.next_char()).is_none() {
+                        return Err(self.error_at(ErrorCode::UnterminatedString, quote));
+                    }
+                },
+                Some(_) => (),
+            }
+        }
+    }
+
+    fn parse_key(&mut self) -> Result<String> {
+        if try!(self.peek()) == Some(b'"') {
+            if self.json_compat {
+                return self.parse_string();
+            }
+            return Err(self.error(ErrorCode::QuotedKey));
+        }
+        let start = self.mark();
+        let mut key = mem::replace(&mut self.key_buf, Vec::new());
+        key.clear();
+        while let Some(ch) = try!(self.peek()) {
+            if !is_key_char(ch) {
+                break;
+            }
+            if exceeds(key.len() + 1, self.options.max_string_length) {
+                return Err(self.error_at(ErrorCode::LimitExceeded(Limit::StringLength), start));
+            }
+            key.push(ch);
+            self.bump();
+        }
+        if key.is_empty() {
+            return Err(self.error(ErrorCode::ExpectedKey));
+        }
+        String::from_utf8(key).map_err(From::from)
+    }
+
+    fn parse_key_separator(&mut self) -> Result<()> {
+        let skipped = try!(self.skip_whitespace());
+        match try!(self.peek()) {
+            Some(b':') => {
+                self.bump();
+                try!(self.skip_whitespace());
+                Ok(())
+            },
+            // The colon is optional before compound values.
+            Some(b'{') | Some(b'[') => Ok(()),
+            Some(_) if !skipped => Err(self.error(ErrorCode::InvalidKey)),
+            _ => Err(self.error(ErrorCode::ExpectedColon)),
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String> {
+        let value = try!(self.parse_string_bytes());
+        String::from_utf8(value).map_err(From::from)
+    }
+
+    // Unterminated strings are reported at the opening quote, and invalid
+    // escape sequences at their backslash.
+    fn parse_string_bytes(&mut self) -> Result<Vec<u8>> {
+        let quote = self.mark();
+        self.bump();
+        let mut value = Vec::new();
+        // Length of the string, whose bytes are not kept when skipping.
+        let mut len = 0;
+        loop {
+            if exceeds(len, self.options.max_string_length) {
+                return Err(self.error_at(ErrorCode::LimitExceeded(Limit::StringLength), quote));
+            }
+            let escape = self.mark();
+            let ch = match try!(self.next_char()) {
+                None => return Err(self.error_at(ErrorCode::UnterminatedString, quote)),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    match try!(self.next_char()) {
+                        None => return Err(self.error_at(ErrorCode::UnterminatedString, quote)),
+                        Some(b'n') => b'\n',
+                        Some(b'r') => b'\r',
+                        Some(b't') => b'\t',
+                        Some(b'"') => b'"',
+                        Some(b'\\') => b'\\',
+                        Some(ch) if self.json_compat => {
+                            let start = value.len();
+                            match self.parse_json_escape(ch, &mut value) {
+                                Err(Error::SyntaxError(ErrorCode::InvalidEscape, ..)) => {
+                                    return Err(self.error_at(ErrorCode::InvalidEscape, escape));
+                                },
+                                Err(Error::SyntaxError(ErrorCode::UnexpectedEof, ..)) => {
+                                    return Err(self.error_at(ErrorCode::UnterminatedString, quote));
+                                },
+                                result => try!(result),
+                            }
+                            len += value.len() - start;
+                            if self.skipping {
+                                value.clear();
+                            }
+                            continue;
+                        },
+                        Some(ch) => {
+                            let high = hex_value(ch);
+                            let low = match try!(self.next_char()) {
+                                Some(ch) => hex_value(ch),
+                                None => return Err(self.error_at(ErrorCode::UnterminatedString, quote)),
+                            };
+                            match (high, low) {
+                                (Some(high), Some(low)) => high << 4 | low,
+                                _ => return Err(self.error_at(ErrorCode::InvalidEscape, escape)),
+                            }
+                        },
+                    }
+                },
+                Some(ch) => ch,
+            };
+            len += 1;
+            if !self.skipping {
+                value.push(ch);
+            }
+        }
+        Ok(value)
+    }
+
+    fn parse_json_escape(&mut self, ch: u8, value: &mut Vec<u8>) -> Result<()> {
+        let ch = match ch {
+            b'/' => '/',
+            b'b' => '\x08',
+            b'f' => '\x0C',
+            b'u' => {
+                let mut code = try!(self.parse_json_unicode());
+                if code >= 0xD800 && code < 0xDC00 {
+                    match (try!(self.next_char()), try!(self.next_char())) {
+                        (Some(b'\\'), Some(b'u')) => (),
+                        (_, None) => return Err(self.error(ErrorCode::UnexpectedEof)),
+                        _ => return Err(self.error(ErrorCode::InvalidEscape)),
+                    }
+                    let low = try!(self.parse_json_unicode());
+                    if low < 0xDC00 || low > 0xDFFF {
+                        return Err(self.error(ErrorCode::InvalidEscape));
+                    }
+                    code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                }
+                match ::std::char::from_u32(code) {
+                    Some(ch) => ch,
+                    None => return Err(self.error(ErrorCode::InvalidEscape)),
+                }
+            },
+            _ => return Err(self.error(ErrorCode::InvalidEscape)),
+        };
+        let mut buf = String::new();
+        buf.push(ch);
+        value.extend(buf.bytes());
+        Ok(())
+    }
+
+    fn parse_json_unicode(&mut self) -> Result<u32> {
+        let mut code = 0;
+        for _ in 0..4 {
+            match try!(self.next_char()) {
+                Some(ch) => match hex_value(ch) {
+                    Some(digit) => code = code << 4 | digit as u32,
+                    None => return Err(self.error(ErrorCode::InvalidEscape)),
+                },
+                None => return Err(self.error(ErrorCode::UnexpectedEof)),
+            }
+        }
+        Ok(code)
+    }
+
+    fn read_token(&mut self) -> Result<String> {
+        let mut token = String::new();
+        while let Some(ch) = try!(self.peek()) {
+            if !is_number_char(ch) {
+                break;
+            }
+            token.push(ch as char);
+            self.bump();
+        }
+        Ok(token)
+    }
+
+    // In JSON compatibility mode, skips a "null" value after a key.
+    fn skip_json_null(&mut self) -> Result<bool> {
+        if !self.json_compat || try!(self.peek()) != Some(b'n') {
+            return Ok(false);
+        }
+        match &try!(self.read_token())[..] {
+            "null" => Ok(true),
+            _ => Err(self.error(ErrorCode::ExpectedValue)),
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Event> {
+        let start = self.mark();
+        let token = try!(self.read_token());
+
+        match &token[..] {
+            "True" => return Ok(Event::Bool(true)),
+            "False" => return Ok(Event::Bool(false)),
+            "true" if self.json_compat => return Ok(Event::Bool(true)),
+            "false" if self.json_compat => return Ok(Event::Bool(false)),
+            "true" | "false" | "TRUE" | "FALSE" =>
+                return Err(try!(self.token_error(ErrorCode::InvalidBool, start))),
+            "null" | "nil" | "None" =>
+                return Err(try!(self.token_error(ErrorCode::NullValue, start))),
+            _ => (),
+        }
+
+        match parse_number_token(&token) {
+            Some(event) => {
+                self.number = token;
+                Ok(event)
+            },
+            None => Err(try!(self.token_error(ErrorCode::InvalidNumber, start))),
+        }
+    }
+
+    /// Text of the last number read, as written in the input.
+    #[inline]
+    pub fn number_text(&self) -> &str {
+        &self.number
+    }
+
+    // Parses a HEP-1 annotation preceding a value, if there is one.
+    fn parse_annotation(&mut self) -> Result<Option<String>> {
+        if try!(self.peek()) != Some(b':') {
+            return Ok(None);
+        }
+        self.bump();
+        let mut annotation = Vec::new();
+        while let Some(ch) = try!(self.peek()) {
+            if !is_key_char(ch) {
+                break;
+            }
+            annotation.push(ch);
+            self.bump();
+        }
+        if annotation.is_empty() {
+            return Err(self.error(ErrorCode::InvalidAnnotation));
+        }
+        let annotation = try!(String::from_utf8(annotation));
+        if annotation.starts_with(".") {
+            if !INTRINSICS.contains(&&annotation[..]) {
+                return Err(self.error(ErrorCode::InvalidAnnotation));
+            }
+            self.intrinsics.push(annotation.clone());
+        } else if self.scalars.contains(&annotation) {
+            self.raw_scalar = true;
+        }
+        self.token_end = Some(self.mark());
+        try!(self.skip_whitespace());
+        self.annotated = true;
+        Ok(Some(annotation))
+    }
+
+    fn parse_value(&mut self) -> Result<Event> {
+        self.annotated = false;
+        let event = if mem::replace(&mut self.raw_scalar, false) {
+            try!(self.parse_raw_scalar())
+        } else {
+            try!(self.parse_value_event())
+        };
+        let valid = self.intrinsics.iter().all(|intrinsic| has_intrinsic_type(&event, intrinsic));
+        self.intrinsics.clear();
+        if valid {
+            Ok(event)
+        } else {
+            Err(self.error(ErrorCode::AnnotationTypeMismatch))
+        }
+    }
+
+    fn parse_raw_scalar(&mut self) -> Result<Event> {
+        let start = self.mark();
+        let mut token = Vec::new();
+        while let Some(ch) = try!(self.peek()) {
+            match ch {
+                b',' | b'[' | b']' | b'{' | b'}' => break,
+                b'"' if token.is_empty() => return self.parse_value_event(),
+                ch if is_whitespace(ch) => break,
+                _ if exceeds(token.len() + 1, self.options.max_string_length) => {
+                    return Err(self.error_at(ErrorCode::LimitExceeded(Limit::StringLength), start));
+                },
+                ch => token.push(ch),
+            }
+            self.bump();
+        }
+        if token.is_empty() {
+            return self.parse_value_event();
+        }
+        self.separator = true;
+        Ok(Event::String(try!(String::from_utf8(token))))
+    }
+
+    fn parse_value_event(&mut self) -> Result<Event> {
+        match try!(self.peek()) {
+            None => Err(self.error(ErrorCode::UnexpectedEof)),
+            Some(b'"') => {
+                let value = try!(self.parse_string_bytes());
+                self.separator = true;
+                match String::from_utf8(value) {
+                    Ok(value) => Ok(Event::String(value)),
+                    Err(err) => Ok(Event::Bytes(err.into_bytes())),
+                }
+            },
+            Some(b'{') => {
+                try!(self.check_recursion_limit());
+                self.bump();
+                self.stack.push(State::Dict(false));
+                self.counts.push(0);
+                self.token_end = Some(self.mark());
+                try!(self.skip_whitespace());
+                Ok(Event::DictStart)
+            },
+            Some(b'[') => {
+                try!(self.check_recursion_limit());
+                self.bump();
+                self.stack.push(State::List);
+                self.counts.push(0);
+                self.token_end = Some(self.mark());
+                try!(self.skip_whitespace());
+                Ok(Event::ListStart)
+            },
+            Some(ch) if is_number_char(ch) => {
+                let value = try!(self.parse_number());
+                self.separator = true;
+                Ok(value)
+            },
+            Some(_) => Err(self.error(ErrorCode::ExpectedValue)),
+        }
+    }
+
+    // The top-level dictionary is at the bottom of the stack, and does not
+    // count towards the limit.
+    fn check_recursion_limit(&self) -> Result<()> {
+        if self.stack.len() > self.recursion_limit {
+            Err(self.error(ErrorCode::RecursionLimitExceeded))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Counts an item of the innermost compound, checking the limit for it.
+    fn count_item(&mut self, limit: Option<usize>, which: Limit) -> Result<()> {
+        let count = match self.counts.last_mut() {
+            Some(count) => {
+                *count += 1;
+                *count
+            },
+            None => return Ok(()),
+        };
+        if exceeds(count, limit) {
+            Err(self.error(ErrorCode::LimitExceeded(which)))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn end_compound(&mut self, event: Event) -> Result<Option<Event>> {
+        self.bump();
+        self.stack.pop();
+        self.counts.pop();
+        self.separator = true;
+        Ok(Some(event))
+    }
+
+    /// Pulls the next event from the input. Returns `None` once the
+    /// complete message has been consumed.
+    pub fn next_event(&mut self) -> Result<Option<Event>> {
+        if !self.started {
+            self.started = true;
+            try!(self.skip_whitespace());
+            self.token_start = self.mark();
+            self.token_end = None;
+            let braced = try!(self.peek()) == Some(b'{');
+            if braced {
+                self.bump();
+                self.token_end = Some(self.mark());
+                try!(self.skip_whitespace());
+            }
+            self.stack.push(State::Message(braced, false));
+            self.counts.push(0);
+            return Ok(Some(Event::DictStart));
+        }
+
+        loop {
+            let state = match self.stack.last() {
+                Some(state) => *state,
+                None => return Ok(None),
+            };
+
+            if self.separator {
+                try!(self.parse_separator());
+            }
+            self.token_start = self.mark();
+            self.token_end = None;
+
+            return match state {
+                State::Message(braced, true) => {
+                    if let Some(annotation) = try!(self.parse_annotation()) {
+                        return Ok(Some(Event::Annotation(annotation)));
+                    }
+                    *self.stack.last_mut().unwrap() = State::Message(braced, false);
+                    self.parse_value().map(Some)
+                },
+                State::Dict(true) => {
+                    if let Some(annotation) = try!(self.parse_annotation()) {
+                        return Ok(Some(Event::Annotation(annotation)));
+                    }
+                    *self.stack.last_mut().unwrap() = State::Dict(false);
+                    self.parse_value().map(Some)
+                },
+                State::Message(braced, false) => {
+                    match try!(self.peek()) {
+                        None if !braced => {
+                            self.stack.pop();
+                            self.counts.pop();
+                            Ok(Some(Event::DictEnd))
+                        },
+                        None => Err(self.error(ErrorCode::UnexpectedEof)),
+                        Some(b'}') if braced => {
+                            self.bump();
+                            self.token_end = Some(self.mark());
+                            self.stack.pop();
+                            self.counts.pop();
+                            if self.multiple {
+                                return Ok(Some(Event::DictEnd));
+                            }
+                            try!(self.skip_whitespace());
+                            match try!(self.peek()) {
+                                None => Ok(Some(Event::DictEnd)),
+                                Some(_) => Err(self.error(ErrorCode::TrailingCharacters)),
+                            }
+                        },
+                        Some(b'}') | Some(b']') => Err(self.error(ErrorCode::MismatchedDelimiter)),
+                        Some(_) => {
+                            try!(self.count_item(self.options.max_dict_size, Limit::DictSize));
+                            let key = try!(self.parse_key());
+                            self.token_end = Some(self.mark());
+                            try!(self.parse_key_separator());
+                            if try!(self.skip_json_null()) {
+                                self.separator = true;
+                                continue;
+                            }
+                            *self.stack.last_mut().unwrap() = State::Message(braced, true);
+                            Ok(Some(Event::Key(key)))
+                        },
+                    }
+                },
+                State::Dict(false) => {
+                    match try!(self.peek()) {
+                        None => Err(self.error(ErrorCode::UnexpectedEof)),
+                        Some(b'}') => self.end_compound(Event::DictEnd),
+                        Some(b']') => Err(self.error(ErrorCode::MismatchedDelimiter)),
+                        Some(_) => {
+                            try!(self.count_item(self.options.max_dict_size, Limit::DictSize));
+                            let key = try!(self.parse_key());
+                            self.token_end = Some(self.mark());
+                            try!(self.parse_key_separator());
+                            if try!(self.skip_json_null()) {
+                                self.separator = true;
+                                continue;
+                            }
+                            *self.stack.last_mut().unwrap() = State::Dict(true);
+                            Ok(Some(Event::Key(key)))
+                        },
+                    }
+                },
+                State::List => {
+                    if let Some(annotation) = try!(self.parse_annotation()) {
+                        return Ok(Some(Event::Annotation(annotation)));
+                    }
+                    match try!(self.peek()) {
+                        Some(b']') if !self.annotated => self.end_compound(Event::ListEnd),
+                        Some(b'}') if !self.annotated => {
+                            Err(self.error(ErrorCode::MismatchedDelimiter))
+                        },
+                        _ => {
+                            try!(self.count_item(self.options.max_list_length, Limit::ListLength));
+                            self.parse_value().map(Some)
+                        },
+                    }
+                },
+            };
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use error::{Error, ErrorCode};
+
+    fn events(input: &str) -> Vec<Event> {
+        let mut parser = Parser::new(input.as_bytes().bytes());
+        let mut result = Vec::new();
+        while let Some(event) = parser.next_event().unwrap() {
+            result.push(event);
+        }
+        result
+    }
+
+    #[test]
+    fn test_multiple_messages() {
+        let mut parser = Parser::new("{a: 1} {}\n".as_bytes().bytes());
+        let mut messages = Vec::new();
+        while parser.next_message().unwrap() {
+            let mut events = Vec::new();
+            while let Some(event) = parser.next_event().unwrap() {
+                events.push(event);
+            }
+            messages.push(events);
+        }
+        assert_eq!(vec![vec![Event::DictStart, Event::Key("a".to_string()), Event::U64(1),
+                             Event::DictEnd],
+                        vec![Event::DictStart, Event::DictEnd]],
+                   messages);
+    }
+
+    #[test]
+    fn test_empty_message() {
+        assert_eq!(vec![Event::DictStart, Event::DictEnd], events(""));
+        assert_eq!(vec![Event::DictStart, Event::DictEnd], events(" {} "));
+    }
+
+    #[test]
+    fn test_scalars() {
+        assert_eq!(vec![Event::DictStart,
+                        Event::Key("a".to_string()), Event::U64(42),
+                        Event::Key("b".to_string()), Event::I64(-0x10),
+                        Event::Key("c".to_string()), Event::U64(0o644),
+                        Event::Key("d".to_string()), Event::F64(1.5),
+                        Event::Key("e".to_string()), Event::Bool(true),
+                        Event::Key("f".to_string()), Event::String("x\ty\0".to_string()),
+                        Event::DictEnd],
+                   events("a: 42, b:-0x10\nc: 0644 # Comment\nd:1.5 e:True f:\"x\\ty\\00\""));
+    }
+
+    #[test]
+    fn test_compound() {
+        assert_eq!(vec![Event::DictStart,
+                        Event::Key("l".to_string()), Event::ListStart,
+                        Event::U64(1), Event::DictStart,
+                        Event::Key("k".to_string()), Event::Bool(false),
+                        Event::DictEnd, Event::ListEnd,
+                        Event::DictEnd],
+                   events("{l [1 {k: False}]}"));
+    }
+
+    #[test]
+    fn test_integer_range() {
+        assert_eq!(vec![Event::DictStart,
+                        Event::Key("a".to_string()), Event::U64(u64::max_value()),
+                        Event::Key("b".to_string()), Event::I64(i64::min_value()),
+                        Event::Key("c".to_string()), Event::U128(u64::max_value() as u128 + 1),
+                        Event::Key("d".to_string()), Event::I128(i128::min_value()),
+                        Event::Key("e".to_string()), Event::U128(u128::max_value()),
+                        Event::DictEnd],
+                   events("a: 18446744073709551615 b: -9223372036854775808 \
+                           c: 0x10000000000000000 d: -0x80000000000000000000000000000000 \
+                           e: 340282366920938463463374607431768211455"));
+        assert_eq!(ErrorCode::InvalidNumber, first_error("a: 340282366920938463463374607431768211456"));
+        assert_eq!(ErrorCode::InvalidNumber, first_error("a: -0x80000000000000000000000000000001"));
+        assert_eq!(ErrorCode::InvalidNumber, first_error("a: ++5"));
+        assert_eq!(ErrorCode::InvalidNumber, first_error("a: -+5"));
+        assert_eq!(ErrorCode::InvalidNumber, first_error("a: 0x+5"));
+        assert_eq!(ErrorCode::InvalidNumber, first_error("a: -0x-5"));
+        assert_eq!(ErrorCode::InvalidNumber, first_error("a: 0+5"));
+    }
+
+    #[test]
+    fn test_recycle_key() {
+        let mut parser = Parser::new("a: 1 bc: 2".as_bytes().bytes());
+        assert_eq!(Event::DictStart, parser.next_event().unwrap().unwrap());
+        assert_eq!(Event::Key("a".to_string()), parser.next_event().unwrap().unwrap());
+        parser.recycle_key(String::with_capacity(64));
+        assert_eq!(Event::U64(1), parser.next_event().unwrap().unwrap());
+        match parser.next_event().unwrap() {
+            Some(Event::Key(key)) => {
+                assert_eq!("bc", key);
+                assert_eq!(64, key.capacity());
+            },
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_annotations() {
+        assert_eq!(vec![Event::DictStart,
+                        Event::Key("a".to_string()),
+                        Event::Annotation("x".to_string()),
+                        Event::Annotation("y".to_string()), Event::U64(1),
+                        Event::Key("b".to_string()),
+                        Event::Annotation("z".to_string()), Event::ListStart,
+                        Event::Annotation("z".to_string()), Event::Bool(true),
+                        Event::ListEnd,
+                        Event::DictEnd],
+                   events("a: :x :y 1 b::z [:z True]"));
+
+        assert_eq!(ErrorCode::ExpectedValue, first_error("a: [:z]"));
+        assert_eq!(ErrorCode::InvalidAnnotation, first_error("a: : 1"));
+    }
+
+    #[test]
+    fn test_intrinsic_annotations() {
+        assert_eq!(14, events("a: :.int 1 b: :.int -1 c: :.float 1.0 d: :.bool True").len());
+        assert_eq!(17, events("a: :.string \"\" b: :.list [] c: :.dict {} d: :x:.int 0x1").len());
+        assert_eq!(ErrorCode::AnnotationTypeMismatch, first_error("a: :.int 1.0"));
+        assert_eq!(ErrorCode::AnnotationTypeMismatch, first_error("a: :.float 1"));
+        assert_eq!(ErrorCode::AnnotationTypeMismatch, first_error("a: :.string [1]"));
+        assert_eq!(ErrorCode::AnnotationTypeMismatch, first_error("a: :.dict []"));
+        assert_eq!(ErrorCode::AnnotationTypeMismatch, first_error("a: [:.int:.bool True]"));
+        assert_eq!(ErrorCode::InvalidAnnotation, first_error("a: :.date \"2015\""));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut parser = Parser::new("a 1".as_bytes().bytes());
+        assert_eq!(Some(Event::DictStart), parser.next_event().unwrap());
+        assert!(parser.next_event().is_err());
+
+        let mut parser = Parser::new("{a: 1".as_bytes().bytes());
+        while let Ok(Some(_)) = parser.next_event() {}
+        assert!(parser.next_event().is_err());
+    }
+
+    fn error_position(input: &str) -> (ErrorCode, usize, usize) {
+        let mut parser = Parser::new(input.as_bytes().bytes());
+        loop {
+            match parser.next_event() {
+                Ok(Some(_)) => (),
+                Ok(None) => panic!("no error in {:?}", input),
+                Err(Error::SyntaxError(code, _, line, column)) => return (code, line, column),
+                Err(err) => panic!("unexpected error {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_positions() {
+        assert_eq!((ErrorCode::InvalidNumber, 2, 4), error_position("a: 1\nb: 1x2 c: 3"));
+        assert_eq!((ErrorCode::InvalidBool, 1, 5), error_position("{a: true}"));
+        assert_eq!((ErrorCode::UnterminatedString, 2, 6), error_position("a: 1\nb: [ \"foo\n"));
+        assert_eq!((ErrorCode::InvalidEscape, 1, 8), error_position("a: \"ñé \\zz\""));
+        assert_eq!((ErrorCode::ExpectedValue, 1, 9), error_position("k: [\"ß\" @]"));
+        // Truncated tokens are reported at the end of the input.
+        assert_eq!((ErrorCode::InvalidNumber, 1, 7), error_position("{a: 0x"));
+    }
+
+    fn first_error(input: &str) -> ErrorCode {
+        let mut parser = Parser::new(input.as_bytes().bytes());
+        loop {
+            match parser.next_event() {
+                Ok(Some(_)) => (),
+                Ok(None) => panic!("no error in {:?}", input),
+                Err(Error::SyntaxError(code, _, _, _)) => return code,
+                Err(err) => panic!("unexpected error {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn test_delimiters() {
+        assert_eq!((ErrorCode::MismatchedDelimiter, 1, 7), error_position("a: [1 }"));
+        assert_eq!((ErrorCode::MismatchedDelimiter, 1, 9), error_position("a: {b: 1]"));
+        assert_eq!((ErrorCode::MismatchedDelimiter, 1, 6), error_position("{a: 1]"));
+        assert_eq!((ErrorCode::MismatchedDelimiter, 1, 1), error_position("}"));
+        assert_eq!((ErrorCode::UnexpectedEof, 1, 7), error_position("a: [1 "));
+    }
+
+    #[test]
+    fn test_recursion_limit() {
+        let mut parser = Parser::new("a: [[[1]]] b: {c: {}}".as_bytes().bytes());
+        parser.set_recursion_limit(3);
+        while let Some(_) = parser.next_event().unwrap() {}
+
+        let mut parser = Parser::new("a: [[[[1]]]]".as_bytes().bytes());
+        parser.set_recursion_limit(3);
+        loop {
+            match parser.next_event() {
+                Ok(Some(_)) => (),
+                Err(Error::SyntaxError(ErrorCode::RecursionLimitExceeded, 6, 1, 7)) => break,
+                result => panic!("unexpected result {:?}", result),
+            }
+        }
+        let input = format!("a: {}{}", "[".repeat(129), "]".repeat(129));
+        assert_eq!(ErrorCode::RecursionLimitExceeded, first_error(&input));
+    }
+
+    #[test]
+    fn test_json_mistakes() {
+        assert_eq!(ErrorCode::QuotedKey, first_error("{\"a\": 1}"));
+        assert_eq!(ErrorCode::InvalidBool, first_error("a: true"));
+        assert_eq!(ErrorCode::InvalidBool, first_error("a: [false]"));
+        assert_eq!(ErrorCode::NullValue, first_error("a: null"));
+        assert!(ErrorCode::InvalidBool.suggestion().unwrap().contains("True/False"));
+        assert_eq!(None, ErrorCode::InvalidNumber.suggestion());
+    }
+}
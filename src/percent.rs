@@ -0,0 +1,117 @@
+//
+// percent.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+//! Percent-encoding of documents, to pass small ones in URLs and HTTP
+//! headers, where braces, quotes, and spaces are not allowed.
+//!
+//! Documents are written in compact form, and every byte other than the
+//! unreserved characters of RFC 3986 (letters, digits, `-`, `.`, `_`, and
+//! `~`) is written as `%XX`, so the output is a single line which can be
+//! used as is in the path or the query of a URL:
+//!
+//! ```text
+//! let query = format!("?filter={}", to_percent_string(&filter)?);
+//! ```
+
+#[cfg(feature = "ser")]
+use serde::ser;
+#[cfg(feature = "de")]
+use serde::de;
+use super::error::{Error, ErrorCode, Result};
+use super::parser::hex_value;
+#[cfg(feature = "de")]
+use super::de::from_slice;
+#[cfg(feature = "ser")]
+use super::ser::to_vec;
+
+
+const HEX_DIGITS: &'static [u8; 16] = b"0123456789ABCDEF";
+
+
+/// Percent-encodes bytes, escaping all but unreserved characters.
+pub fn encode(bytes: &[u8]) -> String {
+    let mut output = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => output.push(byte as char),
+            _ => {
+                output.push('%');
+                output.push(HEX_DIGITS[(byte >> 4) as usize] as char);
+                output.push(HEX_DIGITS[(byte & 0xF) as usize] as char);
+            },
+        }
+    }
+    output
+}
+
+
+/// Decodes percent-encoded text. Escapes may use either case, and other
+/// characters are kept as they are, including `+`. Malformed escapes fail
+/// with `ErrorCode::InvalidEscape`, at their position in the text.
+pub fn decode(text: &str) -> Result<Vec<u8>> {
+    let bytes = text.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if bytes[pos] == b'%' {
+            let digits = (bytes.get(pos + 1).and_then(|&ch| hex_value(ch)),
+                          bytes.get(pos + 2).and_then(|&ch| hex_value(ch)));
+            match digits {
+                (Some(high), Some(low)) => output.push(high << 4 | low),
+                _ => return Err(Error::SyntaxError(ErrorCode::InvalidEscape, pos, 1, pos + 1)),
+            }
+            pos += 3;
+        } else {
+            output.push(bytes[pos]);
+            pos += 1;
+        }
+    }
+    Ok(output)
+}
+
+
+/// Serializes a value as a percent-encoded compact document.
+#[cfg(feature = "ser")]
+pub fn to_percent_string<T: ser::Serialize>(value: &T) -> Result<String> {
+    Ok(encode(&try!(to_vec(value))))
+}
+
+
+/// Deserializes a value from a percent-encoded document.
+#[cfg(feature = "de")]
+pub fn from_percent_str<T: de::DeserializeOwned>(text: &str) -> Result<T> {
+    from_slice(&try!(decode(text)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!("%7Ba%3A1%2Cb-c.d_e~%3A%22x%20y%C3%A9%22%7D", encode("{a:1,b-c.d_e~:\"x yé\"}".as_bytes()));
+        assert_eq!(b"{a:1}".to_vec(), decode("%7ba%3A1%7D").unwrap());
+        assert_eq!(b"a+b".to_vec(), decode("a+b").unwrap());
+        for input in &["%", "a%4", "%4G", "%%41"] {
+            assert!(decode(input).is_err(), "{:?}", input);
+        }
+        match decode("ab%zz") {
+            Err(Error::SyntaxError(ErrorCode::InvalidEscape, 2, 1, 3)) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "de")]
+    fn test_roundtrip() {
+        use value::Value;
+        let value: Value = ::de::from_str("name: \"a b\" ports [80 443] tls {on: True} t: :x 0.5").unwrap();
+        let text = to_percent_string(&value).unwrap();
+        assert!(text.bytes().all(|ch| ch.is_ascii_alphanumeric() || b"%-._~".contains(&ch)), "{}", text);
+        assert_eq!(value, from_percent_str::<Value>(&text).unwrap());
+    }
+}
@@ -0,0 +1,176 @@
+//
+// pull.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+//! Reading HiPack as a stream of events, independently of Serde.
+//!
+//! A `PullParser` yields the structure of a document as it is read, along
+//! with where each event is in the input, without building any values.
+//! This suits tools like syntax highlighters, linters and converters to
+//! other formats:
+//!
+//! ```
+//! use serde_hipack::pull::{Event, PullParser};
+//!
+//! let text = "name: \"web\" ports [80 443]";
+//! for item in PullParser::from_str(text) {
+//!     let (event, span) = item.unwrap();
+//!     if let Event::Key(key) = event {
+//!         println!("Key {} at line {}", key, span.start.line);
+//!     }
+//! }
+//! ```
+
+use std::io;
+use std::ops::Range;
+use super::de::Position;
+use super::error::Result;
+use super::parser::{Mark, Parser};
+pub use super::parser::{Event, ParserOptions};
+
+
+/// Where the token of an event is in the input.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: Position,
+    /// Position right after the last character of the token.
+    pub end: Position,
+}
+
+impl Span {
+    /// Byte range of the token in the input.
+    #[inline]
+    pub fn range(&self) -> Range<usize> {
+        self.start.offset..self.end.offset
+    }
+}
+
+
+fn position(mark: Mark) -> Position {
+    Position { offset: mark.offset, line: mark.line, column: mark.column }
+}
+
+
+/// Iterator over the events of a message, each one along with its `Span`.
+/// Iteration stops after the first error.
+///
+/// Comments and whitespace produce no events, and entries of dictionaries
+/// are given as a `Key` event followed by the events of the value.
+pub struct PullParser<Iter: Iterator<Item=io::Result<u8>>> {
+    parser: Parser<Iter>,
+    failed: bool,
+}
+
+
+impl<'a> PullParser<io::Bytes<&'a [u8]>> {
+    #[inline]
+    pub fn from_slice(v: &'a [u8]) -> Self {
+        PullParser::new(io::Read::bytes(v))
+    }
+
+    #[inline]
+    pub fn from_str(s: &'a str) -> Self {
+        PullParser::from_slice(s.as_bytes())
+    }
+}
+
+
+impl<Iter> PullParser<Iter> where Iter: Iterator<Item=io::Result<u8>> {
+    #[inline]
+    pub fn new(rdr: Iter) -> Self {
+        let mut parser = Parser::new(rdr);
+        // The parser keeps track of nesting with an explicit stack, and
+        // nothing is built from the events, so nesting needs no limit.
+        parser.set_recursion_limit(usize::max_value());
+        PullParser { parser: parser, failed: false }
+    }
+
+    /// Accepts a subset of JSON syntax, see `Deserializer::json_compat()`.
+    #[inline]
+    pub fn json_compat(mut self, enabled: bool) -> Self {
+        self.parser.set_json_compat(enabled);
+        self
+    }
+
+    /// Limits the size of the input, see `ParserOptions`.
+    #[inline]
+    pub fn options(mut self, options: ParserOptions) -> Self {
+        self.parser.set_options(options);
+        self
+    }
+
+    fn next_event(&mut self) -> Result<Option<(Event, Span)>> {
+        Ok(try!(self.parser.next_event()).map(|event| {
+            let (start, end) = self.parser.token_span();
+            (event, Span { start: position(start), end: position(end) })
+        }))
+    }
+}
+
+
+impl<Iter> Iterator for PullParser<Iter> where Iter: Iterator<Item=io::Result<u8>> {
+    type Item = Result<(Event, Span)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.next_event() {
+            Ok(item) => item.map(Ok),
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, PullParser};
+
+    fn tokens(text: &str) -> Vec<(Event, &str)> {
+        PullParser::from_str(text).map(|item| {
+            let (event, span) = item.unwrap();
+            (event, &text[span.range()])
+        }).collect()
+    }
+
+    #[test]
+    fn test_spans() {
+        let text = "# Comment\nname: \"web\", ports [80 :hex 0x1BB]\nnested {a: True}\n";
+        assert_eq!(vec![(Event::DictStart, ""),
+                        (Event::Key("name".to_string()), "name"),
+                        (Event::String("web".to_string()), "\"web\""),
+                        (Event::Key("ports".to_string()), "ports"),
+                        (Event::ListStart, "["),
+                        (Event::U64(80), "80"),
+                        (Event::Annotation("hex".to_string()), ":hex"),
+                        (Event::U64(443), "0x1BB"),
+                        (Event::ListEnd, "]"),
+                        (Event::Key("nested".to_string()), "nested"),
+                        (Event::DictStart, "{"),
+                        (Event::Key("a".to_string()), "a"),
+                        (Event::Bool(true), "True"),
+                        (Event::DictEnd, "}"),
+                        (Event::DictEnd, "")],
+                   tokens(text));
+
+        assert_eq!(vec!["{", "a", "1", "}"],
+                   tokens(" { a: 1 } ").into_iter().map(|(_, token)| token).collect::<Vec<_>>());
+
+        let (_, span) = PullParser::from_str("a: 1\nbb: 2").nth(3).unwrap().unwrap();
+        assert_eq!((2, 1, 2, 3), (span.start.line, span.start.column, span.end.line, span.end.column));
+    }
+
+    #[test]
+    fn test_error() {
+        let mut parser = PullParser::from_str("a: 1 b: ]");
+        assert_eq!(Event::U64(1), parser.nth(2).unwrap().unwrap().0);
+        assert!(parser.nth(1).unwrap().is_err());
+        assert!(parser.next().is_none());
+    }
+}
@@ -0,0 +1,240 @@
+//
+// rpc.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+//! Request/response correlation over framed messages.
+//!
+//! Requests are tagged with a `message-id` header, and responses carry the
+//! identifier of the request they answer in a `correlation-id` header. The
+//! `Correlator` keeps track of pending requests and can be used with any
+//! transport, while `Client` and `Server` implement a simple synchronous
+//! protocol on top of `MessageReader` and `MessageWriter`.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use serde::{de, ser};
+use super::error::{Result, Error, ErrorCode};
+use super::framing::{Headers, MessageReader, MessageWriter, MESSAGE_ID, CORRELATION_ID};
+use super::value::Value;
+
+
+/// Matches responses to pending requests. Each pending request has some
+/// associated state of type `T`, e.g. a channel to send the response to.
+pub struct Correlator<T> {
+    next_id: u64,
+    pending: HashMap<u64, T>,
+}
+
+
+impl<T> Correlator<T> {
+    #[inline]
+    pub fn new() -> Self {
+        Correlator { next_id: 1, pending: HashMap::new() }
+    }
+
+    /// Tags the headers of a request with a new message identifier, and
+    /// keeps `state` until the response to the request arrives. Returns
+    /// the identifier.
+    pub fn tag_request(&mut self, headers: &mut Headers, state: T) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        headers.insert(MESSAGE_ID.to_string(), Value::U64(id));
+        self.pending.insert(id, state);
+        id
+    }
+
+    /// Returns the state of the pending request answered by a response
+    /// with the given headers, which is no longer pending afterwards.
+    pub fn match_response(&mut self, headers: &Headers) -> Option<T> {
+        match headers.get(CORRELATION_ID) {
+            Some(&Value::U64(id)) => self.pending.remove(&id),
+            _ => None,
+        }
+    }
+
+    /// Stops waiting for the response to a request, e.g. when sending it
+    /// failed, and returns its state if it was still pending.
+    #[inline]
+    pub fn cancel(&mut self, id: u64) -> Option<T> {
+        self.pending.remove(&id)
+    }
+
+    /// Number of requests waiting for a response.
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+
+impl<T> Default for Correlator<T> {
+    #[inline]
+    fn default() -> Self {
+        Correlator::new()
+    }
+}
+
+
+/// Returns the headers for the response to a request with the given
+/// headers, which refer to the request's message identifier.
+pub fn response_headers(request: &Headers) -> Headers {
+    let mut headers = Headers::new();
+    if let Some(id) = request.get(MESSAGE_ID) {
+        headers.insert(CORRELATION_ID.to_string(), id.clone());
+    }
+    headers
+}
+
+
+/// Sends requests and waits for their responses, one at a time.
+pub struct Client<R: Read, W: Write> {
+    reader: MessageReader<R>,
+    writer: MessageWriter<W>,
+    correlator: Correlator<()>,
+}
+
+
+impl<R: Read, W: Write> Client<R, W> {
+    #[inline]
+    pub fn new(reader: R, writer: W) -> Self {
+        Client {
+            reader: MessageReader::new(reader),
+            writer: MessageWriter::new(writer),
+            correlator: Correlator::new(),
+        }
+    }
+
+    /// Sends a request and reads its response. Fails with
+    /// `ErrorCode::UnmatchedResponse` if the next message received is
+    /// not the response to the request.
+    pub fn call<Req, Resp>(&mut self, request: &Req) -> Result<Resp>
+        where Req: ser::Serialize, Resp: de::DeserializeOwned
+    {
+        let mut headers = Headers::new();
+        let id = self.correlator.tag_request(&mut headers, ());
+        let result = self.exchange(&headers, request);
+        // Responses arrive in order, so after a failure none is coming.
+        if result.is_err() {
+            self.correlator.cancel(id);
+        }
+        result
+    }
+
+    fn exchange<Req, Resp>(&mut self, headers: &Headers, request: &Req) -> Result<Resp>
+        where Req: ser::Serialize, Resp: de::DeserializeOwned
+    {
+        try!(self.writer.write_message_with_headers(headers, request));
+        let headers = match try!(self.reader.read_headers()) {
+            Some(headers) => headers,
+            None => return Err(Error::SyntaxError(ErrorCode::UnexpectedEof, 0, 0, 0)),
+        };
+        if self.correlator.match_response(&headers).is_none() {
+            return Err(Error::SyntaxError(ErrorCode::UnmatchedResponse, 0, 0, 0));
+        }
+        match try!(self.reader.read_message()) {
+            Some(response) => Ok(response),
+            None => Err(Error::SyntaxError(ErrorCode::UnexpectedEof, 0, 0, 0)),
+        }
+    }
+}
+
+
+/// Reads requests and replies to them.
+pub struct Server<R: Read, W: Write> {
+    reader: MessageReader<R>,
+    writer: MessageWriter<W>,
+}
+
+
+impl<R: Read, W: Write> Server<R, W> {
+    #[inline]
+    pub fn new(reader: R, writer: W) -> Self {
+        Server { reader: MessageReader::new(reader), writer: MessageWriter::new(writer) }
+    }
+
+    /// Reads a request and writes the response returned by the handler.
+    /// Returns `false` once the end of the input is reached in between
+    /// requests.
+    pub fn serve<Req, Resp, F>(&mut self, mut handler: F) -> Result<bool>
+        where Req: de::DeserializeOwned, Resp: ser::Serialize, F: FnMut(Req) -> Resp
+    {
+        let headers = match try!(self.reader.read_headers()) {
+            Some(headers) => headers,
+            None => return Ok(false),
+        };
+        let request = match try!(self.reader.read_message()) {
+            Some(request) => request,
+            None => return Err(Error::SyntaxError(ErrorCode::UnexpectedEof, 0, 0, 0)),
+        };
+        let response = handler(request);
+        try!(self.writer.write_message_with_headers(&response_headers(&headers), &response));
+        Ok(true)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use framing::{Headers, MESSAGE_ID};
+    use value::Value;
+
+    type Message = BTreeMap<String, u32>;
+
+    fn message(value: u32) -> Message {
+        let mut message = Message::new();
+        message.insert("n".to_string(), value);
+        message
+    }
+
+    #[test]
+    fn test_correlator() {
+        let mut correlator = Correlator::new();
+        let mut first = Headers::new();
+        let mut second = Headers::new();
+        assert_eq!(1, correlator.tag_request(&mut first, "first"));
+        assert_eq!(2, correlator.tag_request(&mut second, "second"));
+        assert_eq!(Some(&Value::U64(2)), second.get(MESSAGE_ID));
+        assert_eq!(2, correlator.pending());
+
+        assert_eq!(Some("second"), correlator.match_response(&response_headers(&second)));
+        assert_eq!(None, correlator.match_response(&response_headers(&second)));
+        assert_eq!(None, correlator.match_response(&Headers::new()));
+        assert_eq!(Some("first"), correlator.match_response(&response_headers(&first)));
+        assert_eq!(0, correlator.pending());
+        let id = correlator.tag_request(&mut Headers::new(), "third");
+        assert_eq!(Some("third"), correlator.cancel(id));
+        assert_eq!(None, correlator.cancel(id));
+    }
+
+    #[test]
+    fn test_client_server() {
+        let mut requests = Vec::new();
+        {
+            let mut client = Client::new(&b":headers {correlation-id: 1} {n: 2}"[..], &mut requests);
+            assert_eq!(message(2), client.call::<_, Message>(&message(1)).unwrap());
+        }
+        assert_eq!(":headers {message-id:1} {n:1}\n", String::from_utf8(requests.clone()).unwrap());
+
+        let mut responses = Vec::new();
+        {
+            let mut server = Server::new(&requests[..], &mut responses);
+            assert!(server.serve(|request: Message| message(request["n"] * 10)).unwrap());
+            assert!(!server.serve(|request: Message| request).unwrap());
+        }
+        assert_eq!(":headers {correlation-id:1} {n:10}\n", String::from_utf8(responses).unwrap());
+
+        let mut client = Client::new(&b":headers {correlation-id: 5} {n: 2}"[..], Vec::new());
+        assert!(client.call::<_, Message>(&message(1)).is_err());
+        assert_eq!(0, client.correlator.pending());
+        let mut client = Client::new(&b":headers {correlation-id: 1} {n: \"x\"}"[..], Vec::new());
+        assert!(client.call::<_, Message>(&message(1)).is_err());
+        assert_eq!(0, client.correlator.pending());
+        let mut client = Client::new(&b""[..], Vec::new());
+        assert!(client.call::<_, Message>(&message(1)).is_err());
+        assert_eq!(0, client.correlator.pending());
+    }
+}
@@ -0,0 +1,275 @@
+//
+// schema.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+//! Structural schemas inferred from example documents.
+//!
+//! `Schema::infer_all()` walks a set of samples and records the type of
+//! each value, which keys are missing from some of the samples, and the
+//! range of integers. The resulting schema can check other documents with
+//! `Schema::matches()`, validate entries as a `DocumentBuilder` adds them,
+//! or become Rust type definitions with `codegen::generate_types()`:
+//!
+//! ```
+//! # use serde_hipack::Schema;
+//! # use serde_hipack::value::DictBuilder;
+//! let samples = vec![
+//!     DictBuilder::new().insert("name", "web").insert("port", 80).build(),
+//!     DictBuilder::new().insert("name", "db").build(),
+//! ];
+//! let schema = Schema::infer_all(&samples);
+//! assert!(schema.matches(&DictBuilder::new().insert("name", "cache").insert("port", 6379).build()));
+//! assert!(!schema.matches(&DictBuilder::new().insert("port", 6379).build()));
+//! ```
+
+use std::collections::BTreeMap;
+use super::value::Value;
+
+
+/// Structure of a HiPack document, as inferred from example values.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Schema {
+    /// Nothing is known about the value, e.g. the items of a list which
+    /// was always empty. Matches any value.
+    Any,
+    Bool,
+    /// Integer, with the range of the values seen, which tells the types
+    /// able to hold them: `min` is the smallest negative one, and `max`
+    /// the largest positive one, or zero if there were none.
+    Integer { min: i128, max: u128 },
+    /// Floating point number. Integers are accepted as well.
+    Float,
+    String,
+    List(Box<Schema>),
+    Dict(BTreeMap<String, Field>),
+    /// Values seen with different types, at most one of each kind.
+    Union(Vec<Schema>),
+}
+
+
+/// Entry of a `Schema::Dict`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    pub schema: Schema,
+    /// Whether the key was missing from some of the samples.
+    pub optional: bool,
+}
+
+
+impl Schema {
+    /// Infers the schema of a single example value. Annotations are not
+    /// part of the schema, and are ignored.
+    pub fn infer(value: &Value) -> Schema {
+        match *value {
+            Value::Bool(_) => Schema::Bool,
+            Value::I64(v) => Schema::from_i128(v as i128),
+            Value::U64(v) => Schema::Integer { min: 0, max: v as u128 },
+            Value::I128(v) => Schema::from_i128(v),
+            Value::U128(v) => Schema::Integer { min: 0, max: v },
+            Value::F64(_) => Schema::Float,
+            Value::Number(ref number) => Schema::infer(&number.value()),
+            Value::String(_) => Schema::String,
+            Value::List(ref items) => {
+                Schema::List(Box::new(items.iter().fold(Schema::Any, |schema, item| {
+                    schema.merge(Schema::infer(item))
+                })))
+            },
+            Value::Dict(ref entries) => {
+                Schema::Dict(entries.iter().map(|(key, value)| {
+                    (key.clone(), Field { schema: Schema::infer(value), optional: false })
+                }).collect())
+            },
+            Value::Annotated(_, ref value) => Schema::infer(value),
+        }
+    }
+
+    /// Infers a schema which matches all the given samples. Keys missing
+    /// from some of the dictionaries are marked as optional.
+    pub fn infer_all<'a, I>(samples: I) -> Schema
+        where I: IntoIterator<Item=&'a Value>
+    {
+        samples.into_iter().fold(Schema::Any, |schema, sample| {
+            schema.merge(Schema::infer(sample))
+        })
+    }
+
+    /// Combines two schemas into one which matches values of both.
+    pub fn merge(self, other: Schema) -> Schema {
+        match (self, other) {
+            (Schema::Any, other) => other,
+            (schema, Schema::Any) => schema,
+            (Schema::Union(mut variants), other) => {
+                merge_variants(&mut variants, other);
+                Schema::Union(variants)
+            },
+            (schema, Schema::Union(variants)) => {
+                let mut merged = vec![schema];
+                for variant in variants {
+                    merge_variants(&mut merged, variant);
+                }
+                Schema::Union(merged)
+            },
+            (Schema::Integer { min: a, max: b }, Schema::Integer { min: c, max: d }) => {
+                Schema::Integer { min: a.min(c), max: b.max(d) }
+            },
+            (Schema::Integer { .. }, Schema::Float) | (Schema::Float, Schema::Integer { .. }) => Schema::Float,
+            (Schema::List(a), Schema::List(b)) => Schema::List(Box::new(a.merge(*b))),
+            (Schema::Dict(mut a), Schema::Dict(mut b)) => {
+                for (key, field) in a.iter_mut() {
+                    match b.remove(key) {
+                        Some(other) => {
+                            let schema = ::std::mem::replace(&mut field.schema, Schema::Any);
+                            field.schema = schema.merge(other.schema);
+                            field.optional = field.optional || other.optional;
+                        },
+                        None => field.optional = true,
+                    }
+                }
+                for (key, mut field) in b {
+                    field.optional = true;
+                    a.insert(key, field);
+                }
+                Schema::Dict(a)
+            },
+            (a, b) => {
+                if a.same_kind(&b) {
+                    a
+                } else {
+                    Schema::Union(vec![a, b])
+                }
+            },
+        }
+    }
+
+    /// Checks whether a value conforms to the schema. Dictionaries must
+    /// have all the keys which are not optional, and no others.
+    pub fn matches(&self, value: &Value) -> bool {
+        match (self, value) {
+            (_, &Value::Annotated(_, ref value)) => self.matches(value),
+            (_, &Value::Number(ref number)) => self.matches(&number.value()),
+            (&Schema::Any, _) => true,
+            (&Schema::Bool, &Value::Bool(_)) => true,
+            // Ranges come from the samples, so other integers match too.
+            (&Schema::Integer { .. }, &Value::I64(_)) |
+            (&Schema::Integer { .. }, &Value::U64(_)) |
+            (&Schema::Integer { .. }, &Value::I128(_)) |
+            (&Schema::Integer { .. }, &Value::U128(_)) => true,
+            (&Schema::Float, &Value::F64(_)) |
+            (&Schema::Float, &Value::I64(_)) |
+            (&Schema::Float, &Value::U64(_)) |
+            (&Schema::Float, &Value::I128(_)) |
+            (&Schema::Float, &Value::U128(_)) => true,
+            (&Schema::String, &Value::String(_)) => true,
+            (&Schema::List(ref schema), &Value::List(ref items)) => {
+                items.iter().all(|item| schema.matches(item))
+            },
+            (&Schema::Dict(ref fields), &Value::Dict(ref entries)) => {
+                entries.iter().all(|(key, value)| match fields.get(key) {
+                    Some(field) => field.schema.matches(value),
+                    None => false,
+                }) && fields.iter().all(|(key, field)| {
+                    field.optional || entries.contains_key(key)
+                })
+            },
+            (&Schema::Union(ref variants), value) => {
+                variants.iter().any(|schema| schema.matches(value))
+            },
+            _ => false,
+        }
+    }
+
+    // Numbers are of the same kind, so they end up in a single variant.
+    fn same_kind(&self, other: &Schema) -> bool {
+        match (self, other) {
+            (&Schema::Integer { .. }, &Schema::Integer { .. }) => true,
+            (&Schema::Integer { .. }, &Schema::Float) | (&Schema::Float, &Schema::Integer { .. }) => true,
+            (&Schema::List(_), &Schema::List(_)) => true,
+            (&Schema::Dict(_), &Schema::Dict(_)) => true,
+            (a, b) => a == b,
+        }
+    }
+
+    fn from_i128(v: i128) -> Schema {
+        if v < 0 {
+            Schema::Integer { min: v, max: 0 }
+        } else {
+            Schema::Integer { min: 0, max: v as u128 }
+        }
+    }
+}
+
+
+fn merge_variants(variants: &mut Vec<Schema>, schema: Schema) {
+    match variants.iter().position(|variant| variant.same_kind(&schema)) {
+        Some(index) => {
+            let variant = variants.remove(index);
+            variants.insert(index, variant.merge(schema));
+        },
+        None => variants.push(schema),
+    }
+}
+
+
+#[cfg(all(test, feature = "de"))]
+mod tests {
+    use super::*;
+    use de::from_str;
+
+    fn infer_all(samples: &[&str]) -> Schema {
+        let values: Vec<Value> = samples.iter().map(|s| from_str(s).unwrap()).collect();
+        Schema::infer_all(&values)
+    }
+
+    fn dict(fields: Vec<(&str, Schema, bool)>) -> Schema {
+        Schema::Dict(fields.into_iter().map(|(key, schema, optional)| {
+            (key.to_string(), Field { schema: schema, optional: optional })
+        }).collect())
+    }
+
+    #[test]
+    fn test_infer() {
+        let schema = infer_all(&["name: \"x\" ports: [80 443] tls {on: True} tags: []"]);
+        assert_eq!(dict(vec![("name", Schema::String, false),
+                             ("ports", Schema::List(Box::new(Schema::Integer { min: 0, max: 443 })), false),
+                             ("tags", Schema::List(Box::new(Schema::Any)), false),
+                             ("tls", dict(vec![("on", Schema::Bool, false)]), false)]),
+                   schema);
+    }
+
+    #[test]
+    fn test_infer_optional() {
+        let schema = infer_all(&["a: 1 b: [{x: 1}]", "a: 2.5 b: [{x: 2 y: \"s\"}] c: :tag True"]);
+        assert_eq!(dict(vec![("a", Schema::Float, false),
+                             ("b", Schema::List(Box::new(dict(vec![("x", Schema::Integer { min: 0, max: 2 }, false),
+                                                                  ("y", Schema::String, true)]))), false),
+                             ("c", Schema::Bool, true)]),
+                   schema);
+    }
+
+    #[test]
+    fn test_infer_union() {
+        let schema = infer_all(&["a: [1 \"s\" 2.0 [] True]"]);
+        assert_eq!(dict(vec![("a", Schema::List(Box::new(Schema::Union(vec![
+            Schema::Float, Schema::String, Schema::List(Box::new(Schema::Any)), Schema::Bool,
+        ]))), false)]), schema);
+        assert_eq!(Schema::Any, Schema::infer_all(&[]));
+    }
+
+    #[test]
+    fn test_matches() {
+        let schema = infer_all(&["a: 1 b: \"x\"", "a: 1.5"]);
+        assert!(schema.matches(&from_str("a: 2").unwrap()));
+        assert!(schema.matches(&from_str("a: :x 2 b: \"y\"").unwrap()));
+        assert!(!schema.matches(&from_str("b: \"y\"").unwrap()));
+        assert!(!schema.matches(&from_str("a: 1 c: 2").unwrap()));
+        assert!(!schema.matches(&from_str("a: True").unwrap()));
+
+        let schema = infer_all(&["a: 1", "a: -0x8000000000000000", "a: 0xFFFFFFFFFFFFFFFF"]);
+        assert_eq!(dict(vec![("a", Schema::Integer { min: i64::min_value() as i128,
+                                                     max: u64::max_value() as u128 }, false)]),
+                   schema);
+        assert!(schema.matches(&from_str("a: -0x10000000000000000").unwrap()));
+    }
+}
@@ -4,23 +4,93 @@
 // Distributed under terms of the MIT license.
 //
 
-use std::io::Write;
-use serde::ser::{self, Serialize, SeqVisitor, MapVisitor};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::mem;
+use serde::ser::{self, Serialize};
+use itoa;
+use ryu;
+use super::base64;
 use super::error::{Result, Error, ErrorCode};
+use super::parser::{is_key_char, DEFAULT_RECURSION_LIMIT};
+use super::cancel::CancellationToken;
+use self::filter::{Decision, PathFilter};
 
+mod events;
+mod filter;
+mod fixed;
+mod pending;
+pub use self::events::EventWriter;
+pub use self::fixed::{FixedCompound, FixedSerializer, to_writer_fixed};
+pub use self::pending::{PendingWrite, to_writer_nonblocking};
 
-trait Formatter {
+
+/// Controls the layout of the output of a `Serializer`: formatters write
+/// the punctuation and whitespace around keys and values, while values
+/// themselves are always written by the serializer.
+///
+/// Implementing this trait allows using custom output styles. Note that
+/// the output must be valid HiPack, e.g. items of dictionaries and lists
+/// must be separated by a comma or whitespace.
+pub trait Formatter {
+    /// Writes the opening character `ch` of a dictionary (`{`) or a list
+    /// (`[`) with at least one item.
     fn start_compound<W>(&mut self, writer: &mut W, ch: u8) -> Result<()>
         where W: Write;
+    /// Writes the closing character `ch` of a dictionary (`}`) or a list
+    /// (`]`) with at least one item.
     fn end_compound<W>(&mut self, writer: &mut W, ch: u8) -> Result<()>
         where W: Write;
-    fn key_separator<W>(&mut self, writer: &mut W) -> Result<()>
+    /// Writes the separator in between a dictionary key and a value of
+    /// the given kind.
+    fn key_separator<W>(&mut self, writer: &mut W, kind: ValueKind) -> Result<()>
         where W: Write;
+    /// Writes what goes before each item of a dictionary or a list, where
+    /// `first` tells whether it is the first item.
     fn item_separator<W>(&mut self, writer: &mut W, first: bool) -> Result<()>
         where W: Write;
+    /// Writes the comment of a `Commented` value, which goes after the
+    /// item separator and before the key. By default each line of the
+    /// text is written prefixed by `# ` and followed by a newline.
+    fn comment<W>(&mut self, writer: &mut W, text: &str) -> Result<()>
+        where W: Write
+    {
+        for line in text.lines() {
+            try!(write_comment_line(writer, line));
+            try!(writer.write_all(b"\n"));
+        }
+        Ok(())
+    }
+}
+
+
+fn write_comment_line<W: Write>(writer: &mut W, line: &str) -> Result<()> {
+    if line.is_empty() {
+        writer.write_all(b"#").map_err(From::from)
+    } else {
+        write!(writer, "# {}", line).map_err(From::from)
+    }
+}
+
+
+/// Kind of the value which follows a dictionary key, which formatters may
+/// use to decide how to separate them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueKind {
+    /// A value which is not a dictionary or a list.
+    Scalar,
+    /// A dictionary or a list, for which the colon is optional.
+    Compound,
+    /// A value preceded by annotations, for which the colon is mandatory.
+    Annotated,
 }
 
 
+/// Writes everything in a single line, without whitespace.
+#[derive(Clone, Debug, Default)]
 pub struct CompactFormatter;
 
 impl Formatter for CompactFormatter {
@@ -36,7 +106,7 @@ impl Formatter for CompactFormatter {
         writer.write_all(&[ch]).map_err(From::from)
     }
 
-    fn key_separator<W>(&mut self, writer: &mut W) -> Result<()>
+    fn key_separator<W>(&mut self, writer: &mut W, _kind: ValueKind) -> Result<()>
         where W: Write
     {
         writer.write_all(b":").map_err(From::from)
@@ -54,25 +124,59 @@ impl Formatter for CompactFormatter {
 }
 
 
+/// Writes each item of dictionaries and lists in its own line, indented
+/// with two spaces (or a custom string) for each level of nesting.
+#[derive(Clone, Debug)]
 pub struct PrettyFormatter {
     indent: usize,
+    indent_with: String,
+    fold_markers: bool,
 }
 
 impl PrettyFormatter {
-    fn new() -> Self {
-        PrettyFormatter { indent: 0 }
+    #[inline]
+    pub fn new() -> Self {
+        PrettyFormatter::with_indent("  ")
     }
-}
 
+    /// Creates a formatter which writes `indent` once for each level of
+    /// nesting, e.g. four spaces or a tab. The string should contain only
+    /// whitespace for the output to be valid HiPack.
+    #[inline]
+    pub fn with_indent(indent: &str) -> Self {
+        PrettyFormatter { indent: 0, indent_with: indent.to_string(), fold_markers: false }
+    }
 
-#[inline]
-fn indent<W>(writer: &mut W, indent: usize) -> Result<()>
-    where W: Write
-{
-    for _ in 0..indent {
-        try!(writer.write_all(b"  "));
+    /// Adds editor folding markers (`# {{{` and `# }}}`) as comments after
+    /// the braces of the dictionaries in the top-level one, to help with
+    /// navigating large documents.
+    #[inline]
+    pub fn fold_markers(mut self, enabled: bool) -> Self {
+        self.fold_markers = enabled;
+        self
+    }
+
+    // Whether `ch` is a brace of a dictionary in the top-level one.
+    #[inline]
+    fn is_section(&self, ch: u8) -> bool {
+        self.fold_markers && (ch == b'{' || ch == b'}') && self.indent == 1
+    }
+
+    fn write_indent<W>(&self, writer: &mut W) -> Result<()>
+        where W: Write
+    {
+        for _ in 0..self.indent {
+            try!(writer.write_all(self.indent_with.as_bytes()));
+        }
+        Ok(())
+    }
+}
+
+impl Default for PrettyFormatter {
+    #[inline]
+    fn default() -> Self {
+        PrettyFormatter::new()
     }
-    Ok(())
 }
 
 
@@ -80,9 +184,14 @@ impl Formatter for PrettyFormatter {
     fn start_compound<W>(&mut self, writer: &mut W, ch: u8) -> Result<()>
         where W: Write
     {
+        let section = self.is_section(ch);
         self.indent += 1;
-        try!(writer.write_all(&[ch, b'\n']));
-        indent(writer, self.indent)
+        try!(writer.write_all(&[ch]));
+        if section {
+            try!(writer.write_all(b" # {{{"));
+        }
+        try!(writer.write_all(b"\n"));
+        self.write_indent(writer)
     }
 
     fn end_compound<W>(&mut self, writer: &mut W, ch: u8) -> Result<()>
@@ -90,15 +199,21 @@ impl Formatter for PrettyFormatter {
     {
         self.indent -= 1;
         try!(writer.write(b"\n"));
-        try!(indent(writer, self.indent));
-        writer.write_all(&[ch]).map_err(From::from)
+        try!(self.write_indent(writer));
+        try!(writer.write_all(&[ch]));
+        if self.is_section(ch) {
+            try!(writer.write_all(b" # }}}"));
+        }
+        Ok(())
     }
 
-    fn key_separator<W>(&mut self, writer: &mut W) -> Result<()>
+    fn key_separator<W>(&mut self, writer: &mut W, kind: ValueKind) -> Result<()>
         where W: Write
     {
-        // TODO: Do not write colon when value is dict/list
-        writer.write_all(b": ").map_err(From::from)
+        match kind {
+            ValueKind::Compound => writer.write_all(b" "),
+            ValueKind::Scalar | ValueKind::Annotated => writer.write_all(b": "),
+        }.map_err(From::from)
     }
 
     fn item_separator<W>(&mut self, writer: &mut W, first: bool) -> Result<()>
@@ -108,194 +223,1633 @@ impl Formatter for PrettyFormatter {
             Ok(())
         } else {
             try!(writer.write(b"\n"));
-            indent(writer, self.indent)
+            self.write_indent(writer)
         }
     }
+
+    fn comment<W>(&mut self, writer: &mut W, text: &str) -> Result<()>
+        where W: Write
+    {
+        for line in text.lines() {
+            try!(write_comment_line(writer, line));
+            try!(writer.write_all(b"\n"));
+            try!(self.write_indent(writer));
+        }
+        Ok(())
+    }
+}
+
+
+/// Wraps an integer so it is serialized in hexadecimal notation (`0xFF`).
+///
+/// Wrapping a compound value applies the notation to all the integers
+/// contained in it.
+pub struct Hex<T>(pub T);
+
+/// Wraps an integer so it is serialized in octal notation (`0644`).
+///
+/// Wrapping a compound value applies the notation to all the integers
+/// contained in it.
+pub struct Octal<T>(pub T);
+
+/// A value with HEP-1 annotations, serialized as `:annot1:annot2 value`.
+///
+/// Serializers for formats other than HiPack only get to see the value.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Annotated<T> {
+    pub annotations: Vec<String>,
+    pub value: T,
+}
+
+impl<T> Annotated<T> {
+    #[inline]
+    pub fn new(value: T) -> Self {
+        Annotated { annotations: Vec::new(), value: value }
+    }
+
+    #[inline]
+    pub fn annotate<S: Into<String>>(mut self, annotation: S) -> Self {
+        self.annotations.push(annotation.into());
+        self
+    }
+}
+
+/// A value preceded by a comment, e.g. to explain the settings in a
+/// generated configuration file:
+///
+/// ```
+/// # #[macro_use]
+/// # extern crate serde_derive;
+/// # extern crate serde_hipack;
+/// # use serde_hipack::ser::{to_string_pretty, Commented};
+/// #[derive(Serialize)]
+/// struct Config {
+///     port: Commented<u16>,
+/// }
+///
+/// # fn main() {
+/// let config = Config { port: Commented::new("Port to listen on.", 8080) };
+/// assert_eq!(to_string_pretty(&config).unwrap(),
+///            "{\n  # Port to listen on.\n  port: 8080\n}");
+/// # }
+/// ```
+///
+/// Each line of the comment is written as `# line` above the dictionary
+/// entry or list item holding the value, or at the start of the output
+/// for the top-level value. Comments are left out in canonical form, and
+/// serializers for formats other than HiPack only get to see the value.
+/// The Deserializer reads the value alone, with an empty comment.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Commented<T> {
+    pub comment: String,
+    pub value: T,
+}
+
+impl<T> Commented<T> {
+    #[inline]
+    pub fn new<S: Into<String>>(comment: S, value: T) -> Self {
+        Commented { comment: comment.into(), value: value }
+    }
+}
+
+const HEX_NAME: &'static str = "$hipack::Hex";
+const OCTAL_NAME: &'static str = "$hipack::Octal";
+const ANNOTATED_VALUE_NAME: &'static str = "$hipack::AnnotatedValue";
+const COMMENTED_NAME: &'static str = "$hipack::Commented";
+// Newtype struct name of `RawValue`, whose text is written as is.
+#[doc(hidden)]
+pub const RAW_VALUE_NAME: &'static str = "$hipack::RawValue";
+// Newtype struct name of `Number`, whose text is written instead of the
+// value unless writing in canonical form.
+#[doc(hidden)]
+pub const NUMBER_NAME: &'static str = "$hipack::Number";
+
+/// Annotation of byte strings written as `BytesEncoding::Base64`.
+pub const BASE64_ANNOTATION: &'static str = "base64";
+
+thread_local! {
+    // Annotations of the value passed by serialize_annotated(), which the
+    // Serializer takes when it gets to the value.
+    static ANNOTATIONS: RefCell<Option<Vec<String>>> = RefCell::new(None);
+    // Text of the RawValue being written, passed like the annotations.
+    static RAW_TEXT: RefCell<Option<String>> = RefCell::new(None);
+    // Comment of the Commented value being written, which the Serializer
+    // writes before the key of the entry.
+    static COMMENT: RefCell<Option<String>> = RefCell::new(None);
+}
+
+impl<T: Serialize> Serialize for Annotated<T> {
+    #[inline]
+    fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+        where S: ser::Serializer
+    {
+        serialize_annotated(serializer, &self.annotations, &self.value)
+    }
+}
+
+// The annotations are handed over to the HiPack Serializer on the side,
+// so serializers for other formats only get to see a newtype struct
+// wrapping the value.
+#[doc(hidden)]
+pub fn serialize_annotated<S, T>(serializer: S, annotations: &[String], value: &T)
+    -> ::std::result::Result<S::Ok, S::Error>
+    where S: ser::Serializer, T: ?Sized + Serialize
+{
+    if annotations.is_empty() {
+        return value.serialize(serializer);
+    }
+    ANNOTATIONS.with(|cell| *cell.borrow_mut() = Some(annotations.to_vec()));
+    let result = serializer.serialize_newtype_struct(ANNOTATED_VALUE_NAME, value);
+    ANNOTATIONS.with(|cell| cell.borrow_mut().take());
+    result
+}
+
+impl<T: Serialize> Serialize for Commented<T> {
+    fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+        where S: ser::Serializer
+    {
+        if self.comment.is_empty() {
+            return self.value.serialize(serializer);
+        }
+        let saved = COMMENT.with(|cell| cell.borrow_mut().replace(self.comment.clone()));
+        let result = serializer.serialize_newtype_struct(COMMENTED_NAME, &self.value);
+        COMMENT.with(|cell| *cell.borrow_mut() = saved);
+        result
+    }
+}
+
+// Serializers for other formats write the text of raw values as strings.
+#[doc(hidden)]
+pub fn serialize_raw<S>(serializer: S, text: &str) -> ::std::result::Result<S::Ok, S::Error>
+    where S: ser::Serializer
+{
+    RAW_TEXT.with(|cell| *cell.borrow_mut() = Some(text.to_string()));
+    let result = serializer.serialize_newtype_struct(RAW_VALUE_NAME, text);
+    RAW_TEXT.with(|cell| cell.borrow_mut().take());
+    result
+}
+
+// Writes a number as the given text, while serializers for other formats
+// get the value.
+#[doc(hidden)]
+pub fn serialize_number<S, T: ?Sized>(serializer: S, text: &str, value: &T)
+    -> ::std::result::Result<S::Ok, S::Error>
+    where S: ser::Serializer, T: Serialize
+{
+    RAW_TEXT.with(|cell| *cell.borrow_mut() = Some(text.to_string()));
+    let result = serializer.serialize_newtype_struct(NUMBER_NAME, value);
+    RAW_TEXT.with(|cell| cell.borrow_mut().take());
+    result
+}
+
+// Takes the text of the raw value being written, if it was passed by
+// serialize_raw().
+fn take_raw_text() -> Option<String> {
+    RAW_TEXT.with(|cell| cell.borrow_mut().take())
+}
+
+impl<T: Serialize> Serialize for Hex<T> {
+    #[inline]
+    fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+        where S: ser::Serializer
+    {
+        serializer.serialize_newtype_struct(HEX_NAME, &self.0)
+    }
+}
+
+impl<T: Serialize> Serialize for Octal<T> {
+    #[inline]
+    fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+        where S: ser::Serializer
+    {
+        serializer.serialize_newtype_struct(OCTAL_NAME, &self.0)
+    }
+}
+
+/// How `None` and unit values are represented, as HiPack has no null.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NullStrategy {
+    /// Fail with `ErrorCode::UnrepresentableValue`.
+    Error,
+    /// Leave out dictionary entries which are null. Null list items fail
+    /// with `ErrorCode::UnrepresentableValue`, as leaving them out would
+    /// change the position of the items which follow.
+    Skip,
+    /// Use an empty string, `""`.
+    EmptyString,
+    /// Use an empty dictionary annotated as `:none {}`.
+    AnnotatedDict,
+}
+
+
+impl Default for NullStrategy {
+    /// Skipping null values makes structs with `Option` fields, which are
+    /// deserialized as `None` when their keys are missing, round-trip.
+    #[inline]
+    fn default() -> Self {
+        NullStrategy::Skip
+    }
+}
+
+
+/// How enum variants which contain data are written. Unit variants are
+/// always written as strings with the name of the variant.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnumStyle {
+    /// Use a dictionary whose only key is the name of the variant, e.g.
+    /// `{Circle: 1.5}`.
+    Dict,
+    /// Annotate the data with the name of the variant, e.g. `:Circle 1.5`.
+    Annotated,
+}
+
+
+impl Default for EnumStyle {
+    #[inline]
+    fn default() -> Self {
+        EnumStyle::Dict
+    }
+}
+
+
+/// How byte strings (e.g. `serde::bytes::Bytes`) are written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BytesEncoding {
+    /// Use a list of integers, e.g. `[104,105]`.
+    List,
+    /// Use a string, escaping the bytes which are not printable ASCII
+    /// characters, e.g. `"hi\FF"`.
+    Hex,
+    /// Use a Base64 string annotated as `:base64`, e.g. `:base64 "aGk="`.
+    Base64,
+}
+
+
+impl Default for BytesEncoding {
+    #[inline]
+    fn default() -> Self {
+        BytesEncoding::List
+    }
+}
+
+
+/// How dictionary keys containing characters which are not allowed in
+/// keys (whitespace, `:`, `,`, brackets, braces, and leading `"` or `#`)
+/// are written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyPolicy {
+    /// Fail with `ErrorCode::InvalidKeyChar`.
+    Error,
+    /// Replace each invalid character with an underscore. Different keys
+    /// may end up being written the same.
+    Replace,
+    /// Replace each invalid character, and `%`, with its code in
+    /// hexadecimal preceded by `%`, as in URLs.
+    PercentEncode,
+}
+
+
+/// Decides which characters, besides quotes, backslashes and control
+/// characters, are escaped when writing strings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EscapePolicy {
+    /// Write tabs as `\t` instead of literally. Enabled by default.
+    pub tab: bool,
+    /// Write the DEL character as `\7F`.
+    pub del: bool,
+    /// Escape Unicode bidirectional formatting characters, which may be
+    /// used to make text display differently from how it is parsed.
+    pub bidi: bool,
+}
+
+impl Default for EscapePolicy {
+    fn default() -> Self {
+        EscapePolicy { tab: true, del: false, bidi: false }
+    }
+}
+
+
+/// How records are delimited by `to_writer_batch()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordSeparator {
+    /// Write each record, which may be any value, on its own line.
+    Newline,
+    /// Write each record as a message in the framed wire format of the
+    /// `framing` module, so they can be read with a `MessageReader`.
+    /// Records must be dictionaries.
+    Frame,
+}
+
+
+#[inline]
+fn write_byte_escaped<W>(writer: &mut W, byte: u8) -> io::Result<()>
+    where W: Write
+{
+    write!(writer, "\\{:02X}", byte)
+}
+
+
+// Escapes all the bytes of the UTF-8 encoding of a character, so escapes
+// never split a character and decode back to valid UTF-8.
+fn write_char_escaped<W>(writer: &mut W, ch: char) -> io::Result<()>
+    where W: Write
+{
+    for byte in ch.encode_utf8(&mut [0; 4]).bytes() {
+        try!(write_byte_escaped(writer, byte));
+    }
+    Ok(())
+}
+
+
+// Writes a quoted string, escaping characters as needed.
+fn write_str<W>(writer: &mut W, v: &str, escape: EscapePolicy) -> io::Result<()>
+    where W: Write
+{
+    try!(writer.write_all(b"\""));
+    // Strings are handled a character at a time, never a byte at a time.
+    for (index, ch) in v.char_indices() {
+        let bytes = &v.as_bytes()[index..index + ch.len_utf8()];
+        try!(match ch {
+            '\t' if escape.tab => writer.write_all(b"\\t"),
+            '\t' => writer.write_all(bytes),
+            '\n' => writer.write_all(b"\\n"),
+            '\r' => writer.write_all(b"\\r"),
+            '"' => writer.write_all(b"\\\""),
+            '\\' => writer.write_all(b"\\\\"),
+            '\x7F' if escape.del => write_char_escaped(writer, ch),
+            '\u{061C}' | '\u{200E}' | '\u{200F}' |
+            '\u{202A}'..='\u{202E}' |
+            '\u{2066}'..='\u{2069}' if escape.bidi => write_char_escaped(writer, ch),
+            ch if (ch as u32) < 0x20 => write_char_escaped(writer, ch),
+            _ => writer.write_all(bytes),
+        });
+    }
+    writer.write_all(b"\"")
+}
+
+
+// Writes an integer in decimal form, formatted on the stack.
+#[inline]
+pub(crate) fn write_integer<W, I>(writer: &mut W, v: I) -> io::Result<()>
+    where W: Write, I: itoa::Integer
+{
+    writer.write_all(itoa::Buffer::new().format(v).as_bytes())
+}
+
+
+// Writes a float formatted on the stack, which has a decimal point or an
+// exponent so it is read back as a float, e.g. `1.0` or `1e100`. NaN and
+// infinities are written as `NaN`, `inf` and `-inf`.
+pub(crate) fn write_f64<W>(writer: &mut W, v: f64) -> io::Result<()>
+    where W: Write
+{
+    if v.is_nan() {
+        writer.write_all(b"NaN")
+    } else if v.is_infinite() {
+        writer.write_all(if v > 0.0 { b"inf" } else { b"-inf" })
+    } else {
+        writer.write_all(ryu::Buffer::new().format_finite(v).as_bytes())
+    }
+}
+
+
+/// Progress report passed to the callback set with `Serializer::progress()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Progress {
+    /// Number of bytes written so far.
+    pub bytes_written: u64,
+    /// Number of items of the top-level dictionary or list written so far.
+    pub items_completed: usize,
+}
+
+
+// Keeps track of the amount of bytes written.
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = try!(self.inner.write(buf));
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
 }
 
 
 pub struct Serializer<W: Write, F=PrettyFormatter> {
-    writer: W,
+    writer: CountingWriter<W>,
     format: F,
+    radix: u32,
+    null: NullStrategy,
+    escape: EscapePolicy,
+    keys: KeyPolicy,
+    enums: EnumStyle,
+    bytes: BytesEncoding,
+    depth: usize,
+    recursion_limit: usize,
+    items: usize,
+    progress: Option<Box<FnMut(Progress) -> bool>>,
+    cancel: Option<Box<CancellationToken>>,
+    canonical: bool,
+    filter: Option<Rc<PathFilter>>,
+    // Keys and positions leading to the value being written, which are
+    // only tracked when there are path filters or spans are recorded.
+    path: Vec<String>,
+    spans: Option<BTreeMap<String, Range<usize>>>,
+    pending: Option<PendingItem>,
+    // Where the value of the last item written starts.
+    value_start: u64,
+}
+
+impl<W: Write> Serializer<W, CompactFormatter> {
+    #[inline]
+    pub fn new(writer: W) -> Self {
+        Serializer::with_formatter(writer, CompactFormatter)
+    }
+
+    /// Creates a serializer which writes values in canonical form, so that
+    /// equal values always produce the same bytes, e.g. for hashing or
+    /// signing them. Output is compact, dictionary keys are sorted
+    /// bytewise, integers are always written in decimal, and negative
+    /// zero is written as `0.0`.
+    #[inline]
+    pub fn canonical(writer: W) -> Self {
+        let mut serializer = Serializer::new(writer);
+        serializer.canonical = true;
+        serializer
+    }
+}
+
+
+impl<W: Write> Serializer<W> {
+    #[inline]
+    pub fn pretty(writer: W) -> Self {
+        Serializer::with_formatter(writer, PrettyFormatter::new())
+    }
+
+    /// Sets the string written once for each level of nesting, see
+    /// `PrettyFormatter::with_indent()`.
+    #[inline]
+    pub fn indent(mut self, indent: &str) -> Self {
+        self.format.indent_with = indent.to_string();
+        self
+    }
+
+    /// Adds editor folding markers around sections of the output, see
+    /// `PrettyFormatter::fold_markers()`.
+    #[inline]
+    pub fn fold_markers(mut self, enabled: bool) -> Self {
+        self.format.fold_markers = enabled;
+        self
+    }
+}
+
+
+impl<W: Write, F: Formatter> Serializer<W, F> {
+    /// Creates a serializer which uses a custom formatter.
+    #[inline]
+    pub fn with_formatter(writer: W, format: F) -> Self {
+        Serializer {
+            writer: CountingWriter { inner: writer, count: 0 },
+            format: format,
+            radix: 10,
+            null: NullStrategy::default(),
+            escape: EscapePolicy::default(),
+            keys: KeyPolicy::Error,
+            enums: EnumStyle::default(),
+            bytes: BytesEncoding::default(),
+            depth: 0,
+            recursion_limit: DEFAULT_RECURSION_LIMIT,
+            items: 0,
+            progress: None,
+            cancel: None,
+            canonical: false,
+            filter: None,
+            path: Vec::new(),
+            spans: None,
+            pending: None,
+            value_start: 0,
+        }
+    }
+
+    /// Sets a callback which is called after each item of the top-level
+    /// dictionary or list has been written. Returning `false` from the
+    /// callback stops serialization with `Error::Cancelled`.
+    pub fn progress<C>(mut self, callback: C) -> Self
+        where C: FnMut(Progress) -> bool + 'static
+    {
+        self.progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Sets a token which is checked before writing each item of a
+    /// dictionary or list, stopping serialization with `Error::Cancelled`
+    /// once it is cancelled.
+    pub fn cancellation<C>(mut self, token: C) -> Self
+        where C: CancellationToken + 'static
+    {
+        self.cancel = Some(Box::new(token));
+        self
+    }
+
+    #[inline]
+    fn check_cancelled(&self) -> Result<()> {
+        match self.cancel {
+            Some(ref token) if token.is_cancelled() => Err(Error::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    fn item_completed(&mut self) -> Result<()> {
+        if self.depth != 1 {
+            return Ok(());
+        }
+        self.items += 1;
+        let progress = Progress {
+            bytes_written: self.writer.count,
+            items_completed: self.items,
+        };
+        if let Some(ref mut callback) = self.progress {
+            if !callback(progress) {
+                return Err(Error::Cancelled);
+            }
+        }
+        Ok(())
+    }
+
+    /// Limits how deep lists and dictionaries may be nested, 128 levels by
+    /// default, like `Deserializer::recursion_limit()`. Deeper values fail
+    /// with `ErrorCode::RecursionLimitExceeded` instead of overflowing the
+    /// stack, as serializing them is recursive. For the same reason, the
+    /// limit must stay finite, and be raised only along with the stack size.
+    #[inline]
+    pub fn recursion_limit(mut self, limit: usize) -> Self {
+        self.recursion_limit = limit;
+        self
+    }
+
+    /// Sets how dictionary keys which contain characters not allowed in
+    /// keys are written. The default is `KeyPolicy::Error`. Empty keys
+    /// are always an error.
+    #[inline]
+    pub fn key_policy(mut self, policy: KeyPolicy) -> Self {
+        self.keys = policy;
+        self
+    }
+
+    /// Sets how enum variants which contain data are written. The default
+    /// is `EnumStyle::Dict`.
+    #[inline]
+    pub fn enum_style(mut self, style: EnumStyle) -> Self {
+        self.enums = style;
+        self
+    }
+
+    /// Sets how byte strings are written. The default is
+    /// `BytesEncoding::List`.
+    #[inline]
+    pub fn bytes_encoding(mut self, encoding: BytesEncoding) -> Self {
+        self.bytes = encoding;
+        self
+    }
+
+    /// Sets which characters are escaped in strings.
+    #[inline]
+    pub fn escape_policy(mut self, policy: EscapePolicy) -> Self {
+        self.escape = policy;
+        self
+    }
+
+    /// Writes only the values whose path matches the pattern, along with
+    /// the dictionaries and lists containing them, and everything nested
+    /// inside them. Patterns are written like JSON Pointers, e.g.
+    /// `/servers/0/host`, where `*` matches any key or list position and
+    /// `**` matches any number of them. This may be called more than once
+    /// to include several paths; by default everything is written.
+    /// Values on the way to included paths are serialized twice, once to
+    /// find out whether they are dictionaries or lists.
+    pub fn include(mut self, pattern: &str) -> Self {
+        Rc::make_mut(self.filter.get_or_insert_with(Default::default)).include(pattern);
+        self
+    }
+
+    /// Leaves out the values whose path matches the pattern, which uses
+    /// the same syntax as for `include()`, e.g. `/credentials` or
+    /// `**/password`. Exclusions take precedence over inclusions.
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        Rc::make_mut(self.filter.get_or_insert_with(Default::default)).exclude(pattern);
+        self
+    }
+
+    /// Records where each value nested in the top-level one is written,
+    /// see `spans()`.
+    #[inline]
+    pub fn record_spans(mut self, enabled: bool) -> Self {
+        self.spans = if enabled { Some(BTreeMap::new()) } else { None };
+        self
+    }
+
+    /// Byte ranges of the output where the values written so far are,
+    /// by their JSON Pointer paths, like `/servers/0/host`, if recording
+    /// them was enabled with `record_spans()`. Ranges are relative to the
+    /// start of the output, and start after the key of entries.
+    #[inline]
+    pub fn spans(&self) -> Option<&BTreeMap<String, Range<usize>>> {
+        self.spans.as_ref()
+    }
+
+    #[inline]
+    pub fn take_spans(&mut self) -> Option<BTreeMap<String, Range<usize>>> {
+        self.spans.take()
+    }
+
+    /// Sets how `None` and unit values are written. The default is
+    /// `NullStrategy::Skip`, and `NullStrategy::Error` may be used to
+    /// reject them instead.
+    #[inline]
+    pub fn null_strategy(mut self, strategy: NullStrategy) -> Self {
+        self.null = strategy;
+        self
+    }
+
+    // Writes what goes before the item being written, if any, once the
+    // kind of its value is known, see PendingItem. Returns false when the
+    // item is a scalar which the path filters leave out.
+    fn begin_value(&mut self, kind: ValueKind) -> Result<bool> {
+        if kind == ValueKind::Scalar && self.descends() {
+            return Ok(false);
+        }
+        if let Some(item) = self.pending.take() {
+            if let Some(ch) = item.open {
+                try!(self.format.start_compound(&mut self.writer, ch));
+            }
+            try!(self.format.item_separator(&mut self.writer, item.first));
+            if let Some(text) = item.comment {
+                if !self.canonical {
+                    try!(self.format.comment(&mut self.writer, &text));
+                }
+            }
+            let kind = if item.annotations.is_empty() { kind } else { ValueKind::Annotated };
+            if let Some(key) = item.key {
+                try!(self.writer.write_all(&key));
+                try!(self.format.key_separator(&mut self.writer, kind));
+            }
+            if !item.annotations.is_empty() {
+                for annotation in &item.annotations {
+                    try!(self.write_annotation(annotation));
+                }
+                try!(self.writer.write_all(b" "));
+            }
+            self.value_start = self.writer.count;
+        }
+        Ok(true)
+    }
+
+    // Whether the item being written is kept only if it turns out not to
+    // be a scalar, see Decision::Descend.
+    #[inline]
+    fn descends(&self) -> bool {
+        self.pending.as_ref().map_or(false, |item| item.descend)
+    }
+
+    // Writes what precedes the data of an enum variant.
+    fn start_variant(&mut self, variant: &'static str) -> Result<()> {
+        try!(self.begin_value(match self.enums {
+            EnumStyle::Dict => ValueKind::Compound,
+            EnumStyle::Annotated => ValueKind::Annotated,
+        }));
+        match self.enums {
+            EnumStyle::Dict => {
+                try!(self.format.start_compound(&mut self.writer, b'{'));
+                try!(self.format.item_separator(&mut self.writer, true));
+                let keys = self.keys;
+                try!(ser::Serializer::serialize_str(KeySerializer { writer: &mut self.writer,
+                                                                    policy: keys },
+                                                    variant));
+                self.format.key_separator(&mut self.writer, ValueKind::Compound)
+            },
+            EnumStyle::Annotated => {
+                try!(self.write_annotation(variant));
+                self.writer.write_all(b" ").map_err(From::from)
+            },
+        }
+    }
+
+    // Writes what follows the data of an enum variant.
+    fn end_variant(&mut self) -> Result<()> {
+        match self.enums {
+            EnumStyle::Dict => self.format.end_compound(&mut self.writer, b'}'),
+            EnumStyle::Annotated => Ok(()),
+        }
+    }
+
+    fn write_annotation(&mut self, annotation: &str) -> Result<()> {
+        if annotation.is_empty() || !annotation.bytes().all(is_key_char) {
+            return Err(Error::SyntaxError(ErrorCode::InvalidAnnotation, 0, 0, 0));
+        }
+        try!(self.writer.write_all(b":"));
+        self.writer.write_all(annotation.as_bytes()).map_err(From::from)
+    }
+
+    fn write_annotated<A, T>(&mut self, annotations: &[A], value: &T) -> Result<()>
+        where A: AsRef<str>, T: ?Sized + Serialize
+    {
+        if !annotations.is_empty() {
+            try!(self.begin_value(ValueKind::Annotated));
+            for annotation in annotations {
+                try!(self.write_annotation(annotation.as_ref()));
+            }
+            try!(self.writer.write_all(b" "));
+        }
+        value.serialize(self)
+    }
+
+    // Starts a dictionary or a list, whose opening is written along with
+    // its first item, so those which end up with no items (e.g. as their
+    // values are skipped) are written as `{}` or `[]`. Dictionaries in
+    // canonical mode are written once all their entries are known.
+    fn start_compound(&mut self, ch: u8, len: Option<usize>, variant: bool)
+        -> Result<Compound<'_, W, F>>
+    {
+        try!(self.begin_value(ValueKind::Compound));
+        // The top-level compound and empty ones do not count towards the limit.
+        if len != Some(0) && self.depth > self.recursion_limit {
+            return Err(Error::SyntaxError(ErrorCode::RecursionLimitExceeded, 0, 0, 0));
+        }
+        self.depth += 1;
+        Ok(Compound {
+            ser: self,
+            open: ch,
+            first: true,
+            variant: variant,
+            entries: Vec::new(),
+            key: None,
+            index: 0,
+        })
+    }
+
+    #[inline]
+    fn tracks_path(&self) -> bool {
+        self.filter.is_some() || self.spans.is_some()
+    }
+
+    // Checks the path filters for a value with the given key or position,
+    // which is added to the path unless the value is skipped.
+    fn enter(&mut self, segment: String) -> Decision {
+        self.path.push(segment);
+        let decision = match self.filter {
+            Some(ref filter) => filter.decide(&self.path),
+            None => Decision::Write,
+        };
+        if decision == Decision::Skip {
+            self.path.pop();
+        }
+        decision
+    }
+
+    // Records the span of the value at the current path, which was
+    // written starting at the given offset.
+    fn record_span(&mut self, start: u64) {
+        if let Some(ref mut spans) = self.spans {
+            spans.insert(pointer(&self.path), start as usize..self.writer.count as usize);
+        }
+    }
+
+    // Serializes the value of a dictionary entry in canonical mode, unless
+    // it is a null value to be skipped. Spans are relative to the start of
+    // the value.
+    fn canonical_entry<T: ?Sized>(&self, key: Vec<u8>, value: &T, descend: bool)
+        -> Result<Option<CanonicalEntry>>
+        where T: Serialize
+    {
+        let mut buf = Vec::new();
+        let mut serializer = Serializer::canonical(&mut buf)
+            .null_strategy(self.null)
+            .escape_policy(self.escape)
+            .key_policy(self.keys)
+            .enum_style(self.enums)
+            .bytes_encoding(self.bytes)
+            .recursion_limit(self.recursion_limit);
+        // Values are nested as deep as their entry.
+        serializer.depth = self.depth;
+        serializer.filter = self.filter.clone();
+        serializer.path = self.path.clone();
+        serializer.spans = self.spans.as_ref().map(|_| BTreeMap::new());
+        serializer.pending = Some(PendingItem {
+            first: true,
+            entry: true,
+            descend: descend,
+            open: None,
+            key: None,
+            comment: None,
+            annotations: Vec::new(),
+        });
+        try!(value.serialize(&mut serializer));
+        if serializer.pending.is_some() {
+            return Ok(None);
+        }
+        serializer.record_span(0);
+        let spans = serializer.spans.take();
+        Ok(Some((key, written_kind(&buf), buf, spans)))
+    }
+}
+
+
+impl<'a, W: Write, F: Formatter> ser::Serializer for &'a mut Serializer<W, F> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Compound<'a, W, F>;
+    type SerializeTuple = Compound<'a, W, F>;
+    type SerializeTupleStruct = Compound<'a, W, F>;
+    type SerializeTupleVariant = Compound<'a, W, F>;
+    type SerializeMap = Compound<'a, W, F>;
+    type SerializeStruct = Compound<'a, W, F>;
+    type SerializeStructVariant = Compound<'a, W, F>;
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        if !try!(self.begin_value(ValueKind::Scalar)) {
+            return Ok(());
+        }
+        self.writer.write_all(if v { b"True" } else { b"False" }).map_err(From::from)
+    }
+
+    // Integers
+    #[inline]
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.serialize_i64(v as i64)
+    }
+    #[inline]
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.serialize_i64(v as i64)
+    }
+    #[inline]
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.serialize_i64(v as i64)
+    }
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        if !try!(self.begin_value(ValueKind::Scalar)) {
+            return Ok(());
+        }
+        if v >= 0 {
+            self.serialize_u64(v as u64)
+        } else if self.radix == 10 {
+            write_integer(&mut self.writer, v).map_err(From::from)
+        } else {
+            try!(self.writer.write_all(b"-"));
+            self.serialize_u64((v as u64).wrapping_neg())
+        }
+    }
+    #[inline]
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.serialize_u64(v as u64)
+    }
+    #[inline]
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.serialize_u64(v as u64)
+    }
+    #[inline]
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.serialize_u64(v as u64)
+    }
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        if !try!(self.begin_value(ValueKind::Scalar)) {
+            return Ok(());
+        }
+        match self.radix {
+            16 => write!(self.writer, "0x{:X}", v),
+            8 if v != 0 => write!(self.writer, "0{:o}", v),
+            _ => write_integer(&mut self.writer, v),
+        }.map_err(From::from)
+    }
+    fn serialize_i128(self, v: i128) -> Result<()> {
+        if !try!(self.begin_value(ValueKind::Scalar)) {
+            return Ok(());
+        }
+        if v >= 0 {
+            self.serialize_u128(v as u128)
+        } else if self.radix == 10 {
+            write_integer(&mut self.writer, v).map_err(From::from)
+        } else {
+            try!(self.writer.write_all(b"-"));
+            self.serialize_u128((v as u128).wrapping_neg())
+        }
+    }
+    fn serialize_u128(self, v: u128) -> Result<()> {
+        if !try!(self.begin_value(ValueKind::Scalar)) {
+            return Ok(());
+        }
+        match self.radix {
+            16 => write!(self.writer, "0x{:X}", v),
+            8 if v != 0 => write!(self.writer, "0{:o}", v),
+            _ => write_integer(&mut self.writer, v),
+        }.map_err(From::from)
+    }
+
+    // Float
+    #[inline]
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        self.serialize_f64(v as f64)
+    }
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        if !try!(self.begin_value(ValueKind::Scalar)) {
+            return Ok(());
+        }
+        let v = if self.canonical && v == 0.0 { 0.0 } else { v };
+        write_f64(&mut self.writer, v).map_err(From::from)
+    }
+
+    #[inline]
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+    fn serialize_str(self, v: &str) -> Result<()> {
+        if !try!(self.begin_value(ValueKind::Scalar)) {
+            return Ok(());
+        }
+        write_str(&mut self.writer, v, self.escape).map_err(From::from)
+    }
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        match self.bytes {
+            BytesEncoding::List => {
+                let mut seq = try!(self.serialize_seq(Some(v.len())));
+                for byte in v {
+                    try!(ser::SerializeSeq::serialize_element(&mut seq, byte));
+                }
+                ser::SerializeSeq::end(seq)
+            },
+            BytesEncoding::Hex => {
+                if !try!(self.begin_value(ValueKind::Scalar)) {
+                    return Ok(());
+                }
+                try!(self.writer.write_all(b"\""));
+                for byte in v {
+                    try!(match *byte {
+                        b'"' | b'\\' => write_byte_escaped(&mut self.writer, *byte),
+                        0x20..=0x7E => self.writer.write_all(&[*byte]),
+                        _ => write_byte_escaped(&mut self.writer, *byte),
+                    });
+                }
+                self.writer.write_all(b"\"").map_err(From::from)
+            },
+            BytesEncoding::Base64 => {
+                self.write_annotated(&[BASE64_ANNOTATION], &base64::encode(v))
+            },
+        }
+    }
+    fn serialize_unit(self) -> Result<()> {
+        match self.null {
+            NullStrategy::EmptyString => {
+                if !try!(self.begin_value(ValueKind::Scalar)) {
+                    return Ok(());
+                }
+                self.writer.write_all(b"\"\"")
+            },
+            NullStrategy::AnnotatedDict => {
+                try!(self.begin_value(ValueKind::Annotated));
+                self.writer.write_all(b":none {}")
+            },
+            // Skipped entries are left out by Compound.
+            NullStrategy::Skip if self.pending.as_ref().map_or(false, |item| item.entry) => {
+                return Ok(());
+            },
+            // Scalars left out by the path filters may be null as well.
+            NullStrategy::Error | NullStrategy::Skip if self.descends() => return Ok(()),
+            NullStrategy::Error | NullStrategy::Skip => {
+                return Err(Error::SyntaxError(ErrorCode::UnrepresentableValue, 0, 0, 0));
+            },
+        }.map_err(From::from)
+    }
+    #[inline]
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        self.serialize_unit()
+    }
+    #[inline]
+    fn serialize_none(self) -> Result<()> {
+        self.serialize_unit()
+    }
+    #[inline]
+    fn serialize_some<T: ?Sized>(self, value: &T) -> Result<()> where T: Serialize {
+        value.serialize(self)
+    }
+    fn serialize_newtype_struct<T: ?Sized>(self, name: &'static str, value: &T) -> Result<()>
+        where T: Serialize
+    {
+        let radix = match name {
+            HEX_NAME => 16,
+            OCTAL_NAME => 8,
+            ANNOTATED_VALUE_NAME => {
+                let annotations = ANNOTATIONS.with(|cell| cell.borrow_mut().take());
+                return self.write_annotated(&annotations.unwrap_or_default(), value);
+            },
+            COMMENTED_NAME => {
+                // Comments of items go before their key, and are written
+                // along with it, while the one of the top-level value goes
+                // at the start of the output.
+                if let Some(text) = COMMENT.with(|cell| cell.borrow().clone()) {
+                    match self.pending {
+                        Some(ref mut item) => match item.comment {
+                            Some(ref mut comment) => {
+                                comment.push('\n');
+                                comment.push_str(&text);
+                            },
+                            None => item.comment = Some(text),
+                        },
+                        None if self.writer.count == 0 && !self.canonical => {
+                            try!(self.format.comment(&mut self.writer, &text));
+                        },
+                        None => (),
+                    }
+                }
+                return value.serialize(self);
+            },
+            RAW_VALUE_NAME => {
+                return match take_raw_text() {
+                    Some(text) => {
+                        if !try!(self.begin_value(written_kind(text.as_bytes()))) {
+                            return Ok(());
+                        }
+                        self.writer.write_all(text.as_bytes()).map_err(From::from)
+                    },
+                    None => value.serialize(self),
+                };
+            },
+            NUMBER_NAME => {
+                return match take_raw_text() {
+                    Some(ref text) if !self.canonical => {
+                        if !try!(self.begin_value(ValueKind::Scalar)) {
+                            return Ok(());
+                        }
+                        self.writer.write_all(text.as_bytes()).map_err(From::from)
+                    },
+                    _ => value.serialize(self),
+                };
+            },
+            // Other newtype structs are written as lists with one item.
+            _ => {
+                let mut compound = try!(self.serialize_tuple_struct(name, 1));
+                try!(ser::SerializeTupleStruct::serialize_field(&mut compound, value));
+                return ser::SerializeTupleStruct::end(compound);
+            },
+        };
+        let saved = self.radix;
+        self.radix = if self.canonical { 10 } else { radix };
+        let result = value.serialize(&mut *self);
+        self.radix = saved;
+        result
+    }
+    fn serialize_unit_variant(self,
+                              _name: &'static str,
+                              _variant_index: u32,
+                              variant: &'static str) -> Result<()> {
+        self.serialize_str(variant)
+    }
+    fn serialize_newtype_variant<T: ?Sized>(self,
+                                            _name: &'static str,
+                                            _variant_index: u32,
+                                            variant: &'static str,
+                                            value: &T) -> Result<()>
+        where T: Serialize
+    {
+        match self.enums {
+            EnumStyle::Dict => {
+                let mut map = try!(self.serialize_map(Some(1)));
+                try!(ser::SerializeMap::serialize_entry(&mut map, variant, value));
+                ser::SerializeMap::end(map)
+            },
+            EnumStyle::Annotated => self.write_annotated(&[variant], value),
+        }
+    }
+    fn serialize_seq(self, len: Option<usize>) -> Result<Compound<'a, W, F>> {
+        self.start_compound(b'[', len, false)
+    }
+    fn serialize_tuple(self, len: usize) -> Result<Compound<'a, W, F>> {
+        self.start_compound(b'[', Some(len), false)
+    }
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize)
+        -> Result<Compound<'a, W, F>>
+    {
+        self.start_compound(b'[', Some(len), false)
+    }
+    fn serialize_tuple_variant(self,
+                               _name: &'static str,
+                               _variant_index: u32,
+                               variant: &'static str,
+                               len: usize) -> Result<Compound<'a, W, F>> {
+        try!(self.start_variant(variant));
+        self.start_compound(b'[', Some(len), true)
+    }
+    fn serialize_map(self, len: Option<usize>) -> Result<Compound<'a, W, F>> {
+        self.start_compound(b'{', len, false)
+    }
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Compound<'a, W, F>> {
+        self.start_compound(b'{', Some(len), false)
+    }
+    fn serialize_struct_variant(self,
+                                _name: &'static str,
+                                _variant_index: u32,
+                                variant: &'static str,
+                                len: usize) -> Result<Compound<'a, W, F>> {
+        try!(self.start_variant(variant));
+        self.start_compound(b'{', Some(len), true)
+    }
+}
+
+
+// Kind of a value, found from how it was written.
+fn written_kind(text: &[u8]) -> ValueKind {
+    match text.first() {
+        Some(&b'{') | Some(&b'[') => ValueKind::Compound,
+        Some(&b':') => ValueKind::Annotated,
+        _ => ValueKind::Scalar,
+    }
+}
+
+
+// List item or dictionary entry whose value is about to be serialized.
+// What goes before the value is written by Serializer::begin_value()
+// once the value starts, and the kind of value is known. This way values
+// are serialized only once, which matters for those which can only be
+// serialized once, e.g. when transcoding from another format. Skipped
+// null values leave it untouched.
+struct PendingItem {
     first: bool,
+    // Set for dictionary entries, which are skipped when null.
+    entry: bool,
+    // Set when the path filters include values nested in the item, which
+    // is then skipped if it is a scalar.
+    descend: bool,
+    // Opening of the enclosing compound, which is written along with its
+    // first item.
+    open: Option<u8>,
+    key: Option<Vec<u8>>,
+    comment: Option<String>,
+    // Annotations given to EventWriter for the value.
+    annotations: Vec<String>,
+}
+
+
+// Key, kind of value, value, and spans of an entry written in canonical mode.
+type CanonicalEntry = (Vec<u8>, ValueKind, Vec<u8>, Option<BTreeMap<String, Range<usize>>>);
+
+
+// Writes a path as a JSON Pointer.
+fn pointer(path: &[String]) -> String {
+    let mut pointer = String::new();
+    for segment in path {
+        pointer.push('/');
+        pointer.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+    }
+    pointer
+}
+
+
+/// Writes the items of a dictionary or a list, see `ser::Serializer`.
+pub struct Compound<'a, W: 'a + Write, F: 'a + Formatter> {
+    ser: &'a mut Serializer<W, F>,
+    // Opening bracket or brace, which is written along with the first item.
+    open: u8,
+    first: bool,
+    // Set for the data of enum variants, which may need to be closed.
+    variant: bool,
+    // Entries of a dictionary written in canonical mode, which are
+    // buffered to be sorted by key.
+    entries: Vec<CanonicalEntry>,
+    // Key written by SerializeMap::serialize_key(), which is kept until
+    // its value is known, as null values are skipped along with it.
+    key: Option<Vec<u8>>,
+    // Position of the next list item, used for path filters.
+    index: usize,
+}
+
+
+impl<'a, W: Write, F: Formatter> Compound<'a, W, F> {
+    fn item<T: ?Sized>(&mut self, value: &T) -> Result<()> where T: Serialize {
+        try!(self.ser.check_cancelled());
+        let index = self.index;
+        self.index += 1;
+        if self.ser.tracks_path() {
+            let decision = self.ser.enter(index.to_string());
+            if decision == Decision::Skip {
+                return Ok(());
+            }
+            let result = self.write_item(None, value, decision == Decision::Descend);
+            self.ser.path.pop();
+            return result;
+        }
+        self.write_item(None, value, false)
+    }
+
+    fn entry<T: ?Sized>(&mut self, key: Vec<u8>, value: &T) -> Result<()> where T: Serialize {
+        try!(self.ser.check_cancelled());
+        if self.ser.tracks_path() {
+            let decision = self.ser.enter(String::from_utf8_lossy(&key).into_owned());
+            if decision == Decision::Skip {
+                return Ok(());
+            }
+            let result = self.write_item(Some(key), value, decision == Decision::Descend);
+            self.ser.path.pop();
+            return result;
+        }
+        self.write_item(Some(key), value, false)
+    }
+
+    // Writes a list item, or a dictionary entry with the given key, which
+    // is skipped if it is a scalar when descending into it.
+    fn write_item<T: ?Sized>(&mut self, key: Option<Vec<u8>>, value: &T, descend: bool)
+        -> Result<()>
+        where T: Serialize
+    {
+        let key = match key {
+            Some(key) if self.ser.canonical => {
+                return match try!(self.ser.canonical_entry(key, value, descend)) {
+                    Some(entry) => {
+                        self.entries.push(entry);
+                        self.ser.item_completed()
+                    },
+                    None => Ok(()),
+                };
+            },
+            key => key,
+        };
+        // The start of the enclosing value is kept for its own span.
+        let outer = self.ser.value_start;
+        self.ser.pending = Some(PendingItem {
+            first: self.first,
+            entry: key.is_some(),
+            descend: descend,
+            open: if self.first { Some(self.open) } else { None },
+            key: key,
+            comment: None,
+            annotations: Vec::new(),
+        });
+        let result = value.serialize(&mut *self.ser);
+        let skipped = self.ser.pending.take().is_some();
+        let start = mem::replace(&mut self.ser.value_start, outer);
+        try!(result);
+        if skipped {
+            return Ok(());
+        }
+        self.ser.record_span(start);
+        self.first = false;
+        self.ser.item_completed()
+    }
+
+    fn end_list(mut self) -> Result<()> {
+        self.ser.depth -= 1;
+        try!(self.close(b']'));
+        if self.variant { self.ser.end_variant() } else { Ok(()) }
+    }
+
+    fn end_dict(mut self) -> Result<()> {
+        self.ser.depth -= 1;
+        if self.ser.canonical {
+            try!(self.write_entries());
+        } else {
+            try!(self.close(b'}'));
+        }
+        if self.variant { self.ser.end_variant() } else { Ok(()) }
+    }
+
+    // Writes the closing bracket or brace, or the whole compound if no
+    // item was written.
+    fn close(&mut self, ch: u8) -> Result<()> {
+        if self.first {
+            self.ser.writer.write_all(&[self.open, ch]).map_err(From::from)
+        } else {
+            self.ser.format.end_compound(&mut self.ser.writer, ch)
+        }
+    }
+
+    fn write_entries(&mut self) -> Result<()> {
+        let mut entries = mem::replace(&mut self.entries, Vec::new());
+        if entries.is_empty() {
+            return self.ser.writer.write_all(b"{}").map_err(From::from);
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        try!(self.ser.format.start_compound(&mut self.ser.writer, b'{'));
+        for (index, (key, kind, value, spans)) in entries.into_iter().enumerate() {
+            try!(self.ser.format.item_separator(&mut self.ser.writer, index == 0));
+            try!(self.ser.writer.write_all(&key));
+            try!(self.ser.format.key_separator(&mut self.ser.writer, kind));
+            let start = self.ser.writer.count as usize;
+            try!(self.ser.writer.write_all(&value));
+            if let (Some(all), Some(spans)) = (self.ser.spans.as_mut(), spans) {
+                all.extend(spans.into_iter().map(|(path, span)| {
+                    (path, start + span.start..start + span.end)
+                }));
+            }
+        }
+        self.ser.format.end_compound(&mut self.ser.writer, b'}')
+    }
+}
+
+
+impl<'a, W: Write, F: Formatter> ser::SerializeSeq for Compound<'a, W, F> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<()> where T: Serialize {
+        self.item(value)
+    }
+    #[inline]
+    fn end(self) -> Result<()> {
+        self.end_list()
+    }
+}
+
+
+impl<'a, W: Write, F: Formatter> ser::SerializeTuple for Compound<'a, W, F> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<()> where T: Serialize {
+        self.item(value)
+    }
+    #[inline]
+    fn end(self) -> Result<()> {
+        self.end_list()
+    }
+}
+
+
+impl<'a, W: Write, F: Formatter> ser::SerializeTupleStruct for Compound<'a, W, F> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<()> where T: Serialize {
+        self.item(value)
+    }
+    #[inline]
+    fn end(self) -> Result<()> {
+        self.end_list()
+    }
+}
+
+
+impl<'a, W: Write, F: Formatter> ser::SerializeTupleVariant for Compound<'a, W, F> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<()> where T: Serialize {
+        self.item(value)
+    }
+    #[inline]
+    fn end(self) -> Result<()> {
+        self.end_list()
+    }
+}
+
+
+impl<'a, W: Write, F: Formatter> ser::SerializeMap for Compound<'a, W, F> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized>(&mut self, key: &T) -> Result<()> where T: Serialize {
+        let mut buf = Vec::new();
+        try!(key.serialize(KeySerializer { writer: &mut buf, policy: self.ser.keys }));
+        self.key = Some(buf);
+        Ok(())
+    }
+    fn serialize_value<T: ?Sized>(&mut self, value: &T) -> Result<()> where T: Serialize {
+        let key = self.key.take().unwrap_or_default();
+        self.entry(key, value)
+    }
+    #[inline]
+    fn end(self) -> Result<()> {
+        self.end_dict()
+    }
+}
+
+
+impl<'a, W: Write, F: Formatter> ser::SerializeStruct for Compound<'a, W, F> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_field<T: ?Sized>(&mut self, key: &'static str, value: &T) -> Result<()>
+        where T: Serialize
+    {
+        ser::SerializeMap::serialize_entry(self, key, value)
+    }
+    #[inline]
+    fn end(self) -> Result<()> {
+        self.end_dict()
+    }
+}
+
+
+impl<'a, W: Write, F: Formatter> ser::SerializeStructVariant for Compound<'a, W, F> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_field<T: ?Sized>(&mut self, key: &'static str, value: &T) -> Result<()>
+        where T: Serialize
+    {
+        ser::SerializeMap::serialize_entry(self, key, value)
+    }
+    #[inline]
+    fn end(self) -> Result<()> {
+        self.end_dict()
+    }
 }
 
 
-impl<W: Write> Serializer<W, CompactFormatter> {
-    #[inline]
-    pub fn new(writer: W) -> Self {
-        Serializer::with_formatter(writer, CompactFormatter)
+// Checks whether a character can be part of a key at the given position.
+// Keys cannot start with a quote or a comment sign.
+#[inline]
+fn is_valid_key_char(index: usize, ch: char) -> bool {
+    if index == 0 && (ch == '"' || ch == '#') {
+        return false;
     }
+    !ch.is_ascii() || is_key_char(ch as u8)
 }
 
 
-impl<W: Write> Serializer<W> {
-    #[inline]
-    pub fn pretty(writer: W) -> Self {
-        Serializer::with_formatter(writer, PrettyFormatter::new())
+/// Checks whether a string can be written as a key as it is.
+#[doc(hidden)]
+pub fn check_key(key: &str) -> Result<()> {
+    if key.is_empty() {
+        return Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0));
+    }
+    match key.char_indices().find(|&(i, ch)| !is_valid_key_char(i, ch)) {
+        Some((_, ch)) => Err(Error::SyntaxError(ErrorCode::InvalidKeyChar(ch), 0, 0, 0)),
+        None => Ok(()),
     }
 }
 
 
-impl<W: Write, F: Formatter> Serializer<W, F> {
-    #[inline]
-    fn with_formatter(writer: W, format: F) -> Self {
-        Serializer {
-            writer: writer,
-            format: format,
-            first: false,
+fn sanitize_key(key: &str, policy: KeyPolicy) -> String {
+    let mut result = String::with_capacity(key.len());
+    for (index, ch) in key.char_indices() {
+        if is_valid_key_char(index, ch) && !(policy == KeyPolicy::PercentEncode && ch == '%') {
+            result.push(ch);
+        } else if policy == KeyPolicy::PercentEncode {
+            // Characters which need encoding are all ASCII.
+            result.push_str(&format!("%{:02X}", ch as u32));
+        } else {
+            result.push('_');
         }
     }
+    result
+}
+
+
+struct KeySerializer<'a> {
+    writer: &'a mut Write,
+    policy: KeyPolicy,
 }
 
 
-impl<W: Write, F: Formatter> ser::Serializer for Serializer<W, F> {
+impl<'a> ser::Serializer for KeySerializer<'a> {
+    type Ok = ();
     type Error = Error;
+    type SerializeSeq = ser::Impossible<(), Error>;
+    type SerializeTuple = ser::Impossible<(), Error>;
+    type SerializeTupleStruct = ser::Impossible<(), Error>;
+    type SerializeTupleVariant = ser::Impossible<(), Error>;
+    type SerializeMap = ser::Impossible<(), Error>;
+    type SerializeStruct = ser::Impossible<(), Error>;
+    type SerializeStructVariant = ser::Impossible<(), Error>;
 
-    fn visit_bool(&mut self, v: bool) -> Result<()> {
-        self.writer.write_all(if v { b"True" } else { b"False" }).map_err(From::from)
+    #[inline]
+    fn serialize_str(self, value: &str) -> Result<()> {
+        if value.is_empty() {
+            return Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0));
+        }
+        if self.policy != KeyPolicy::Error {
+            let key = sanitize_key(value, self.policy);
+            return self.writer.write_all(key.as_bytes()).map_err(From::from);
+        }
+        try!(check_key(value));
+        self.writer.write_all(value.as_bytes()).map_err(From::from)
     }
 
-    // Integers
-    fn visit_i64(&mut self, v: i64) -> Result<()> {
-        write!(self.writer, "{}", v).map_err(From::from)
+    #[inline]
+    fn serialize_char(self, value: char) -> Result<()> {
+        self.serialize_str(value.encode_utf8(&mut [0; 4]))
     }
-    fn visit_u64(&mut self, v: u64) -> Result<()> {
-        write!(self.writer, "{}", v).map_err(From::from)
+
+    #[inline]
+    fn serialize_unit_variant(self,
+                              _name: &'static str,
+                              _variant_index: u32,
+                              variant: &'static str) -> Result<()> {
+        self.serialize_str(variant)
     }
 
-    // Float
-    fn visit_f64(&mut self, v: f64) -> Result<()> {
-        if v.is_nan() || v.is_infinite() {
-            write!(self.writer, "{}", v).map_err(From::from)
-        } else {
-            let s = format!("{}", v);
-            try!(self.writer.write_all(s.as_bytes()));
-            if !s.contains(".") {
-                try!(self.writer.write_all(b".0"));
-            }
-            Ok(())
-        }
+    fn serialize_bool(self, _value: bool) -> Result<()> {
+        Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-    fn visit_str(&mut self, v: &str) -> Result<()> {
-        try!(self.writer.write_all(b"\""));
-        for ch in v.bytes() {
-            try!(match ch {
-                0x09 => self.writer.write_all(b"\\t"),
-                0x0A => self.writer.write_all(b"\\n"),
-                0x0D => self.writer.write_all(b"\\r"),
-                0x22 => self.writer.write_all(b"\\\""),
-                0x5C => self.writer.write_all(b"\\\\"),
-                ch if ch < 0xF => write!(self.writer, "\\0{:X}", ch),
-                ch if ch < 0x20 => write!(self.writer, "\\{:X}", ch),
-                ch => self.writer.write_all(&[ch]),
-            });
-        }
-        self.writer.write_all(b"\"").map_err(From::from)
+    fn serialize_i8(self, _value: i8) -> Result<()> {
+        Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-    fn visit_unit(&mut self) -> Result<()> {
-        Err(Error::SyntaxError(ErrorCode::UnrepresentableValue, 0, 0, 0))
+    fn serialize_i16(self, _value: i16) -> Result<()> {
+        Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-    fn visit_none(&mut self) -> Result<()> {
-        self.visit_unit()
+    fn serialize_i32(self, _value: i32) -> Result<()> {
+        Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-    fn visit_some<V>(&mut self, value: V) -> Result<()> where V: Serialize {
-        value.serialize(self)
+    fn serialize_i64(self, _value: i64) -> Result<()> {
+        Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-    fn visit_seq<V>(&mut self, mut visitor: V) -> Result<()> where V: SeqVisitor {
-        match visitor.len() {
-            Some(len) if len == 0 => self.writer.write_all(b"[]").map_err(From::from),
-            _ => {
-                try!(self.format.start_compound(&mut self.writer, b'['));
-                self.first = true;
-                while let Some(()) = try!(visitor.visit(self)) {}
-                self.format.end_compound(&mut self.writer, b']')
-            }
-        }
+    fn serialize_u8(self, _value: u8) -> Result<()> {
+        Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-    fn visit_seq_elt<T>(&mut self, value: T) -> Result<()> where T: Serialize {
-        try!(self.format.item_separator(&mut self.writer, self.first));
-        try!(value.serialize(self));
-        self.first = false;
-        Ok(())
+    fn serialize_u16(self, _value: u16) -> Result<()> {
+        Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-    fn visit_map<V>(&mut self, mut visitor: V) -> Result<()> where V: MapVisitor {
-        match visitor.len() {
-            Some(len) if len == 0 => self.writer.write_all(b"{}").map_err(From::from),
-            _ => {
-                try!(self.format.start_compound(&mut self.writer, b'{'));
-                self.first = true;
-                while let Some(()) = try!(visitor.visit(self)) {}
-                self.format.end_compound(&mut self.writer, b'}')
-            }
-        }
+    fn serialize_u32(self, _value: u32) -> Result<()> {
+        Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-    fn visit_map_elt<K, V>(&mut self, key: K, value: V) -> Result<()>
-        where K: Serialize, V: Serialize
-    {
-        try!(self.format.item_separator(&mut self.writer, self.first));
-        try!(key.serialize(&mut KeySerializer { serializer: self }));
-        try!(self.format.key_separator(&mut self.writer));
-        try!(value.serialize(self));
-        self.first = false;
-        Ok(())
+    fn serialize_u64(self, _value: u64) -> Result<()> {
+        Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-}
-
-
-struct KeySerializer<'a, W: 'a + Write, F: 'a + Formatter> {
-    serializer: &'a mut Serializer<W, F>,
-}
-
-
-impl<'a, W: Write, F: Formatter> ser::Serializer for KeySerializer<'a, W, F>
-{
-    type Error = Error;
-
-    #[inline]
-    fn visit_str(&mut self, value: &str) -> Result<()> {
-        // TODO: Check that all characters are valid
-        self.serializer.writer.write_all(value.as_bytes()).map_err(From::from)
+    fn serialize_i128(self, _value: i128) -> Result<()> {
+        Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-
-    fn visit_bool(&mut self, _value: bool) -> Result<()> {
+    fn serialize_u128(self, _value: u128) -> Result<()> {
+        Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
+    }
+    fn serialize_f32(self, _value: f32) -> Result<()> {
+        Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
+    }
+    fn serialize_f64(self, _value: f64) -> Result<()> {
         Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-    fn visit_i64(&mut self, _value: i64) -> Result<()> {
+    fn serialize_bytes(self, _value: &[u8]) -> Result<()> {
         Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-    fn visit_u64(&mut self, _value: u64) -> Result<()> {
+    fn serialize_none(self) -> Result<()> {
         Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-    fn visit_f64(&mut self, _value: f64) -> Result<()> {
+    fn serialize_some<T: ?Sized>(self, _value: &T) -> Result<()> where T: Serialize {
         Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-    fn visit_none(&mut self) -> Result<()> {
+    fn serialize_unit(self) -> Result<()> {
         Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-    fn visit_unit(&mut self) -> Result<()> {
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
         Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-    fn visit_some<V>(&mut self, _value: V) -> Result<()> where V: Serialize {
+    fn serialize_newtype_struct<T: ?Sized>(self, _name: &'static str, _value: &T) -> Result<()>
+        where T: Serialize
+    {
         Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-    fn visit_seq<V>(&mut self, _visitor: V) -> Result<()> where V: SeqVisitor {
+    fn serialize_newtype_variant<T: ?Sized>(self,
+                                            _name: &'static str,
+                                            _variant_index: u32,
+                                            _variant: &'static str,
+                                            _value: &T) -> Result<()>
+        where T: Serialize
+    {
         Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-    fn visit_seq_elt<T>(&mut self, _value: T) -> Result<()> where T: Serialize {
+    fn serialize_seq(self, _len: Option<usize>) -> Result<ser::Impossible<(), Error>> {
         Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-    fn visit_map<V>(&mut self, _visitor: V) -> Result<()> where V: MapVisitor {
+    fn serialize_tuple(self, _len: usize) -> Result<ser::Impossible<(), Error>> {
         Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-    fn visit_map_elt<K, V>(&mut self, _key: K, _value: V) -> Result<()>
-        where K: Serialize, V: Serialize
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize)
+        -> Result<ser::Impossible<(), Error>>
     {
         Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
-
-    fn format() -> &'static str {
-        "hipack"
+    fn serialize_tuple_variant(self,
+                               _name: &'static str,
+                               _variant_index: u32,
+                               _variant: &'static str,
+                               _len: usize) -> Result<ser::Impossible<(), Error>> {
+        Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
+    }
+    fn serialize_map(self, _len: Option<usize>) -> Result<ser::Impossible<(), Error>> {
+        Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
+    }
+    fn serialize_struct(self, _name: &'static str, _len: usize)
+        -> Result<ser::Impossible<(), Error>>
+    {
+        Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
+    }
+    fn serialize_struct_variant(self,
+                                _name: &'static str,
+                                _variant_index: u32,
+                                _variant: &'static str,
+                                _len: usize) -> Result<ser::Impossible<(), Error>> {
+        Err(Error::SyntaxError(ErrorCode::InvalidKey, 0, 0, 0))
     }
 }
 
@@ -308,6 +1862,14 @@ pub fn to_writer<W, T>(writer: &mut W, value: &T) -> Result<()>
     value.serialize(&mut serializer)
 }
 
+#[inline]
+pub fn to_writer_with_formatter<W, F, T>(writer: &mut W, formatter: F, value: &T) -> Result<()>
+    where W: Write, F: Formatter, T: Serialize
+{
+    let mut serializer = Serializer::with_formatter(writer, formatter);
+    value.serialize(&mut serializer)
+}
+
 #[inline]
 pub fn to_writer_pretty<W, T>(writer: &mut W, value: &T) -> Result<()>
     where W: Write, T: Serialize
@@ -316,6 +1878,39 @@ pub fn to_writer_pretty<W, T>(writer: &mut W, value: &T) -> Result<()>
     value.serialize(&mut serializer)
 }
 
+/// Serializes a value in canonical form, see `Serializer::canonical()`.
+#[inline]
+pub fn to_writer_canonical<W, T>(writer: &mut W, value: &T) -> Result<()>
+    where W: Write, T: Serialize
+{
+    let mut serializer = Serializer::canonical(writer);
+    value.serialize(&mut serializer)
+}
+
+const BATCH_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Serializes many values in compact form, one after another. Records are
+/// serialized into a shared buffer, which is written out in large chunks.
+pub fn to_writer_batch<'a, W, I, T>(writer: &mut W, values: I, separator: RecordSeparator)
+    -> Result<()>
+    where W: Write, I: IntoIterator<Item=&'a T>, T: 'a + Serialize
+{
+    let mut buf = Vec::with_capacity(BATCH_BUFFER_SIZE);
+    for value in values {
+        let start = buf.len();
+        try!(value.serialize(&mut Serializer::new(&mut buf)));
+        if separator == RecordSeparator::Frame && buf.get(start) != Some(&b'{') {
+            return Err(Error::SyntaxError(ErrorCode::UnrepresentableValue, 0, 0, 0));
+        }
+        buf.push(b'\n');
+        if buf.len() >= BATCH_BUFFER_SIZE {
+            try!(writer.write_all(&buf));
+            buf.clear();
+        }
+    }
+    writer.write_all(&buf).map_err(From::from)
+}
+
 #[inline]
 pub fn to_vec<T>(value: &T) -> Result<Vec<u8>>
     where T: Serialize
@@ -325,6 +1920,22 @@ pub fn to_vec<T>(value: &T) -> Result<Vec<u8>>
     Ok(writer)
 }
 
+/// Serializes a value in compact form, returning along with the output
+/// where each value nested in it was written, see `Serializer::spans()`.
+/// The top-level value has the empty path.
+pub fn to_vec_with_spans<T>(value: &T) -> Result<(Vec<u8>, BTreeMap<String, Range<usize>>)>
+    where T: Serialize
+{
+    let mut writer = Vec::new();
+    let mut spans = {
+        let mut serializer = Serializer::new(&mut writer).record_spans(true);
+        try!(value.serialize(&mut serializer));
+        serializer.take_spans().unwrap_or_default()
+    };
+    spans.insert(String::new(), 0..writer.len());
+    Ok((writer, spans))
+}
+
 #[inline]
 pub fn to_vec_pretty<T>(value: &T) -> Result<Vec<u8>>
     where T: Serialize
@@ -334,6 +1945,24 @@ pub fn to_vec_pretty<T>(value: &T) -> Result<Vec<u8>>
     Ok(writer)
 }
 
+#[inline]
+pub fn to_vec_with_formatter<F, T>(formatter: F, value: &T) -> Result<Vec<u8>>
+    where F: Formatter, T: Serialize
+{
+    let mut writer = Vec::new();
+    try!(to_writer_with_formatter(&mut writer, formatter, value));
+    Ok(writer)
+}
+
+#[inline]
+pub fn to_vec_canonical<T>(value: &T) -> Result<Vec<u8>>
+    where T: Serialize
+{
+    let mut writer = Vec::new();
+    try!(to_writer_canonical(&mut writer, value));
+    Ok(writer)
+}
+
 #[inline]
 pub fn to_string<T>(value: &T) -> Result<String>
     where T: Serialize
@@ -352,12 +1981,22 @@ pub fn to_string_pretty<T>(value: &T) -> Result<String>
     Ok(string)
 }
 
+#[inline]
+pub fn to_string_canonical<T>(value: &T) -> Result<String>
+    where T: Serialize
+{
+    let vec = try!(to_vec_canonical(value));
+    let string = try!(String::from_utf8(vec));
+    Ok(string)
+}
+
 
-#[cfg(test)]
+#[cfg(all(test, feature = "de"))]
 mod tests {
     use super::*;
     use std::collections::{HashMap, BTreeMap};
     use std::f64::{NAN, INFINITY};
+    use value::Value;
 
     #[test]
     fn test_empty_object() {
@@ -375,9 +2014,178 @@ mod tests {
     #[test]
     fn test_two_item_object() {
         let mut obj = BTreeMap::new();
-        obj.insert("pi", 3.14);
+        obj.insert("pi", 3.25);
         obj.insert("phi", 1.67);
-        assert_eq!("{phi:1.67,pi:3.14}", to_string(&obj).unwrap());
+        assert_eq!("{phi:1.67,pi:3.25}", to_string(&obj).unwrap());
+    }
+
+    #[test]
+    fn test_to_writer_batch() {
+        let values = vec![vec![1, 2], vec![], vec![3]];
+        let mut writer = Vec::new();
+        to_writer_batch(&mut writer, &values, RecordSeparator::Newline).unwrap();
+        assert_eq!("[1,2]\n[]\n[3]\n", String::from_utf8(writer).unwrap());
+
+        let mut writer = Vec::new();
+        to_writer_batch(&mut writer, &Vec::<u8>::new(), RecordSeparator::Newline).unwrap();
+        assert!(writer.is_empty());
+
+        let mut obj = BTreeMap::new();
+        obj.insert("a", 1);
+        let mut writer = Vec::new();
+        to_writer_batch(&mut writer, vec![&obj, &obj], RecordSeparator::Frame).unwrap();
+        assert_eq!("{a:1}\n{a:1}\n", String::from_utf8(writer).unwrap());
+        assert!(to_writer_batch(&mut Vec::new(), &values, RecordSeparator::Frame).is_err());
+    }
+
+    #[test]
+    fn test_custom_formatter() {
+        struct SpacedFormatter;
+
+        impl Formatter for SpacedFormatter {
+            fn start_compound<W: Write>(&mut self, writer: &mut W, ch: u8) -> Result<()> {
+                writer.write_all(&[ch, b' ']).map_err(From::from)
+            }
+            fn end_compound<W: Write>(&mut self, writer: &mut W, ch: u8) -> Result<()> {
+                writer.write_all(&[b' ', ch]).map_err(From::from)
+            }
+            fn key_separator<W: Write>(&mut self, writer: &mut W, _kind: ValueKind) -> Result<()> {
+                writer.write_all(b" : ").map_err(From::from)
+            }
+            fn item_separator<W: Write>(&mut self, writer: &mut W, first: bool) -> Result<()> {
+                writer.write_all(if first { b"" } else { b", " }).map_err(From::from)
+            }
+        }
+
+        let mut obj = BTreeMap::new();
+        obj.insert("a", vec![1, 2]);
+        obj.insert("b", vec![]);
+        let mut writer = Vec::new();
+        to_writer_with_formatter(&mut writer, SpacedFormatter, &obj).unwrap();
+        assert_eq!("{ a : [ 1, 2 ], b : [] }", String::from_utf8(writer).unwrap());
+    }
+
+    #[test]
+    fn test_indent() {
+        let mut obj = BTreeMap::new();
+        obj.insert("a", vec![1]);
+        let vec = to_vec_with_formatter(PrettyFormatter::with_indent("    "), &obj).unwrap();
+        assert_eq!("{\n    a [\n        1\n    ]\n}", String::from_utf8(vec).unwrap());
+
+        let mut writer = Vec::new();
+        obj.serialize(&mut Serializer::pretty(&mut writer).indent("\t")).unwrap();
+        assert_eq!("{\n\ta [\n\t\t1\n\t]\n}", String::from_utf8(writer).unwrap());
+    }
+
+    #[test]
+    fn test_recursion_limit() {
+        fn nested(depth: usize) -> Value {
+            (0..depth).fold(Value::U64(1), |value, _| Value::List(vec![value]))
+        }
+
+        let mut obj = BTreeMap::new();
+        obj.insert("a", nested(128));
+        assert!(to_string(&obj).is_ok());
+        obj.insert("a", nested(129));
+        match to_string(&obj) {
+            Err(Error::SyntaxError(ErrorCode::RecursionLimitExceeded, _, _, _)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(to_string(&obj.get("a").unwrap()).is_ok());
+        let mut writer = Vec::new();
+        assert!(obj.serialize(&mut Serializer::new(&mut writer).recursion_limit(129)).is_ok());
+
+        let mut writer = Vec::new();
+        assert!(obj.serialize(&mut Serializer::canonical(&mut writer)).is_err());
+        let mut writer = Vec::new();
+        assert!(obj.serialize(&mut Serializer::canonical(&mut writer).recursion_limit(129)).is_ok());
+    }
+
+    #[test]
+    fn test_fold_markers() {
+        let mut inner = BTreeMap::new();
+        inner.insert("c", BTreeMap::new());
+        inner.get_mut("c").unwrap().insert("d", 1);
+        let mut obj = BTreeMap::new();
+        obj.insert("a", inner.clone());
+        obj.insert("b", inner);
+        let mut writer = Vec::new();
+        obj.serialize(&mut Serializer::pretty(&mut writer).fold_markers(true).indent(" ")).unwrap();
+        let output = String::from_utf8(writer).unwrap();
+        assert_eq!("{\n a { # {{{\n  c {\n   d: 1\n  }\n } # }}}\n \
+                    b { # {{{\n  c {\n   d: 1\n  }\n } # }}}\n}", output);
+        let value: BTreeMap<String, BTreeMap<String, BTreeMap<String, u32>>> =
+            ::de::from_str(&output).unwrap();
+        assert_eq!(1, value["b"]["c"]["d"]);
+    }
+
+    #[test]
+    fn test_commented() {
+        #[derive(Serialize)]
+        struct Config {
+            port: Commented<u16>,
+            hosts: Commented<Vec<Commented<&'static str>>>,
+            debug: Commented<Option<bool>>,
+        }
+
+        let config = Commented::new("Generated file.", Config {
+            port: Commented::new("Port to listen on.\n\nPrivileged below 1024.", 8080),
+            hosts: Commented::new("", vec![Commented::new("Main", "example.org"),
+                                           Commented::new("", "example.com")]),
+            debug: Commented::new("Skipped", None),
+        });
+        assert_eq!("# Generated file.\n{\n  # Port to listen on.\n  #\n  # Privileged below 1024.\n  \
+                    port: 8080\n  hosts [\n    # Main\n    \"example.org\"\n    \"example.com\"\n  ]\n}",
+                   to_string_pretty(&config).unwrap());
+        let output = to_string(&config).unwrap();
+        assert_eq!("# Generated file.\n{# Port to listen on.\n#\n# Privileged below 1024.\n\
+                    port:8080,hosts:[# Main\n\"example.org\",\"example.com\"]}", output);
+        let value: Value = ::de::from_str(&output).unwrap();
+        assert_eq!(Value::U64(8080), value["port"]);
+        assert_eq!(Value::from("example.com"), value["hosts"][1]);
+        assert_eq!("{hosts:[\"example.org\",\"example.com\"],port:8080}",
+                   to_string_canonical(&config).unwrap());
+    }
+
+    #[test]
+    fn test_invalid_key() {
+        fn key_error(key: &str) -> ErrorCode {
+            let mut obj = BTreeMap::new();
+            obj.insert(key, 1);
+            match to_string(&obj) {
+                Err(Error::SyntaxError(code, _, _, _)) => code,
+                result => panic!("unexpected result {:?}", result),
+            }
+        }
+
+        assert_eq!(ErrorCode::InvalidKeyChar(' '), key_error("a b"));
+        assert_eq!(ErrorCode::InvalidKeyChar(':'), key_error("a:b"));
+        assert_eq!(ErrorCode::InvalidKeyChar('{'), key_error("{"));
+        assert_eq!(ErrorCode::InvalidKeyChar('#'), key_error("#a"));
+        assert_eq!(ErrorCode::InvalidKeyChar('"'), key_error("\"a"));
+        assert_eq!(ErrorCode::InvalidKey, key_error(""));
+        assert_eq!("{a#\"b:1,é:2}", to_string(&{
+            let mut obj = BTreeMap::new();
+            obj.insert("a#\"b", 1);
+            obj.insert("é", 2);
+            obj
+        }).unwrap());
+    }
+
+    #[test]
+    fn test_key_policy() {
+        fn to_string_keys<T: Serialize>(value: &T, policy: KeyPolicy) -> String {
+            let mut writer = Vec::new();
+            value.serialize(&mut Serializer::new(&mut writer).key_policy(policy)).unwrap();
+            String::from_utf8(writer).unwrap()
+        }
+
+        let mut obj = BTreeMap::new();
+        obj.insert("#a b:c", 1);
+        obj.insert("d%{é}", 2);
+        assert_eq!("{_a_b_c:1,d%_é_:2}", to_string_keys(&obj, KeyPolicy::Replace));
+        assert_eq!("{%23a%20b%3Ac:1,d%25%7Bé%7D:2}", to_string_keys(&obj, KeyPolicy::PercentEncode));
+        assert!(to_string(&obj).is_err());
     }
 
     macro_rules! make_write_test {
@@ -431,7 +2239,28 @@ mod tests {
                              string_non_empty, "foo bar", "\"foo bar\"",
                              string_unicode, "☺", "\"☺\"",
                              string_escapes, "\n\r\t\\\"", "\"\\n\\r\\t\\\\\\\"\"",
-                             string_hexcode, "\0", "\"\\00\"");
+                             string_hexcode, "\0", "\"\\00\"",
+                             string_hexcode_high, "\x0F\x1B", "\"\\0F\\1B\"",
+                             string_del_bidi, "\x7F\u{202E}", "\"\x7F\u{202E}\"",
+                             string_astral, "\u{1D11E}\u{1F600}", "\"\u{1D11E}\u{1F600}\"");
+
+    #[test]
+    fn test_escape_policy() {
+        let policy = EscapePolicy { tab: false, del: true, bidi: true };
+        let mut writer = Vec::new();
+        "\t\x7F\u{202E}é".serialize(&mut Serializer::new(&mut writer).escape_policy(policy)).unwrap();
+        assert_eq!("\"\t\\7F\\E2\\80\\AEé\"", String::from_utf8(writer).unwrap());
+
+        // Characters outside of the BMP next to escaped ones are kept whole.
+        let text = "\u{1F600}\u{202E}\u{1D11E}\x01\u{10FFFF}";
+        let mut writer = Vec::new();
+        text.serialize(&mut Serializer::new(&mut writer).escape_policy(policy)).unwrap();
+        let output = String::from_utf8(writer).unwrap();
+        assert_eq!("\"\u{1F600}\\E2\\80\\AE\u{1D11E}\\01\u{10FFFF}\"", output);
+        let value: BTreeMap<String, String> =
+            ::de::from_str(&format!("s: {}", output)).unwrap();
+        assert_eq!(text, value["s"]);
+    }
 
     macro_rules! make_write_number_tests {
         ($($name:ident, $value:expr, $expected:expr),+) => {
@@ -441,10 +2270,334 @@ mod tests {
 
     make_write_number_tests!(integer_zero, 0, "0",
                              integer_negative, -34, "-34");
+    make_write_number_tests!(integer_hex, Hex(255u8), "0xFF",
+                             integer_hex_negative, Hex(-16i32), "-0x10",
+                             integer_hex_min, Hex(i64::min_value()), "-0x8000000000000000",
+                             integer_octal, Octal(0o644), "0644",
+                             integer_octal_zero, Octal(0), "0");
+    make_write_number_tests!(integer_u128_max, u128::max_value(),
+                             "340282366920938463463374607431768211455",
+                             integer_i128_min, i128::min_value(),
+                             "-170141183460469231731687303715884105728",
+                             integer_hex_u128, Hex(1u128 << 64), "0x10000000000000000");
+    make_write_number_tests!(integer_i64_min, i64::min_value(), "-9223372036854775808");
+    make_write_test!(annotated_none, Annotated::new(1), "1", "1");
+    make_write_test!(annotated_one, Annotated::new("2015-01-01").annotate("timestamp"),
+                     ":timestamp \"2015-01-01\"", ":timestamp \"2015-01-01\"");
+    make_write_test!(annotated_dict, {
+            let mut b = BTreeMap::new();
+            b.insert("a", Annotated::new(vec![1]).annotate("x").annotate("y"));
+            b
+        }, "{\n  a: :x:y [\n    1\n  ]\n}", "{a::x:y [1]}");
+
+    #[test]
+    fn test_invalid_annotation() {
+        assert!(to_string(&Annotated::new(1).annotate("a b")).is_err());
+        assert!(to_string(&Annotated::new(1).annotate("")).is_err());
+    }
+
+    fn to_string_null<T: Serialize>(value: &T, strategy: NullStrategy) -> Result<String> {
+        let mut writer = Vec::new();
+        try!(value.serialize(&mut Serializer::new(&mut writer).null_strategy(strategy)));
+        Ok(String::from_utf8(writer).unwrap())
+    }
+
+    #[test]
+    fn test_null_strategy() {
+        let mut obj = BTreeMap::new();
+        obj.insert("a", vec![Some(1), None, Some(2)]);
+        obj.insert("b", vec![]);
+        let mut nested = BTreeMap::new();
+        nested.insert("x", None);
+        nested.insert("y", Some(obj));
+
+        let mut entries = BTreeMap::new();
+        entries.insert("x", None);
+        entries.insert("y", Some(vec![Some(1), Some(2)]));
+        assert_eq!("{y:[1,2]}", to_string(&entries).unwrap());
+        assert!(to_string_null(&entries, NullStrategy::Error).is_err());
+        // List items keep their positions.
+        assert!(to_string(&nested).is_err());
+        assert!(to_string(&vec![None, Some(1), None]).is_err());
+        assert!(to_string_null(&nested, NullStrategy::Error).is_err());
+        assert_eq!("{x:\"\",y:{a:[1,\"\",2],b:[]}}",
+                   to_string_null(&nested, NullStrategy::EmptyString).unwrap());
+        assert_eq!("{x::none {},y:{a:[1,:none {},2],b:[]}}",
+                   to_string_null(&nested, NullStrategy::AnnotatedDict).unwrap());
+        assert!(to_string_null(&(), NullStrategy::Skip).is_err());
+
+        // Dictionaries whose entries are all skipped are written as empty.
+        let mut empty = BTreeMap::new();
+        empty.insert("x", None::<u32>);
+        let mut outer = BTreeMap::new();
+        outer.insert("e", empty.clone());
+        assert_eq!("{}", to_string_pretty(&empty).unwrap());
+        assert_eq!("{\n  e {}\n}", to_string_pretty(&outer).unwrap());
+        assert_eq!("{e:{}}", to_string(&outer).unwrap());
+    }
+
+    #[test]
+    fn test_progress() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        let mut obj = BTreeMap::new();
+        obj.insert("a", vec![1, 2]);
+        obj.insert("b", vec![3]);
+        obj.insert("c", vec![]);
+
+        let reports = Rc::new(RefCell::new(Vec::new()));
+        let mut writer = Vec::new();
+        {
+            let reports = reports.clone();
+            let mut serializer = Serializer::new(&mut writer).progress(move |progress| {
+                reports.borrow_mut().push(progress);
+                true
+            });
+            obj.serialize(&mut serializer).unwrap();
+        }
+        assert_eq!("{a:[1,2],b:[3],c:[]}", String::from_utf8(writer).unwrap());
+        assert_eq!(vec![(8, 1), (14, 2), (19, 3)],
+                   reports.borrow().iter()
+                       .map(|p| (p.bytes_written, p.items_completed))
+                       .collect::<Vec<_>>());
+
+        let mut writer = Vec::new();
+        let mut serializer = Serializer::new(&mut writer).progress(|progress| {
+            progress.items_completed < 2
+        });
+        match obj.serialize(&mut serializer) {
+            Err(Error::Cancelled) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_cancellation() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let token = Arc::new(AtomicBool::new(false));
+        let mut writer = Vec::new();
+        {
+            let mut serializer = Serializer::new(&mut writer).cancellation(token.clone());
+            vec![1, 2].serialize(&mut serializer).unwrap();
+        }
+        assert_eq!("[1,2]", String::from_utf8(writer).unwrap());
+
+        token.store(true, Ordering::Relaxed);
+        let mut writer = Vec::new();
+        let mut serializer = Serializer::new(&mut writer).cancellation(token);
+        match vec![1, 2].serialize(&mut serializer) {
+            Err(Error::Cancelled) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_canonical() {
+        let inner = || {
+            let mut inner = HashMap::new();
+            inner.insert("z".to_string(), Hex(255u8));
+            inner.insert("b".to_string(), Hex(0u8));
+            inner
+        };
+        let mut map = HashMap::new();
+        map.insert("zz".to_string(), vec![inner()]);
+        map.insert("a".to_string(), Vec::new());
+        map.insert("Z".to_string(), vec![inner()]);
+        let expected = "{Z:[{b:0,z:255}],a:[],zz:[{b:0,z:255}]}";
+        assert_eq!(expected, to_string_canonical(&map).unwrap());
+
+        let mut map = BTreeMap::new();
+        map.insert("x".to_string(), -0.0);
+        map.insert("w".to_string(), 1e3);
+        assert_eq!("{w:1000.0,x:0.0}", to_string_canonical(&map).unwrap());
+    }
+
+    #[derive(Serialize)]
+    struct Server {
+        host: &'static str,
+        port: u16,
+        password: &'static str,
+    }
+
+    #[derive(Serialize)]
+    struct Config {
+        name: &'static str,
+        servers: Vec<Server>,
+        credentials: BTreeMap<&'static str, &'static str>,
+    }
+
+    #[test]
+    fn test_path_filters() {
+        let mut credentials = BTreeMap::new();
+        credentials.insert("token", "s3cr3t");
+        let config = Config {
+            name: "test",
+            servers: vec![
+                Server { host: "a", port: 80, password: "x" },
+                Server { host: "b", port: 81, password: "y" },
+            ],
+            credentials: credentials,
+        };
+        let write = |serializer: Serializer<&mut Vec<u8>, CompactFormatter>| {
+            let mut serializer = serializer;
+            config.serialize(&mut serializer).unwrap();
+        };
+
+        let mut buf = Vec::new();
+        write(Serializer::new(&mut buf).exclude("/credentials/**").exclude("**/password"));
+        assert_eq!("{name:\"test\",servers:[{host:\"a\",port:80},{host:\"b\",port:81}]}",
+                   String::from_utf8(buf).unwrap());
+
+        let mut buf = Vec::new();
+        write(Serializer::new(&mut buf).include("/servers/*/host").exclude("/servers/1"));
+        assert_eq!("{servers:[{host:\"a\"}]}", String::from_utf8(buf).unwrap());
+
+        let mut buf = Vec::new();
+        write(Serializer::canonical(&mut buf).include("/credentials").include("/name"));
+        assert_eq!("{credentials:{token:\"s3cr3t\"},name:\"test\"}",
+                   String::from_utf8(buf).unwrap());
+    }
+
+    fn at(text: &str, spans: &BTreeMap<String, ::std::ops::Range<usize>>, path: &str) -> String {
+        text[spans[path].clone()].to_string()
+    }
+
+    #[test]
+    fn test_spans() {
+        let mut credentials = BTreeMap::new();
+        credentials.insert("a/b", "s3cr3t");
+        let config = Config {
+            name: "test",
+            servers: vec![Server { host: "a", port: 80, password: "x" }],
+            credentials: credentials,
+        };
+        let (output, spans) = to_vec_with_spans(&config).unwrap();
+        let text = String::from_utf8(output).unwrap();
+        assert_eq!(text, at(&text, &spans, ""));
+        assert_eq!("\"test\"", at(&text, &spans, "/name"));
+        assert_eq!("{host:\"a\",port:80,password:\"x\"}", at(&text, &spans, "/servers/0"));
+        assert_eq!("80", at(&text, &spans, "/servers/0/port"));
+        assert_eq!("\"s3cr3t\"", at(&text, &spans, "/credentials/a~1b"));
+        assert_eq!(9, spans.len());
+
+        let mut buf = Vec::new();
+        let spans = {
+            let mut serializer = Serializer::canonical(&mut buf).record_spans(true);
+            config.serialize(&mut serializer).unwrap();
+            serializer.take_spans().unwrap()
+        };
+        let text = String::from_utf8(buf).unwrap();
+        assert_eq!("{host:\"a\",password:\"x\",port:80}", at(&text, &spans, "/servers/0"));
+        assert_eq!("80", at(&text, &spans, "/servers/0/port"));
+        assert_eq!("\"test\"", at(&text, &spans, "/name"));
+    }
+
+    // Can be serialized only once, like the values which transcoders from
+    // other formats pass to serializers.
+    struct Once<T>(::std::cell::RefCell<Option<T>>);
+
+    impl<T: Serialize> Serialize for Once<T> {
+        fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+            where S: ser::Serializer
+        {
+            self.0.borrow_mut().take().expect("serialized twice").serialize(serializer)
+        }
+    }
+
+    #[test]
+    fn test_serialize_once() {
+        fn entries() -> BTreeMap<&'static str, Once<Option<Value>>> {
+            let mut dict = ::value::Map::new();
+            dict.insert("e".to_string(), Value::from("f"));
+            let mut entries = BTreeMap::new();
+            for (key, value) in vec![("a", Some(Value::List(vec![Value::U64(1), Value::U64(2)]))),
+                                     ("b", Some(Value::Annotated(vec!["x".to_string()],
+                                                                 Box::new(Value::U64(1))))),
+                                     ("c", None),
+                                     ("d", Some(Value::Dict(dict.clone())))] {
+                entries.insert(key, Once(::std::cell::RefCell::new(Some(value))));
+            }
+            entries
+        }
+        assert_eq!("{a:[1,2],b::x 1,d:{e:\"f\"}}", to_string(&entries()).unwrap());
+        assert_eq!("{a:[1,2],b::x 1,d:{e:\"f\"}}", to_string_canonical(&entries()).unwrap());
+        assert_eq!("{\n  a [\n    1\n    2\n  ]\n  b: :x 1\n  d {\n    e: \"f\"\n  }\n}",
+                   to_string_pretty(&entries()).unwrap());
+
+        let mut buf = Vec::new();
+        entries().serialize(&mut Serializer::new(&mut buf).include("/*/e")).unwrap();
+        assert_eq!("{a:[],b::x 1,d:{e:\"f\"}}", String::from_utf8(buf).unwrap());
+    }
+
+    #[derive(Clone, Copy, Serialize)]
+    enum Shape {
+        Point,
+        Circle(f64),
+        Rect(u32, u32),
+        Named { id: u32 },
+    }
+
+    #[test]
+    fn test_enum() {
+        let shapes = vec![Shape::Point, Shape::Circle(1.5), Shape::Rect(2, 3),
+                          Shape::Named { id: 4 }];
+        assert_eq!("[\"Point\",{Circle:1.5},{Rect:[2,3]},{Named:{id:4}}]",
+                   to_string(&shapes).unwrap());
+
+        let mut writer = Vec::new();
+        shapes.serialize(&mut Serializer::new(&mut writer).enum_style(EnumStyle::Annotated)).unwrap();
+        assert_eq!("[\"Point\",:Circle 1.5,:Rect [2,3],:Named {id:4}]",
+                   String::from_utf8(writer).unwrap());
+
+        let mut map = BTreeMap::new();
+        map.insert("a", Shape::Point);
+        map.insert("b", Shape::Rect(1, 1));
+        assert_eq!("{\n  a: \"Point\"\n  b {\n    Rect [\n      1\n      1\n    ]\n  }\n}",
+                   to_string_pretty(&map).unwrap());
+    }
+
+    #[test]
+    fn test_bytes() {
+        // Serialized as bytes, like serde_bytes::Bytes.
+        struct Bytes<'a>(&'a [u8]);
+
+        impl<'a> Serialize for Bytes<'a> {
+            fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+                where S: ser::Serializer
+            {
+                serializer.serialize_bytes(self.0)
+            }
+        }
+
+        let mut map = BTreeMap::new();
+        map.insert("a", Bytes(&b"hi\"\xFF"[..]));
+        assert_eq!("{a:[104,105,34,255]}", to_string(&map).unwrap());
+        for &(encoding, expected) in &[(BytesEncoding::Hex, "{a:\"hi\\22\\FF\"}"),
+                                       (BytesEncoding::Base64, "{a::base64 \"aGki/w==\"}")] {
+            let mut writer = Vec::new();
+            map.serialize(&mut Serializer::new(&mut writer).bytes_encoding(encoding)).unwrap();
+            assert_eq!(expected, String::from_utf8(writer).unwrap());
+        }
+        assert_eq!("{\n  a: :base64 \"aGki/w==\"\n}",
+                   String::from_utf8({
+                       let mut writer = Vec::new();
+                       map.serialize(&mut Serializer::pretty(&mut writer)
+                                     .bytes_encoding(BytesEncoding::Base64)).unwrap();
+                       writer
+                   }).unwrap());
+    }
+
     make_write_number_tests!(float_zero, 0.0, "0.0",
                              float_suffix, 1f64, "1.0",
                              float_positive, 4.5, "4.5",
                              float_negative, -3.2, "-3.2",
                              float_nan, NAN, "NaN",
-                             float_infinite, INFINITY, "inf");
+                             float_infinite, INFINITY, "inf",
+                             float_infinite_negative, -INFINITY, "-inf",
+                             float_fraction, 0.1, "0.1",
+                             float_exponent, 1e100, "1e100",
+                             float_exponent_negative, -1.5e-9, "-1.5e-9");
 }
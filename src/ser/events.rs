@@ -0,0 +1,380 @@
+//
+// events.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use std::io::Write;
+use std::mem;
+use serde::ser::{self, Serialize};
+use error::{Result, Error, ErrorCode};
+use parser::is_key_char;
+use super::{CompactFormatter, Formatter, KeySerializer, PendingItem, PrettyFormatter, Serializer,
+            ValueKind};
+
+
+// Dictionary or list being written.
+struct Level {
+    dict: bool,
+    // Whether no item has been written yet, in which case the opening
+    // brace or bracket is still pending.
+    first: bool,
+}
+
+
+/// Writes HiPack from a sequence of calls, the counterpart of the events
+/// of `pull::PullParser`, for data which is produced incrementally and
+/// has no `Serialize` implementation:
+///
+/// ```
+/// # use serde_hipack::error::Result;
+/// # use serde_hipack::ser::EventWriter;
+/// # fn run() -> Result<()> {
+/// let mut writer = EventWriter::new(Vec::new());
+/// try!(writer.begin_dict());
+/// try!(writer.key("ports"));
+/// try!(writer.begin_list());
+/// try!(writer.value_u64(80));
+/// try!(writer.end_list());
+/// try!(writer.end_dict());
+/// let output = try!(writer.finish());
+/// assert_eq!(output, b"{ports:[80]}");
+/// # Ok(())
+/// # }
+/// # fn main() { run().unwrap() }
+/// ```
+///
+/// Calls which would produce invalid HiPack fail, e.g. a value in a
+/// dictionary without a key fails with `ErrorCode::ExpectedKey`, and
+/// closing a list with `end_dict()` with `ErrorCode::MismatchedDelimiter`.
+/// The top-level value must be a dictionary. Scalars are written by a
+/// `Serializer`, whose options apply as well.
+pub struct EventWriter<W: Write, F=CompactFormatter> {
+    ser: Serializer<W, F>,
+    stack: Vec<Level>,
+    // Key of the next entry, as written.
+    key: Option<Vec<u8>>,
+    annotations: Vec<String>,
+    comment: Option<String>,
+    done: bool,
+}
+
+
+impl<W: Write> EventWriter<W, CompactFormatter> {
+    #[inline]
+    pub fn new(writer: W) -> Self {
+        EventWriter::from_serializer(Serializer::new(writer))
+    }
+}
+
+
+impl<W: Write> EventWriter<W, PrettyFormatter> {
+    #[inline]
+    pub fn pretty(writer: W) -> Self {
+        EventWriter::from_serializer(Serializer::pretty(writer))
+    }
+}
+
+
+impl<W: Write, F: Formatter> EventWriter<W, F> {
+    #[inline]
+    pub fn with_formatter(writer: W, format: F) -> Self {
+        EventWriter::from_serializer(Serializer::with_formatter(writer, format))
+    }
+
+    /// Writes using a configured `Serializer`, e.g. with a `KeyPolicy` or
+    /// an `EscapePolicy`. Path filters and spans do not apply.
+    #[inline]
+    pub fn from_serializer(ser: Serializer<W, F>) -> Self {
+        EventWriter { ser: ser, stack: Vec::new(), key: None, annotations: Vec::new(),
+                      comment: None, done: false }
+    }
+
+    /// Checks that the top-level dictionary has been closed, and unwraps
+    /// the writer.
+    pub fn finish(self) -> Result<W> {
+        if !self.done {
+            return Err(Error::SyntaxError(ErrorCode::UnexpectedEof, 0, 0, 0));
+        }
+        Ok(self.ser.writer.inner)
+    }
+
+    pub fn begin_dict(&mut self) -> Result<()> {
+        // The top-level dictionary is the only value without a key.
+        if !self.stack.is_empty() || self.done || !self.annotations.is_empty() {
+            try!(self.start_value(ValueKind::Compound));
+        } else if let Some(text) = self.comment.take() {
+            try!(self.ser.format.comment(&mut self.ser.writer, &text));
+        }
+        self.stack.push(Level { dict: true, first: true });
+        Ok(())
+    }
+
+    pub fn end_dict(&mut self) -> Result<()> {
+        self.end_compound(true)
+    }
+
+    pub fn begin_list(&mut self) -> Result<()> {
+        try!(self.start_value(ValueKind::Compound));
+        self.stack.push(Level { dict: false, first: true });
+        Ok(())
+    }
+
+    pub fn end_list(&mut self) -> Result<()> {
+        self.end_compound(false)
+    }
+
+    /// Sets the key of the next entry of the dictionary being written.
+    pub fn key(&mut self, key: &str) -> Result<()> {
+        match self.stack.last() {
+            Some(&Level { dict: true, .. }) if self.key.is_none() && self.annotations.is_empty() => (),
+            _ => return Err(Error::SyntaxError(ErrorCode::ExpectedValue, 0, 0, 0)),
+        }
+        let mut buf = Vec::new();
+        try!(ser::Serializer::serialize_str(KeySerializer { writer: &mut buf,
+                                                            policy: self.ser.keys },
+                                            key));
+        self.key = Some(buf);
+        Ok(())
+    }
+
+    /// Adds a comment above the next entry or item, or at the start of the
+    /// output before the top-level dictionary. Calling it before `key()`
+    /// or after it makes no difference.
+    pub fn comment(&mut self, text: &str) {
+        match self.comment {
+            Some(ref mut comment) => {
+                comment.push('\n');
+                comment.push_str(text);
+            },
+            None => self.comment = Some(text.to_string()),
+        }
+    }
+
+    /// Adds an annotation to the next value.
+    pub fn annotation(&mut self, annotation: &str) -> Result<()> {
+        if annotation.is_empty() || !annotation.bytes().all(is_key_char) {
+            return Err(Error::SyntaxError(ErrorCode::InvalidAnnotation, 0, 0, 0));
+        }
+        self.annotations.push(annotation.to_string());
+        Ok(())
+    }
+
+    #[inline]
+    pub fn value_bool(&mut self, value: bool) -> Result<()> {
+        self.value(&value)
+    }
+
+    #[inline]
+    pub fn value_i64(&mut self, value: i64) -> Result<()> {
+        self.value(&value)
+    }
+
+    #[inline]
+    pub fn value_u64(&mut self, value: u64) -> Result<()> {
+        self.value(&value)
+    }
+
+    #[inline]
+    pub fn value_f64(&mut self, value: f64) -> Result<()> {
+        self.value(&value)
+    }
+
+    #[inline]
+    pub fn value_str(&mut self, value: &str) -> Result<()> {
+        self.value(value)
+    }
+
+    /// Writes any value, including dictionaries and lists. Null values
+    /// are handled by the `NullStrategy`, and skipped entries leave out
+    /// their key, annotations and comment as well.
+    pub fn value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        let (first, dict) = try!(self.next_item());
+        // What goes before the value is written by the Serializer once the
+        // value starts, so rejected and skipped null values write nothing.
+        self.ser.pending = Some(PendingItem {
+            first: first,
+            entry: dict,
+            descend: false,
+            open: if first { Some(if dict { b'{' } else { b'[' }) } else { None },
+            key: self.key.take(),
+            comment: self.comment.take(),
+            annotations: mem::replace(&mut self.annotations, Vec::new()),
+        });
+        let result = value.serialize(&mut self.ser);
+        let skipped = self.ser.pending.take().is_some();
+        try!(result);
+        if !skipped {
+            if let Some(level) = self.stack.last_mut() {
+                level.first = false;
+            }
+        }
+        Ok(())
+    }
+
+    // Checks that an item may be written in the compound being written,
+    // and returns whether it is its first item, and whether it is a
+    // dictionary.
+    fn next_item(&self) -> Result<(bool, bool)> {
+        match self.stack.last() {
+            Some(level) if level.dict && self.key.is_none() => {
+                Err(Error::SyntaxError(ErrorCode::ExpectedKey, 0, 0, 0))
+            },
+            Some(level) => Ok((level.first, level.dict)),
+            None if self.done => Err(Error::SyntaxError(ErrorCode::TrailingCharacters, 0, 0, 0)),
+            None => Err(Error::SyntaxError(ErrorCode::UnrepresentableValue, 0, 0, 0)),
+        }
+    }
+
+    // Writes what goes before a dictionary or list: the opening of the
+    // enclosing compound before its first item, the item separator, the
+    // key of dictionary entries, and annotations.
+    fn start_value(&mut self, kind: ValueKind) -> Result<()> {
+        let (first, dict) = try!(self.next_item());
+        let kind = if self.annotations.is_empty() { kind } else { ValueKind::Annotated };
+        if first {
+            try!(self.ser.format.start_compound(&mut self.ser.writer, if dict { b'{' } else { b'[' }));
+        }
+        try!(self.ser.format.item_separator(&mut self.ser.writer, first));
+        if let Some(level) = self.stack.last_mut() {
+            level.first = false;
+        }
+        if let Some(text) = self.comment.take() {
+            try!(self.ser.format.comment(&mut self.ser.writer, &text));
+        }
+        if let Some(key) = self.key.take() {
+            try!(self.ser.writer.write_all(&key));
+            try!(self.ser.format.key_separator(&mut self.ser.writer, kind));
+        }
+        let annotations = mem::replace(&mut self.annotations, Vec::new());
+        if !annotations.is_empty() {
+            for annotation in &annotations {
+                try!(self.ser.write_annotation(annotation));
+            }
+            try!(self.ser.writer.write_all(b" "));
+        }
+        Ok(())
+    }
+
+    fn end_compound(&mut self, dict: bool) -> Result<()> {
+        if self.key.is_some() || !self.annotations.is_empty() || self.comment.is_some() {
+            return Err(Error::SyntaxError(ErrorCode::ExpectedValue, 0, 0, 0));
+        }
+        // The open compound is kept on a mismatch, so it can still be closed.
+        match self.stack.last() {
+            Some(level) if level.dict == dict => (),
+            _ => return Err(Error::SyntaxError(ErrorCode::MismatchedDelimiter, 0, 0, 0)),
+        }
+        let level = self.stack.pop().unwrap();
+        let (open, close) = if dict { (b'{', b'}') } else { (b'[', b']') };
+        if self.stack.is_empty() {
+            self.done = true;
+        }
+        if level.first {
+            self.ser.writer.write_all(&[open, close]).map_err(From::from)
+        } else {
+            self.ser.format.end_compound(&mut self.ser.writer, close)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::EventWriter;
+    use ser::{Commented, NullStrategy, Serializer};
+    use error::{Error, ErrorCode};
+
+    fn code(err: Error) -> ErrorCode {
+        match err {
+            Error::SyntaxError(code, ..) => code,
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_event_writer() {
+        let mut writer = EventWriter::new(Vec::new());
+        writer.begin_dict().unwrap();
+        writer.key("name").unwrap();
+        writer.value_str("web").unwrap();
+        writer.key("ports").unwrap();
+        writer.begin_list().unwrap();
+        writer.value_u64(80).unwrap();
+        writer.annotation("tls").unwrap();
+        writer.value_i64(443).unwrap();
+        writer.end_list().unwrap();
+        writer.key("empty").unwrap();
+        writer.begin_dict().unwrap();
+        writer.end_dict().unwrap();
+        writer.key("skipped").unwrap();
+        writer.value(&None::<u32>).unwrap();
+        writer.key("ratio").unwrap();
+        writer.value_f64(0.5).unwrap();
+        writer.end_dict().unwrap();
+        assert_eq!("{name:\"web\",ports:[80,:tls 443],empty:{},ratio:0.5}",
+                   String::from_utf8(writer.finish().unwrap()).unwrap());
+
+        let mut writer = EventWriter::pretty(Vec::new());
+        writer.begin_dict().unwrap();
+        writer.key("a").unwrap();
+        writer.begin_list().unwrap();
+        writer.value_bool(true).unwrap();
+        writer.end_list().unwrap();
+        writer.end_dict().unwrap();
+        assert_eq!("{\n  a [\n    True\n  ]\n}", String::from_utf8(writer.finish().unwrap()).unwrap());
+
+        let mut writer = EventWriter::new(Vec::new());
+        writer.comment("Top");
+        writer.begin_dict().unwrap();
+        writer.key("a").unwrap();
+        writer.comment("One");
+        writer.value_u64(1).unwrap();
+        writer.key("b").unwrap();
+        writer.value(&Commented::new("Two", 2)).unwrap();
+        writer.end_dict().unwrap();
+        assert_eq!("# Top\n{# One\na:1,# Two\nb:2}", String::from_utf8(writer.finish().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_event_writer_errors() {
+        let mut writer = EventWriter::new(Vec::new());
+        assert_eq!(ErrorCode::UnrepresentableValue, code(writer.value_u64(1).unwrap_err()));
+        writer.begin_dict().unwrap();
+        assert_eq!(ErrorCode::ExpectedKey, code(writer.value_u64(1).unwrap_err()));
+        assert_eq!(ErrorCode::InvalidKeyChar(' '), code(writer.key("a b").unwrap_err()));
+        assert_eq!(ErrorCode::InvalidAnnotation, code(writer.annotation("").unwrap_err()));
+        writer.key("a").unwrap();
+        assert_eq!(ErrorCode::ExpectedValue, code(writer.key("b").unwrap_err()));
+        assert_eq!(ErrorCode::ExpectedValue, code(writer.end_dict().unwrap_err()));
+        writer.begin_list().unwrap();
+        assert_eq!(ErrorCode::ExpectedValue, code(writer.key("c").unwrap_err()));
+        assert_eq!(ErrorCode::MismatchedDelimiter, code(writer.end_dict().unwrap_err()));
+        writer.end_list().unwrap();
+        writer.end_dict().unwrap();
+        assert_eq!(b"{a:[]}".to_vec(), writer.finish().unwrap());
+
+        // Null list items are not skipped, which would shift the others,
+        // and rejected null values write nothing.
+        let mut writer = EventWriter::new(Vec::new());
+        writer.begin_dict().unwrap();
+        writer.key("a").unwrap();
+        writer.begin_list().unwrap();
+        assert_eq!(ErrorCode::UnrepresentableValue, code(writer.value(&None::<u32>).unwrap_err()));
+        writer.value_u64(1).unwrap();
+        writer.end_list().unwrap();
+        writer.end_dict().unwrap();
+        assert_eq!(b"{a:[1]}".to_vec(), writer.finish().unwrap());
+
+        let ser = Serializer::new(Vec::new()).null_strategy(NullStrategy::Error);
+        let mut writer = EventWriter::from_serializer(ser);
+        writer.begin_dict().unwrap();
+        writer.key("a").unwrap();
+        writer.annotation("x").unwrap();
+        assert_eq!(ErrorCode::UnrepresentableValue, code(writer.value(&None::<u32>).unwrap_err()));
+        writer.key("b").unwrap();
+        writer.value_u64(2).unwrap();
+        writer.end_dict().unwrap();
+        assert_eq!(b"{b:2}".to_vec(), writer.finish().unwrap());
+    }
+}
@@ -0,0 +1,156 @@
+//
+// filter.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+// Matching of the paths of values against the include and exclude
+// patterns given to a Serializer.
+
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Key(String),
+    // Matches a single key or position.
+    Any,
+    // Matches any number of keys or positions, including none.
+    AnyDepth,
+}
+
+
+#[derive(Clone, Debug)]
+struct Pattern(Vec<Segment>);
+
+impl Pattern {
+    fn parse(pattern: &str) -> Pattern {
+        if pattern.is_empty() {
+            return Pattern(Vec::new());
+        }
+        let pattern = if pattern.starts_with('/') { &pattern[1..] } else { pattern };
+        Pattern(pattern.split('/').map(|token| match token {
+            "*" => Segment::Any,
+            "**" => Segment::AnyDepth,
+            _ => Segment::Key(token.replace("~1", "/").replace("~0", "~")),
+        }).collect())
+    }
+
+    fn matches(&self, path: &[String]) -> bool {
+        matches(&self.0, path)
+    }
+
+    // Whether the pattern may match some value nested inside the one at
+    // the path, which then needs to be written for the match to be.
+    fn matches_below(&self, path: &[String]) -> bool {
+        let mut segments = self.0.iter();
+        for component in path {
+            match segments.next() {
+                None => return false,
+                Some(&Segment::AnyDepth) => return true,
+                Some(&Segment::Any) => (),
+                Some(&Segment::Key(ref key)) if key == component => (),
+                Some(&Segment::Key(_)) => return false,
+            }
+        }
+        segments.next().is_some()
+    }
+}
+
+fn matches(segments: &[Segment], path: &[String]) -> bool {
+    match segments.split_first() {
+        None => path.is_empty(),
+        Some((&Segment::AnyDepth, rest)) => {
+            (0..path.len() + 1).any(|skip| matches(rest, &path[skip..]))
+        },
+        Some((segment, rest)) => match path.split_first() {
+            None => false,
+            Some((component, path)) => {
+                let ok = match *segment {
+                    Segment::Key(ref key) => key == component,
+                    _ => true,
+                };
+                ok && matches(rest, path)
+            },
+        },
+    }
+}
+
+
+/// What to do with a value, depending on its path.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+    /// The value is written.
+    Write,
+    /// The value is written if it is a dictionary or list, as some of
+    /// its items may be included.
+    Descend,
+    /// The value is left out.
+    Skip,
+}
+
+
+#[derive(Clone, Debug, Default)]
+pub struct PathFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl PathFilter {
+    pub fn include(&mut self, pattern: &str) {
+        self.include.push(Pattern::parse(pattern));
+    }
+
+    pub fn exclude(&mut self, pattern: &str) {
+        self.exclude.push(Pattern::parse(pattern));
+    }
+
+    pub fn decide(&self, path: &[String]) -> Decision {
+        if self.exclude.iter().any(|pattern| pattern.matches(path)) {
+            return Decision::Skip;
+        }
+        if self.include.is_empty() {
+            return Decision::Write;
+        }
+        // Values nested inside an included one are included as well.
+        let included = (0..path.len() + 1).any(|len| {
+            self.include.iter().any(|pattern| pattern.matches(&path[..len]))
+        });
+        if included {
+            Decision::Write
+        } else if self.include.iter().any(|pattern| pattern.matches_below(path)) {
+            Decision::Descend
+        } else {
+            Decision::Skip
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Decision, PathFilter};
+
+    fn path(components: &[&str]) -> Vec<String> {
+        components.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn test_decide() {
+        let mut filter = PathFilter::default();
+        filter.include("/servers/*/host");
+        filter.include("/name");
+        filter.exclude("/servers/1/**");
+        assert_eq!(Decision::Write, filter.decide(&path(&["name"])));
+        assert_eq!(Decision::Descend, filter.decide(&path(&["servers"])));
+        assert_eq!(Decision::Descend, filter.decide(&path(&["servers", "0"])));
+        assert_eq!(Decision::Write, filter.decide(&path(&["servers", "0", "host"])));
+        assert_eq!(Decision::Skip, filter.decide(&path(&["servers", "0", "port"])));
+        assert_eq!(Decision::Skip, filter.decide(&path(&["servers", "1"])));
+        assert_eq!(Decision::Skip, filter.decide(&path(&["version"])));
+
+        let mut filter = PathFilter::default();
+        filter.exclude("**/pass~1word");
+        assert_eq!(Decision::Skip, filter.decide(&path(&["pass/word"])));
+        assert_eq!(Decision::Skip, filter.decide(&path(&["a", "b", "pass/word"])));
+        assert_eq!(Decision::Write, filter.decide(&path(&["a", "password"])));
+    }
+}
@@ -0,0 +1,729 @@
+//
+// fixed.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use std::io::Write;
+use std::mem;
+use serde::ser::{self, Serialize};
+use error::{Result, Error, ErrorCode};
+use super::{EscapePolicy, KeyPolicy, KeySerializer, ANNOTATIONS,
+            ANNOTATED_VALUE_NAME, COMMENTED_NAME, HEX_NAME, OCTAL_NAME, RAW_VALUE_NAME, NUMBER_NAME,
+            take_raw_text, write_f64, write_integer, write_str};
+
+
+/// Serializer for devices with little memory, e.g. microcontrollers,
+/// which writes values as it goes and whose state lives on the stack.
+/// Lists and dictionaries may be nested at most `DEPTH` levels, which
+/// bounds the stack used while serializing: deeper values fail with
+/// `ErrorCode::RecursionLimitExceeded`.
+///
+/// The output is compact, and the options of `Serializer` are fixed to
+/// their defaults: null items and entries are skipped, enum variants
+/// with data are written as dictionaries, byte strings as lists, and
+/// keys must be valid. The comments of `Commented` values are left out.
+///
+/// Serializing does not allocate memory, except for `Annotated` values,
+/// whose annotations are copied when they are serialized, and for the
+/// messages of errors.
+pub struct FixedSerializer<W: Write, const DEPTH: usize> {
+    writer: W,
+    depth: usize,
+    radix: u32,
+}
+
+
+impl<W: Write, const DEPTH: usize> FixedSerializer<W, DEPTH> {
+    #[inline]
+    pub fn new(writer: W) -> Self {
+        FixedSerializer { writer: writer, depth: 0, radix: 10 }
+    }
+
+    /// Unwraps the writer.
+    #[inline]
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn start_compound(&mut self, ch: u8, variant: bool) -> Result<FixedCompound<'_, W, DEPTH>> {
+        if self.depth >= DEPTH {
+            return Err(Error::SyntaxError(ErrorCode::RecursionLimitExceeded, 0, 0, 0));
+        }
+        self.depth += 1;
+        try!(self.writer.write_all(&[ch]));
+        let close = if ch == b'[' { b']' } else { b'}' };
+        Ok(FixedCompound { ser: self, close: close, first: true, variant: variant })
+    }
+
+    // Writes the key of the dictionary which holds the data of a variant.
+    fn start_variant(&mut self, variant: &'static str) -> Result<()> {
+        try!(self.writer.write_all(b"{"));
+        try!(ser::Serializer::serialize_str(KeySerializer { writer: &mut self.writer,
+                                                            policy: KeyPolicy::Error },
+                                            variant));
+        self.writer.write_all(b":").map_err(From::from)
+    }
+}
+
+
+impl<'a, W: Write, const DEPTH: usize> ser::Serializer for &'a mut FixedSerializer<W, DEPTH> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = FixedCompound<'a, W, DEPTH>;
+    type SerializeTuple = FixedCompound<'a, W, DEPTH>;
+    type SerializeTupleStruct = FixedCompound<'a, W, DEPTH>;
+    type SerializeTupleVariant = FixedCompound<'a, W, DEPTH>;
+    type SerializeMap = FixedCompound<'a, W, DEPTH>;
+    type SerializeStruct = FixedCompound<'a, W, DEPTH>;
+    type SerializeStructVariant = FixedCompound<'a, W, DEPTH>;
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.writer.write_all(if v { b"True" } else { b"False" }).map_err(From::from)
+    }
+
+    // Integers
+    #[inline]
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.serialize_i128(v as i128)
+    }
+    #[inline]
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.serialize_i128(v as i128)
+    }
+    #[inline]
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.serialize_i128(v as i128)
+    }
+    #[inline]
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        self.serialize_i128(v as i128)
+    }
+    #[inline]
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.serialize_u128(v as u128)
+    }
+    #[inline]
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.serialize_u128(v as u128)
+    }
+    #[inline]
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.serialize_u128(v as u128)
+    }
+    #[inline]
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        self.serialize_u128(v as u128)
+    }
+    fn serialize_i128(self, v: i128) -> Result<()> {
+        if v >= 0 {
+            self.serialize_u128(v as u128)
+        } else {
+            try!(self.writer.write_all(b"-"));
+            self.serialize_u128((v as u128).wrapping_neg())
+        }
+    }
+    fn serialize_u128(self, v: u128) -> Result<()> {
+        match self.radix {
+            16 => write!(self.writer, "0x{:X}", v),
+            8 if v != 0 => write!(self.writer, "0{:o}", v),
+            _ => write_integer(&mut self.writer, v),
+        }.map_err(From::from)
+    }
+
+    // Float
+    #[inline]
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        self.serialize_f64(v as f64)
+    }
+    #[inline]
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        write_f64(&mut self.writer, v).map_err(From::from)
+    }
+
+    #[inline]
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+    #[inline]
+    fn serialize_str(self, v: &str) -> Result<()> {
+        write_str(&mut self.writer, v, EscapePolicy::default()).map_err(From::from)
+    }
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        let mut seq = try!(self.serialize_seq(Some(v.len())));
+        for byte in v {
+            try!(ser::SerializeSeq::serialize_element(&mut seq, byte));
+        }
+        ser::SerializeSeq::end(seq)
+    }
+    fn serialize_unit(self) -> Result<()> {
+        Err(Error::SyntaxError(ErrorCode::UnrepresentableValue, 0, 0, 0))
+    }
+    #[inline]
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        self.serialize_unit()
+    }
+    #[inline]
+    fn serialize_none(self) -> Result<()> {
+        self.serialize_unit()
+    }
+    #[inline]
+    fn serialize_some<T: ?Sized>(self, value: &T) -> Result<()> where T: Serialize {
+        value.serialize(self)
+    }
+    fn serialize_newtype_struct<T: ?Sized>(self, name: &'static str, value: &T) -> Result<()>
+        where T: Serialize
+    {
+        let radix = match name {
+            HEX_NAME => 16,
+            OCTAL_NAME => 8,
+            ANNOTATED_VALUE_NAME => {
+                let annotations = ANNOTATIONS.with(|cell| cell.borrow_mut().take());
+                for annotation in annotations.iter().flat_map(|annotations| annotations) {
+                    if annotation.is_empty() || !annotation.bytes().all(super::is_key_char) {
+                        return Err(Error::SyntaxError(ErrorCode::InvalidAnnotation, 0, 0, 0));
+                    }
+                    try!(write!(self.writer, ":{}", annotation));
+                }
+                try!(self.writer.write_all(b" "));
+                return value.serialize(self);
+            },
+            COMMENTED_NAME => return value.serialize(self),
+            RAW_VALUE_NAME | NUMBER_NAME => {
+                return match take_raw_text() {
+                    Some(text) => self.writer.write_all(text.as_bytes()).map_err(From::from),
+                    None => value.serialize(self),
+                };
+            },
+            _ => {
+                let mut compound = try!(self.serialize_tuple_struct(name, 1));
+                try!(ser::SerializeTupleStruct::serialize_field(&mut compound, value));
+                return ser::SerializeTupleStruct::end(compound);
+            },
+        };
+        let saved = self.radix;
+        self.radix = radix;
+        let result = value.serialize(&mut *self);
+        self.radix = saved;
+        result
+    }
+    #[inline]
+    fn serialize_unit_variant(self,
+                              _name: &'static str,
+                              _variant_index: u32,
+                              variant: &'static str) -> Result<()> {
+        self.serialize_str(variant)
+    }
+    fn serialize_newtype_variant<T: ?Sized>(self,
+                                            _name: &'static str,
+                                            _variant_index: u32,
+                                            variant: &'static str,
+                                            value: &T) -> Result<()>
+        where T: Serialize
+    {
+        let mut map = try!(self.serialize_map(Some(1)));
+        try!(ser::SerializeMap::serialize_entry(&mut map, variant, value));
+        ser::SerializeMap::end(map)
+    }
+    #[inline]
+    fn serialize_seq(self, _len: Option<usize>) -> Result<FixedCompound<'a, W, DEPTH>> {
+        self.start_compound(b'[', false)
+    }
+    #[inline]
+    fn serialize_tuple(self, _len: usize) -> Result<FixedCompound<'a, W, DEPTH>> {
+        self.start_compound(b'[', false)
+    }
+    #[inline]
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize)
+        -> Result<FixedCompound<'a, W, DEPTH>>
+    {
+        self.start_compound(b'[', false)
+    }
+    fn serialize_tuple_variant(self,
+                               _name: &'static str,
+                               _variant_index: u32,
+                               variant: &'static str,
+                               _len: usize) -> Result<FixedCompound<'a, W, DEPTH>> {
+        try!(self.start_variant(variant));
+        self.start_compound(b'[', true)
+    }
+    #[inline]
+    fn serialize_map(self, _len: Option<usize>) -> Result<FixedCompound<'a, W, DEPTH>> {
+        self.start_compound(b'{', false)
+    }
+    #[inline]
+    fn serialize_struct(self, _name: &'static str, _len: usize)
+        -> Result<FixedCompound<'a, W, DEPTH>>
+    {
+        self.start_compound(b'{', false)
+    }
+    fn serialize_struct_variant(self,
+                                _name: &'static str,
+                                _variant_index: u32,
+                                variant: &'static str,
+                                _len: usize) -> Result<FixedCompound<'a, W, DEPTH>> {
+        try!(self.start_variant(variant));
+        self.start_compound(b'{', true)
+    }
+}
+
+
+/// Writes the items of a dictionary or a list, see `FixedSerializer`.
+pub struct FixedCompound<'a, W: 'a + Write, const DEPTH: usize> {
+    ser: &'a mut FixedSerializer<W, DEPTH>,
+    // Closing bracket or brace.
+    close: u8,
+    first: bool,
+    // Set for the data of enum variants, which need closing their dictionary.
+    variant: bool,
+}
+
+
+impl<'a, W: Write, const DEPTH: usize> FixedCompound<'a, W, DEPTH> {
+    fn item<T: ?Sized>(&mut self, value: &T) -> Result<()> where T: Serialize {
+        let first = &mut self.first;
+        value.serialize(ItemSerializer {
+            ser: &mut *self.ser,
+            prefix: move |ser: &mut FixedSerializer<W, DEPTH>| Self::separator(first, ser),
+        })
+    }
+
+    fn entry<K: ?Sized, T: ?Sized>(&mut self, key: &K, value: &T) -> Result<()>
+        where K: Serialize, T: Serialize
+    {
+        let first = &mut self.first;
+        value.serialize(ItemSerializer {
+            ser: &mut *self.ser,
+            prefix: move |ser: &mut FixedSerializer<W, DEPTH>| Self::write_key(first, ser, key),
+        })
+    }
+
+    #[inline]
+    fn key<K: ?Sized>(&mut self, key: &K) -> Result<()> where K: Serialize {
+        Self::write_key(&mut self.first, self.ser, key)
+    }
+
+    fn write_key<K: ?Sized>(first: &mut bool,
+                            ser: &mut FixedSerializer<W, DEPTH>,
+                            key: &K) -> Result<()>
+        where K: Serialize
+    {
+        try!(Self::separator(first, ser));
+        try!(key.serialize(KeySerializer { writer: &mut ser.writer,
+                                           policy: KeyPolicy::Error }));
+        ser.writer.write_all(b":").map_err(From::from)
+    }
+
+    #[inline]
+    fn separator(first: &mut bool, ser: &mut FixedSerializer<W, DEPTH>) -> Result<()> {
+        if mem::replace(first, false) {
+            Ok(())
+        } else {
+            ser.writer.write_all(b",").map_err(From::from)
+        }
+    }
+
+    fn end(self) -> Result<()> {
+        self.ser.depth -= 1;
+        try!(self.ser.writer.write_all(&[self.close]));
+        if self.variant {
+            try!(self.ser.writer.write_all(b"}"));
+        }
+        Ok(())
+    }
+}
+
+
+// Serializes an item or entry of a compound: the separator and key in
+// `prefix` are written only when the value is not null, so null values
+// are skipped in the same pass which writes the rest.
+struct ItemSerializer<'a, W: 'a + Write, F, const DEPTH: usize> {
+    ser: &'a mut FixedSerializer<W, DEPTH>,
+    prefix: F,
+}
+
+
+impl<'a, W: Write, F, const DEPTH: usize> ItemSerializer<'a, W, F, DEPTH>
+    where F: FnOnce(&mut FixedSerializer<W, DEPTH>) -> Result<()>
+{
+    #[inline]
+    fn start(self) -> Result<&'a mut FixedSerializer<W, DEPTH>> {
+        try!((self.prefix)(&mut *self.ser));
+        Ok(self.ser)
+    }
+}
+
+
+impl<'a, W: Write, F, const DEPTH: usize> ser::Serializer for ItemSerializer<'a, W, F, DEPTH>
+    where F: FnOnce(&mut FixedSerializer<W, DEPTH>) -> Result<()>
+{
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = FixedCompound<'a, W, DEPTH>;
+    type SerializeTuple = FixedCompound<'a, W, DEPTH>;
+    type SerializeTupleStruct = FixedCompound<'a, W, DEPTH>;
+    type SerializeTupleVariant = FixedCompound<'a, W, DEPTH>;
+    type SerializeMap = FixedCompound<'a, W, DEPTH>;
+    type SerializeStruct = FixedCompound<'a, W, DEPTH>;
+    type SerializeStructVariant = FixedCompound<'a, W, DEPTH>;
+
+    // Null values
+    #[inline]
+    fn serialize_unit(self) -> Result<()> {
+        Ok(())
+    }
+    #[inline]
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        Ok(())
+    }
+    #[inline]
+    fn serialize_none(self) -> Result<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        try!(self.start()).serialize_bool(v)
+    }
+    #[inline]
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        try!(self.start()).serialize_i8(v)
+    }
+    #[inline]
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        try!(self.start()).serialize_i16(v)
+    }
+    #[inline]
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        try!(self.start()).serialize_i32(v)
+    }
+    #[inline]
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        try!(self.start()).serialize_i64(v)
+    }
+    #[inline]
+    fn serialize_i128(self, v: i128) -> Result<()> {
+        try!(self.start()).serialize_i128(v)
+    }
+    #[inline]
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        try!(self.start()).serialize_u8(v)
+    }
+    #[inline]
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        try!(self.start()).serialize_u16(v)
+    }
+    #[inline]
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        try!(self.start()).serialize_u32(v)
+    }
+    #[inline]
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        try!(self.start()).serialize_u64(v)
+    }
+    #[inline]
+    fn serialize_u128(self, v: u128) -> Result<()> {
+        try!(self.start()).serialize_u128(v)
+    }
+    #[inline]
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        try!(self.start()).serialize_f32(v)
+    }
+    #[inline]
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        try!(self.start()).serialize_f64(v)
+    }
+    #[inline]
+    fn serialize_char(self, v: char) -> Result<()> {
+        try!(self.start()).serialize_char(v)
+    }
+    #[inline]
+    fn serialize_str(self, v: &str) -> Result<()> {
+        try!(self.start()).serialize_str(v)
+    }
+    #[inline]
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        try!(self.start()).serialize_bytes(v)
+    }
+    #[inline]
+    fn serialize_some<T: ?Sized>(self, value: &T) -> Result<()> where T: Serialize {
+        try!(self.start()).serialize_some(value)
+    }
+    fn serialize_newtype_struct<T: ?Sized>(self, name: &'static str, value: &T) -> Result<()>
+        where T: Serialize
+    {
+        match name {
+            // Comments are left out, and a commented null is still skipped.
+            COMMENTED_NAME => value.serialize(self),
+            _ => try!(self.start()).serialize_newtype_struct(name, value),
+        }
+    }
+    #[inline]
+    fn serialize_unit_variant(self,
+                              name: &'static str,
+                              variant_index: u32,
+                              variant: &'static str) -> Result<()> {
+        try!(self.start()).serialize_unit_variant(name, variant_index, variant)
+    }
+    #[inline]
+    fn serialize_newtype_variant<T: ?Sized>(self,
+                                            name: &'static str,
+                                            variant_index: u32,
+                                            variant: &'static str,
+                                            value: &T) -> Result<()>
+        where T: Serialize
+    {
+        try!(self.start()).serialize_newtype_variant(name, variant_index, variant, value)
+    }
+    #[inline]
+    fn serialize_seq(self, len: Option<usize>) -> Result<FixedCompound<'a, W, DEPTH>> {
+        try!(self.start()).serialize_seq(len)
+    }
+    #[inline]
+    fn serialize_tuple(self, len: usize) -> Result<FixedCompound<'a, W, DEPTH>> {
+        try!(self.start()).serialize_tuple(len)
+    }
+    #[inline]
+    fn serialize_tuple_struct(self, name: &'static str, len: usize)
+        -> Result<FixedCompound<'a, W, DEPTH>>
+    {
+        try!(self.start()).serialize_tuple_struct(name, len)
+    }
+    #[inline]
+    fn serialize_tuple_variant(self,
+                               name: &'static str,
+                               variant_index: u32,
+                               variant: &'static str,
+                               len: usize) -> Result<FixedCompound<'a, W, DEPTH>> {
+        try!(self.start()).serialize_tuple_variant(name, variant_index, variant, len)
+    }
+    #[inline]
+    fn serialize_map(self, len: Option<usize>) -> Result<FixedCompound<'a, W, DEPTH>> {
+        try!(self.start()).serialize_map(len)
+    }
+    #[inline]
+    fn serialize_struct(self, name: &'static str, len: usize)
+        -> Result<FixedCompound<'a, W, DEPTH>>
+    {
+        try!(self.start()).serialize_struct(name, len)
+    }
+    #[inline]
+    fn serialize_struct_variant(self,
+                                name: &'static str,
+                                variant_index: u32,
+                                variant: &'static str,
+                                len: usize) -> Result<FixedCompound<'a, W, DEPTH>> {
+        try!(self.start()).serialize_struct_variant(name, variant_index, variant, len)
+    }
+}
+
+
+impl<'a, W: Write, const DEPTH: usize> ser::SerializeSeq for FixedCompound<'a, W, DEPTH> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<()> where T: Serialize {
+        self.item(value)
+    }
+    #[inline]
+    fn end(self) -> Result<()> {
+        FixedCompound::end(self)
+    }
+}
+
+
+impl<'a, W: Write, const DEPTH: usize> ser::SerializeTuple for FixedCompound<'a, W, DEPTH> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<()> where T: Serialize {
+        self.item(value)
+    }
+    #[inline]
+    fn end(self) -> Result<()> {
+        FixedCompound::end(self)
+    }
+}
+
+
+impl<'a, W: Write, const DEPTH: usize> ser::SerializeTupleStruct for FixedCompound<'a, W, DEPTH> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<()> where T: Serialize {
+        self.item(value)
+    }
+    #[inline]
+    fn end(self) -> Result<()> {
+        FixedCompound::end(self)
+    }
+}
+
+
+impl<'a, W: Write, const DEPTH: usize> ser::SerializeTupleVariant for FixedCompound<'a, W, DEPTH> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<()> where T: Serialize {
+        self.item(value)
+    }
+    #[inline]
+    fn end(self) -> Result<()> {
+        FixedCompound::end(self)
+    }
+}
+
+
+impl<'a, W: Write, const DEPTH: usize> ser::SerializeMap for FixedCompound<'a, W, DEPTH> {
+    type Ok = ();
+    type Error = Error;
+
+    // Keys are written right away, so null values given separately with
+    // serialize_value() cannot be skipped, and are an error.
+    #[inline]
+    fn serialize_key<T: ?Sized>(&mut self, key: &T) -> Result<()> where T: Serialize {
+        self.key(key)
+    }
+    #[inline]
+    fn serialize_value<T: ?Sized>(&mut self, value: &T) -> Result<()> where T: Serialize {
+        value.serialize(&mut *self.ser)
+    }
+    #[inline]
+    fn serialize_entry<K: ?Sized, V: ?Sized>(&mut self, key: &K, value: &V) -> Result<()>
+        where K: Serialize, V: Serialize
+    {
+        self.entry(key, value)
+    }
+    #[inline]
+    fn end(self) -> Result<()> {
+        FixedCompound::end(self)
+    }
+}
+
+
+impl<'a, W: Write, const DEPTH: usize> ser::SerializeStruct for FixedCompound<'a, W, DEPTH> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_field<T: ?Sized>(&mut self, key: &'static str, value: &T) -> Result<()>
+        where T: Serialize
+    {
+        self.entry(key, value)
+    }
+    #[inline]
+    fn end(self) -> Result<()> {
+        FixedCompound::end(self)
+    }
+}
+
+
+impl<'a, W: Write, const DEPTH: usize> ser::SerializeStructVariant for FixedCompound<'a, W, DEPTH> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_field<T: ?Sized>(&mut self, key: &'static str, value: &T) -> Result<()>
+        where T: Serialize
+    {
+        self.entry(key, value)
+    }
+    #[inline]
+    fn end(self) -> Result<()> {
+        FixedCompound::end(self)
+    }
+}
+
+
+/// Serializes a value with a `FixedSerializer` which allows nesting lists
+/// and dictionaries `DEPTH` levels deep, e.g. into a buffer on the stack:
+///
+/// ```text
+/// let mut buf = [0u8; 64];
+/// let mut output = &mut buf[..];
+/// to_writer_fixed::<_, _, 4>(&mut output, &reading)?;
+/// ```
+#[inline]
+pub fn to_writer_fixed<W, T, const DEPTH: usize>(writer: &mut W, value: &T) -> Result<()>
+    where W: Write, T: ?Sized + Serialize
+{
+    value.serialize(&mut FixedSerializer::<_, DEPTH>::new(writer))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ser::{to_string, Annotated, Hex};
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize)]
+    enum Shape {
+        Point,
+        Circle(f64),
+        Rect(u8, u8),
+        Poly { sides: u8 },
+    }
+
+    #[derive(Serialize)]
+    struct Reading {
+        name: &'static str,
+        values: Vec<i32>,
+        unit: Option<&'static str>,
+        flags: Hex<u16>,
+        scale: f32,
+        shapes: Vec<Shape>,
+        tags: BTreeMap<&'static str, Annotated<bool>>,
+        raw: &'static [u8],
+    }
+
+    fn fixed_string<T: Serialize, const DEPTH: usize>(value: &T) -> Result<String> {
+        let mut buf = [0u8; 256];
+        let len = {
+            let mut output = &mut buf[..];
+            try!(to_writer_fixed::<_, _, DEPTH>(&mut output, value));
+            256 - output.len()
+        };
+        Ok(String::from_utf8(buf[..len].to_vec()).unwrap())
+    }
+
+    #[test]
+    fn test_fixed_serializer() {
+        let mut tags = BTreeMap::new();
+        tags.insert("ok", Annotated::new(true).annotate("checked"));
+        let reading = Reading {
+            name: "t\t\"1\"",
+            values: vec![-3, 0, 1 << 20],
+            unit: None,
+            flags: Hex(0xBEEF),
+            scale: 2.0,
+            shapes: vec![Shape::Point, Shape::Circle(0.5), Shape::Rect(1, 2),
+                         Shape::Poly { sides: 5 }],
+            tags: tags,
+            raw: b"hi",
+        };
+        assert_eq!(to_string(&reading).unwrap(), fixed_string::<_, 3>(&reading).unwrap());
+        let floats = [1.0, -0.25, f64::INFINITY, f64::NAN];
+        assert_eq!("[1.0,-0.25,inf,NaN]", fixed_string::<_, 1>(&floats).unwrap());
+        let items = [None, Some(1), None, Some(2), None];
+        assert_eq!("[1,2]", fixed_string::<_, 1>(&items).unwrap());
+    }
+
+    #[test]
+    fn test_fixed_depth() {
+        let value = vec![vec![vec![1]]];
+        assert_eq!("[[[1]]]", fixed_string::<_, 3>(&value).unwrap());
+        match fixed_string::<_, 2>(&value) {
+            Err(Error::SyntaxError(ErrorCode::RecursionLimitExceeded, _, _, _)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(fixed_string::<_, 1>(&()).is_err());
+        let mut map = BTreeMap::new();
+        map.insert("a b", 1);
+        assert!(fixed_string::<_, 1>(&map).is_err());
+    }
+}
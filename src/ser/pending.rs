@@ -0,0 +1,132 @@
+//
+// pending.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use std::io::{self, Write};
+use serde::ser::Serialize;
+use error::Result;
+use super::to_vec;
+
+
+/// Output which a non-blocking writer did not accept yet, returned by
+/// `to_writer_nonblocking()`. Once the writer is ready again, e.g. when
+/// the event loop reports a socket as writable, call `resume()` to write
+/// the rest.
+///
+/// Serialization itself cannot be suspended, so the output is produced
+/// up front, and the value does not need to be kept around. Output of
+/// other serializers, e.g. `to_vec_pretty()`, can be written this way
+/// using `PendingWrite::from()`.
+#[derive(Clone, Debug)]
+pub struct PendingWrite {
+    buf: Vec<u8>,
+    written: usize,
+}
+
+
+impl PendingWrite {
+    /// Writes as much of the output as the writer accepts. Returns `true`
+    /// once all of it has been written, or `false` if the writer would
+    /// block, in which case it should be called again later.
+    pub fn resume<W: Write>(&mut self, writer: &mut W) -> Result<bool> {
+        while self.written < self.buf.len() {
+            match writer.write(&self.buf[self.written..]) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                                   "failed to write whole buffer").into()),
+                Ok(n) => self.written += n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Number of bytes which have not been written yet.
+    #[inline]
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.written
+    }
+
+    #[inline]
+    pub fn is_done(&self) -> bool {
+        self.remaining() == 0
+    }
+}
+
+
+impl From<Vec<u8>> for PendingWrite {
+    #[inline]
+    fn from(buf: Vec<u8>) -> PendingWrite {
+        PendingWrite { buf: buf, written: 0 }
+    }
+}
+
+
+/// Serializes a value in compact form into a non-blocking writer. When
+/// the writer would block, the output which is left is returned to be
+/// written later with `PendingWrite::resume()`, instead of failing with
+/// `io::ErrorKind::WouldBlock` and losing track of what was written.
+pub fn to_writer_nonblocking<W, T>(writer: &mut W, value: &T) -> Result<Option<PendingWrite>>
+    where W: Write, T: Serialize
+{
+    let mut pending = PendingWrite::from(try!(to_vec(value)));
+    if try!(pending.resume(writer)) {
+        Ok(None)
+    } else {
+        Ok(Some(pending))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+    use std::collections::BTreeMap;
+    use super::to_writer_nonblocking;
+
+    // Accepts a few bytes at a time, and would block in between.
+    struct Throttled {
+        output: Vec<u8>,
+        ready: bool,
+    }
+
+    impl Write for Throttled {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !self.ready {
+                self.ready = true;
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "not ready"));
+            }
+            self.ready = false;
+            let n = buf.len().min(4);
+            self.output.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_nonblocking() {
+        let mut value = BTreeMap::new();
+        value.insert("name", "web");
+        value.insert("host", "example.org");
+        let mut writer = Throttled { output: Vec::new(), ready: true };
+        let mut pending = to_writer_nonblocking(&mut writer, &value).unwrap().unwrap();
+        assert_eq!(4, writer.output.len());
+        let mut calls = 0;
+        while !pending.resume(&mut writer).unwrap() {
+            calls += 1;
+        }
+        assert!(pending.is_done());
+        assert!(calls > 1);
+        assert_eq!("{host:\"example.org\",name:\"web\"}", String::from_utf8(writer.output).unwrap());
+
+        let mut writer = Vec::new();
+        assert!(to_writer_nonblocking(&mut writer, &value).unwrap().is_none());
+    }
+}
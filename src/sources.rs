@@ -0,0 +1,83 @@
+//
+// sources.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+//! Using HiPack files as sources of layered configuration.
+//!
+//! With the `config` feature, `HipackFormat` is a file format for the
+//! `config` crate, so HiPack files can be used alongside other sources:
+//!
+//! ```
+//! # #[cfg(feature = "config")]
+//! # extern crate config;
+//! # extern crate serde_hipack;
+//! # #[cfg(feature = "config")]
+//! # fn main() -> Result<(), config::ConfigError> {
+//! # use config::{Config, Environment, File};
+//! # use serde_hipack::sources::HipackFormat;
+//! let settings = Config::builder()
+//!     .add_source(File::new("settings", HipackFormat).required(false))
+//!     .add_source(Environment::with_prefix("APP"))
+//!     .build()?;
+//! # Ok(())
+//! # }
+//! # #[cfg(not(feature = "config"))]
+//! # fn main() {}
+//! ```
+//!
+//! With the `figment` feature, `HipackFile` and `HipackString` are
+//! providers which can be merged into a `Figment`, e.g. in Rocket:
+//!
+//! ```
+//! # #[cfg(feature = "figment")]
+//! # extern crate figment;
+//! # extern crate serde_hipack;
+//! # #[cfg(feature = "figment")]
+//! # fn main() -> Result<(), figment::Error> {
+//! # use figment::Figment;
+//! # use serde_hipack::sources::{HipackFile, HipackString};
+//! let figment = Figment::new()
+//!     .merge(HipackFile::new("App.hipack"))
+//!     .merge(HipackString::new("port: 8080"));
+//! let port: u16 = figment.extract_inner("port")?;
+//! # assert_eq!(port, 8080);
+//! # Ok(())
+//! # }
+//! # #[cfg(not(feature = "figment"))]
+//! # fn main() {}
+//! ```
+//!
+//! Annotations are left out of the values, except that `:none {}` (see
+//! `value::NONE_ANNOTATION`) is read as an unset value.
+
+#[cfg(feature = "config")]
+mod config;
+#[cfg(feature = "figment")]
+mod figment;
+
+#[cfg(feature = "config")]
+pub use self::config::HipackFormat;
+#[cfg(feature = "figment")]
+pub use self::figment::{HipackFile, HipackString};
+
+
+// Layers of configuration read by the tests of each kind of source: the
+// second one overrides the port of the first one, which leaves `tls` unset.
+#[cfg(test)]
+mod fixtures {
+    #[derive(Debug, PartialEq, Deserialize)]
+    pub struct Server {
+        pub host: String,
+        pub port: u16,
+        pub tls: Option<bool>,
+    }
+
+    pub const BASE: &'static str = "server {host: \"a\" port: 80 tls: :none {}} debug: True";
+    pub const OVERRIDE: &'static str = "server {port: :tcp 0x1F90}";
+
+    pub fn expected() -> Server {
+        Server { host: "a".to_string(), port: 8080, tls: None }
+    }
+}
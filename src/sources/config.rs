@@ -0,0 +1,77 @@
+//
+// config.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use std::error;
+use config::{FileStoredFormat, Format, Map, Value as ConfigValue, ValueKind};
+use super::super::de::from_str;
+use super::super::value::{self, is_none_marker, Value};
+
+
+/// HiPack file format for the `config` crate, for files with the
+/// `.hipack` extension.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HipackFormat;
+
+
+impl Format for HipackFormat {
+    fn parse(&self, uri: Option<&String>, text: &str)
+        -> Result<Map<String, ConfigValue>, Box<error::Error + Send + Sync>>
+    {
+        let entries: value::Map = try!(from_str(text));
+        Ok(entries.into_iter().map(|(key, value)| (key, convert(uri, value))).collect())
+    }
+}
+
+
+impl FileStoredFormat for HipackFormat {
+    #[inline]
+    fn file_extensions(&self) -> &'static [&'static str] {
+        &["hipack"]
+    }
+}
+
+
+fn convert(uri: Option<&String>, value: Value) -> ConfigValue {
+    let kind = match value {
+        Value::Annotated(ref annotations, ref value) if is_none_marker(annotations, value) => ValueKind::Nil,
+        Value::Annotated(_, value) => return convert(uri, *value),
+        Value::Bool(v) => ValueKind::Boolean(v),
+        Value::I64(v) => ValueKind::I64(v),
+        Value::U64(v) => ValueKind::U64(v),
+        Value::I128(v) => ValueKind::I128(v),
+        Value::U128(v) => ValueKind::U128(v),
+        Value::F64(v) => ValueKind::Float(v),
+        Value::Number(v) => return convert(uri, v.value()),
+        Value::String(v) => ValueKind::String(v),
+        Value::List(items) => ValueKind::Array(items.into_iter().map(|item| convert(uri, item)).collect()),
+        Value::Dict(entries) => {
+            ValueKind::Table(entries.into_iter().map(|(key, value)| (key, convert(uri, value))).collect())
+        },
+    };
+    ConfigValue::new(uri, kind)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use config::{Config, File};
+    use super::HipackFormat;
+    use super::super::fixtures::{expected, Server, BASE, OVERRIDE};
+
+    #[test]
+    fn test_config_format() {
+        let settings = Config::builder()
+            .add_source(File::from_str(BASE, HipackFormat))
+            .add_source(File::from_str(OVERRIDE, HipackFormat))
+            .build()
+            .unwrap();
+        assert_eq!(expected(), settings.get::<Server>("server").unwrap());
+        assert!(settings.get_bool("debug").unwrap());
+
+        let settings = Config::builder().add_source(File::from_str("a: [", HipackFormat)).build();
+        assert!(settings.is_err());
+    }
+}
@@ -0,0 +1,151 @@
+//
+// figment.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use figment::{Error, Metadata, Profile, Provider};
+use figment::value::{Dict, Empty, Map, Num, Tag, Value as FigmentValue};
+use super::super::de::from_str;
+use super::super::value::{self, is_none_marker, Value};
+
+
+/// Provider of values read from a HiPack file, for the `Default` profile
+/// unless another one is set with `profile()`. A file which does not
+/// exist provides no values, unless it is `required()`.
+#[derive(Clone, Debug)]
+pub struct HipackFile {
+    path: PathBuf,
+    profile: Profile,
+    required: bool,
+}
+
+
+impl HipackFile {
+    #[inline]
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        HipackFile { path: path.as_ref().to_owned(), profile: Profile::Default, required: false }
+    }
+
+    /// Sets the profile of the values read from the file.
+    #[inline]
+    pub fn profile<P: Into<Profile>>(mut self, profile: P) -> Self {
+        self.profile = profile.into();
+        self
+    }
+
+    /// Fails when the file does not exist, instead of providing no values.
+    #[inline]
+    pub fn required(mut self, required: bool) -> Self {
+        self.required = required;
+        self
+    }
+}
+
+
+impl Provider for HipackFile {
+    fn metadata(&self) -> Metadata {
+        Metadata::from("HiPack file", &*self.path)
+    }
+
+    fn data(&self) -> Result<Map<Profile, Dict>, Error> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound && !self.required => {
+                return Ok(Map::new());
+            },
+            Err(err) => return Err(format!("{}: {}", self.path.display(), err).into()),
+        };
+        parse(&text, &self.profile)
+    }
+}
+
+
+/// Provider of values read from a HiPack document in a string, for the
+/// `Default` profile unless another one is set with `profile()`.
+#[derive(Clone, Debug)]
+pub struct HipackString {
+    text: String,
+    profile: Profile,
+}
+
+
+impl HipackString {
+    #[inline]
+    pub fn new<S: Into<String>>(text: S) -> Self {
+        HipackString { text: text.into(), profile: Profile::Default }
+    }
+
+    /// Sets the profile of the values read from the string.
+    #[inline]
+    pub fn profile<P: Into<Profile>>(mut self, profile: P) -> Self {
+        self.profile = profile.into();
+        self
+    }
+}
+
+
+impl Provider for HipackString {
+    fn metadata(&self) -> Metadata {
+        Metadata::named("HiPack source string")
+    }
+
+    fn data(&self) -> Result<Map<Profile, Dict>, Error> {
+        parse(&self.text, &self.profile)
+    }
+}
+
+
+fn parse(text: &str, profile: &Profile) -> Result<Map<Profile, Dict>, Error> {
+    let entries: value::Map = try!(from_str(text).map_err(|err| err.to_string()));
+    Ok(profile.collect(entries.into_iter().map(|(key, value)| (key, convert(value))).collect()))
+}
+
+
+fn convert(value: Value) -> FigmentValue {
+    match value {
+        Value::Annotated(ref annotations, ref value) if is_none_marker(annotations, value) => {
+            FigmentValue::Empty(Tag::Default, Empty::None)
+        },
+        Value::Annotated(_, value) => convert(*value),
+        Value::Bool(v) => FigmentValue::Bool(Tag::Default, v),
+        Value::I64(v) => FigmentValue::Num(Tag::Default, Num::I64(v)),
+        Value::U64(v) => FigmentValue::Num(Tag::Default, Num::U64(v)),
+        Value::I128(v) => FigmentValue::Num(Tag::Default, Num::I128(v)),
+        Value::U128(v) => FigmentValue::Num(Tag::Default, Num::U128(v)),
+        Value::F64(v) => FigmentValue::Num(Tag::Default, Num::F64(v)),
+        Value::Number(v) => convert(v.value()),
+        Value::String(v) => FigmentValue::String(Tag::Default, v),
+        Value::List(items) => FigmentValue::Array(Tag::Default, items.into_iter().map(convert).collect()),
+        Value::Dict(entries) => {
+            FigmentValue::Dict(Tag::Default, entries.into_iter().map(|(key, value)| (key, convert(value))).collect())
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use figment::{Figment, Profile};
+    use super::{HipackFile, HipackString};
+    use super::super::fixtures::{expected, Server, BASE, OVERRIDE};
+
+    #[test]
+    fn test_figment_provider() {
+        let figment = Figment::new()
+            .merge(HipackString::new(BASE))
+            .merge(HipackString::new(OVERRIDE))
+            .merge(HipackString::new("server {port: 443}").profile("release"))
+            .merge(HipackFile::new("/nonexistent/App.hipack"));
+        assert_eq!(expected(), figment.extract_inner::<Server>("server").unwrap());
+        assert!(figment.extract_inner::<bool>("debug").unwrap());
+        assert_eq!(443, figment.select(Profile::new("release")).extract_inner::<u16>("server.port").unwrap());
+
+        assert!(Figment::new().merge(HipackString::new("a: [")).extract_inner::<u32>("a").is_err());
+        let file = HipackFile::new("/nonexistent/App.hipack").required(true);
+        assert!(Figment::new().merge(file).extract_inner::<u32>("a").is_err());
+    }
+}
@@ -0,0 +1,123 @@
+//
+// split.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+//! Documents split into one file per top-level key, e.g. to manage a
+//! large configuration as a `conf.d`-style directory.
+//!
+//! `write_split()` writes each entry of the top-level dictionary into its
+//! own file named after the key, like `servers.hipack`, which holds just
+//! that entry. `read_split()` reads the HiPack files of a directory in
+//! order of their names, and merges their entries back into a single
+//! document: files may hold several entries, and keys found in a later
+//! file replace the ones from earlier files.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::{de, ser};
+use super::de::from_slice;
+use super::error::{Error, ErrorCode, Result};
+use super::mime::{has_hipack_extension, EXTENSIONS};
+use super::ser::{to_vec, to_vec_pretty};
+use super::value::{self, Map, Value};
+
+
+/// Writes each top-level entry of a value into its own file in `dir`,
+/// which is created if needed, and returns the paths of the files. Files
+/// for keys which the value does not have are left alone. Keys which are
+/// not valid file names, like `..` or ones with slashes, are rejected
+/// before writing any file.
+pub fn write_split<P, T>(dir: P, value: &T) -> Result<Vec<PathBuf>>
+    where P: AsRef<Path>, T: ser::Serialize
+{
+    let entries = match try!(from_slice(&try!(to_vec(value)))) {
+        Value::Dict(entries) => entries,
+        _ => return Err(Error::SyntaxError(ErrorCode::UnrepresentableValue, 0, 0, 0)),
+    };
+    if let Some(key) = entries.keys().find(|key| !is_file_name(key)) {
+        return Err(Error::SyntaxError(ErrorCode::InvalidValue(
+            format!("Key \"{}\" cannot be used as a file name", key)), 0, 0, 0));
+    }
+    let dir = dir.as_ref();
+    try!(fs::create_dir_all(dir));
+    let mut paths = Vec::with_capacity(entries.len());
+    for (key, value) in entries {
+        let path = dir.join(format!("{}.{}", key, EXTENSIONS[0]));
+        let mut entry = Map::new();
+        entry.insert(key, value);
+        try!(fs::write(&path, try!(to_vec_pretty(&entry))));
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+
+/// Reads the files with one of the `mime::EXTENSIONS` in `dir`, sorted by
+/// name, and deserializes the dictionary which results from merging their
+/// entries.
+pub fn read_split<P, T>(dir: P) -> Result<T>
+    where P: AsRef<Path>, T: de::DeserializeOwned
+{
+    let mut paths = Vec::new();
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+        if path.is_file() && has_hipack_extension(&path) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    let mut entries = Map::new();
+    for path in paths {
+        let file: Map = try!(from_slice(&try!(fs::read(&path))));
+        entries.extend(file);
+    }
+    de::Deserialize::deserialize(value::Deserializer::new(Value::Dict(entries)))
+}
+
+
+fn is_file_name(key: &str) -> bool {
+    key != "." && key != ".." && !key.contains(|c| c == '/' || c == '\\' || c == '\0')
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+    use std::collections::BTreeMap;
+    use super::{read_split, write_split};
+    use value::Value;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Config {
+        name: String,
+        ports: Vec<u16>,
+        tls: BTreeMap<String, bool>,
+    }
+
+    #[test]
+    fn test_split() {
+        let dir = env::temp_dir().join(format!("serde-hipack-split-{}", process::id()));
+        let mut tls = BTreeMap::new();
+        tls.insert("enabled".to_string(), true);
+        let config = Config { name: "web".to_string(), ports: vec![80, 443], tls: tls };
+
+        let paths = write_split(&dir, &config).unwrap();
+        assert_eq!(vec![dir.join("name.hipack"), dir.join("ports.hipack"), dir.join("tls.hipack")],
+                   paths);
+        assert_eq!("{\n  ports [\n    80\n    443\n  ]\n}",
+                   fs::read_to_string(dir.join("ports.hipack")).unwrap());
+        assert_eq!(config, read_split(&dir).unwrap());
+
+        // Later files override earlier ones.
+        fs::write(dir.join("zz-local.hipack"), "name: \"local\"").unwrap();
+        let value: Value = read_split(&dir).unwrap();
+        assert_eq!(Value::from("local"), value["name"]);
+
+        let mut bad = BTreeMap::new();
+        bad.insert("..", 1);
+        assert!(write_split(&dir, &bad).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
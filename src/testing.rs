@@ -0,0 +1,119 @@
+//
+// testing.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+//! Helpers for testing that types can be written as HiPack and read back.
+//!
+//! Crates using HiPack for their own types can check that they keep
+//! working with the format with one line per value:
+//!
+//! ```
+//! #[macro_use]
+//! extern crate serde_derive;
+//! extern crate serde_hipack;
+//!
+//! #[derive(Debug, PartialEq, Serialize, Deserialize)]
+//! struct Server {
+//!     host: String,
+//!     ports: Vec<u16>,
+//! }
+//!
+//! fn main() {
+//!     let server = Server { host: "example.org".to_string(), ports: vec![80, 443] };
+//!     serde_hipack::testing::assert_roundtrip(&server);
+//! }
+//! ```
+
+use std::fmt;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use super::de::from_str;
+use super::error::Result;
+use super::ser::{to_string, to_string_pretty};
+
+
+/// Writes `value` both in compact and pretty form, reads each output back
+/// and checks that the result is equal to `value`.
+///
+/// # Panics
+///
+/// When writing or reading fails, or the value read differs. The message
+/// includes the output, to help finding out which part of the value does
+/// not survive the round trip.
+pub fn assert_roundtrip<T>(value: &T)
+    where T: Serialize + DeserializeOwned + PartialEq + fmt::Debug
+{
+    check(value, "compact", to_string(value));
+    check(value, "pretty", to_string_pretty(value));
+}
+
+
+fn check<T>(value: &T, form: &str, output: Result<String>)
+    where T: DeserializeOwned + PartialEq + fmt::Debug
+{
+    let text = match output {
+        Ok(text) => text,
+        Err(err) => panic!("writing {:?} in {} form failed: {}", value, form, err),
+    };
+    let result: T = match from_str(&text) {
+        Ok(result) => result,
+        Err(err) => panic!("reading back {} output failed: {}\n{}", form, err, text),
+    };
+    if result != *value {
+        panic!("value read back from {} output differs:\n{}\nexpected: {:?}\n  actual: {:?}",
+               form, text, value, result);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::f64::NAN;
+    use super::assert_roundtrip;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Protocol {
+        Tcp,
+        Unix(String),
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Server {
+        name: String,
+        ports: Vec<u16>,
+        protocol: Protocol,
+        ratio: Option<f64>,
+        labels: BTreeMap<String, String>,
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut labels = BTreeMap::new();
+        labels.insert("tier".to_string(), "front\n\"end\"".to_string());
+        assert_roundtrip(&Server {
+            name: "web".to_string(),
+            ports: vec![80, 443],
+            protocol: Protocol::Unix("/run/web.sock".to_string()),
+            ratio: Some(0.5),
+            labels: labels,
+        });
+        assert_roundtrip(&Server {
+            name: String::new(),
+            ports: Vec::new(),
+            protocol: Protocol::Tcp,
+            ratio: None,
+            labels: BTreeMap::new(),
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "differs")]
+    fn test_roundtrip_differs() {
+        let mut value = BTreeMap::new();
+        value.insert("ratio".to_string(), NAN);
+        assert_roundtrip(&value);
+    }
+}
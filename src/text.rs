@@ -0,0 +1,175 @@
+//
+// text.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+//! Values written as validated strings, e.g. network addresses in
+//! configuration files.
+//!
+//! Serde writes the types in `std::net` as strings already, but reports
+//! malformed ones with generic messages. Wrapped in `Text`, they are
+//! checked against their HiPack string form when read back, and errors
+//! name what was expected, like `Invalid socket address at line 4
+//! column 20`. Other types implementing `TextValue` are written as
+//! strings the same way.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
+use std::fmt;
+use std::marker::PhantomData;
+use serde::{ser, de};
+
+
+/// Types which can be written as strings.
+pub trait TextValue: Sized {
+    /// Describes the type in error messages, e.g. `IP address`.
+    fn description() -> &'static str;
+    fn to_text(&self) -> String;
+    /// Parses a string, returning `None` if it is not valid.
+    fn from_text(text: &str) -> Option<Self>;
+}
+
+
+macro_rules! impl_text_value {
+    ($($ty:ty => $description:expr),*) => {
+        $(
+            impl TextValue for $ty {
+                #[inline]
+                fn description() -> &'static str {
+                    $description
+                }
+                #[inline]
+                fn to_text(&self) -> String {
+                    self.to_string()
+                }
+                #[inline]
+                fn from_text(text: &str) -> Option<Self> {
+                    text.parse().ok()
+                }
+            }
+        )*
+    }
+}
+
+impl_text_value!(IpAddr => "IP address",
+                 Ipv4Addr => "IPv4 address",
+                 Ipv6Addr => "IPv6 address",
+                 SocketAddr => "socket address",
+                 SocketAddrV4 => "IPv4 socket address",
+                 SocketAddrV6 => "IPv6 socket address");
+
+
+/// Paths which are not valid Unicode are written with replacement
+/// characters, and empty paths are not valid.
+impl TextValue for PathBuf {
+    #[inline]
+    fn description() -> &'static str {
+        "path"
+    }
+    #[inline]
+    fn to_text(&self) -> String {
+        self.to_string_lossy().into_owned()
+    }
+    #[inline]
+    fn from_text(text: &str) -> Option<Self> {
+        if text.is_empty() { None } else { Some(PathBuf::from(text)) }
+    }
+}
+
+
+/// Wraps a value which is written as a string, e.g. `Text<SocketAddr>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Text<T>(pub T);
+
+
+impl<T> Deref for Text<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+
+impl<T> DerefMut for Text<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+
+impl<T: TextValue> ser::Serialize for Text<T> {
+    #[inline]
+    fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+        where S: ser::Serializer
+    {
+        serializer.serialize_str(&self.0.to_text())
+    }
+}
+
+
+impl<'de, T: TextValue> de::Deserialize<'de> for Text<T> {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> ::std::result::Result<Self, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        deserializer.deserialize_string(TextVisitor(PhantomData))
+    }
+}
+
+
+struct TextVisitor<T>(PhantomData<T>);
+
+
+impl<'de, T: TextValue> de::Visitor<'de> for TextVisitor<T> {
+    type Value = Text<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a string with a {}", T::description())
+    }
+
+    fn visit_str<E>(self, v: &str) -> ::std::result::Result<Text<T>, E>
+        where E: de::Error
+    {
+        match T::from_text(v) {
+            Some(value) => Ok(Text(value)),
+            None => Err(E::custom(format!("Invalid {}", T::description()))),
+        }
+    }
+}
+
+
+#[cfg(all(test, feature = "de"))]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::net::{IpAddr, SocketAddr};
+    use std::path::PathBuf;
+    use de::from_str;
+    use ser::to_string;
+
+    #[test]
+    fn test_text() {
+        let mut map = BTreeMap::new();
+        map.insert("listen", Text("[::1]:8080".parse::<SocketAddr>().unwrap()));
+        let output = to_string(&map).unwrap();
+        assert_eq!("{listen:\"[::1]:8080\"}", output);
+        let value: BTreeMap<String, Text<SocketAddr>> = from_str(&output).unwrap();
+        assert_eq!(map["listen"], value["listen"]);
+
+        let value: BTreeMap<String, Text<IpAddr>> = from_str("a: \"10.0.0.1\"").unwrap();
+        assert!(value["a"].is_ipv4());
+        let value: BTreeMap<String, Text<PathBuf>> = from_str("a: \"/etc/hosts\"").unwrap();
+        assert_eq!(Some("hosts".as_ref()), value["a"].file_name());
+
+        let input = "a: \"[::1]:80\"\nb: \"localhost\"";
+        let error = from_str::<BTreeMap<String, Text<SocketAddr>>>(input).unwrap_err();
+        assert_eq!("b: Invalid socket address at line 2 column 15", error.to_string());
+        assert!(from_str::<BTreeMap<String, Text<IpAddr>>>("a: 1").is_err());
+        assert!(from_str::<BTreeMap<String, Text<PathBuf>>>("a: \"\"").is_err());
+    }
+}
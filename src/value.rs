@@ -0,0 +1,1362 @@
+//
+// value.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+#[cfg(not(feature = "preserve_order"))]
+use std::collections::{BTreeMap, btree_map};
+use std::{fmt, mem, ops, str, vec};
+use std::convert::TryFrom;
+#[cfg(feature = "preserve_order")]
+use indexmap::{IndexMap, map as index_map};
+use serde::{ser, de};
+use serde::de::IntoDeserializer;
+use super::error::{Result, Error};
+use super::ser::{serialize_annotated, to_string, to_string_pretty, RAW_VALUE_NAME};
+
+#[cfg(feature = "allocator_api")]
+mod alloc;
+mod builder;
+#[cfg(feature = "json")]
+mod json;
+mod number;
+mod raw;
+#[cfg(feature = "allocator_api")]
+pub use self::alloc::{from_str_in, AllocSeed, AllocString, AllocValue};
+pub use self::builder::{DictBuilder, DocumentBuilder, ListBuilder};
+pub use self::number::{Number, NumberKind};
+pub use self::raw::{BorrowedRawValue, RawValue};
+
+
+// Deserializing a newtype struct with this name makes the Deserializer
+// pass the annotations of the value, if any, as a map with two entries.
+#[doc(hidden)]
+pub const ANNOTATED_NAME: &'static str = "$hipack::Annotated";
+#[doc(hidden)]
+pub const ANNOTATIONS_KEY: &'static str = "$hipack::annotations";
+#[doc(hidden)]
+pub const VALUE_KEY: &'static str = "$hipack::value";
+// Used to deserialize a Value, like ANNOTATED_NAME, except that numbers
+// are passed as their text in an entry with NUMBER_KEY instead.
+#[doc(hidden)]
+pub const VALUE_NAME: &'static str = "$hipack::Value";
+#[doc(hidden)]
+pub const NUMBER_KEY: &'static str = "$hipack::number";
+// Deserializing a newtype struct with this name makes the Deserializer
+// pass the value as a map, along with where it starts and ends.
+#[doc(hidden)]
+pub const SPANNED_NAME: &'static str = "$hipack::Spanned";
+#[doc(hidden)]
+pub const SPAN_START_KEY: &'static str = "$hipack::start";
+#[doc(hidden)]
+pub const SPAN_END_KEY: &'static str = "$hipack::end";
+
+
+/// Annotation of the empty dictionary which stands for a null value, as
+/// written for `None` with `NullStrategy::AnnotatedDict`: `:none {}`.
+/// Conversions from and to formats which have null values use it too.
+pub const NONE_ANNOTATION: &'static str = "none";
+
+
+// Whether an annotated value is the marker written for null values, see
+// NONE_ANNOTATION.
+#[inline]
+pub(crate) fn is_none_marker(annotations: &[String], value: &Value) -> bool {
+    annotations.len() == 1 && annotations[0] == NONE_ANNOTATION &&
+        value.as_dict().map_or(false, |entries| entries.is_empty())
+}
+
+/// Entries of a `Value::Dict`, sorted by key unless the `preserve_order`
+/// feature is enabled, which keeps them in the order they are read or
+/// inserted, e.g. for tools which rewrite configuration files.
+#[cfg(not(feature = "preserve_order"))]
+pub type Map = BTreeMap<String, Value>;
+#[cfg(feature = "preserve_order")]
+pub type Map = IndexMap<String, Value>;
+
+#[cfg(not(feature = "preserve_order"))]
+type MapIntoIter = btree_map::IntoIter<String, Value>;
+#[cfg(feature = "preserve_order")]
+type MapIntoIter = index_map::IntoIter<String, Value>;
+
+#[cfg(not(feature = "preserve_order"))]
+type MapEntry<'a> = btree_map::Entry<'a, String, Value>;
+#[cfg(feature = "preserve_order")]
+type MapEntry<'a> = index_map::Entry<'a, String, Value>;
+
+
+/// Represents any valid HiPack value.
+///
+/// Keys of a dictionary which a struct does not know about can be kept,
+/// and written back when serializing it, by adding a catch-all field with
+/// `#[serde(flatten)]` which holds a map of `Value`s:
+///
+/// ```text
+/// #[derive(Serialize, Deserialize)]
+/// struct Config {
+///     name: String,
+///     #[serde(flatten)]
+///     other: HashMap<String, Value>,
+/// }
+/// ```
+///
+/// Serde reads the entries of such structs ahead of time without asking
+/// for annotations, so captured values lose theirs.
+///
+/// Values are stored using the global allocator. With the `allocator_api`
+/// feature, documents can be read into an `AllocValue` instead, which is
+/// stored with an allocator given by the program.
+#[derive(Clone, Debug)]
+pub enum Value {
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    /// An integer outside of the range of `I64` and `U64`.
+    I128(i128),
+    /// An integer outside of the range of `U64`.
+    U128(u128),
+    F64(f64),
+    /// A number which keeps the text it was written with.
+    Number(Number),
+    String(String),
+    List(Vec<Value>),
+    Dict(Map),
+    /// A value with HEP-1 annotations, which is never empty.
+    Annotated(Vec<String>, Box<Value>),
+}
+
+
+/// Numbers are equal to plain numbers with the same value, however they
+/// are written, so `0xFF` is equal to `255`.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (&Value::Number(ref a), &Value::Number(ref b)) => a == b,
+            (&Value::Number(ref a), b) => a.value() == *b,
+            (a, &Value::Number(ref b)) => *a == b.value(),
+            (&Value::Bool(a), &Value::Bool(b)) => a == b,
+            (&Value::I64(a), &Value::I64(b)) => a == b,
+            (&Value::U64(a), &Value::U64(b)) => a == b,
+            (&Value::I128(a), &Value::I128(b)) => a == b,
+            (&Value::U128(a), &Value::U128(b)) => a == b,
+            (&Value::F64(a), &Value::F64(b)) => a == b,
+            (&Value::String(ref a), &Value::String(ref b)) => a == b,
+            (&Value::List(ref a), &Value::List(ref b)) => a == b,
+            (&Value::Dict(ref a), &Value::Dict(ref b)) => a == b,
+            (&Value::Annotated(ref a, ref v), &Value::Annotated(ref b, ref w)) => a == b && v == w,
+            _ => false,
+        }
+    }
+}
+
+
+impl Value {
+    /// Returns the entry of a dictionary with the given key, or the item
+    /// of a list at the given position, if there is one. Annotations of
+    /// the value are looked through.
+    #[inline]
+    pub fn get<I: Index>(&self, index: I) -> Option<&Value> {
+        index.index_into(self.bare())
+    }
+
+    /// Mutable version of `get()`.
+    #[inline]
+    pub fn get_mut<I: Index>(&mut self, index: I) -> Option<&mut Value> {
+        index.index_into_mut(self.bare_mut())
+    }
+
+    /// Looks up a nested value by a path like `/servers/0/port`, made of
+    /// dictionary keys and list positions. As in JSON Pointer, `/` and `~`
+    /// in keys are written as `~1` and `~0`, and the empty path refers to
+    /// the value itself.
+    pub fn pointer(&self, pointer: &str) -> Option<&Value> {
+        pointer_tokens(pointer).and_then(|tokens| {
+            tokens.iter().fold(Some(self), |target, token| {
+                target.and_then(|target| match *target.bare() {
+                    Value::List(_) => parse_position(token).and_then(|i| target.get(i)),
+                    _ => target.get(&token[..]),
+                })
+            })
+        })
+    }
+
+    /// Mutable version of `pointer()`.
+    pub fn pointer_mut(&mut self, pointer: &str) -> Option<&mut Value> {
+        pointer_tokens(pointer).and_then(|tokens| {
+            tokens.iter().fold(Some(self), |target, token| {
+                target.and_then(|target| match *target.bare_mut() {
+                    Value::List(ref mut items) => parse_position(token).and_then(move |i| items.get_mut(i)),
+                    Value::Dict(ref mut entries) => entries.get_mut(&token[..]),
+                    _ => None,
+                })
+            })
+        })
+    }
+
+    /// Returns the entry of a dictionary with the given key, to be looked
+    /// up or inserted in place. Annotations of the value are looked
+    /// through.
+    ///
+    /// Panics if the value is not a dictionary.
+    pub fn entry<S: Into<String>>(&mut self, key: S) -> Entry<'_> {
+        match *self.bare_mut() {
+            Value::Dict(ref mut entries) => Entry(entries.entry(key.into())),
+            ref value => panic!("cannot look up entries in a non-dictionary value: {:?}", value),
+        }
+    }
+
+    /// Merges another value into this one, e.g. to layer configuration
+    /// overrides on top of defaults: entries of dictionaries are merged
+    /// recursively, keys missing from this value are added, and other
+    /// values are replaced. Lists are replaced as well, see `merge_with()`
+    /// to append them instead. Annotations of `other`, if any, replace
+    /// the annotations of this value.
+    #[inline]
+    pub fn merge(&mut self, other: Value) {
+        self.merge_with(other, ListMerge::Replace)
+    }
+
+    /// Merges another value into this one like `merge()` does, combining
+    /// lists found at the same place in both with the given strategy.
+    pub fn merge_with(&mut self, other: Value, lists: ListMerge) {
+        let (annotations, other) = match other {
+            Value::Annotated(annotations, value) => (Some(annotations), value.into_bare()),
+            value => (None, value),
+        };
+        match (self.bare_mut(), other) {
+            (&mut Value::Dict(ref mut entries), Value::Dict(other)) => {
+                for (key, value) in other {
+                    match entries.get_mut(&key) {
+                        Some(entry) => entry.merge_with(value, lists),
+                        None => {
+                            entries.insert(key, value);
+                        },
+                    }
+                }
+            },
+            (&mut Value::List(ref mut items), Value::List(other)) if lists == ListMerge::Append => {
+                items.extend(other);
+            },
+            (target, other) => *target = other,
+        }
+        if let Some(annotations) = annotations {
+            let value = mem::replace(self, Value::Bool(false)).into_bare();
+            *self = Value::Annotated(annotations, Box::new(value));
+        }
+    }
+
+    /// Returns the string, if the value is one.
+    pub fn as_str(&self) -> Option<&str> {
+        match *self.bare() {
+            Value::String(ref v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Returns the integer, if the value is one which fits in an `i64`.
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self.bare() {
+            Value::I64(v) => Some(v),
+            Value::U64(v) => i64::try_from(v).ok(),
+            Value::I128(v) => i64::try_from(v).ok(),
+            Value::U128(v) => i64::try_from(v).ok(),
+            Value::Number(ref v) => v.value().as_i64(),
+            _ => None,
+        }
+    }
+
+    /// Returns the integer, if the value is one which fits in a `u64`.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self.bare() {
+            Value::I64(v) => u64::try_from(v).ok(),
+            Value::U64(v) => Some(v),
+            Value::I128(v) => u64::try_from(v).ok(),
+            Value::U128(v) => u64::try_from(v).ok(),
+            Value::Number(ref v) => v.value().as_u64(),
+            _ => None,
+        }
+    }
+
+    /// Returns the number as a float, if the value is a number. Integers
+    /// are converted, and may lose precision.
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self.bare() {
+            Value::I64(v) => Some(v as f64),
+            Value::U64(v) => Some(v as f64),
+            Value::I128(v) => Some(v as f64),
+            Value::U128(v) => Some(v as f64),
+            Value::F64(v) => Some(v),
+            Value::Number(ref v) => v.value().as_f64(),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self.bare() {
+            Value::Bool(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&Map> {
+        match *self.bare() {
+            Value::Dict(ref entries) => Some(entries),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict_mut(&mut self) -> Option<&mut Map> {
+        match *self.bare_mut() {
+            Value::Dict(ref mut entries) => Some(entries),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&Vec<Value>> {
+        match *self.bare() {
+            Value::List(ref items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_list_mut(&mut self) -> Option<&mut Vec<Value>> {
+        match *self.bare_mut() {
+            Value::List(ref mut items) => Some(items),
+            _ => None,
+        }
+    }
+
+    /// Returns the annotations of the value, empty if it has none.
+    pub fn annotations(&self) -> &[String] {
+        match *self {
+            Value::Annotated(ref annotations, _) => annotations,
+            _ => &[],
+        }
+    }
+
+    #[inline]
+    pub fn is_string(&self) -> bool {
+        self.as_str().is_some()
+    }
+
+    /// Whether the value is an integer which fits in an `i64`.
+    #[inline]
+    pub fn is_i64(&self) -> bool {
+        self.as_i64().is_some()
+    }
+
+    /// Whether the value is an integer which fits in a `u64`.
+    #[inline]
+    pub fn is_u64(&self) -> bool {
+        self.as_u64().is_some()
+    }
+
+    /// Whether the value is a float. Integers are not.
+    pub fn is_f64(&self) -> bool {
+        match *self.bare() {
+            Value::F64(_) => true,
+            Value::Number(ref v) => v.kind() == NumberKind::Float,
+            _ => false,
+        }
+    }
+
+    /// Whether the value is an integer or a float.
+    #[inline]
+    pub fn is_number(&self) -> bool {
+        self.as_f64().is_some()
+    }
+
+    #[inline]
+    pub fn is_bool(&self) -> bool {
+        self.as_bool().is_some()
+    }
+
+    #[inline]
+    pub fn is_dict(&self) -> bool {
+        self.as_dict().is_some()
+    }
+
+    #[inline]
+    pub fn is_list(&self) -> bool {
+        self.as_list().is_some()
+    }
+
+    #[inline]
+    pub fn is_annotated(&self) -> bool {
+        match *self {
+            Value::Annotated(..) => true,
+            _ => false,
+        }
+    }
+
+    /// Takes the value out, leaving an empty dictionary in its place, as
+    /// HiPack has no null value.
+    #[inline]
+    pub fn take(&mut self) -> Value {
+        mem::replace(self, Value::default())
+    }
+
+    // What the value is, to report it not being of the expected type.
+    fn unexpected(&self) -> de::Unexpected<'_> {
+        match *self.bare() {
+            Value::Bool(v) => de::Unexpected::Bool(v),
+            Value::I64(v) => de::Unexpected::Signed(v),
+            Value::U64(v) => de::Unexpected::Unsigned(v),
+            Value::I128(_) | Value::U128(_) => de::Unexpected::Other("integer"),
+            Value::F64(v) => de::Unexpected::Float(v),
+            Value::Number(ref v) => match v.value() {
+                Value::I64(v) => de::Unexpected::Signed(v),
+                Value::U64(v) => de::Unexpected::Unsigned(v),
+                Value::F64(v) => de::Unexpected::Float(v),
+                _ => de::Unexpected::Other("integer"),
+            },
+            Value::String(ref v) => de::Unexpected::Str(v),
+            Value::List(_) => de::Unexpected::Seq,
+            Value::Dict(_) => de::Unexpected::Map,
+            Value::Annotated(..) => unreachable!(),
+        }
+    }
+
+    // The value without its annotations.
+    fn bare(&self) -> &Value {
+        match *self {
+            Value::Annotated(_, ref value) => value.bare(),
+            ref value => value,
+        }
+    }
+
+    fn bare_mut(&mut self) -> &mut Value {
+        match *self {
+            Value::Annotated(_, ref mut value) => value.bare_mut(),
+            ref mut value => value,
+        }
+    }
+
+    fn into_bare(self) -> Value {
+        match self {
+            Value::Annotated(_, value) => value.into_bare(),
+            value => value,
+        }
+    }
+}
+
+
+/// An entry of a dictionary, which may be vacant, returned by
+/// `Value::entry()`.
+pub struct Entry<'a>(MapEntry<'a>);
+
+
+impl<'a> Entry<'a> {
+    #[inline]
+    pub fn key(&self) -> &String {
+        self.0.key()
+    }
+
+    /// Inserts the value if the entry is vacant, and returns the value
+    /// of the entry.
+    #[inline]
+    pub fn or_insert<V: Into<Value>>(self, value: V) -> &'a mut Value {
+        self.0.or_insert(value.into())
+    }
+
+    /// Inserts the result of the function if the entry is vacant, and
+    /// returns the value of the entry.
+    #[inline]
+    pub fn or_insert_with<F: FnOnce() -> Value>(self, default: F) -> &'a mut Value {
+        self.0.or_insert_with(default)
+    }
+
+    /// Modifies the value of the entry, if it is occupied.
+    #[inline]
+    pub fn and_modify<F: FnOnce(&mut Value)>(self, f: F) -> Self {
+        Entry(self.0.and_modify(f))
+    }
+}
+
+
+/// How `Value::merge_with()` combines two lists.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ListMerge {
+    /// Use the items of the list being merged in.
+    Replace,
+    /// Add the items of the list being merged in after the existing ones.
+    Append,
+}
+
+
+// Splits a pointer into unescaped tokens.
+fn pointer_tokens(pointer: &str) -> Option<Vec<String>> {
+    if pointer.is_empty() {
+        Some(Vec::new())
+    } else if pointer.starts_with('/') {
+        Some(pointer[1..].split('/').map(|token| token.replace("~1", "/").replace("~0", "~")).collect())
+    } else {
+        None
+    }
+}
+
+
+fn parse_position(token: &str) -> Option<usize> {
+    if token.starts_with('+') || (token.len() > 1 && token.starts_with('0')) {
+        None
+    } else {
+        token.parse().ok()
+    }
+}
+
+
+/// Types which can be used to look up values inside a `Value`, with
+/// `Value::get()` or with brackets: keys of dictionaries, and positions
+/// of lists.
+pub trait Index {
+    fn index_into<'v>(&self, value: &'v Value) -> Option<&'v Value>;
+    fn index_into_mut<'v>(&self, value: &'v mut Value) -> Option<&'v mut Value>;
+}
+
+
+impl Index for usize {
+    fn index_into<'v>(&self, value: &'v Value) -> Option<&'v Value> {
+        match *value {
+            Value::List(ref items) => items.get(*self),
+            _ => None,
+        }
+    }
+
+    fn index_into_mut<'v>(&self, value: &'v mut Value) -> Option<&'v mut Value> {
+        match *value {
+            Value::List(ref mut items) => items.get_mut(*self),
+            _ => None,
+        }
+    }
+}
+
+
+impl Index for str {
+    fn index_into<'v>(&self, value: &'v Value) -> Option<&'v Value> {
+        match *value {
+            Value::Dict(ref entries) => entries.get(self),
+            _ => None,
+        }
+    }
+
+    fn index_into_mut<'v>(&self, value: &'v mut Value) -> Option<&'v mut Value> {
+        match *value {
+            Value::Dict(ref mut entries) => entries.get_mut(self),
+            _ => None,
+        }
+    }
+}
+
+
+impl Index for String {
+    #[inline]
+    fn index_into<'v>(&self, value: &'v Value) -> Option<&'v Value> {
+        self[..].index_into(value)
+    }
+
+    #[inline]
+    fn index_into_mut<'v>(&self, value: &'v mut Value) -> Option<&'v mut Value> {
+        self[..].index_into_mut(value)
+    }
+}
+
+
+impl<'a, T: Index + ?Sized> Index for &'a T {
+    #[inline]
+    fn index_into<'v>(&self, value: &'v Value) -> Option<&'v Value> {
+        (**self).index_into(value)
+    }
+
+    #[inline]
+    fn index_into_mut<'v>(&self, value: &'v mut Value) -> Option<&'v mut Value> {
+        (**self).index_into_mut(value)
+    }
+}
+
+
+/// Looks up a value like `Value::get()` does, panicking if there is none.
+impl<I: Index> ops::Index<I> for Value {
+    type Output = Value;
+
+    fn index(&self, index: I) -> &Value {
+        self.get(index).expect("no entry found for key or position")
+    }
+}
+
+
+/// Looks up a value like `Value::get_mut()` does, panicking if there is
+/// none. Entries are not added to dictionaries.
+impl<I: Index> ops::IndexMut<I> for Value {
+    fn index_mut(&mut self, index: I) -> &mut Value {
+        self.get_mut(index).expect("no entry found for key or position")
+    }
+}
+
+
+/// An empty dictionary, which is also the empty document.
+impl Default for Value {
+    #[inline]
+    fn default() -> Value {
+        Value::Dict(Map::new())
+    }
+}
+
+
+// Integers are stored as deserializing them would, which uses I64 only
+// for negative ones, so that e.g. `Value::from(1)` equals a parsed `1`.
+macro_rules! impl_from_integer {
+    ($($ty:ty),+) => {
+        $(
+            impl From<$ty> for Value {
+                #[inline]
+                fn from(v: $ty) -> Value {
+                    Value::from(v as i128)
+                }
+            }
+        )+
+    }
+}
+
+impl_from_integer!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+
+impl From<i128> for Value {
+    fn from(v: i128) -> Value {
+        if v >= 0 {
+            Value::from(v as u128)
+        } else if v >= i64::min_value() as i128 {
+            Value::I64(v as i64)
+        } else {
+            Value::I128(v)
+        }
+    }
+}
+
+
+impl From<u128> for Value {
+    fn from(v: u128) -> Value {
+        if v <= u64::max_value() as u128 {
+            Value::U64(v as u64)
+        } else {
+            Value::U128(v)
+        }
+    }
+}
+
+
+impl From<f32> for Value {
+    #[inline]
+    fn from(v: f32) -> Value {
+        Value::F64(v as f64)
+    }
+}
+
+
+impl From<f64> for Value {
+    #[inline]
+    fn from(v: f64) -> Value {
+        Value::F64(v)
+    }
+}
+
+
+impl From<bool> for Value {
+    #[inline]
+    fn from(v: bool) -> Value {
+        Value::Bool(v)
+    }
+}
+
+
+impl From<String> for Value {
+    #[inline]
+    fn from(v: String) -> Value {
+        Value::String(v)
+    }
+}
+
+
+impl<'a> From<&'a str> for Value {
+    #[inline]
+    fn from(v: &'a str) -> Value {
+        Value::String(v.to_string())
+    }
+}
+
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    #[inline]
+    fn from(v: Vec<T>) -> Value {
+        Value::List(v.into_iter().map(Into::into).collect())
+    }
+}
+
+
+impl From<Map> for Value {
+    #[inline]
+    fn from(v: Map) -> Value {
+        Value::Dict(v)
+    }
+}
+
+
+// Conversions back look through annotations, as the accessors do, and
+// fail with the same errors as deserializing from a Value would.
+macro_rules! impl_try_from_value {
+    ($ty:ty, $expected:expr, $value:ident => $convert:expr) => {
+        impl TryFrom<Value> for $ty {
+            type Error = Error;
+
+            fn try_from($value: Value) -> Result<$ty> {
+                match $convert {
+                    Some(v) => Ok(v),
+                    None => Err(de::Error::invalid_type($value.unexpected(), &$expected)),
+                }
+            }
+        }
+    }
+}
+
+impl_try_from_value!(bool, "a boolean", value => value.as_bool());
+impl_try_from_value!(i64, "an i64", value => value.as_i64());
+impl_try_from_value!(u64, "a u64", value => value.as_u64());
+impl_try_from_value!(f64, "a number", value => value.as_f64());
+
+
+impl TryFrom<Value> for String {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<String> {
+        match value.into_bare() {
+            Value::String(v) => Ok(v),
+            value => Err(de::Error::invalid_type(value.unexpected(), &"a string")),
+        }
+    }
+}
+
+
+impl TryFrom<Value> for Vec<Value> {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Vec<Value>> {
+        match value.into_bare() {
+            Value::List(items) => Ok(items),
+            value => Err(de::Error::invalid_type(value.unexpected(), &"a list")),
+        }
+    }
+}
+
+
+impl TryFrom<Value> for Map {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Map> {
+        match value.into_bare() {
+            Value::Dict(entries) => Ok(entries),
+            value => Err(de::Error::invalid_type(value.unexpected(), &"a dictionary")),
+        }
+    }
+}
+
+
+impl Value {
+    /// Writes the value as HiPack, in compact form. Values with keys or
+    /// annotations which cannot be written fail, which is why `Value`
+    /// does not implement `Display`.
+    #[inline]
+    pub fn to_hipack_string(&self) -> Result<String> {
+        to_string(self)
+    }
+
+    /// Writes the value as pretty-printed HiPack, see `to_hipack_string()`.
+    #[inline]
+    pub fn to_hipack_string_pretty(&self) -> Result<String> {
+        to_string_pretty(self)
+    }
+}
+
+
+impl ser::Serialize for Value {
+    fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+        where S: ser::Serializer
+    {
+        match *self {
+            Value::Bool(v) => serializer.serialize_bool(v),
+            Value::I64(v) => serializer.serialize_i64(v),
+            Value::U64(v) => serializer.serialize_u64(v),
+            Value::I128(v) => serializer.serialize_i128(v),
+            Value::U128(v) => serializer.serialize_u128(v),
+            Value::F64(v) => serializer.serialize_f64(v),
+            Value::Number(ref v) => v.serialize(serializer),
+            Value::String(ref v) => serializer.serialize_str(v),
+            Value::List(ref v) => v.serialize(serializer),
+            Value::Dict(ref v) => v.serialize(serializer),
+            Value::Annotated(ref annotations, ref v) => {
+                serialize_annotated(serializer, annotations, v)
+            },
+        }
+    }
+}
+
+
+struct ValueVisitor;
+
+
+impl<'de> de::Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("any HiPack value")
+    }
+
+    #[inline]
+    fn visit_bool<E>(self, v: bool) -> ::std::result::Result<Value, E> {
+        Ok(Value::Bool(v))
+    }
+
+    #[inline]
+    fn visit_i64<E>(self, v: i64) -> ::std::result::Result<Value, E> {
+        Ok(Value::I64(v))
+    }
+
+    #[inline]
+    fn visit_u64<E>(self, v: u64) -> ::std::result::Result<Value, E> {
+        Ok(Value::U64(v))
+    }
+
+    fn visit_i128<E>(self, v: i128) -> ::std::result::Result<Value, E>
+        where E: de::Error
+    {
+        if v >= 0 {
+            self.visit_u128(v as u128)
+        } else if v >= i64::min_value() as i128 {
+            Ok(Value::I64(v as i64))
+        } else {
+            Ok(Value::I128(v))
+        }
+    }
+
+    fn visit_u128<E>(self, v: u128) -> ::std::result::Result<Value, E> {
+        if v <= u64::max_value() as u128 {
+            Ok(Value::U64(v as u64))
+        } else {
+            Ok(Value::U128(v))
+        }
+    }
+
+    #[inline]
+    fn visit_f64<E>(self, v: f64) -> ::std::result::Result<Value, E> {
+        Ok(Value::F64(v))
+    }
+
+    #[inline]
+    fn visit_str<E>(self, v: &str) -> ::std::result::Result<Value, E>
+        where E: de::Error
+    {
+        self.visit_string(v.to_string())
+    }
+
+    #[inline]
+    fn visit_string<E>(self, v: String) -> ::std::result::Result<Value, E> {
+        Ok(Value::String(v))
+    }
+
+    #[inline]
+    fn visit_newtype_struct<D>(self, deserializer: D) -> ::std::result::Result<Value, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_seq<V>(self, mut visitor: V) -> ::std::result::Result<Value, V::Error>
+        where V: de::SeqAccess<'de>
+    {
+        let mut values = Vec::new();
+        while let Some(value) = try!(visitor.next_element()) {
+            values.push(value);
+        }
+        Ok(Value::List(values))
+    }
+
+    fn visit_map<V>(self, mut visitor: V) -> ::std::result::Result<Value, V::Error>
+        where V: de::MapAccess<'de>
+    {
+        let key = try!(visitor.next_key::<String>());
+        if key.as_ref().map_or(false, |key| key == ANNOTATIONS_KEY) {
+            return visit_annotated(visitor);
+        }
+        let mut values = Map::new();
+        if let Some(key) = key {
+            let value = try!(visitor.next_value());
+            values.insert(key, value);
+        }
+        while let Some((key, value)) = try!(visitor.next_entry()) {
+            values.insert(key, value);
+        }
+        Ok(Value::Dict(values))
+    }
+}
+
+
+// Reads the annotations of a value and the value itself, as passed by the
+// HiPack Deserializer. Every nested value goes through here, so it is kept
+// apart from the rest of visit_map() to take up little stack.
+fn visit_annotated<'de, V>(mut visitor: V) -> ::std::result::Result<Value, V::Error>
+    where V: de::MapAccess<'de>
+{
+    let annotations: Vec<String> = try!(visitor.next_value());
+    let value = match try!(visitor.next_key::<String>()) {
+        Some(ref key) if key == VALUE_KEY => try!(visitor.next_value::<BareValue>()).0,
+        Some(ref key) if key == NUMBER_KEY => try!(visit_number(&mut visitor)),
+        _ => return Err(de::Error::custom("expected annotated value")),
+    };
+    Ok(if annotations.is_empty() {
+        value
+    } else {
+        Value::Annotated(annotations, Box::new(value))
+    })
+}
+
+
+#[inline(never)]
+fn visit_number<'de, V>(visitor: &mut V) -> ::std::result::Result<Value, V::Error>
+    where V: de::MapAccess<'de>
+{
+    let text: String = try!(visitor.next_value());
+    let number: Number = try!(text.parse().map_err(de::Error::custom));
+    Ok(Value::from(number))
+}
+
+
+// The HiPack Deserializer passes the annotations of the value as a map,
+// while deserializers for other formats pass the value itself.
+impl<'de> de::Deserialize<'de> for Value {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> ::std::result::Result<Value, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        deserializer.deserialize_newtype_struct(VALUE_NAME, ValueVisitor)
+    }
+}
+
+
+// Deserializes the value which follows the annotations, which must not
+// request them again.
+struct BareValue(Value);
+
+
+impl<'de> de::Deserialize<'de> for BareValue {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> ::std::result::Result<BareValue, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        deserializer.deserialize_any(ValueVisitor).map(BareValue)
+    }
+}
+
+
+/// Deserializes Rust values out of a `Value`.
+pub struct Deserializer {
+    value: Value,
+}
+
+
+impl Deserializer {
+    #[inline]
+    pub fn new(value: Value) -> Self {
+        Deserializer { value: value }
+    }
+}
+
+
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de>
+    {
+        match self.value {
+            Value::Bool(v) => visitor.visit_bool(v),
+            Value::I64(v) => visitor.visit_i64(v),
+            Value::U64(v) => visitor.visit_u64(v),
+            Value::I128(v) => visitor.visit_i128(v),
+            Value::U128(v) => visitor.visit_u128(v),
+            Value::F64(v) => visitor.visit_f64(v),
+            Value::Number(v) => Deserializer::new(v.value()).deserialize_any(visitor),
+            Value::String(v) => visitor.visit_string(v),
+            Value::List(v) => {
+                let len = v.len();
+                let mut seq = SeqDeserializer { iter: v.into_iter(), len: len };
+                let value = try!(visitor.visit_seq(&mut seq));
+                if seq.len == 0 {
+                    Ok(value)
+                } else {
+                    Err(de::Error::invalid_length(len, &"fewer elements in list"))
+                }
+            },
+            Value::Dict(v) => {
+                visitor.visit_map(MapDeserializer { iter: v.into_iter(), value: None })
+            },
+            Value::Annotated(_, v) => Deserializer::new(*v).deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_newtype_struct<V>(self, name: &'static str, visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de>
+    {
+        if name == RAW_VALUE_NAME {
+            return visitor.visit_string(try!(to_string(&self.value)));
+        }
+        if name == SPANNED_NAME {
+            return visitor.visit_newtype_struct(self);
+        }
+        if name != ANNOTATED_NAME && name != VALUE_NAME {
+            return self.deserialize_any(visitor);
+        }
+        let (annotations, value) = match self.value {
+            Value::Annotated(annotations, value) => (annotations, *value),
+            value => (Vec::new(), value),
+        };
+        visitor.visit_map(AnnotatedDeserializer {
+            annotations: Some(annotations),
+            value: Some(value),
+            numbers: name == VALUE_NAME,
+        })
+    }
+
+    #[inline]
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de>
+    {
+        match self.value {
+            Value::Annotated(ref annotations, ref value) if is_none_marker(annotations, value) => {
+                visitor.visit_none()
+            },
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        enum identifier ignored_any
+    }
+}
+
+
+impl<'de> IntoDeserializer<'de, Error> for Value {
+    type Deserializer = Deserializer;
+
+    #[inline]
+    fn into_deserializer(self) -> Deserializer {
+        Deserializer::new(self)
+    }
+}
+
+
+struct SeqDeserializer {
+    iter: vec::IntoIter<Value>,
+    len: usize,
+}
+
+
+impl<'de> de::SeqAccess<'de> for SeqDeserializer {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+        where T: de::DeserializeSeed<'de>
+    {
+        match self.iter.next() {
+            Some(value) => {
+                self.len -= 1;
+                let value = try!(seed.deserialize(Deserializer::new(value)));
+                Ok(Some(value))
+            },
+            None => Ok(None),
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+
+struct MapDeserializer {
+    iter: MapIntoIter,
+    value: Option<Value>,
+}
+
+
+impl<'de> de::MapAccess<'de> for MapDeserializer {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+        where K: de::DeserializeSeed<'de>
+    {
+        match self.iter.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                let key = try!(seed.deserialize(Deserializer::new(Value::String(key))));
+                Ok(Some(key))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+        where V: de::DeserializeSeed<'de>
+    {
+        match self.value.take() {
+            Some(value) => seed.deserialize(Deserializer::new(value)),
+            None => Err(de::Error::custom("expected value")),
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+
+struct AnnotatedDeserializer {
+    annotations: Option<Vec<String>>,
+    value: Option<Value>,
+    // Whether numbers are passed with NUMBER_KEY, see VALUE_NAME.
+    numbers: bool,
+}
+
+
+impl<'de> de::MapAccess<'de> for AnnotatedDeserializer {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+        where K: de::DeserializeSeed<'de>
+    {
+        let key = match (&self.annotations, &self.value) {
+            (&Some(_), _) => ANNOTATIONS_KEY,
+            (&None, &Some(Value::Number(_))) if self.numbers => NUMBER_KEY,
+            (&None, &Some(_)) => VALUE_KEY,
+            (&None, &None) => return Ok(None),
+        };
+        let key = try!(seed.deserialize(Deserializer::new(Value::String(key.to_string()))));
+        Ok(Some(key))
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+        where V: de::DeserializeSeed<'de>
+    {
+        if let Some(annotations) = self.annotations.take() {
+            return seed.deserialize(annotations.into_deserializer());
+        }
+        match self.value.take() {
+            Some(Value::Number(number)) if self.numbers => {
+                seed.deserialize(Deserializer::new(Value::String(number.into_string())))
+            },
+            Some(value) => seed.deserialize(Deserializer::new(value)),
+            None => Err(de::Error::custom("expected value")),
+        }
+    }
+}
+
+
+#[cfg(all(test, feature = "de"))]
+mod tests {
+    use super::*;
+    use de::from_str;
+    use ser::to_string;
+
+    #[test]
+    fn test_parse() {
+        let value: Value = from_str("a: 1, b: [-2 3.5 \"s\"] c {d: True}").unwrap();
+        let mut c = Map::new();
+        c.insert("d".to_string(), Value::Bool(true));
+        let mut expected = Map::new();
+        expected.insert("a".to_string(), Value::U64(1));
+        expected.insert("b".to_string(), Value::List(vec![Value::I64(-2),
+                                                          Value::F64(3.5),
+                                                          Value::String("s".to_string())]));
+        expected.insert("c".to_string(), Value::Dict(c));
+        assert_eq!(Value::Dict(expected), value);
+    }
+
+    #[test]
+    fn test_annotations() {
+        let value: Value = from_str("a: :x:y [:z 1 2]").unwrap();
+        let list = Value::List(vec![Value::Annotated(vec!["z".to_string()],
+                                                     Box::new(Value::U64(1))),
+                                    Value::U64(2)]);
+        let mut expected = Map::new();
+        expected.insert("a".to_string(), Value::Annotated(vec!["x".to_string(), "y".to_string()],
+                                                          Box::new(list)));
+        assert_eq!(Value::Dict(expected), value);
+        assert_eq!("{a::x:y [:z 1,2]}", to_string(&value).unwrap());
+    }
+
+    #[test]
+    fn test_flatten_unknown_fields() {
+        use std::collections::HashMap;
+
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Config {
+            name: String,
+            #[serde(flatten)]
+            other: HashMap<String, Value>,
+        }
+
+        let config: Config = from_str("name: \"x\" extra: [1 -2] nested {a: 1.5}").unwrap();
+        assert_eq!("x", config.name);
+        assert_eq!(2, config.other.len());
+        assert_eq!(Value::List(vec![Value::U64(1), Value::I64(-2)]), config.other["extra"]);
+        let mut nested = Map::new();
+        nested.insert("a".to_string(), Value::F64(1.5));
+        assert_eq!(Value::Dict(nested), config.other["nested"]);
+
+        let mut config = Config { name: "y".to_string(), other: HashMap::new() };
+        config.other.insert("extra".to_string(), Value::Bool(true));
+        assert_eq!("{name:\"y\",extra:True}", to_string(&config).unwrap());
+    }
+
+    #[cfg(feature = "preserve_order")]
+    #[test]
+    fn test_preserve_order() {
+        let input = "{z:1,a:{y:True,b:\"s\"},m:[{c:2,b:3}]}";
+        let value: Value = from_str(input).unwrap();
+        assert_eq!(input, to_string(&value).unwrap());
+        match value {
+            Value::Dict(ref entries) => {
+                assert_eq!(vec!["z", "a", "m"], entries.keys().collect::<Vec<_>>());
+            },
+            _ => panic!("expected a dictionary"),
+        }
+    }
+
+    #[test]
+    fn test_index() {
+        let mut value: Value = from_str("servers [{host: \"a\" port: 80} :x {a/b~: 1}] t: :y True").unwrap();
+        assert_eq!(Value::U64(80), value["servers"][0]["port"]);
+        assert_eq!(Some(&Value::U64(1)), value["servers"][1].get("a/b~"));
+        assert_eq!(Some(&Value::Annotated(vec!["y".to_string()], Box::new(Value::Bool(true)))),
+                   value.get("t"));
+        assert_eq!(None, value.get(0));
+        assert_eq!(None, value["servers"].get(2));
+        assert_eq!(None, value["t"].get("x"));
+        value["servers"][0]["port"] = Value::U64(8080);
+        *value.get_mut(&"t".to_string()).unwrap() = Value::Bool(false);
+        assert_eq!("{servers:[{host:\"a\",port:8080},:x {a/b~:1}],t:False}", to_string(&value).unwrap());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_index_missing() {
+        let value: Value = from_str("a: 1").unwrap();
+        let _ = &value["b"];
+    }
+
+    #[test]
+    fn test_pointer() {
+        let mut value: Value = from_str("servers [{port: 80} :x {a/b~: [True]}] 0: \"s\"").unwrap();
+        assert_eq!(Some(&value.clone()), value.pointer(""));
+        assert_eq!(Some(&Value::U64(80)), value.pointer("/servers/0/port"));
+        assert_eq!(Some(&Value::Bool(true)), value.pointer("/servers/1/a~1b~0/0"));
+        assert_eq!(Some(&Value::String("s".to_string())), value.pointer("/0"));
+        for pointer in &["servers", "/servers/00/port", "/servers/+0/port", "/servers/2", "/0/x", "/x"] {
+            assert_eq!(None, value.pointer(pointer), "{}", pointer);
+        }
+        *value.pointer_mut("/servers/0/port").unwrap() = Value::U64(8080);
+        assert_eq!(Value::U64(8080), value["servers"][0]["port"]);
+        assert!(value.pointer_mut("/servers/0/host").is_none());
+    }
+
+    #[test]
+    fn test_merge() {
+        let defaults = "name: \"web\" tags: [\"a\"] server {host: \"localhost\" port: 80} key: :base64 \"AA==\"";
+        let overrides: Value = from_str("tags: [\"b\"] server {port: 8080 tls: True} key: \"x\"").unwrap();
+        let mut value: Value = from_str(defaults).unwrap();
+        value.merge(overrides.clone());
+        let expected: Value = from_str("name: \"web\" tags: [\"b\"] server {host: \"localhost\" port: 8080 \
+                                        tls: True} key: :base64 \"x\"").unwrap();
+        assert_eq!(expected, value);
+
+        let mut value: Value = from_str(defaults).unwrap();
+        value.merge_with(overrides, ListMerge::Append);
+        assert_eq!(Value::List(vec![Value::String("a".to_string()), Value::String("b".to_string())]),
+                   value["tags"]);
+        value.merge(from_str("server: :x 1 name: :y {a: 1}").unwrap());
+        assert_eq!(Value::Annotated(vec!["x".to_string()], Box::new(Value::U64(1))), value["server"]);
+        assert_eq!(from_str::<Value>("a: :y {a: 1}").unwrap()["a"], value["name"]);
+    }
+
+    #[test]
+    fn test_to_hipack_string() {
+        let value: Value = from_str("a: :x 1 b: [1.5 \"s\\n\"] c {}").unwrap();
+        assert_eq!("{a::x 1,b:[1.5,\"s\\n\"],c:{}}", value.to_hipack_string().unwrap());
+        assert_eq!(::ser::to_string_pretty(&value).unwrap(), value.to_hipack_string_pretty().unwrap());
+        assert_eq!("True", Value::Bool(true).to_hipack_string().unwrap());
+        let mut entries = Map::new();
+        entries.insert("a b".to_string(), Value::Bool(true));
+        assert!(Value::Dict(entries).to_hipack_string().is_err());
+    }
+
+    #[test]
+    fn test_accessors() {
+        let mut value: Value = from_str("s: :x \"a\" n: -2 u: 0xFFFFFFFFFFFFFFFF f: 1.5 b: True l: [1] d {}").unwrap();
+        assert_eq!(Some("a"), value["s"].as_str());
+        assert_eq!(&["x".to_string()], value["s"].annotations());
+        assert_eq!(Some(-2), value["n"].as_i64());
+        assert_eq!(None, value["n"].as_u64());
+        assert_eq!(None, value["u"].as_i64());
+        assert_eq!(Some(u64::max_value()), value["u"].as_u64());
+        assert_eq!(Some(-2.0), value["n"].as_f64());
+        assert!(value["f"].is_f64() && value["f"].is_number() && !value["f"].is_i64());
+        assert_eq!(Some(true), value["b"].as_bool());
+        assert_eq!(Some(&vec![Value::U64(1)]), value["l"].as_list());
+        assert!(value["d"].is_dict() && value["s"].is_string() && value["s"].is_annotated());
+        assert_eq!(None, value["s"].as_bool());
+        value["l"].as_list_mut().unwrap().push(Value::from("x"));
+        assert_eq!(Value::from(vec![Value::from(1u8), Value::from("x")]), value["l"]);
+        assert_eq!(Value::U64(1), value["l"][0].take());
+        assert_eq!(Value::default(), value["l"][0]);
+        assert_eq!(7, value.as_dict().unwrap().len());
+    }
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(Value::I64(-1), Value::from(-1i8));
+        assert_eq!(Value::U64(2), Value::from(2));
+        assert_eq!(Value::U64(1), Value::from(1i128));
+        assert_eq!(Value::I128(i128::min_value()), Value::from(i128::min_value()));
+        assert_eq!(Value::U128(u128::max_value()), Value::from(u128::max_value()));
+        assert_eq!(Value::F64(0.5), Value::from(0.5f32));
+        assert_eq!(Value::List(vec![Value::Bool(true)]), Value::from(vec![true]));
+        assert!(bool::try_from(Value::Bool(true)).unwrap());
+        assert_eq!("a", String::try_from(Value::Annotated(vec!["x".to_string()],
+                                                             Box::new(Value::from("a")))).unwrap());
+        assert_eq!(3.0, f64::try_from(Value::U64(3)).unwrap());
+        assert_eq!(vec![Value::U64(1)], Vec::<Value>::try_from(Value::from(vec![1u8])).unwrap());
+        assert!(Map::try_from(Value::default()).unwrap().is_empty());
+        let err = i64::try_from(Value::from("1")).unwrap_err();
+        assert_eq!("invalid type: string \"1\", expected an i64", err.to_string());
+        assert!(u64::try_from(Value::I64(-1)).is_err());
+    }
+
+    #[test]
+    fn test_entry() {
+        let mut value: Value = from_str("a: :x {b: 1}").unwrap();
+        *value["a"].entry("b").or_insert(2) = Value::U64(3);
+        value["a"].entry("c").or_insert_with(|| Value::from("x"));
+        value.entry("n").and_modify(|v| *v = Value::Bool(false)).or_insert(true);
+        value.entry("n").and_modify(|v| *v = Value::Bool(false)).or_insert(true);
+        assert_eq!("n", value.entry("n").key());
+        assert_eq!(from_str::<Value>("a: :x {b: 3 c: \"x\"} n: False").unwrap(), value);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_entry_not_dict() {
+        Value::Bool(true).entry("a");
+    }
+}
@@ -0,0 +1,499 @@
+//
+// alloc.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use std::{fmt, ops, str};
+use allocator_api2::alloc::{Allocator, Global};
+use allocator_api2::boxed::Box;
+use allocator_api2::vec::Vec;
+use serde::{de, ser};
+use de::Deserializer;
+use error::Result;
+use ser::serialize_annotated;
+use super::{Map, Value, ANNOTATED_NAME, ANNOTATIONS_KEY, VALUE_KEY};
+
+
+/// A string stored with the allocator `A`.
+#[derive(Clone)]
+pub struct AllocString<A: Allocator = Global>(Vec<u8, A>);
+
+
+impl<A: Allocator> AllocString<A> {
+    pub fn from_str_in(text: &str, alloc: A) -> Self {
+        let mut bytes = Vec::with_capacity_in(text.len(), alloc);
+        bytes.extend_from_slice(text.as_bytes());
+        AllocString(bytes)
+    }
+
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        // The bytes are only ever copied from a str.
+        unsafe { str::from_utf8_unchecked(&self.0) }
+    }
+}
+
+
+impl<A: Allocator> ops::Deref for AllocString<A> {
+    type Target = str;
+
+    #[inline]
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+
+impl<A: Allocator> fmt::Debug for AllocString<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+
+impl<A: Allocator, B: Allocator> PartialEq<AllocString<B>> for AllocString<A> {
+    #[inline]
+    fn eq(&self, other: &AllocString<B>) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+
+/// A HiPack value whose strings, lists, dictionaries and annotations are
+/// stored with the allocator `A` instead of the global one, e.g. to place
+/// the documents read by a program with soft real-time requirements in a
+/// region or a pool it controls. Any `Allocator` from the `allocator-api2`
+/// crate can be used, including references to allocators which implement
+/// it, like `&bumpalo::Bump`.
+///
+/// Numbers keep only their value, and dictionaries keep their entries in
+/// the order they were first found, with the last value given for each
+/// key. Values are read with `from_str_in()`, or with an `AllocSeed` for
+/// other deserializers, and convert into a `Value` with `to_value()`.
+pub enum AllocValue<A: Allocator = Global> {
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    I128(i128),
+    U128(u128),
+    F64(f64),
+    String(AllocString<A>),
+    List(Vec<AllocValue<A>, A>),
+    Dict(Vec<(AllocString<A>, AllocValue<A>), A>),
+    /// A value with HEP-1 annotations, which is never empty.
+    Annotated(Vec<AllocString<A>, A>, Box<AllocValue<A>, A>),
+}
+
+
+impl<A: Allocator> AllocValue<A> {
+    #[inline]
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            AllocValue::String(ref v) => Some(v),
+            AllocValue::Annotated(_, ref v) => v.as_str(),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of a key, if this is a dictionary which has it.
+    pub fn get(&self, key: &str) -> Option<&AllocValue<A>> {
+        match *self {
+            AllocValue::Dict(ref entries) => {
+                entries.iter().find(|entry| entry.0.as_str() == key).map(|entry| &entry.1)
+            },
+            AllocValue::Annotated(_, ref v) => v.get(key),
+            _ => None,
+        }
+    }
+
+    /// Copies the value into a `Value` which uses the global allocator.
+    pub fn to_value(&self) -> Value {
+        match *self {
+            AllocValue::Bool(v) => Value::Bool(v),
+            AllocValue::I64(v) => Value::I64(v),
+            AllocValue::U64(v) => Value::U64(v),
+            AllocValue::I128(v) => Value::I128(v),
+            AllocValue::U128(v) => Value::U128(v),
+            AllocValue::F64(v) => Value::F64(v),
+            AllocValue::String(ref v) => Value::String(v.to_string()),
+            AllocValue::List(ref items) => Value::List(items.iter().map(AllocValue::to_value).collect()),
+            AllocValue::Dict(ref entries) => {
+                let mut map = Map::new();
+                for &(ref key, ref value) in entries.iter() {
+                    map.insert(key.to_string(), value.to_value());
+                }
+                Value::Dict(map)
+            },
+            AllocValue::Annotated(ref annotations, ref v) => {
+                Value::Annotated(annotations.iter().map(|annotation| annotation.to_string()).collect(),
+                                 ::std::boxed::Box::new(v.to_value()))
+            },
+        }
+    }
+}
+
+
+impl<A: Allocator + Clone> Clone for AllocValue<A> {
+    fn clone(&self) -> Self {
+        match *self {
+            AllocValue::Bool(v) => AllocValue::Bool(v),
+            AllocValue::I64(v) => AllocValue::I64(v),
+            AllocValue::U64(v) => AllocValue::U64(v),
+            AllocValue::I128(v) => AllocValue::I128(v),
+            AllocValue::U128(v) => AllocValue::U128(v),
+            AllocValue::F64(v) => AllocValue::F64(v),
+            AllocValue::String(ref v) => AllocValue::String(v.clone()),
+            AllocValue::List(ref v) => AllocValue::List(v.clone()),
+            AllocValue::Dict(ref v) => AllocValue::Dict(v.clone()),
+            AllocValue::Annotated(ref annotations, ref v) => {
+                AllocValue::Annotated(annotations.clone(), v.clone())
+            },
+        }
+    }
+}
+
+
+impl<A: Allocator> fmt::Debug for AllocValue<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AllocValue::Bool(ref v) => f.debug_tuple("Bool").field(v).finish(),
+            AllocValue::I64(ref v) => f.debug_tuple("I64").field(v).finish(),
+            AllocValue::U64(ref v) => f.debug_tuple("U64").field(v).finish(),
+            AllocValue::I128(ref v) => f.debug_tuple("I128").field(v).finish(),
+            AllocValue::U128(ref v) => f.debug_tuple("U128").field(v).finish(),
+            AllocValue::F64(ref v) => f.debug_tuple("F64").field(v).finish(),
+            AllocValue::String(ref v) => f.debug_tuple("String").field(v).finish(),
+            AllocValue::List(ref v) => f.debug_tuple("List").field(&&v[..]).finish(),
+            AllocValue::Dict(ref v) => f.debug_tuple("Dict").field(&&v[..]).finish(),
+            AllocValue::Annotated(ref annotations, ref v) => {
+                f.debug_tuple("Annotated").field(&&annotations[..]).field(&**v).finish()
+            },
+        }
+    }
+}
+
+
+impl<A: Allocator, B: Allocator> PartialEq<AllocValue<B>> for AllocValue<A> {
+    fn eq(&self, other: &AllocValue<B>) -> bool {
+        match (self, other) {
+            (&AllocValue::Bool(a), &AllocValue::Bool(b)) => a == b,
+            (&AllocValue::I64(a), &AllocValue::I64(b)) => a == b,
+            (&AllocValue::U64(a), &AllocValue::U64(b)) => a == b,
+            (&AllocValue::I128(a), &AllocValue::I128(b)) => a == b,
+            (&AllocValue::U128(a), &AllocValue::U128(b)) => a == b,
+            (&AllocValue::F64(a), &AllocValue::F64(b)) => a == b,
+            (&AllocValue::String(ref a), &AllocValue::String(ref b)) => a == b,
+            (&AllocValue::List(ref a), &AllocValue::List(ref b)) => a[..] == b[..],
+            (&AllocValue::Dict(ref a), &AllocValue::Dict(ref b)) => {
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| a.0 == b.0 && a.1 == b.1)
+            },
+            (&AllocValue::Annotated(ref a, ref x), &AllocValue::Annotated(ref b, ref y)) => {
+                a[..] == b[..] && **x == **y
+            },
+            _ => false,
+        }
+    }
+}
+
+
+impl<A: Allocator> ser::Serialize for AllocString<A> {
+    #[inline]
+    fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+        where S: ser::Serializer
+    {
+        serializer.serialize_str(self)
+    }
+}
+
+
+impl<A: Allocator> ser::Serialize for AllocValue<A> {
+    fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+        where S: ser::Serializer
+    {
+        match *self {
+            AllocValue::Bool(v) => serializer.serialize_bool(v),
+            AllocValue::I64(v) => serializer.serialize_i64(v),
+            AllocValue::U64(v) => serializer.serialize_u64(v),
+            AllocValue::I128(v) => serializer.serialize_i128(v),
+            AllocValue::U128(v) => serializer.serialize_u128(v),
+            AllocValue::F64(v) => serializer.serialize_f64(v),
+            AllocValue::String(ref v) => serializer.serialize_str(v),
+            AllocValue::List(ref v) => serializer.collect_seq(v.iter()),
+            AllocValue::Dict(ref v) => serializer.collect_map(v.iter().map(|entry| (&entry.0, &entry.1))),
+            AllocValue::Annotated(ref annotations, ref v) => {
+                let annotations: ::std::vec::Vec<String> =
+                    annotations.iter().map(|annotation| annotation.to_string()).collect();
+                serialize_annotated(serializer, &annotations, &**v)
+            },
+        }
+    }
+}
+
+
+/// Deserializes an `AllocValue` stored with the allocator it holds.
+#[derive(Clone, Copy, Debug)]
+pub struct AllocSeed<A>(pub A);
+
+
+impl<'de, A: Allocator + Clone> de::DeserializeSeed<'de> for AllocSeed<A> {
+    type Value = AllocValue<A>;
+
+    #[inline]
+    fn deserialize<D>(self, deserializer: D) -> ::std::result::Result<AllocValue<A>, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        deserializer.deserialize_newtype_struct(ANNOTATED_NAME, AllocVisitor(self.0))
+    }
+}
+
+
+/// Reads a value from a string, storing it with the allocator `alloc`.
+pub fn from_str_in<A: Allocator + Clone>(s: &str, alloc: A) -> Result<AllocValue<A>> {
+    let mut de = Deserializer::from_slice(s.as_bytes());
+    let value = try!(de::DeserializeSeed::deserialize(AllocSeed(alloc), &mut de));
+    try!(de.end());
+    Ok(value)
+}
+
+
+// Deserializes the value which follows the annotations, which must not
+// request them again.
+struct BareSeed<A>(A);
+
+
+impl<'de, A: Allocator + Clone> de::DeserializeSeed<'de> for BareSeed<A> {
+    type Value = AllocValue<A>;
+
+    #[inline]
+    fn deserialize<D>(self, deserializer: D) -> ::std::result::Result<AllocValue<A>, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        deserializer.deserialize_any(AllocVisitor(self.0))
+    }
+}
+
+
+struct StringSeed<A>(A);
+
+
+impl<'de, A: Allocator> de::DeserializeSeed<'de> for StringSeed<A> {
+    type Value = AllocString<A>;
+
+    #[inline]
+    fn deserialize<D>(self, deserializer: D) -> ::std::result::Result<AllocString<A>, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        deserializer.deserialize_str(self)
+    }
+}
+
+
+impl<'de, A: Allocator> de::Visitor<'de> for StringSeed<A> {
+    type Value = AllocString<A>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a string")
+    }
+
+    #[inline]
+    fn visit_str<E>(self, v: &str) -> ::std::result::Result<AllocString<A>, E> {
+        Ok(AllocString::from_str_in(v, self.0))
+    }
+}
+
+
+struct AnnotationsSeed<A>(A);
+
+
+impl<'de, A: Allocator + Clone> de::DeserializeSeed<'de> for AnnotationsSeed<A> {
+    type Value = Vec<AllocString<A>, A>;
+
+    #[inline]
+    fn deserialize<D>(self, deserializer: D) -> ::std::result::Result<Self::Value, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+
+impl<'de, A: Allocator + Clone> de::Visitor<'de> for AnnotationsSeed<A> {
+    type Value = Vec<AllocString<A>, A>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a list of annotations")
+    }
+
+    fn visit_seq<V>(self, mut visitor: V) -> ::std::result::Result<Self::Value, V::Error>
+        where V: de::SeqAccess<'de>
+    {
+        let mut annotations = Vec::new_in(self.0.clone());
+        while let Some(annotation) = try!(visitor.next_element_seed(StringSeed(self.0.clone()))) {
+            annotations.push(annotation);
+        }
+        Ok(annotations)
+    }
+}
+
+
+struct AllocVisitor<A>(A);
+
+
+impl<'de, A: Allocator + Clone> de::Visitor<'de> for AllocVisitor<A> {
+    type Value = AllocValue<A>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("any HiPack value")
+    }
+
+    #[inline]
+    fn visit_bool<E>(self, v: bool) -> ::std::result::Result<AllocValue<A>, E> {
+        Ok(AllocValue::Bool(v))
+    }
+
+    #[inline]
+    fn visit_i64<E>(self, v: i64) -> ::std::result::Result<AllocValue<A>, E> {
+        Ok(AllocValue::I64(v))
+    }
+
+    #[inline]
+    fn visit_u64<E>(self, v: u64) -> ::std::result::Result<AllocValue<A>, E> {
+        Ok(AllocValue::U64(v))
+    }
+
+    fn visit_i128<E>(self, v: i128) -> ::std::result::Result<AllocValue<A>, E>
+        where E: de::Error
+    {
+        if v >= 0 {
+            self.visit_u128(v as u128)
+        } else if v >= i64::min_value() as i128 {
+            Ok(AllocValue::I64(v as i64))
+        } else {
+            Ok(AllocValue::I128(v))
+        }
+    }
+
+    fn visit_u128<E>(self, v: u128) -> ::std::result::Result<AllocValue<A>, E> {
+        if v <= u64::max_value() as u128 {
+            Ok(AllocValue::U64(v as u64))
+        } else {
+            Ok(AllocValue::U128(v))
+        }
+    }
+
+    #[inline]
+    fn visit_f64<E>(self, v: f64) -> ::std::result::Result<AllocValue<A>, E> {
+        Ok(AllocValue::F64(v))
+    }
+
+    #[inline]
+    fn visit_str<E>(self, v: &str) -> ::std::result::Result<AllocValue<A>, E> {
+        Ok(AllocValue::String(AllocString::from_str_in(v, self.0)))
+    }
+
+    #[inline]
+    fn visit_newtype_struct<D>(self, deserializer: D) -> ::std::result::Result<AllocValue<A>, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_seq<V>(self, mut visitor: V) -> ::std::result::Result<AllocValue<A>, V::Error>
+        where V: de::SeqAccess<'de>
+    {
+        let mut items = Vec::new_in(self.0.clone());
+        while let Some(item) = try!(visitor.next_element_seed(AllocSeed(self.0.clone()))) {
+            items.push(item);
+        }
+        Ok(AllocValue::List(items))
+    }
+
+    fn visit_map<V>(self, mut visitor: V) -> ::std::result::Result<AllocValue<A>, V::Error>
+        where V: de::MapAccess<'de>
+    {
+        let mut key = try!(visitor.next_key_seed(StringSeed(self.0.clone())));
+        if key.as_ref().map_or(false, |key| key.as_str() == ANNOTATIONS_KEY) {
+            return visit_annotated(self.0, visitor);
+        }
+        let mut entries: Vec<(AllocString<A>, AllocValue<A>), A> = Vec::new_in(self.0.clone());
+        while let Some(name) = key {
+            let value = try!(visitor.next_value_seed(AllocSeed(self.0.clone())));
+            match entries.iter().position(|entry| entry.0 == name) {
+                Some(index) => entries[index].1 = value,
+                None => entries.push((name, value)),
+            }
+            key = try!(visitor.next_key_seed(StringSeed(self.0.clone())));
+        }
+        Ok(AllocValue::Dict(entries))
+    }
+}
+
+
+// Reads the annotations of a value and the value itself, as passed by the
+// HiPack Deserializer.
+fn visit_annotated<'de, A, V>(alloc: A, mut visitor: V) -> ::std::result::Result<AllocValue<A>, V::Error>
+    where A: Allocator + Clone, V: de::MapAccess<'de>
+{
+    let annotations = try!(visitor.next_value_seed(AnnotationsSeed(alloc.clone())));
+    let value = match try!(visitor.next_key_seed(StringSeed(alloc.clone()))) {
+        Some(ref key) if key.as_str() == VALUE_KEY => try!(visitor.next_value_seed(BareSeed(alloc.clone()))),
+        _ => return Err(de::Error::custom("expected annotated value")),
+    };
+    Ok(if annotations.is_empty() {
+        value
+    } else {
+        AllocValue::Annotated(annotations, Box::new_in(value, alloc))
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::ptr::NonNull;
+    use allocator_api2::alloc::{AllocError, Allocator, Global, Layout};
+    use ser::to_string;
+    use value::Value;
+    use super::*;
+
+    // Counts the bytes allocated through it.
+    struct Counting(Cell<usize>);
+
+    unsafe impl<'a> Allocator for &'a Counting {
+        fn allocate(&self, layout: Layout) -> ::std::result::Result<NonNull<[u8]>, AllocError> {
+            self.0.set(self.0.get() + layout.size());
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    #[test]
+    fn test_alloc_value() {
+        let input = "name: \"web\" ports [80 443] limits: {rate: :per-second 1.5} name: \"api\"";
+        let counting = Counting(Cell::new(0));
+        let value = from_str_in(input, &counting).unwrap();
+        assert!(counting.0.get() > 0);
+        assert_eq!(Some("api"), value.get("name").and_then(AllocValue::as_str));
+        match value.get("ports") {
+            Some(&AllocValue::List(ref ports)) => assert_eq!(2, ports.len()),
+            other => panic!("unexpected {:?}", other),
+        }
+        match value.get("limits").and_then(|limits| limits.get("rate")) {
+            Some(&AllocValue::Annotated(ref annotations, ref rate)) => {
+                assert_eq!("per-second", annotations[0].as_str());
+                assert_eq!(AllocValue::<Global>::F64(1.5), **rate);
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let expected: Value = ::de::from_str(input).unwrap();
+        assert_eq!(expected, value.to_value());
+        assert_eq!("{name:\"api\",ports:[80,443],limits:{rate::per-second 1.5}}", to_string(&value).unwrap());
+        assert_eq!(value, value.clone());
+        assert_eq!(value, from_str_in(input, Global).unwrap());
+    }
+}
@@ -0,0 +1,317 @@
+//
+// builder.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use std::collections::BTreeMap;
+use error::{Error, ErrorCode, Result};
+use schema::{Field, Schema};
+use ser::check_key;
+use super::{Map, Value};
+
+
+/// Builds a dictionary `Value` one entry at a time:
+///
+/// ```
+/// # use serde_hipack::value::{DictBuilder, ListBuilder};
+/// let config = DictBuilder::new()
+///     .insert("name", "web")
+///     .insert("ports", ListBuilder::new().push(80).push(443))
+///     .build();
+/// assert_eq!(config["ports"][1].as_u64(), Some(443));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct DictBuilder {
+    entries: Map,
+    annotations: Vec<String>,
+}
+
+
+impl DictBuilder {
+    #[inline]
+    pub fn new() -> Self {
+        DictBuilder::default()
+    }
+
+    /// Adds an entry, replacing any previous one with the same key.
+    pub fn insert<K: Into<String>, V: Into<Value>>(mut self, key: K, value: V) -> Self {
+        self.entries.insert(key.into(), value.into());
+        self
+    }
+
+    /// Adds an annotation to the dictionary.
+    pub fn annotation<S: Into<String>>(mut self, name: S) -> Self {
+        self.annotations.push(name.into());
+        self
+    }
+
+    #[inline]
+    pub fn build(self) -> Value {
+        annotate(self.annotations, Value::Dict(self.entries))
+    }
+}
+
+
+/// Builds a list `Value` one item at a time, see `DictBuilder`.
+#[derive(Clone, Debug, Default)]
+pub struct ListBuilder {
+    items: Vec<Value>,
+    annotations: Vec<String>,
+}
+
+
+impl ListBuilder {
+    #[inline]
+    pub fn new() -> Self {
+        ListBuilder::default()
+    }
+
+    pub fn push<V: Into<Value>>(mut self, item: V) -> Self {
+        self.items.push(item.into());
+        self
+    }
+
+    /// Adds an annotation to the list.
+    pub fn annotation<S: Into<String>>(mut self, name: S) -> Self {
+        self.annotations.push(name.into());
+        self
+    }
+
+    #[inline]
+    pub fn build(self) -> Value {
+        annotate(self.annotations, Value::List(self.items))
+    }
+}
+
+
+impl From<DictBuilder> for Value {
+    #[inline]
+    fn from(builder: DictBuilder) -> Value {
+        builder.build()
+    }
+}
+
+
+impl From<ListBuilder> for Value {
+    #[inline]
+    fn from(builder: ListBuilder) -> Value {
+        builder.build()
+    }
+}
+
+
+/// Builds a document one top-level entry at a time, like `DictBuilder`,
+/// but checks each entry as it is inserted: keys must be valid HiPack
+/// keys, and entries must conform to the schema, if one is given. Errors
+/// have the path of the offending key or value, e.g. `servers[2].port`,
+/// so problems are found where the document is built instead of when it
+/// is serialized:
+///
+/// ```
+/// # use serde_hipack::error::Result;
+/// # use serde_hipack::value::{DictBuilder, DocumentBuilder};
+/// # use serde_hipack::Schema;
+/// # fn run() -> Result<()> {
+/// # let sample = DictBuilder::new().insert("name", "db").insert("ports", vec![5432]).build();
+/// # let schema = Schema::infer(&sample);
+/// let mut builder = DocumentBuilder::new().schema(schema);
+/// try!(builder.insert("name", "web"));
+/// try!(builder.insert("ports", vec![80, 443]));
+/// let config = try!(builder.build());
+/// assert!(DocumentBuilder::new().insert("no spaces", 1).is_err());
+/// # assert_eq!(config["name"].as_str(), Some("web"));
+/// # Ok(())
+/// # }
+/// # fn main() { run().unwrap() }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct DocumentBuilder {
+    entries: Map,
+    schema: Option<Schema>,
+}
+
+
+impl DocumentBuilder {
+    #[inline]
+    pub fn new() -> Self {
+        DocumentBuilder::default()
+    }
+
+    /// Checks entries against a schema, usually a `Schema::Dict`.
+    #[inline]
+    pub fn schema(mut self, schema: Schema) -> Self {
+        self.schema = Some(schema);
+        self
+    }
+
+    /// Adds an entry, replacing any previous one with the same key, once
+    /// it has been checked. Nothing is added when the check fails.
+    pub fn insert<V: Into<Value>>(&mut self, key: &str, value: V) -> Result<&mut Self> {
+        let value = value.into();
+        match self.schema {
+            Some(Schema::Dict(ref fields)) => try!(check_entry(Some(fields), key, &value, "")),
+            _ => try!(check_entry(None, key, &value, "")),
+        }
+        self.entries.insert(key.to_string(), value);
+        Ok(self)
+    }
+
+    /// Gives the document, once checked that it has all the entries which
+    /// the schema requires.
+    pub fn build(self) -> Result<Value> {
+        let value = Value::Dict(self.entries);
+        match self.schema {
+            Some(Schema::Dict(ref fields)) => try!(check_required(fields, &value, "")),
+            Some(ref schema) => try!(check(&value, Some(schema), "")),
+            None => (),
+        }
+        Ok(value)
+    }
+}
+
+
+fn error_at(path: &str, code: ErrorCode) -> Error {
+    let err = Error::SyntaxError(code, 0, 0, 0);
+    if path.is_empty() {
+        err
+    } else {
+        Error::AtPath(path.to_string(), Box::new(err))
+    }
+}
+
+
+fn mismatch(path: &str) -> Error {
+    error_at(path, ErrorCode::InvalidValue("Value does not match the schema".to_string()))
+}
+
+
+fn check_entry(fields: Option<&BTreeMap<String, Field>>, key: &str, value: &Value, path: &str)
+    -> Result<()>
+{
+    let path = if path.is_empty() { key.to_string() } else { format!("{}.{}", path, key) };
+    if let Err(err) = check_key(key) {
+        return Err(Error::AtPath(path, Box::new(err)));
+    }
+    match fields.map(|fields| fields.get(key)) {
+        Some(Some(field)) => check(value, Some(&field.schema), &path),
+        Some(None) => Err(error_at(&path, ErrorCode::UnknownField(key.to_string()))),
+        None => check(value, None, &path),
+    }
+}
+
+
+fn check_required(fields: &BTreeMap<String, Field>, value: &Value, path: &str) -> Result<()> {
+    match fields.iter().find(|&(key, field)| !field.optional && value.get(key).is_none()) {
+        Some((key, _)) => {
+            Err(error_at(path, ErrorCode::InvalidValue(format!("Missing field \"{}\"", key))))
+        },
+        None => Ok(()),
+    }
+}
+
+
+// Checks the keys of the dictionaries in a value, and the value against a
+// schema, descending into dictionaries and lists to find which part of
+// the value does not conform to it.
+fn check(value: &Value, schema: Option<&Schema>, path: &str) -> Result<()> {
+    let schema = match schema {
+        Some(&Schema::Any) => None,
+        Some(schema @ &Schema::Union(_)) => {
+            try!(check(value, None, path));
+            return if schema.matches(value) { Ok(()) } else { Err(mismatch(path)) };
+        },
+        schema => schema,
+    };
+    match (value, schema) {
+        (&Value::Annotated(_, ref value), schema) => check(value, schema, path),
+        (&Value::Dict(ref entries), Some(&Schema::Dict(ref fields))) => {
+            for (key, value) in entries {
+                try!(check_entry(Some(fields), key, value, path));
+            }
+            check_required(fields, value, path)
+        },
+        (&Value::Dict(ref entries), None) => {
+            for (key, value) in entries {
+                try!(check_entry(None, key, value, path));
+            }
+            Ok(())
+        },
+        (&Value::List(ref items), Some(&Schema::List(ref schema))) => {
+            for (index, item) in items.iter().enumerate() {
+                try!(check(item, Some(schema), &format!("{}[{}]", path, index)));
+            }
+            Ok(())
+        },
+        (&Value::List(ref items), None) => {
+            for (index, item) in items.iter().enumerate() {
+                try!(check(item, None, &format!("{}[{}]", path, index)));
+            }
+            Ok(())
+        },
+        (value, Some(schema)) if !schema.matches(value) => Err(mismatch(path)),
+        _ => Ok(()),
+    }
+}
+
+
+fn annotate(annotations: Vec<String>, value: Value) -> Value {
+    if annotations.is_empty() {
+        value
+    } else {
+        Value::Annotated(annotations, Box::new(value))
+    }
+}
+
+
+#[cfg(all(test, feature = "de"))]
+mod tests {
+    use super::*;
+    use de::from_str;
+    use error::Error;
+    use schema::Schema;
+
+    #[test]
+    fn test_builders() {
+        let value = DictBuilder::new()
+            .insert("name", "web")
+            .insert("ports", ListBuilder::new().push(80).push(443u16).annotation("tcp"))
+            .insert("tls", DictBuilder::new().insert("enabled", true))
+            .insert("name", "db")
+            .build();
+        let expected: Value = from_str("name: \"db\" ports: :tcp [80 443] tls {enabled: True}").unwrap();
+        assert_eq!(expected, value);
+        assert_eq!(Value::List(Vec::new()), ListBuilder::new().build());
+    }
+
+    #[test]
+    fn test_document_builder() {
+        let example: Value = from_str("name: \"web\" servers [{host: \"a\" port: 80}]").unwrap();
+        let mut builder = DocumentBuilder::new().schema(Schema::infer(&example));
+        builder.insert("name", "api").unwrap();
+
+        let path = |err: Error| err.path().map(|path| path.to_string());
+        assert_eq!(Some("bad key".to_string()), path(builder.insert("bad key", 1).unwrap_err()));
+        assert_eq!(Some("version".to_string()), path(builder.insert("version", 1).unwrap_err()));
+        assert_eq!(Some("name".to_string()), path(builder.insert("name", 1).unwrap_err()));
+        let servers = ListBuilder::new()
+            .push(DictBuilder::new().insert("host", "a").insert("port", 80))
+            .push(DictBuilder::new().insert("host", "b").insert("port", "80"));
+        assert_eq!(Some("servers[1].port".to_string()),
+                   path(builder.insert("servers", servers).unwrap_err()));
+        let servers = ListBuilder::new().push(DictBuilder::new().insert("host", "a"));
+        assert_eq!(Some("servers[0]".to_string()),
+                   path(builder.insert("servers", servers).unwrap_err()));
+        assert!(builder.clone().build().is_err());
+
+        let servers = ListBuilder::new().push(DictBuilder::new().insert("host", "b").insert("port", 8080));
+        builder.insert("servers", servers).unwrap();
+        let expected: Value = from_str("name: \"api\" servers [{host: \"b\" port: 8080}]").unwrap();
+        assert_eq!(expected, builder.build().unwrap());
+
+        // Without a schema, only keys are checked.
+        let mut builder = DocumentBuilder::new();
+        let nested = DictBuilder::new().insert("a b", 1);
+        assert_eq!(Some("x.a b".to_string()), path(builder.insert("x", nested).unwrap_err()));
+    }
+}
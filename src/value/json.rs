@@ -0,0 +1,150 @@
+//
+// json.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use std::convert::TryFrom;
+use serde_json;
+use error::{Error, ErrorCode, Result};
+use super::{is_none_marker, Map, Value, NONE_ANNOTATION};
+
+
+/// Converts a JSON value, which always succeeds. HiPack has no null, so
+/// `null` becomes `:none {}`, see `NONE_ANNOTATION`, which is converted
+/// back to `null`. Keys of objects are kept as they are, and those which
+/// are not valid HiPack keys fail to be written unless a `KeyPolicy`
+/// says otherwise.
+impl From<serde_json::Value> for Value {
+    fn from(value: serde_json::Value) -> Value {
+        match value {
+            serde_json::Value::Null => {
+                Value::Annotated(vec![NONE_ANNOTATION.to_string()], Box::new(Value::Dict(Map::new())))
+            },
+            serde_json::Value::Bool(v) => Value::Bool(v),
+            serde_json::Value::Number(v) => {
+                if let Some(v) = v.as_u64() {
+                    Value::U64(v)
+                } else if let Some(v) = v.as_i64() {
+                    Value::I64(v)
+                } else {
+                    Value::F64(v.as_f64().unwrap_or(0.0))
+                }
+            },
+            serde_json::Value::String(v) => Value::String(v),
+            serde_json::Value::Array(items) => Value::List(items.into_iter().map(Value::from).collect()),
+            serde_json::Value::Object(entries) => {
+                Value::Dict(entries.into_iter().map(|(key, value)| (key, Value::from(value))).collect())
+            },
+        }
+    }
+}
+
+
+/// Converts a value to JSON. Annotations are left out, except for those
+/// of `:none {}`, which becomes `null`. Fails for floats which are `NaN`
+/// or infinite, and for integers outside of the range of `i64` and `u64`,
+/// which JSON cannot represent, with the path of the value in the error.
+impl TryFrom<Value> for serde_json::Value {
+    type Error = Error;
+
+    #[inline]
+    fn try_from(value: Value) -> Result<serde_json::Value> {
+        to_json(value, "")
+    }
+}
+
+
+fn to_json(value: Value, path: &str) -> Result<serde_json::Value> {
+    Ok(match value {
+        Value::Annotated(ref annotations, ref value) if is_none_marker(annotations, value) => {
+            serde_json::Value::Null
+        },
+        Value::Annotated(_, value) => return to_json(*value, path),
+        Value::Bool(v) => serde_json::Value::Bool(v),
+        Value::I64(v) => serde_json::Value::from(v),
+        Value::U64(v) => serde_json::Value::from(v),
+        Value::I128(_) | Value::U128(_) => {
+            return Err(unrepresentable(path, "Integer out of the range of JSON numbers"));
+        },
+        Value::F64(v) => match serde_json::Number::from_f64(v) {
+            Some(v) => serde_json::Value::Number(v),
+            None => return Err(unrepresentable(path, "NaN and infinite floats cannot be written as JSON")),
+        },
+        Value::Number(v) => return to_json(v.value(), path),
+        Value::String(v) => serde_json::Value::String(v),
+        Value::List(items) => {
+            let mut values = Vec::with_capacity(items.len());
+            for (index, item) in items.into_iter().enumerate() {
+                values.push(try!(to_json(item, &format!("{}[{}]", path, index))));
+            }
+            serde_json::Value::Array(values)
+        },
+        Value::Dict(entries) => {
+            let mut values = serde_json::Map::new();
+            for (key, value) in entries {
+                let value = if path.is_empty() {
+                    try!(to_json(value, &key))
+                } else {
+                    try!(to_json(value, &format!("{}.{}", path, key)))
+                };
+                values.insert(key, value);
+            }
+            serde_json::Value::Object(values)
+        },
+    })
+}
+
+
+fn unrepresentable(path: &str, msg: &str) -> Error {
+    let err = Error::SyntaxError(ErrorCode::InvalidValue(msg.to_string()), 0, 0, 0);
+    if path.is_empty() {
+        err
+    } else {
+        Error::AtPath(path.to_string(), Box::new(err))
+    }
+}
+
+
+#[cfg(all(test, feature = "de"))]
+mod tests {
+    use std::convert::TryFrom;
+    use std::f64::NAN;
+    use serde_json;
+    use de::from_str;
+    use serde::Deserialize;
+    use value::{Deserializer, Value};
+
+    #[test]
+    fn test_json() {
+        let json: serde_json::Value =
+            serde_json::from_str(r#"{"name": "web", "ports": [80, -1, 0.5], "tls": null,
+                                     "nested": {"on": true}}"#).unwrap();
+        let value = Value::from(json.clone());
+        assert_eq!(from_str::<Value>("name: \"web\" ports [80 -1 0.5] tls: :none {} \
+                                      nested {on: True}").unwrap(),
+                   value);
+        assert_eq!(json, serde_json::Value::try_from(value).unwrap());
+
+        let value: Value = from_str("a [1 {b: NaN}] c: :x 1 d: 0x10").unwrap();
+        let err = serde_json::Value::try_from(value).unwrap_err();
+        assert_eq!(Some("a[1].b"), err.path());
+        let value = Value::List(vec![Value::F64(NAN)]);
+        assert!(serde_json::Value::try_from(value).unwrap_err().path().is_some());
+
+        let value: Value = from_str("c: :x 1 d: 0x10").unwrap();
+        assert_eq!(serde_json::json!({"c": 1, "d": 16}), serde_json::Value::try_from(value).unwrap());
+    }
+
+    #[test]
+    fn test_json_null() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct S {
+            a: Option<u32>,
+            b: Vec<Option<u32>>,
+        }
+        let value = Value::from(serde_json::json!({"a": null, "b": [1, null]}));
+        assert_eq!(S { a: None, b: vec![Some(1), None] },
+                   S::deserialize(Deserializer::new(value)).unwrap());
+    }
+}
@@ -0,0 +1,210 @@
+//
+// number.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use std::{fmt, str};
+use serde::ser;
+use error::{Error, ErrorCode};
+use parser::{parse_number_token, Event};
+use ser::{serialize_number, write_f64, write_integer};
+use super::Value;
+
+
+/// How a number is written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum NumberKind {
+    /// An integer in decimal.
+    Integer,
+    /// A float, `NaN` or an infinity.
+    Float,
+    /// An integer in hexadecimal, e.g. `0xFF`.
+    Hex,
+    /// An integer in octal, e.g. `0755`.
+    Octal,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum N {
+    I64(i64),
+    U64(u64),
+    I128(i128),
+    U128(u128),
+    F64(f64),
+}
+
+
+/// A number along with its text as written in the input.
+///
+/// A `Value` read by the HiPack Deserializer holds a `Value::Number`
+/// instead of a plain number when writing it would change its text, e.g.
+/// `0xFF` would become `255`, `0755` would become `493`, and `1e3` would
+/// become `1000.0`, so documents round-trip as they were written. The
+/// Serializer writes the text as is, except in canonical form, which
+/// writes the number in decimal. Other serializers write the number.
+///
+/// Numbers are compared by their value, regardless of their text.
+#[derive(Clone, Debug)]
+pub struct Number {
+    text: String,
+    n: N,
+}
+
+
+impl Number {
+    /// How the number is written.
+    pub fn kind(&self) -> NumberKind {
+        let digits = self.text.trim_start_matches(|c| c == '-' || c == '+');
+        if digits.starts_with("0x") || digits.starts_with("0X") {
+            NumberKind::Hex
+        } else if let N::F64(_) = self.n {
+            NumberKind::Float
+        } else if digits.len() > 1 && digits.starts_with("0") {
+            NumberKind::Octal
+        } else {
+            NumberKind::Integer
+        }
+    }
+
+    /// The text of the number, as written.
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    #[inline]
+    pub fn into_string(self) -> String {
+        self.text
+    }
+
+    /// The number as a plain `Value`, i.e. without its text.
+    pub fn value(&self) -> Value {
+        match self.n {
+            N::I64(v) => Value::I64(v),
+            N::U64(v) => Value::U64(v),
+            N::I128(v) => Value::I128(v),
+            N::U128(v) => Value::U128(v),
+            N::F64(v) => Value::F64(v),
+        }
+    }
+
+    /// Whether the Serializer writes the plain number with the same text,
+    /// in which case there is nothing to preserve.
+    pub fn is_plain(&self) -> bool {
+        let mut text = Vec::new();
+        let result = match self.n {
+            N::I64(v) => write_integer(&mut text, v),
+            N::U64(v) => write_integer(&mut text, v),
+            N::I128(v) => write_integer(&mut text, v),
+            N::U128(v) => write_integer(&mut text, v),
+            N::F64(v) => write_f64(&mut text, v),
+        };
+        result.is_ok() && text == self.text.as_bytes()
+    }
+}
+
+
+impl PartialEq for Number {
+    #[inline]
+    fn eq(&self, other: &Number) -> bool {
+        self.value() == other.value()
+    }
+}
+
+
+impl str::FromStr for Number {
+    type Err = Error;
+
+    /// Parses a number written as in HiPack text.
+    fn from_str(text: &str) -> Result<Number, Error> {
+        let n = match parse_number_token(text) {
+            Some(Event::I64(v)) => N::I64(v),
+            Some(Event::U64(v)) => N::U64(v),
+            Some(Event::I128(v)) => N::I128(v),
+            Some(Event::U128(v)) => N::U128(v),
+            Some(Event::F64(v)) => N::F64(v),
+            _ => return Err(Error::SyntaxError(ErrorCode::InvalidNumber, 0, 0, 0)),
+        };
+        Ok(Number { text: text.to_string(), n: n })
+    }
+}
+
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+
+/// Gives the plain number when there is nothing to preserve of its text.
+impl From<Number> for Value {
+    fn from(number: Number) -> Value {
+        if number.is_plain() {
+            number.value()
+        } else {
+            Value::Number(number)
+        }
+    }
+}
+
+
+impl ser::Serialize for Number {
+    fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+        where S: ser::Serializer
+    {
+        serialize_number(serializer, &self.text, &self.value())
+    }
+}
+
+
+#[cfg(all(test, feature = "de"))]
+mod tests {
+    use super::{Number, NumberKind};
+    use de::from_str;
+    use ser::{to_string, to_string_canonical};
+    use value::{Deserializer, Value};
+    use serde::Deserialize;
+
+    #[test]
+    fn test_number() {
+        let number: Number = "0xFF".parse().unwrap();
+        assert_eq!(NumberKind::Hex, number.kind());
+        assert_eq!(Some(255), Value::Number(number).as_u64());
+        assert_eq!(NumberKind::Octal, "-0755".parse::<Number>().unwrap().kind());
+        assert_eq!(NumberKind::Float, "1e3".parse::<Number>().unwrap().kind());
+        assert_eq!(NumberKind::Integer, "0".parse::<Number>().unwrap().kind());
+        assert!("0xZZ".parse::<Number>().is_err());
+
+        assert_eq!(Value::U64(12), Value::from("12".parse::<Number>().unwrap()));
+        assert_eq!(Value::F64(1.5), Value::from("1.5".parse::<Number>().unwrap()));
+        assert!(Value::from("+12".parse::<Number>().unwrap()).is_number());
+        assert_eq!(Value::F64(1e20), Value::from("1e20".parse::<Number>().unwrap()));
+        assert_eq!(Value::F64(-1.5e-7), Value::from("-1.5e-7".parse::<Number>().unwrap()));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let text = "{a:0xFF,b:[0755,1e3,2.50],c:12,d:1.5}";
+        let value: Value = from_str(text).unwrap();
+        assert_eq!(Value::U64(12), value["c"]);
+        assert_eq!(Value::F64(1.5), value["d"]);
+        assert_eq!(Some(255), value["a"].as_u64());
+        assert_eq!(Some(1000.0), value["b"][1].as_f64());
+        assert_eq!(text, to_string(&value).unwrap());
+        assert_eq!("{a:255,b:[493,1000.0,2.5],c:12,d:1.5}", to_string_canonical(&value).unwrap());
+
+        // Plain floats which would be written with an exponent.
+        let floats = "{large:100000000000000000000.0,small:0.0000001}";
+        let plain: Value = from_str(floats).unwrap();
+        assert_eq!(floats, to_string(&plain).unwrap());
+        assert_eq!("{large:1e20,small:1e-7}", to_string_canonical(&plain).unwrap());
+
+        let copy: Value = Value::deserialize(Deserializer::new(value.clone())).unwrap();
+        assert_eq!(value, copy);
+        let port: u8 = u8::deserialize(Deserializer::new(value["a"].clone())).unwrap();
+        assert_eq!(255, port);
+    }
+}
@@ -0,0 +1,299 @@
+//
+// raw.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use std::fmt;
+use serde::{de, ser};
+use error::{Error, ErrorCode, Result};
+use parser::{Event, Parser};
+use ser::{serialize_raw, to_string, RAW_VALUE_NAME};
+use super::Value;
+
+
+/// A value kept as HiPack text, without parsing it into a data structure,
+/// e.g. to pass parts of a message along as they are:
+///
+/// ```text
+/// #[derive(Serialize, Deserialize)]
+/// struct Envelope {
+///     to: String,
+///     payload: RawValue,
+/// }
+/// ```
+///
+/// The HiPack Deserializer keeps the text of the value as written in the
+/// input, including comments and annotations but not the whitespace
+/// around it, skipping over it byte by byte: it only checks that strings
+/// are terminated and delimiters are balanced. Handlers of annotations
+/// do not see the value. Values which are not read straight from HiPack
+/// text (e.g. the whole message, or a value from a `Value`) are written
+/// back in compact form. The Serializer writes the text as is, other
+/// serializers write it as a string.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RawValue {
+    text: String,
+}
+
+
+impl RawValue {
+    /// Wraps the text of a value, checking that it is a single valid
+    /// value. Whitespace around it is removed.
+    pub fn from_string(text: String) -> Result<RawValue> {
+        let text = if text.trim() == text { text } else { text.trim().to_string() };
+        try!(check_value(&text));
+        Ok(RawValue { text: text })
+    }
+
+    /// Writes a value in compact form.
+    pub fn from_value<T: ser::Serialize>(value: &T) -> Result<RawValue> {
+        Ok(RawValue { text: try!(to_string(value)) })
+    }
+
+    #[inline]
+    pub fn get(&self) -> &str {
+        &self.text
+    }
+
+    #[inline]
+    pub fn into_string(self) -> String {
+        self.text
+    }
+}
+
+
+impl fmt::Display for RawValue {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+
+/// A value kept as HiPack text borrowed from the input, like `RawValue`
+/// but without copying it. Only the HiPack Deserializer reading from a
+/// slice or a string, e.g. with `de::from_str()`, can give the text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BorrowedRawValue<'a> {
+    text: &'a str,
+}
+
+
+impl<'a> BorrowedRawValue<'a> {
+    #[inline]
+    pub fn get(&self) -> &'a str {
+        self.text
+    }
+
+    #[inline]
+    pub fn to_raw_value(&self) -> RawValue {
+        RawValue { text: self.text.to_string() }
+    }
+}
+
+
+impl<'a> fmt::Display for BorrowedRawValue<'a> {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.text)
+    }
+}
+
+
+// Parses the text as the value of a dictionary entry, which is the only
+// entry. Errors are reported at their position in the text.
+fn check_value(text: &str) -> Result<()> {
+    const PREFIX: &'static [u8] = b"{v:";
+    let input = PREFIX.iter().chain(text.as_bytes()).chain(b"}").map(|&ch| Ok(ch));
+    let mut parser = Parser::new(input);
+    let mut depth = 0;
+    let mut keys = 0;
+    loop {
+        match parser.next_event() {
+            Ok(Some(Event::DictStart)) | Ok(Some(Event::ListStart)) => depth += 1,
+            Ok(Some(Event::DictEnd)) | Ok(Some(Event::ListEnd)) => depth -= 1,
+            Ok(Some(Event::Key(_))) if depth == 1 => keys += 1,
+            Ok(Some(_)) => (),
+            Ok(None) if keys == 1 => return Ok(()),
+            Ok(None) => return Err(Error::SyntaxError(ErrorCode::TrailingCharacters, 0, 0, 0)),
+            Err(Error::SyntaxError(code, offset, line, column)) => {
+                let offset = offset.saturating_sub(PREFIX.len()).min(text.len());
+                let column = if line == 1 { column.saturating_sub(PREFIX.len()).max(1) } else { column };
+                return Err(Error::SyntaxError(code, offset, line, column));
+            },
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+
+impl ser::Serialize for RawValue {
+    #[inline]
+    fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+        where S: ser::Serializer
+    {
+        serialize_raw(serializer, &self.text)
+    }
+}
+
+
+impl<'de> de::Deserialize<'de> for RawValue {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> ::std::result::Result<RawValue, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        deserializer.deserialize_newtype_struct(RAW_VALUE_NAME, RawValueVisitor)
+    }
+}
+
+
+impl<'a> ser::Serialize for BorrowedRawValue<'a> {
+    #[inline]
+    fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+        where S: ser::Serializer
+    {
+        serialize_raw(serializer, self.text)
+    }
+}
+
+
+impl<'de: 'a, 'a> de::Deserialize<'de> for BorrowedRawValue<'a> {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> ::std::result::Result<BorrowedRawValue<'a>, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        deserializer.deserialize_newtype_struct(RAW_VALUE_NAME, BorrowedRawValueVisitor)
+    }
+}
+
+
+struct BorrowedRawValueVisitor;
+
+
+impl<'de> de::Visitor<'de> for BorrowedRawValueVisitor {
+    type Value = BorrowedRawValue<'de>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("HiPack text borrowed from the input")
+    }
+
+    #[inline]
+    fn visit_borrowed_str<E>(self, v: &'de str) -> ::std::result::Result<BorrowedRawValue<'de>, E> {
+        Ok(BorrowedRawValue { text: v })
+    }
+}
+
+
+struct RawValueVisitor;
+
+
+impl<'de> de::Visitor<'de> for RawValueVisitor {
+    type Value = RawValue;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("any HiPack value")
+    }
+
+    // The HiPack deserializers pass the text of the value.
+    #[inline]
+    fn visit_string<E>(self, v: String) -> ::std::result::Result<RawValue, E> {
+        Ok(RawValue { text: v })
+    }
+
+    #[inline]
+    fn visit_str<E>(self, v: &str) -> ::std::result::Result<RawValue, E> {
+        Ok(RawValue { text: v.to_string() })
+    }
+
+    // Used by deserializers for other formats.
+    fn visit_newtype_struct<D>(self, deserializer: D) -> ::std::result::Result<RawValue, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        let value: Value = try!(de::Deserialize::deserialize(deserializer));
+        RawValue::from_value(&value).map_err(de::Error::custom)
+    }
+}
+
+
+#[cfg(all(test, feature = "de"))]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use de::from_str;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Envelope {
+        to: String,
+        payload: RawValue,
+        items: Vec<RawValue>,
+    }
+
+    #[test]
+    fn test_raw_value() {
+        let input = "to: \"a\"\npayload: :x {b: 0x1F  # Comment\n c: [1, 2]}\nitems [ 07, \"s\" # Last\n ]";
+        let envelope: Envelope = from_str(input).unwrap();
+        assert_eq!("a", envelope.to);
+        assert_eq!(":x {b: 0x1F  # Comment\n c: [1, 2]}", envelope.payload.get());
+        assert_eq!(vec!["07", "\"s\""], envelope.items.iter().map(RawValue::get).collect::<Vec<_>>());
+        let output = ::ser::to_string(&envelope).unwrap();
+        assert_eq!("{to:\"a\",payload::x {b: 0x1F  # Comment\n c: [1, 2]},items:[07,\"s\"]}", output);
+        let value: Value = from_str(&output).unwrap();
+        assert_eq!(Some(31), value.pointer("/payload/b").and_then(Value::as_u64));
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct BorrowedEnvelope<'a> {
+        #[serde(borrow)]
+        payload: BorrowedRawValue<'a>,
+        items: Vec<BorrowedRawValue<'a>>,
+    }
+
+    #[test]
+    fn test_borrowed_raw_value() {
+        let input = "payload: {a: \"]\" # }\n b: [\"\\\"\"]}\nitems [:x 1.5e3 True]";
+        let envelope: BorrowedEnvelope = from_str(input).unwrap();
+        assert_eq!("{a: \"]\" # }\n b: [\"\\\"\"]}", envelope.payload.get());
+        assert_eq!(vec![":x 1.5e3", "True"], envelope.items.iter().map(|item| item.get()).collect::<Vec<_>>());
+        let output = ::ser::to_string(&envelope).unwrap();
+        assert_eq!("{payload:{a: \"]\" # }\n b: [\"\\\"\"]},items:[:x 1.5e3,True]}", output);
+        // Readers copy the text, so there is nothing to borrow.
+        let mut deserializer = ::de::Deserializer::new("payload: 1 items []".bytes().map(Ok));
+        let result: ::std::result::Result<BorrowedEnvelope, _> = de::Deserialize::deserialize(&mut deserializer);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_raw_value_errors() {
+        for input in &["payload: [1 }", "payload: {a: \"}", "payload: ]", "payload: {a: [1}]",
+                       "payload: :x", "payload: [1 2"] {
+            assert!(from_str::<BTreeMap<String, RawValue>>(input).is_err(), "{:?}", input);
+        }
+        let raw: BTreeMap<String, RawValue> = from_str("a: [[1] {b: 2}], c: 3").unwrap();
+        assert_eq!("[[1] {b: 2}]", raw["a"].get());
+        assert_eq!("3", raw["c"].get());
+    }
+
+    #[test]
+    fn test_raw_value_fallback() {
+        let raw: BTreeMap<String, RawValue> = from_str("a: 1").unwrap();
+        assert_eq!("1", raw["a"].get());
+        let raw: RawValue = from_str("a: [1 {b: True}]").unwrap();
+        assert_eq!("{a:[1,{b:True}]}", raw.get());
+        let value: Value = from_str("a: :x 1").unwrap();
+        let raw: RawValue = de::Deserialize::deserialize(::value::Deserializer::new(value)).unwrap();
+        assert_eq!("{a::x 1}", raw.get());
+    }
+
+    #[test]
+    fn test_from_string() {
+        assert_eq!(":x [1 2]", RawValue::from_string(" :x [1 2]\n".to_string()).unwrap().get());
+        for input in &["", "1 2", "1, b: 2", "[1", "1 # Comment", "}"] {
+            assert!(RawValue::from_string(input.to_string()).is_err(), "{:?}", input);
+        }
+        match RawValue::from_string("[1 }".to_string()) {
+            Err(Error::SyntaxError(ErrorCode::MismatchedDelimiter, 3, 1, 4)) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//
+// web.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+//! Using HiPack as the format of web APIs.
+//!
+//! With the `axum` or the `actix` feature, `Hipack<T>` can be used in the
+//! handlers of the respective framework, both as an extractor which reads
+//! the body of requests with the `application/x-hipack` content type, and
+//! as a response, which is written in compact form:
+//!
+//! ```text
+//! async fn create(Hipack(server): Hipack<Server>) -> Hipack<Status> {
+//!     Hipack(start(server))
+//! }
+//! ```
+//!
+//! Bodies are read with the limits of the framework, e.g. `PayloadConfig`
+//! for actix.
+
+use std::{error, fmt};
+use std::future::Future;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use serde::de;
+use super::de::from_slice;
+use super::error::Error;
+use super::mime::MIME_TYPE;
+
+#[cfg(feature = "actix")]
+mod actix;
+#[cfg(feature = "axum")]
+mod axum;
+
+
+/// Extracts a value from the body of a request, or writes it as the body
+/// of a response, as HiPack.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Hipack<T>(pub T);
+
+
+impl<T> Hipack<T> {
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+
+impl<T> Deref for Hipack<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+
+impl<T> DerefMut for Hipack<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+
+/// Reason for `Hipack<T>` to reject a request, which is turned into a
+/// response with a matching status code.
+#[derive(Debug)]
+pub enum HipackRejection {
+    /// The request does not have the HiPack content type (415).
+    UnsupportedMediaType,
+    /// The body could not be read, e.g. as it is too large, with the
+    /// status code and the message given by the framework.
+    ReadBody(u16, String),
+    /// The body is not a valid document for the expected type (400).
+    InvalidBody(Error),
+}
+
+
+impl HipackRejection {
+    /// Status code of the response for the rejection.
+    pub fn status(&self) -> u16 {
+        match *self {
+            HipackRejection::UnsupportedMediaType => 415,
+            HipackRejection::ReadBody(status, _) => status,
+            HipackRejection::InvalidBody(_) => 400,
+        }
+    }
+}
+
+
+impl fmt::Display for HipackRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HipackRejection::UnsupportedMediaType => {
+                write!(f, "Expected request with `Content-Type: {}`", MIME_TYPE)
+            },
+            HipackRejection::ReadBody(_, ref message) => f.write_str(message),
+            HipackRejection::InvalidBody(ref error) => write!(f, "Invalid HiPack body: {}", error),
+        }
+    }
+}
+
+
+impl error::Error for HipackRejection {
+    fn description(&self) -> &str {
+        match *self {
+            HipackRejection::UnsupportedMediaType => "unsupported media type",
+            HipackRejection::ReadBody(..) => "failed to read body",
+            HipackRejection::InvalidBody(_) => "invalid body",
+        }
+    }
+}
+
+
+/// Future returned by the `Hipack<T>` extractors, which parses the body
+/// of the request once it has been read.
+pub struct ParseBody<F, T> {
+    body: Pin<Box<F>>,
+    // Whether the request has the HiPack content type.
+    hipack: bool,
+    output: PhantomData<fn() -> T>,
+}
+
+
+impl<F, T> ParseBody<F, T> {
+    // Reads the body, unless the request is rejected for not having the
+    // HiPack content type.
+    fn new(hipack: bool, body: F) -> Self {
+        ParseBody { body: Box::pin(body), hipack: hipack, output: PhantomData }
+    }
+}
+
+
+impl<F, B, E, T> Future for ParseBody<F, T>
+    where F: Future<Output = Result<B, E>>,
+          B: AsRef<[u8]>,
+          E: Into<HipackRejection>,
+          T: de::DeserializeOwned
+{
+    type Output = Result<Hipack<T>, HipackRejection>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if !self.hipack {
+            return Poll::Ready(Err(HipackRejection::UnsupportedMediaType));
+        }
+        match self.body.as_mut().poll(cx) {
+            Poll::Ready(Ok(body)) => {
+                Poll::Ready(from_slice(body.as_ref()).map(Hipack).map_err(HipackRejection::InvalidBody))
+            },
+            Poll::Ready(Err(error)) => Poll::Ready(Err(error.into())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use tokio::runtime::Builder;
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Server {
+        host: String,
+        port: u16,
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        Builder::new_current_thread().build().unwrap().block_on(future)
+    }
+
+    #[cfg(feature = "axum")]
+    #[test]
+    fn test_axum() {
+        use axum::body::{Body, to_bytes};
+        use axum::extract::{FromRequest, Request};
+        use axum::response::IntoResponse;
+
+        let request = |content_type: &str, body: &'static str| {
+            Request::builder().header("content-type", content_type).body(Body::from(body)).unwrap()
+        };
+        let server = block_on(Hipack::<Server>::from_request(request(MIME_TYPE, "host: \"a\" port: 80"), &()));
+        assert_eq!(Server { host: "a".to_string(), port: 80 }, server.unwrap().into_inner());
+        match block_on(Hipack::<Server>::from_request(request("application/json", "{}"), &())) {
+            Err(HipackRejection::UnsupportedMediaType) => (),
+            other => panic!("{:?}", other),
+        }
+        let rejection = block_on(Hipack::<Server>::from_request(request(MIME_TYPE, "host: 1"), &())).unwrap_err();
+        assert_eq!(400, rejection.status());
+        assert_eq!(400, rejection.into_response().status().as_u16());
+
+        let response = Hipack(Server { host: "b".to_string(), port: 8080 }).into_response();
+        assert_eq!(MIME_TYPE, response.headers()["content-type"]);
+        let body = block_on(to_bytes(response.into_body(), usize::max_value())).unwrap();
+        assert_eq!(&b"{host:\"b\",port:8080}"[..], &body[..]);
+    }
+
+    #[cfg(feature = "actix")]
+    #[test]
+    fn test_actix() {
+        use actix_web::{FromRequest, Responder, ResponseError};
+        use actix_web::body::to_bytes;
+        use actix_web::test::TestRequest;
+
+        let extract = |content_type: &str, body: &'static str| {
+            let (request, mut payload) = TestRequest::default()
+                .insert_header(("content-type", content_type))
+                .set_payload(body)
+                .to_http_parts();
+            block_on(Hipack::<Server>::from_request(&request, &mut payload))
+        };
+        assert_eq!(Server { host: "a".to_string(), port: 80 }, extract(MIME_TYPE, "host: \"a\" port: 80").unwrap().0);
+        let rejection = extract("text/plain", "host: \"a\" port: 80").unwrap_err();
+        assert_eq!(415, rejection.error_response().status().as_u16());
+        assert_eq!(400, extract(MIME_TYPE, "port: 80").unwrap_err().status());
+
+        let request = TestRequest::default().to_http_request();
+        let response = Hipack(Server { host: "b".to_string(), port: 8080 }).respond_to(&request);
+        assert_eq!(MIME_TYPE, response.headers().get("content-type").unwrap());
+        let body = block_on(to_bytes(response.into_body())).unwrap();
+        assert_eq!(&b"{host:\"b\",port:8080}"[..], &body[..]);
+    }
+}
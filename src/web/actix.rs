@@ -0,0 +1,51 @@
+//
+// actix.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use actix_web::{Error as ActixError, FromRequest, HttpMessage, HttpRequest, HttpResponse, Responder, ResponseError};
+use actix_web::body::BoxBody;
+use actix_web::dev::Payload;
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use serde::{de, ser};
+use super::{Hipack, HipackRejection, ParseBody};
+use super::super::mime::{MIME_TYPE, is_hipack_media_type};
+use super::super::ser::to_vec;
+
+
+impl<T: de::DeserializeOwned> FromRequest for Hipack<T> {
+    type Error = HipackRejection;
+    type Future = ParseBody<<Bytes as FromRequest>::Future, T>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        ParseBody::new(is_hipack_media_type(req.content_type()), Bytes::from_request(req, payload))
+    }
+}
+
+
+impl<T: ser::Serialize> Responder for Hipack<T> {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse {
+        match to_vec(&self.0) {
+            Ok(body) => HttpResponse::Ok().content_type(MIME_TYPE).body(body),
+            Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+        }
+    }
+}
+
+
+impl ResponseError for HipackRejection {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.status()).unwrap_or(StatusCode::BAD_REQUEST)
+    }
+}
+
+
+impl From<ActixError> for HipackRejection {
+    fn from(error: ActixError) -> Self {
+        HipackRejection::ReadBody(error.as_response_error().status_code().as_u16(), error.to_string())
+    }
+}
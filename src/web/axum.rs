@@ -0,0 +1,56 @@
+//
+// axum.rs
+// Copyright (C) 2015 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use std::future::Future;
+use axum::body::Bytes;
+use axum::extract::{FromRequest, Request};
+use axum::extract::rejection::BytesRejection;
+use axum::http::{HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use serde::{de, ser};
+use super::{Hipack, HipackRejection, ParseBody};
+use super::super::mime::{MIME_TYPE, is_hipack_media_type};
+use super::super::ser::to_vec;
+
+
+impl<T, S> FromRequest<S> for Hipack<T>
+    where T: de::DeserializeOwned,
+          S: Send + Sync
+{
+    type Rejection = HipackRejection;
+
+    fn from_request(req: Request, state: &S) -> impl Future<Output = Result<Self, HipackRejection>> + Send {
+        let hipack = req.headers().get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map_or(false, is_hipack_media_type);
+        ParseBody::new(hipack, Bytes::from_request(req, state))
+    }
+}
+
+
+impl<T: ser::Serialize> IntoResponse for Hipack<T> {
+    fn into_response(self) -> Response {
+        match to_vec(&self.0) {
+            Ok(body) => ([(header::CONTENT_TYPE, HeaderValue::from_static(MIME_TYPE))], body).into_response(),
+            Err(error) => (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()).into_response(),
+        }
+    }
+}
+
+
+impl IntoResponse for HipackRejection {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.status()).unwrap_or(StatusCode::BAD_REQUEST);
+        (status, self.to_string()).into_response()
+    }
+}
+
+
+impl From<BytesRejection> for HipackRejection {
+    fn from(rejection: BytesRejection) -> Self {
+        HipackRejection::ReadBody(rejection.status().as_u16(), rejection.body_text())
+    }
+}